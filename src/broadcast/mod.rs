@@ -0,0 +1,130 @@
+use core::fmt::Write;
+
+use defmt::*;
+use embassy_net::{
+    IpEndpoint, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::String;
+
+use crate::{
+    led_orchestrator::{LightState, light_state},
+    network::{AddressSource, address_source},
+    stream::Color,
+};
+
+/// Length of the longest status, e.g.
+/// `{"on":false,"brightness":255,"color":[255,255,255],"address":"link_local","uptime":18446744073709551615}`
+pub const MAX_STATUS_LENGTH: usize = 112;
+
+pub struct BroadcastOptions {
+    /// Broadcast or multicast address and port the status is sent to
+    pub target: IpEndpoint,
+    pub interval: Duration,
+}
+
+/// Sends the status of the device as a single UDP datagram every interval, so it can be monitored
+/// without a broker. Nothing is received.
+#[embassy_executor::task]
+pub async fn status_broadcast_task(stack: Stack<'static>, options: BroadcastOptions) -> ! {
+    let mut rx_metadata = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 1];
+    let mut tx_metadata = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; MAX_STATUS_LENGTH];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_metadata,
+        &mut rx_buffer,
+        &mut tx_metadata,
+        &mut tx_buffer,
+    );
+    // Any local port will do
+    unwrap!(socket.bind(0));
+
+    let mut ticker = Ticker::every(options.interval);
+    loop {
+        ticker.next().await;
+
+        let Ok(status) = status_json(light_state(), address_source(), Instant::now().as_secs())
+        else {
+            warn!("Status exceeds the broadcast buffer");
+            continue;
+        };
+        if let Err(err) = socket.send_to(status.as_bytes(), options.target).await {
+            warn!("Failed to broadcast the status: {}", err);
+        }
+    }
+}
+
+/// `{"on":<bool>,"brightness":<0-255>,"color":[<r>,<g>,<b>],"address":<source>,"uptime":<seconds>}`.
+/// The light fields are `null` until the light state has been restored, and so is the color if
+/// the effect is shown in its own colors. The address is `"dhcp"`, `"link_local"` or `"static"`.
+pub fn status_json(
+    light: Option<LightState>,
+    address: Option<AddressSource>,
+    uptime: u64,
+) -> Result<String<MAX_STATUS_LENGTH>, core::fmt::Error> {
+    let mut json = String::new();
+    match light {
+        Some(light) => {
+            core::write!(
+                json,
+                r#"{{"on":{},"brightness":{},"#,
+                light.on,
+                light.brightness
+            )?;
+            match light.color {
+                Some(Color(r, g, b)) => core::write!(json, r#""color":[{r},{g},{b}],"#)?,
+                None => core::write!(json, r#""color":null,"#)?,
+            }
+        }
+        None => core::write!(json, r#"{{"on":null,"brightness":null,"color":null,"#)?,
+    }
+    match address {
+        Some(address) => core::write!(json, r#""address":"{}","#, address.as_str())?,
+        None => core::write!(json, r#""address":null,"#)?,
+    }
+    core::write!(json, r#""uptime":{uptime}}}"#)?;
+
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::status_json;
+    use crate::{led_orchestrator::LightState, network::AddressSource, stream::Color};
+
+    #[test]
+    fn status_reports_the_light_and_address() {
+        let light = LightState {
+            on: true,
+            brightness: 128,
+            color: Some(Color(255, 0, 64)),
+        };
+
+        assert_eq!(
+            status_json(Some(light), Some(AddressSource::LinkLocal), 3600).unwrap(),
+            r#"{"on":true,"brightness":128,"color":[255,0,64],"address":"link_local","uptime":3600}"#
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_null() {
+        assert_eq!(
+            status_json(None, None, 0).unwrap(),
+            r#"{"on":null,"brightness":null,"color":null,"address":null,"uptime":0}"#
+        );
+    }
+
+    #[test]
+    fn longest_status_fits() {
+        let light = LightState {
+            on: false,
+            brightness: 255,
+            color: Some(Color(255, 255, 255)),
+        };
+
+        assert!(status_json(Some(light), Some(AddressSource::LinkLocal), u64::MAX).is_ok());
+    }
+}
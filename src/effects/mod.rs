@@ -0,0 +1,225 @@
+use embassy_time::Duration;
+use heapless::Vec;
+
+use crate::stream::{Color, Hz, MixMode, StreamConfig, TimingGroup};
+
+/// Maximum number of streams an effect can consist of.
+///
+/// Every stream slot costs `size_of::<StreamConfig>()` (32 bytes) of RAM in the active
+/// configuration, whether it is used or not. Calculating a step also takes time proportional to
+/// the number of streams actually used.
+pub const MAX_STREAMS: usize = 8;
+
+/// All selectable effects, in the order they are presented to users
+pub const EFFECTS: [Effect; 5] = [
+    Effect::Droplets,
+    Effect::Chase {
+        period: Duration::from_secs(3),
+    },
+    Effect::Confetti { seed: 0 },
+    Effect::Sweep {
+        period: Duration::from_secs(4),
+    },
+    Effect::Solid,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Effect {
+    /// Three colors strobing at slightly different frequencies, making droplets appear to float
+    Droplets,
+    /// Red, green and blue light up one after another, mixing where they overlap. `period` is the
+    /// time until the sequence repeats.
+    Chase { period: Duration },
+    /// Short flashes in random colors, from streams at unrelated frequencies. The colors repeat
+    /// for the same `seed`.
+    Confetti { seed: u16 },
+    /// Fades from orange to blue in `GRADIENT_STEPS` steps over `period`, then starts over
+    Sweep { period: Duration },
+    /// A steady white light, or the color of the light state
+    Solid,
+}
+
+impl Effect {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Effect::Droplets => "Droplets",
+            Effect::Chase { .. } => "Chase",
+            Effect::Confetti { .. } => "Confetti",
+            Effect::Sweep { .. } => "Sweep",
+            Effect::Solid => "Solid",
+        }
+    }
+
+    /// Brightness the light is set to when switching to the effect, `None` keeps the current
+    /// one
+    pub fn default_brightness(&self) -> Option<u8> {
+        match self {
+            // The flashes are too short to be seen dimmed
+            Effect::Confetti { .. } => Some(u8::MAX),
+            Effect::Droplets | Effect::Chase { .. } | Effect::Sweep { .. } | Effect::Solid => None,
+        }
+    }
+
+    /// How the streams are combined where they overlap
+    pub fn mix_mode(&self) -> MixMode {
+        match self {
+            // Adding up overlapping flashes of random colors would wash them out to white
+            Effect::Confetti { .. } => MixMode::Max,
+            Effect::Droplets | Effect::Chase { .. } | Effect::Sweep { .. } | Effect::Solid => {
+                MixMode::Additive
+            }
+        }
+    }
+
+    pub fn streams(&self) -> Vec<StreamConfig, MAX_STREAMS> {
+        match *self {
+            Effect::Droplets => [
+                StreamConfig::new(Color(255, 0, 0), Hz(60.), Duration::from_millis(3), None),
+                StreamConfig::new(
+                    Color(0, 255, 255),
+                    Hz(60.5),
+                    Duration::from_millis(3),
+                    Some(Duration::from_millis(500)),
+                ),
+                StreamConfig::new(
+                    Color(0, 255, 00),
+                    Hz(59.5),
+                    Duration::from_millis(3),
+                    Some(Duration::from_millis(2500)),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            Effect::Chase { period } => {
+                let group = TimingGroup::new(Hz(1e6 / period.as_micros() as f32), None);
+                // Every color is on for half the period and offset by a third of it, so each one
+                // overlaps with its neighbours
+                let stream = |color, index| group.stream(color, period / 2, period * index / 3);
+
+                [
+                    stream(Color(255, 0, 0), 0),
+                    stream(Color(0, 255, 0), 1),
+                    stream(Color(0, 0, 255), 2),
+                ]
+                .into_iter()
+                .collect()
+            }
+            Effect::Confetti { seed } => {
+                let stream = |frequency, index| {
+                    StreamConfig::new(
+                        Color(255, 255, 255),
+                        Hz(frequency),
+                        Duration::from_millis(20),
+                        None,
+                    )
+                    .with_random_colors(seed.wrapping_add(index))
+                };
+
+                [stream(7., 0), stream(11., 1), stream(13., 2)]
+                    .into_iter()
+                    .collect()
+            }
+            Effect::Sweep { period } => [StreamConfig::new(
+                Color(255, 96, 0),
+                Hz(1e6 / period.as_micros() as f32),
+                period,
+                None,
+            )
+            .with_gradient(Color(0, 96, 255))]
+            .into_iter()
+            .collect(),
+            Effect::Solid => [StreamConfig::solid(Color(255, 255, 255))]
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::{Duration, Instant};
+
+    use super::{EFFECTS, Effect, MAX_STREAMS};
+    use crate::stream::{Color, Config, ConfigError, StreamConfig};
+
+    fn on(stream: &StreamConfig, at: Duration) -> bool {
+        stream.get_color_at_instant(Instant::MIN + at) != Color::black()
+    }
+
+    #[test]
+    fn chase_streams_are_offset_by_a_third() {
+        let period = Duration::from_secs(3);
+        let streams = Effect::Chase { period }.streams();
+        assert_eq!(streams.len(), 3);
+
+        let millis = Duration::from_millis;
+        for (index, stream) in streams.iter().enumerate() {
+            let start = period * index as u32 / 3;
+            let end = start + period / 2;
+
+            if index > 0 {
+                assert!(!on(stream, start - millis(1)));
+            }
+            assert!(on(stream, start));
+            assert!(on(stream, end - millis(1)));
+            assert!(!on(stream, end));
+            // And again a period later
+            assert!(on(stream, start + period));
+            assert!(!on(stream, end + period));
+        }
+    }
+
+    /// Colors of the first steps of every stream, from one change to the next
+    fn confetti_colors(seed: u16) -> std::vec::Vec<Color> {
+        Effect::Confetti { seed }
+            .streams()
+            .iter()
+            .flat_map(|stream| {
+                let mut time = stream.get_next_change_after(None);
+                (0..40).map(move |_| {
+                    let color = stream.get_color_at_instant(time);
+                    time = stream.get_next_change_after(Some(time));
+                    color
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn configs_hold_up_to_max_streams() {
+        let stream = StreamConfig::solid(Color(255, 0, 0));
+        let streams = [stream; MAX_STREAMS + 1];
+
+        assert!(Config::<MAX_STREAMS>::try_new(&streams[..MAX_STREAMS], 1, 0).is_ok());
+        assert_eq!(
+            Config::<MAX_STREAMS>::try_new(&streams, 1, 0).unwrap_err(),
+            ConfigError::TooManyStreams
+        );
+        // Every effect fits, with streams that are valid on their own
+        for effect in EFFECTS {
+            assert!(Config::<MAX_STREAMS>::try_new(&effect.streams(), 1, 0).is_ok());
+        }
+    }
+
+    #[test]
+    fn confetti_colors_repeat_for_the_same_seed() {
+        let colors = confetti_colors(7);
+
+        assert_eq!(colors, confetti_colors(7));
+        assert_ne!(colors, confetti_colors(8));
+        // Every other step is a flash, and they don't all share a color
+        assert!(
+            colors
+                .iter()
+                .step_by(2)
+                .all(|color| *color != Color::black())
+        );
+        assert!(
+            colors
+                .iter()
+                .skip(2)
+                .step_by(2)
+                .any(|color| *color != colors[0])
+        );
+    }
+}
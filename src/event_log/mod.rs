@@ -0,0 +1,164 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Instant;
+use heapless::{Deque, String};
+
+/// Number of events kept, older ones are dropped
+pub const MAX_EVENTS: usize = 32;
+
+/// Length of a line of the dump, longer messages are cut off
+const MAX_LINE_LENGTH: usize = 64;
+
+struct Event {
+    time: Instant,
+    message: &'static str,
+}
+
+/// The last `MAX_EVENTS` events, oldest first
+struct EventLog {
+    events: Deque<Event, MAX_EVENTS>,
+}
+
+impl EventLog {
+    const fn new() -> Self {
+        Self {
+            events: Deque::new(),
+        }
+    }
+
+    fn record(&mut self, time: Instant, message: &'static str) {
+        if self.events.is_full() {
+            self.events.pop_front();
+        }
+
+        _ = self.events.push_back(Event { time, message });
+    }
+
+    fn dump<const N: usize>(&self) -> String<N> {
+        let mut length = 0;
+        let fitting = self
+            .events
+            .iter()
+            .rev()
+            .take_while(|event| {
+                length += line(event).len();
+                length <= N
+            })
+            .count();
+
+        let mut dump = String::new();
+        for event in self.events.iter().skip(self.events.len() - fitting) {
+            _ = dump.push_str(&line(event));
+        }
+
+        dump
+    }
+}
+
+static EVENTS: Mutex<CriticalSectionRawMutex, RefCell<EventLog>> =
+    Mutex::new(RefCell::new(EventLog::new()));
+
+/// Records an application event, for devices that can't be debugged over RTT. Only the last
+/// `MAX_EVENTS` events are kept.
+pub fn record(message: &'static str) {
+    EVENTS.lock(|events| events.borrow_mut().record(Instant::now(), message));
+}
+
+/// Recorded events, oldest first, one `<milliseconds since boot> <message>` per line. Drops the
+/// oldest events if they don't all fit into `N` bytes.
+pub fn dump<const N: usize>() -> String<N> {
+    EVENTS.lock(|events| events.borrow().dump())
+}
+
+fn line(event: &Event) -> String<MAX_LINE_LENGTH> {
+    let mut line = String::new();
+    _ = write!(line, "{} ", event.time.as_millis());
+
+    // A truncated line is still better than none. It's cut at a character boundary, leaving room
+    // for the newline.
+    let room = MAX_LINE_LENGTH - 1 - line.len();
+    let mut end = event.message.len().min(room);
+    while !event.message.is_char_boundary(end) {
+        end -= 1;
+    }
+    _ = line.push_str(&event.message[..end]);
+    _ = line.push('\n');
+
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Instant;
+
+    use super::{EventLog, MAX_EVENTS};
+
+    /// Messages of `MAX_EVENTS + 1` events, so the first one doesn't fit
+    const MESSAGES: [&str; MAX_EVENTS + 1] = [
+        "e0", "e1", "e2", "e3", "e4", "e5", "e6", "e7", "e8", "e9", "e10", "e11", "e12", "e13",
+        "e14", "e15", "e16", "e17", "e18", "e19", "e20", "e21", "e22", "e23", "e24", "e25", "e26",
+        "e27", "e28", "e29", "e30", "e31", "e32",
+    ];
+
+    #[test]
+    fn events_are_dumped_oldest_first() {
+        let mut log = EventLog::new();
+        log.record(Instant::from_millis(5), "Connected");
+        log.record(Instant::from_millis(1250), "LED zone shut down");
+
+        assert_eq!(
+            log.dump::<256>().as_str(),
+            "5 Connected\n1250 LED zone shut down\n"
+        );
+    }
+
+    #[test]
+    fn the_oldest_event_is_evicted_when_full() {
+        let mut log = EventLog::new();
+        for (millis, message) in MESSAGES.into_iter().enumerate() {
+            log.record(Instant::from_millis(millis as u64), message);
+        }
+
+        let dump = log.dump::<1024>();
+        assert_eq!(dump.lines().count(), MAX_EVENTS);
+        assert_eq!(dump.lines().next(), Some("1 e1"));
+        assert_eq!(dump.lines().last(), Some("32 e32"));
+    }
+
+    #[test]
+    fn dumps_keep_the_newest_events_that_fit() {
+        let mut log = EventLog::new();
+        log.record(Instant::from_millis(1), "first");
+        log.record(Instant::from_millis(2), "second");
+        log.record(Instant::from_millis(3), "third");
+
+        // "2 second\n" and "3 third\n" take 17 bytes
+        assert_eq!(log.dump::<17>().as_str(), "2 second\n3 third\n");
+        assert_eq!(log.dump::<16>().as_str(), "3 third\n");
+        assert_eq!(log.dump::<7>().as_str(), "");
+    }
+
+    #[test]
+    fn long_messages_are_cut_off() {
+        let mut log = EventLog::new();
+        log.record(
+            Instant::from_millis(1),
+            std::string::String::leak("x".repeat(100)),
+        );
+
+        let dump = log.dump::<256>();
+        assert_eq!(dump.len(), super::MAX_LINE_LENGTH);
+        assert!(dump.starts_with("1 xxx"));
+        assert!(dump.ends_with("x\n"));
+
+        // Not in the middle of a character
+        let mut log = EventLog::new();
+        log.record(
+            Instant::from_millis(1),
+            std::string::String::leak("ä".repeat(40)),
+        );
+        assert_eq!(log.dump::<256>().len(), super::MAX_LINE_LENGTH - 1);
+    }
+}
@@ -0,0 +1,60 @@
+use defmt::*;
+use embassy_futures::select::select;
+use embassy_rp::gpio::{Input, Pull};
+use embassy_time::{Instant, with_deadline};
+
+use super::{BRIGHTNESS_STEP, Button, Quadrature};
+use crate::{
+    led_orchestrator::{LedCommand, LedCommandSender},
+    peripherals::{ButtonPeripherals, EncoderPeripherals},
+};
+
+/// Reads a push button between the button pin and ground. A short press switches to the next
+/// effect, a long one turns the light on or off, which is fired once `LONG_PRESS` is reached
+/// instead of waiting for the release.
+#[embassy_executor::task]
+pub async fn button_task(p: ButtonPeripherals, led_sender: LedCommandSender<'static>) -> ! {
+    let mut pin = Input::new(p.pin, Pull::Up);
+    let mut button = Button::new(pin.is_low(), Instant::now());
+
+    loop {
+        // Either the level changed, or it has been stable until the deadline. Changes since the
+        // last read end the wait right away.
+        let pressed = button.level;
+        let change = async {
+            if pressed {
+                pin.wait_for_high().await
+            } else {
+                pin.wait_for_low().await
+            }
+        };
+        match button.deadline() {
+            Some(deadline) => _ = with_deadline(deadline, change).await,
+            None => change.await,
+        }
+
+        if let Some(press) = button.update(pin.is_low(), Instant::now()) {
+            info!("Button pressed ({})", press);
+            led_sender.send(press.command()).await;
+        }
+    }
+}
+
+/// Reads a rotary encoder with its A and B pins pulled to ground. Every detent clockwise raises
+/// the brightness by `BRIGHTNESS_STEP`, counterclockwise lowers it.
+#[embassy_executor::task]
+pub async fn encoder_task(p: EncoderPeripherals, led_sender: LedCommandSender<'static>) -> ! {
+    let mut a = Input::new(p.a_pin, Pull::Up);
+    let mut b = Input::new(p.b_pin, Pull::Up);
+    let mut quadrature = Quadrature::new(a.is_high(), b.is_high());
+
+    loop {
+        select(a.wait_for_any_edge(), b.wait_for_any_edge()).await;
+
+        let direction = quadrature.update(a.is_high(), b.is_high());
+        if direction != 0 {
+            let delta = direction as i16 * BRIGHTNESS_STEP;
+            led_sender.send(LedCommand::AdjustBrightness(delta)).await;
+        }
+    }
+}
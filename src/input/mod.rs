@@ -0,0 +1,285 @@
+use embassy_time::{Duration, Instant};
+
+use crate::led_orchestrator::LedCommand;
+
+#[cfg(target_os = "none")]
+pub use driver::{button_task, encoder_task};
+
+#[cfg(target_os = "none")]
+mod driver;
+
+/// Time the button level has to be stable for to count, longer than the contacts bounce
+const DEBOUNCE_TIME: Duration = Duration::from_millis(20);
+
+/// Presses held at least this long toggle the light instead of switching the effect
+const LONG_PRESS: Duration = Duration::from_secs(1);
+
+/// What a press of the button does
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Press {
+    Short,
+    Long,
+}
+
+impl Press {
+    fn from_duration(held: Duration) -> Press {
+        if held >= LONG_PRESS {
+            Press::Long
+        } else {
+            Press::Short
+        }
+    }
+
+    fn command(self) -> LedCommand {
+        match self {
+            Press::Short => LedCommand::NextEffect,
+            Press::Long => LedCommand::ToggleLight,
+        }
+    }
+}
+
+/// Debounces the level of the button and tells its presses apart. The level has to be stable
+/// for `DEBOUNCE_TIME` to count, and a long press fires once `LONG_PRESS` is reached instead of
+/// waiting for the release.
+struct Button {
+    /// Debounced level, `true` while pressed
+    pressed: bool,
+    /// Last level read, and since when it has been read
+    level: bool,
+    since: Instant,
+    /// Start of the current press, `None` once its long press has fired
+    press_start: Option<Instant>,
+}
+
+impl Button {
+    fn new(pressed: bool, now: Instant) -> Self {
+        Self {
+            pressed,
+            level: pressed,
+            since: now,
+            // A button held while booting doesn't count as a press
+            press_start: None,
+        }
+    }
+
+    /// Time `update` has to be called at without a change of the level, `None` if only a change
+    /// can make a difference
+    fn deadline(&self) -> Option<Instant> {
+        if self.level != self.pressed {
+            return Some(self.since + DEBOUNCE_TIME);
+        }
+
+        self.press_start.map(|start| start + LONG_PRESS)
+    }
+
+    /// Takes the level read at `now`, returning the press it completes
+    fn update(&mut self, pressed: bool, now: Instant) -> Option<Press> {
+        if pressed != self.level {
+            self.level = pressed;
+            self.since = now;
+        }
+
+        if self.level != self.pressed && now - self.since >= DEBOUNCE_TIME {
+            self.pressed = self.level;
+            if self.pressed {
+                self.press_start = Some(self.since);
+            } else if let Some(start) = self.press_start.take() {
+                return Some(Press::from_duration(self.since - start));
+            }
+        }
+
+        match self.press_start {
+            Some(start) if self.pressed && now - start >= LONG_PRESS => {
+                self.press_start = None;
+                Some(Press::Long)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Change of the brightness per detent of the encoder
+const BRIGHTNESS_STEP: i16 = 16;
+
+/// Transitions of the encoder per detent
+const TRANSITIONS_PER_DETENT: i8 = 4;
+
+/// Decodes the Gray code of a quadrature encoder
+#[derive(Clone, Copy, Default)]
+struct Quadrature {
+    /// Levels of A and B as the two lowest bits
+    state: u8,
+    /// Transitions since the last detent, positive clockwise
+    count: i8,
+}
+
+impl Quadrature {
+    /// Direction of every transition from `[previous state][state]`. Invalid ones, where both
+    /// pins changed at once, are ignored. Bouncing contacts go back and forth, so they cancel out.
+    const DIRECTIONS: [[i8; 4]; 4] = [[0, -1, 1, 0], [1, 0, 0, -1], [-1, 0, 0, 1], [0, 1, -1, 0]];
+
+    fn new(a: bool, b: bool) -> Self {
+        Self {
+            state: Self::levels(a, b),
+            count: 0,
+        }
+    }
+
+    fn levels(a: bool, b: bool) -> u8 {
+        ((a as u8) << 1) | b as u8
+    }
+
+    /// Returns `1` or `-1` once a full detent has been turned
+    fn update(&mut self, a: bool, b: bool) -> i8 {
+        let state = Self::levels(a, b);
+        self.count += Self::DIRECTIONS[self.state as usize][state as usize];
+        self.state = state;
+
+        if self.count.abs() < TRANSITIONS_PER_DETENT {
+            return 0;
+        }
+
+        let direction = self.count.signum();
+        self.count = 0;
+        direction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::{Duration, Instant};
+
+    use super::{Button, DEBOUNCE_TIME, LONG_PRESS, Press, Quadrature};
+    use crate::led_orchestrator::LedCommand;
+
+    /// Levels read at the given milliseconds after booting, returning the presses they completed
+    fn presses(button: &mut Button, levels: &[(u64, bool)]) -> Vec<Press> {
+        levels
+            .iter()
+            .filter_map(|&(millis, pressed)| {
+                button.update(pressed, Instant::from_millis(1000 + millis))
+            })
+            .collect()
+    }
+
+    fn released() -> Button {
+        Button::new(false, Instant::from_millis(1000))
+    }
+
+    #[test]
+    fn short_presses_fire_on_release() {
+        let mut button = released();
+
+        assert_eq!(
+            presses(&mut button, &[(0, true), (20, true), (200, false)]),
+            []
+        );
+        assert_eq!(button.deadline(), Some(Instant::from_millis(1220)));
+        assert_eq!(presses(&mut button, &[(220, false)]), [Press::Short]);
+        assert_eq!(button.deadline(), None);
+    }
+
+    #[test]
+    fn bounces_within_the_window_dont_count() {
+        let mut button = released();
+        let bouncing = [(0, true), (5, false), (10, true), (15, false), (100, false)];
+
+        assert_eq!(presses(&mut button, &bouncing), []);
+        assert_eq!(button.deadline(), None);
+
+        // Bouncing on release doesn't add another press
+        let press = [
+            (200, true),
+            (230, true),
+            (400, false),
+            (405, true),
+            (410, false),
+        ];
+        assert_eq!(presses(&mut button, &press), []);
+        assert_eq!(presses(&mut button, &[(430, false)]), [Press::Short]);
+    }
+
+    #[test]
+    fn long_presses_fire_while_held() {
+        let mut button = released();
+
+        assert_eq!(presses(&mut button, &[(0, true), (20, true)]), []);
+        assert_eq!(
+            button.deadline(),
+            Some(Instant::from_millis(1000) + LONG_PRESS)
+        );
+        assert_eq!(presses(&mut button, &[(1000, true)]), [Press::Long]);
+        assert_eq!(presses(&mut button, &[(3000, false), (3020, false)]), []);
+    }
+
+    #[test]
+    fn held_buttons_dont_count_after_booting() {
+        let mut button = Button::new(true, Instant::from_millis(1000));
+
+        assert_eq!(button.deadline(), None);
+        assert_eq!(presses(&mut button, &[(2000, false), (2020, false)]), []);
+    }
+
+    #[test]
+    fn presses_cycle_effects_or_toggle_the_light() {
+        assert!(DEBOUNCE_TIME < LONG_PRESS);
+        assert_eq!(
+            Press::from_duration(LONG_PRESS - Duration::from_millis(1)),
+            Press::Short
+        );
+        assert!(matches!(Press::Short.command(), LedCommand::NextEffect));
+        assert!(matches!(Press::Long.command(), LedCommand::ToggleLight));
+    }
+
+    /// Levels of A and B over a clockwise detent, starting and ending at rest
+    const CLOCKWISE: [(bool, bool); 4] =
+        [(true, false), (true, true), (false, true), (false, false)];
+
+    fn turn(encoder: &mut Quadrature, levels: impl IntoIterator<Item = (bool, bool)>) -> Vec<i8> {
+        levels
+            .into_iter()
+            .map(|(a, b)| encoder.update(a, b))
+            .collect()
+    }
+
+    #[test]
+    fn detents_are_counted_once() {
+        let mut encoder = Quadrature::new(false, false);
+
+        assert_eq!(turn(&mut encoder, CLOCKWISE), [0, 0, 0, 1]);
+        assert_eq!(turn(&mut encoder, CLOCKWISE), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn counterclockwise_detents_are_negative() {
+        let mut encoder = Quadrature::new(false, false);
+        let counterclockwise = [(false, true), (true, true), (true, false), (false, false)];
+
+        assert_eq!(turn(&mut encoder, counterclockwise), [0, 0, 0, -1]);
+    }
+
+    #[test]
+    fn bouncing_contacts_cancel_out() {
+        let mut encoder = Quadrature::new(false, false);
+        let bouncing = [(true, false), (false, false), (true, false), (false, false)];
+
+        assert_eq!(turn(&mut encoder, bouncing), [0; 4]);
+        assert_eq!(turn(&mut encoder, CLOCKWISE), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn invalid_transitions_are_ignored() {
+        let mut encoder = Quadrature::new(false, false);
+
+        // Both pins changed at once, e.g. because a transition was missed
+        assert_eq!(encoder.update(true, true), 0);
+        // Turning on from there still takes a full detent
+        assert_eq!(
+            turn(
+                &mut encoder,
+                [(false, true), (false, false), (true, false), (true, true)]
+            ),
+            [0, 0, 0, 1]
+        );
+    }
+}
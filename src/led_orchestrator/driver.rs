@@ -0,0 +1,590 @@
+use core::pin::pin;
+
+use defmt::*;
+use embassy_futures::{
+    join::join4,
+    select::{Either3, Either4, select3, select4},
+};
+use embassy_rp::{
+    bind_interrupts,
+    clocks::clk_sys_freq,
+    dma::{AnyChannel, Channel, Word},
+    gpio::{Input, Pin, Pull},
+    pac::{self, dma::regs::CtrlTrig},
+    peripherals::PIO1,
+    pio::{InterruptHandler, Pio, ShiftConfig},
+    pwm::{self, Pwm, Slice},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Instant, Timer};
+use pio::pio_asm;
+
+use super::{
+    Action, BRIGHTNESS_RAMP_STEP, BUFFER_LENGTH, BrightnessRamp, FADE_OUT_DURATION, FADE_OUT_STEPS,
+    LedCommand, LedCommandReceiver, PWM_TOP, Playback, Restart, SWAP_SEQUENCE, SYNC_BUFFER_LENGTH,
+    SYNC_INPUT, Selection, SwapStep, YIELD_BATCH_SIZE, Zone, calculate_next_buffer, compute_timing,
+    dump_streams, fade_out_step, fade_out_wrap, pwm_duties, stream_config, wait_until,
+};
+use crate::{
+    event_log,
+    mqtt::MqttTxSender,
+    peripherals::{LED_DMA_CHANNELS, LedPeripherals, PwmChannel, pwm_slice_of},
+    stream::Color,
+};
+
+bind_interrupts!(struct Irqs {
+    PIO1_IRQ_0 => InterruptHandler<PIO1>;
+});
+
+#[embassy_executor::task]
+pub async fn orchestrate_leds(
+    mut p: LedPeripherals,
+    commands: LedCommandReceiver<'static>,
+    sender: MqttTxSender<'static>,
+) {
+    // The duties are written to the A half of the compare registers, see `set_pwm_outputs`
+    for (pin, slice) in [
+        (p.red_pin.pin(), p.red_slice.number()),
+        (p.green_pin.pin(), p.green_slice.number()),
+        (p.blue_pin.pin(), p.blue_slice.number()),
+    ] {
+        core::assert_eq!(
+            pwm_slice_of(pin),
+            (slice, PwmChannel::A),
+            "LED pin isn't output A of its PWM slice"
+        );
+    }
+
+    core::assert_eq!(
+        [
+            p.dma_pwm_red_a.number(),
+            p.dma_pwm_red_b.number(),
+            p.dma_pwm_green_a.number(),
+            p.dma_pwm_green_b.number(),
+            p.dma_pwm_blue_a.number(),
+            p.dma_pwm_blue_b.number(),
+            p.dma_pio_red.number(),
+            p.dma_pio_green.number(),
+            p.dma_pio_blue.number(),
+        ],
+        LED_DMA_CHANNELS,
+        "LED DMA channels differ from LED_DMA_CHANNELS"
+    );
+
+    let mut pio = Pio::new(p.pio, Irqs);
+
+    let timing_program = pio_asm! {
+        r#"
+            .define public MICROS_PER_TICK 64
+            .define public TICK_OVERHEAD 5
+            wait 0 irq 0
+
+        .wrap_target
+            out y 8
+            in null 8
+            in y 8
+            out x 24
+        delay:
+            jmp x-- delay
+        .wrap
+        "#
+    };
+
+    let mut timing_config = embassy_rp::pio::Config::default();
+    timing_config.use_program(&pio.common.load_program(&timing_program.program), &[]);
+    let clock = clk_sys_freq();
+    let Some((target_frequency, clock_divider)) =
+        compute_timing(clock, timing_program.public_defines.MICROS_PER_TICK, 1)
+    else {
+        error!(
+            "Can't run the timing program at {} us per tick from a {} Hz system clock",
+            timing_program.public_defines.MICROS_PER_TICK, clock
+        );
+        return;
+    };
+    info!(
+        "Timing program runs at {} Hz, clock divider {}",
+        target_frequency,
+        clock_divider.to_num::<f32>()
+    );
+    timing_config.clock_divider = clock_divider;
+    timing_config.shift_out = ShiftConfig {
+        direction: embassy_rp::pio::ShiftDirection::Left,
+        auto_fill: true,
+        threshold: 32,
+    };
+    timing_config.shift_in = ShiftConfig {
+        direction: embassy_rp::pio::ShiftDirection::Left,
+        auto_fill: true,
+        threshold: 16,
+    };
+
+    let mut pwm_config = pwm::Config::default();
+    pwm_config.enable = true;
+    pwm_config.top = PWM_TOP;
+
+    let pwm_slice_red = p.red_slice.number();
+    let pwm = Pwm::new_output_a(p.red_slice, p.red_pin, pwm_config.clone());
+    core::mem::forget(pwm);
+
+    sync_pio_to_pwm(
+        [*p.dma_pwm_red_a.into(), *p.dma_pwm_red_b.into()],
+        pwm_slice_red,
+        1,
+        0,
+    );
+
+    let pwm_slice_green = p.green_slice.number();
+    let pwm = Pwm::new_output_a(p.green_slice, p.green_pin, pwm_config.clone());
+    core::mem::forget(pwm);
+
+    sync_pio_to_pwm(
+        [*p.dma_pwm_green_a.into(), *p.dma_pwm_green_b.into()],
+        pwm_slice_green,
+        1,
+        1,
+    );
+
+    let pwm_slice_blue = p.blue_slice.number();
+    let pwm = Pwm::new_output_a(p.blue_slice, p.blue_pin, pwm_config.clone());
+    core::mem::forget(pwm);
+
+    sync_pio_to_pwm(
+        [*p.dma_pwm_blue_a.into(), *p.dma_pwm_blue_b.into()],
+        pwm_slice_blue,
+        1,
+        2,
+    );
+
+    pio.sm0.set_config(&timing_config);
+    pio.sm0.set_enable(true);
+
+    pio.sm1.set_config(&timing_config);
+    pio.sm1.set_enable(true);
+
+    pio.sm2.set_config(&timing_config);
+    pio.sm2.set_enable(true);
+
+    pio.irq_flags.set_all(0);
+
+    let make_config = |selection: &Selection| {
+        stream_config(
+            selection,
+            timing_program.public_defines.MICROS_PER_TICK,
+            timing_program.public_defines.TICK_OVERHEAD,
+        )
+    };
+
+    let mut selection = Selection::new(Zone::First);
+    let mut config = make_config(&selection);
+
+    let mut pending = None;
+    let (mut red, mut green, mut blue) = calculate_next_buffer::<_, BUFFER_LENGTH>(
+        &mut config,
+        &mut pending,
+        BUFFER_LENGTH,
+        YIELD_BATCH_SIZE,
+    )
+    .await;
+
+    let pwm_slices = [pwm_slice_red, pwm_slice_green, pwm_slice_blue];
+    let mut playback = Playback::Running;
+    let mut dump_requested = false;
+    // Set while fading out, along with the time the fade started
+    let mut shutdown_requested: Option<(Instant, &Signal<CriticalSectionRawMutex, ()>)> = None;
+    let mut pending_restart: Option<Restart> = None;
+    let mut sync = SYNC_INPUT.then(|| Input::new(p.sync_pin, Pull::Down));
+
+    loop {
+        info!("Loop");
+
+        if let Some(restart) = pending_restart.take() {
+            for step in SWAP_SEQUENCE {
+                match step {
+                    // Leaving the transfer block dropped the DMA transfers, which aborts them
+                    // and waits until the channels are idle. The buffers are borrowed by the
+                    // transfers, so they can't be replaced before that.
+                    SwapStep::CheckDmaIdle => core::assert!(
+                        dma_idle(&[
+                            p.dma_pio_red.number(),
+                            p.dma_pio_green.number(),
+                            p.dma_pio_blue.number(),
+                        ]),
+                        "LED DMA still running on reconfiguration"
+                    ),
+                    SwapStep::StopStateMachines => stop_state_machines(&mut pio),
+                    SwapStep::Reconfigure => {
+                        restart.apply(&mut config, &mut pending, || make_config(&selection))
+                    }
+                    SwapStep::FillBuffers => {
+                        (red, green, blue) = calculate_next_buffer(
+                            &mut config,
+                            &mut pending,
+                            SYNC_BUFFER_LENGTH,
+                            YIELD_BATCH_SIZE,
+                        )
+                        .await
+                    }
+                    SwapStep::StartStateMachines => start_state_machines(&mut pio, &timing_config),
+                }
+            }
+            // New streams of a ramp are dimmed right away, the others are shown undimmed. The
+            // fade out overrides both.
+            if shutdown_requested.is_none() {
+                let wrap = selection.ramp.map(|ramp| ramp.wrap(Instant::now()));
+                set_pwm_wrap(pwm_slices, wrap.unwrap_or(PWM_TOP as u32 + 1));
+            }
+        }
+
+        let result = {
+            let mut transfer = pin!(join4(
+                calculate_next_buffer(&mut config, &mut pending, BUFFER_LENGTH, YIELD_BATCH_SIZE),
+                pio.sm0.tx().dma_push(p.dma_pio_red.reborrow(), &red, false),
+                pio.sm1
+                    .tx()
+                    .dma_push(p.dma_pio_green.reborrow(), &green, false),
+                pio.sm2
+                    .tx()
+                    .dma_push(p.dma_pio_blue.reborrow(), &blue, false),
+            ));
+
+            // Pausing only takes effect once the current buffer has been sent, so all channels
+            // stop at the same step
+            loop {
+                match select4(
+                    transfer.as_mut(),
+                    commands.receive(),
+                    select3(
+                        wait_for_sync(&mut sync),
+                        wait_until(selection.overlay.map(|(_, end)| end)),
+                        ramp_brightness(
+                            pwm_slices,
+                            selection.ramp.filter(|_| shutdown_requested.is_none()),
+                        ),
+                    ),
+                    fade_out(pwm_slices, shutdown_requested.map(|(started, _)| started)),
+                )
+                .await
+                {
+                    Either4::First(buffers) => break Ok(buffers),
+                    // Neither would return before the fade out is done
+                    Either4::Second(LedCommand::Pause | LedCommand::Manual(_))
+                        if shutdown_requested.is_some() => {}
+                    // The config is in use while the next buffer is calculated
+                    Either4::Second(LedCommand::DumpStreams) => dump_requested = true,
+                    Either4::Second(LedCommand::Shutdown(stopped)) => {
+                        shutdown_requested.get_or_insert((Instant::now(), stopped));
+                    }
+                    Either4::Second(command) => match playback.apply(command) {
+                        None => {
+                            if let Playback::Manual(color) = playback {
+                                break Err(Interruption::Manual(color));
+                            }
+                        }
+                        Some(command) => match selection.apply(command, &sender) {
+                            Action::None => {}
+                            Action::Restart(restart) => {
+                                break Err(Interruption::Restart(restart));
+                            }
+                            Action::Overlay => {
+                                playback = Playback::Running;
+                                break Err(Interruption::Restart(Restart::Reconfigure));
+                            }
+                        },
+                    },
+                    Either4::Third(Either3::First(())) => {
+                        break Err(Interruption::Restart(Restart::Phase));
+                    }
+                    Either4::Third(Either3::Second(())) => {
+                        selection.overlay = None;
+                        break Err(Interruption::Restart(Restart::Reconfigure));
+                    }
+                    Either4::Third(Either3::Third(())) => {
+                        if selection.finish_ramp() {
+                            break Err(Interruption::Restart(Restart::Reconfigure));
+                        }
+                    }
+                    Either4::Fourth(()) => {
+                        let (_, stopped) = shutdown_requested.unwrap();
+                        break Err(Interruption::Shutdown(stopped));
+                    }
+                }
+            }
+        };
+
+        let ((r, g, b), _, _, _) = match result {
+            Ok(buffers) => buffers,
+            Err(Interruption::Restart(restart)) => {
+                pending_restart = Some(restart);
+                continue;
+            }
+            Err(Interruption::Manual(color)) => {
+                let manual = show_manual(
+                    &mut pio,
+                    pwm_slices,
+                    color,
+                    &commands,
+                    &sender,
+                    &mut selection,
+                );
+                if let Some(stopped) = manual.await {
+                    fade_out(pwm_slices, Some(Instant::now())).await;
+                    return shutdown(&mut pio, pwm_slices, stopped);
+                }
+
+                playback = Playback::Running;
+                pending_restart = Some(Restart::Reconfigure);
+                continue;
+            }
+            Err(Interruption::Shutdown(stopped)) => {
+                return shutdown(&mut pio, pwm_slices, stopped);
+            }
+        };
+
+        red = r;
+        green = g;
+        blue = b;
+
+        if core::mem::take(&mut dump_requested) {
+            dump_streams(&config, &sender);
+        }
+
+        // Without new data the state machines stall, while the PWM outputs keep the color of the
+        // last step. Changes of the shown pattern are applied once resumed.
+        while playback == Playback::Paused {
+            match commands.receive().await {
+                LedCommand::DumpStreams => dump_streams(&config, &sender),
+                LedCommand::Shutdown(stopped) => {
+                    fade_out(pwm_slices, Some(Instant::now())).await;
+                    return shutdown(&mut pio, pwm_slices, stopped);
+                }
+                command => match playback.apply(command) {
+                    None => {
+                        if let Playback::Manual(color) = playback {
+                            let manual = show_manual(
+                                &mut pio,
+                                pwm_slices,
+                                color,
+                                &commands,
+                                &sender,
+                                &mut selection,
+                            );
+                            if let Some(stopped) = manual.await {
+                                fade_out(pwm_slices, Some(Instant::now())).await;
+                                return shutdown(&mut pio, pwm_slices, stopped);
+                            }
+
+                            playback = Playback::Running;
+                            pending_restart = Some(Restart::Reconfigure);
+                        }
+                    }
+                    Some(command) => match selection.apply(command, &sender) {
+                        Action::None => {}
+                        // A new config starts from the beginning as well
+                        Action::Restart(Restart::Phase) => {
+                            pending_restart.get_or_insert(Restart::Phase);
+                        }
+                        Action::Restart(Restart::Reconfigure) => {
+                            pending_restart = Some(Restart::Reconfigure)
+                        }
+                        Action::Overlay => {
+                            playback = Playback::Running;
+                            pending_restart = Some(Restart::Reconfigure);
+                        }
+                    },
+                },
+            }
+        }
+    }
+}
+
+/// Reason for aborting the buffer being sent
+enum Interruption {
+    Restart(Restart),
+    Manual(Color),
+    Shutdown(&'static Signal<CriticalSectionRawMutex, ()>),
+}
+
+fn shutdown(
+    pio: &mut Pio<'_, PIO1>,
+    pwm_slices: [usize; 3],
+    stopped: &Signal<CriticalSectionRawMutex, ()>,
+) {
+    stop_state_machines(pio);
+    set_pwm_outputs(pwm_slices, Color::black());
+
+    info!("LEDs shut down");
+    event_log::record("LEDs shut down");
+    stopped.signal(());
+}
+
+/// Shows `color` until the animation is resumed. Returns the signal of a shutdown requested in
+/// the meantime. The state machines are left stopped in either case, changes of the selection
+/// take effect once the animation restarts.
+async fn show_manual(
+    pio: &mut Pio<'_, PIO1>,
+    pwm_slices: [usize; 3],
+    color: Color,
+    commands: &LedCommandReceiver<'_>,
+    sender: &MqttTxSender<'_>,
+    selection: &mut Selection,
+) -> Option<&'static Signal<CriticalSectionRawMutex, ()>> {
+    stop_state_machines(pio);
+    // The color is shown as given, even while the brightness of the effect ramps
+    set_pwm_wrap(pwm_slices, PWM_TOP as u32 + 1);
+    info!("Entering manual mode");
+    event_log::record("Entering manual mode");
+
+    let mut playback = Playback::Manual(color);
+    while let Playback::Manual(color) = playback {
+        set_pwm_outputs(pwm_slices, color);
+
+        match commands.receive().await {
+            LedCommand::DumpStreams => {}
+            LedCommand::Shutdown(stopped) => return Some(stopped),
+            command => {
+                if let Some(command) = playback.apply(command) {
+                    // The animation restarts with the new selection anyway
+                    match selection.apply(command, sender) {
+                        Action::None | Action::Restart(_) => {}
+                        Action::Overlay => return None,
+                    }
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn stop_state_machines(pio: &mut Pio<'_, PIO1>) {
+    // Running DMA transfers have already been aborted when their futures got dropped
+    pio.sm0.set_enable(false);
+    pio.sm1.set_enable(false);
+    pio.sm2.set_enable(false);
+    pio.sm0.clear_fifos();
+    pio.sm1.clear_fifos();
+    pio.sm2.clear_fifos();
+}
+
+/// Whether none of the DMA `channels` is transferring anymore
+fn dma_idle(channels: &[u8]) -> bool {
+    channels
+        .iter()
+        .all(|&channel| !pac::DMA.ch(channel as usize).ctrl_trig().read().busy())
+}
+
+/// Restarts stopped state machines from the beginning of the program
+fn start_state_machines<'d>(pio: &mut Pio<'d, PIO1>, config: &embassy_rp::pio::Config<'d, PIO1>) {
+    pio.sm0.set_config(config);
+    pio.sm1.set_config(config);
+    pio.sm2.set_config(config);
+    pio.sm0.restart();
+    pio.sm1.restart();
+    pio.sm2.restart();
+    pio.sm0.set_enable(true);
+    pio.sm1.set_enable(true);
+    pio.sm2.set_enable(true);
+}
+
+/// Dims the LEDs according to the time since the fade out `started`, returning once it's done.
+/// Never returns if no fade out has been started.
+///
+/// Raising the counter wrap of the PWM slices lowers the duty cycle of every level at once, so
+/// this works with whatever feeds the PWM outputs. The PWM frequency drops along with it.
+async fn fade_out(pwm_slices: [usize; 3], started: Option<Instant>) {
+    let Some(started) = started else {
+        return core::future::pending().await;
+    };
+
+    let step_duration = FADE_OUT_DURATION / FADE_OUT_STEPS;
+    while let Some(step) = fade_out_step(FADE_OUT_DURATION, started, Instant::now()) {
+        set_pwm_wrap(pwm_slices, fade_out_wrap(step));
+
+        Timer::at(started + step_duration * (step + 1)).await;
+    }
+}
+
+/// Dims the LEDs along the ramp, returning once it's done. Never returns without a ramp.
+async fn ramp_brightness(pwm_slices: [usize; 3], ramp: Option<BrightnessRamp>) {
+    let Some(ramp) = ramp else {
+        return core::future::pending().await;
+    };
+
+    loop {
+        let now = Instant::now();
+        set_pwm_wrap(pwm_slices, ramp.wrap(now));
+        if now >= ramp.end() {
+            return;
+        }
+
+        Timer::after(BRIGHTNESS_RAMP_STEP).await;
+    }
+}
+
+/// Sets the counter wrap of the PWM slices, `PWM_TOP + 1` for full brightness. Larger wraps dim
+/// all levels proportionally, up to the 16 bits of the counter.
+fn set_pwm_wrap(pwm_slices: [usize; 3], wrap: u32) {
+    for slice in pwm_slices {
+        pac::PWM
+            .ch(slice)
+            .top()
+            .write(|w| w.set_top((wrap - 1).min(u16::MAX as u32) as u16));
+    }
+}
+
+/// Sets the duty cycle of the red, green and blue channel. The timing program has to be stopped,
+/// otherwise its next step overwrites them.
+fn set_pwm_outputs(pwm_slices: [usize; 3], color: Color) {
+    for (slice, duty) in pwm_slices.into_iter().zip(pwm_duties(color)) {
+        pac::PWM.ch(slice).cc().write(|w| w.set_a(duty));
+    }
+}
+
+/// Resolves on the next rising edge of the sync input, never if it's disabled
+async fn wait_for_sync(sync: &mut Option<Input<'_>>) {
+    match sync {
+        Some(input) => input.wait_for_rising_edge().await,
+        None => core::future::pending().await,
+    }
+}
+
+fn sync_pio_to_pwm(dmas: [AnyChannel; 2], pwm_slice: usize, pio_number: u8, sm: u8) {
+    let raw_pwm = pac::PWM.ch(pwm_slice);
+
+    let treq_sel = pac::dma::vals::TreqSel::from(pio_number * 8 + sm + 4);
+
+    let [first_dma, second_dma] = dmas;
+    let r = first_dma.regs();
+
+    r.write_addr().write_value(raw_pwm.cc().as_ptr() as u32);
+    r.read_addr()
+        .write_value(pac::PIO1.rxf(sm as usize).as_ptr() as u32);
+    r.trans_count().write_value(u32::MAX);
+    r.al1_ctrl().write(|val| {
+        let mut w = CtrlTrig(0);
+        w.set_treq_sel(treq_sel);
+        w.set_data_size(u16::size());
+        w.set_chain_to(second_dma.number());
+        w.set_incr_read(false);
+        w.set_incr_write(false);
+        w.set_en(true);
+
+        *val = w.0;
+    });
+
+    let r = second_dma.regs();
+
+    r.write_addr().write_value(raw_pwm.cc().as_ptr() as u32);
+    r.read_addr()
+        .write_value(pac::PIO1.rxf(sm as usize).as_ptr() as u32);
+    r.trans_count().write_value(u32::MAX);
+    r.ctrl_trig().write(|w| {
+        w.set_treq_sel(treq_sel);
+        w.set_data_size(u16::size());
+        w.set_chain_to(first_dma.number());
+        w.set_incr_read(false);
+        w.set_incr_write(false);
+        w.set_en(true);
+    });
+}
@@ -1,159 +1,991 @@
+use core::{fmt::Write, ops::RangeInclusive, str::FromStr};
+
 use defmt::*;
-use embassy_futures::{join::join4, yield_now};
-use embassy_rp::{
-    bind_interrupts,
-    clocks::clk_sys_freq,
-    dma::{AnyChannel, Channel, Word},
-    pac::{self, dma::regs::CtrlTrig},
-    peripherals::PIO1,
-    pio::{InterruptHandler, Pio, ShiftConfig},
-    pwm::{self, Pwm, Slice},
+use embassy_futures::yield_now;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Receiver, Sender},
+    signal::Signal,
+    watch::{self, Watch},
 };
-use embassy_time::Duration;
+use embassy_time::{Duration, Instant, Timer};
 use fixed::{FixedU32, types::extra::U8};
-use heapless::Vec;
-use pio::pio_asm;
+use heapless::{String, Vec};
 
 use crate::{
-    peripherals::LedPeripherals,
-    stream::{self, Color, ColorStepIterator, Hz, StreamConfig},
+    effects::{EFFECTS, Effect, MAX_STREAMS},
+    mqtt::{MAX_PAYLOAD_LENGTH, MqttTxSender, PublishProperties, TxPacket},
+    stream::{
+        self, Color, ColorCorrection, ColorStep, ColorStepIterator, DimmingCurve, Gamma, Hz,
+        MixMode, StreamConfig, TimingGroup,
+    },
 };
 
-bind_interrupts!(struct Irqs {
-    PIO1_IRQ_0 => InterruptHandler<PIO1>;
-});
-
-#[embassy_executor::task]
-pub async fn orchestrate_leds(mut p: LedPeripherals) {
-    let mut pio = Pio::new(p.pio, Irqs);
-
-    let timing_program = pio_asm! {
-        r#"
-            .define public MICROS_PER_TICK 64
-            .define public TICK_OVERHEAD 5
-            wait 0 irq 0
-
-        .wrap_target
-            out y 8
-            in null 8
-            in y 8
-            out x 24
-        delay:
-            jmp x-- delay
-        .wrap
-        "#
+#[cfg(target_os = "none")]
+mod driver;
+#[cfg(all(target_os = "none", feature = "second_zone"))]
+mod second_zone;
+mod status;
+mod zone;
+
+#[cfg(target_os = "none")]
+pub use driver::orchestrate_leds;
+#[cfg(all(target_os = "none", feature = "second_zone"))]
+pub use second_zone::orchestrate_second_zone;
+pub use status::ConnectionStatus;
+pub use zone::Zone;
+
+/// Commands that concern the whole device are sent to every zone, the others to the zone they
+/// name
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+pub enum LedCommand {
+    /// Fades the LEDs out over `FADE_OUT_DURATION`, then aborts any running DMA transfers, stops
+    /// the state machines and turns all LEDs off. The orchestrator exits afterwards and signals
+    /// completion, so the power can be cut safely once it's signaled.
+    Shutdown(&'static Signal<CriticalSectionRawMutex, ()>),
+    /// Freezes the animation on the current frame, holding its color
+    Pause,
+    /// Stops the animation and shows a constant color by setting the PWM outputs directly,
+    /// bypassing the timing program. Useful to check the wiring and color balance.
+    Manual(Color),
+    /// Continues a paused animation where it left off, also leaving manual mode
+    Resume,
+    /// Starts the animation over from its beginning. Steps already queued for the state machines
+    /// are discarded, so the restart is visible right away.
+    Restart,
+    /// Publishes the active stream configuration as JSON to `picow/debug/streams`, for remote
+    /// troubleshooting. It is sent once the current buffer is done, and ignored in manual mode.
+    DumpStreams,
+    /// Publishes the name of the effect, retained, to `picow/effect/state`. Sent after every
+    /// connect, so the state is there even if the broker lost it.
+    ReportEffect,
+    /// Publishes the effect like `ReportEffect`, and the light state like `ToggleLight`, for
+    /// consumers that connected after the device
+    ReportState,
+    /// Switches to the effect after the shown one in `EFFECTS`, publishing its name like
+    /// `ReportEffect`. Also leaves custom streams. The brightness changes to the default of the
+    /// effect, if it has one, published like `ToggleLight`.
+    NextEffect,
+    /// Shows the given streams instead of the effect, adjusted to the light state and speed like
+    /// it. Its name is reported as `Custom`. `None` returns to the effect.
+    SetStreams(Option<Vec<StreamConfig, MAX_STREAMS>>),
+    /// Turns the light on or off, keeping its brightness and color. The new state is published,
+    /// retained, to the light state topic of the zone, so it's restored after booting.
+    ToggleLight,
+    /// Turns the light on (`true`) or off like `ToggleLight`, for the light entity
+    SwitchLight(bool),
+    /// Sets the brightness of the light, keeping it on or off. Published like `ToggleLight`.
+    SetBrightness(u8),
+    /// Changes the brightness of the light by the given amount, turning it on if it's raised.
+    /// Published like `ToggleLight`.
+    AdjustBrightness(i16),
+    /// Shows the status pattern for the given connection state, if status patterns are enabled
+    Status(ConnectionStatus),
+    /// Shows the effect in the given state
+    SetLightState(LightState),
+    /// Limits the brightness of the effect to the given level while idle, `None` once active again
+    Idle(Option<u8>),
+    /// Plays the effect at the given multiple of its normal speed, within `SPEED_RANGE`
+    Speed(f32),
+    /// Applies the given gamma, within `GAMMA_RANGE`, to the effect
+    Gamma(f32),
+    /// Maps the colors to PWM duties with the given curve, after the gamma
+    DimmingCurve(DimmingCurve),
+    /// Shows red, green, blue and white for `TEST_PATTERN_STEP` each, to check every channel and
+    /// the color balance, then returns to what was shown before. Also leaves pause and manual mode.
+    TestPattern,
+    /// Shows `IDENTIFY_COLOR` for `IDENTIFY_DURATION` to locate the device, then returns to what
+    /// was shown before. Leaves pause and manual mode like `TestPattern`.
+    Identify,
+}
+
+/// Speeds the effect can be played at, as multiples of its normal speed
+pub const SPEED_RANGE: RangeInclusive<f32> = 0.1..=10.;
+
+/// Gammas that can be set at runtime
+pub const GAMMA_RANGE: RangeInclusive<f32> = 1.0..=3.0;
+
+/// On-state of the light, restored from `picow/light/state` after booting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LightState {
+    pub on: bool,
+    pub brightness: u8,
+    /// Replaces the colors of all streams of the effect, if set
+    pub color: Option<Color>,
+}
+
+impl LightState {
+    /// Used if no state has been retained
+    pub const DEFAULT: LightState = LightState {
+        on: true,
+        brightness: u8::MAX,
+        color: None,
     };
 
-    let mut timing_config = embassy_rp::pio::Config::default();
-    timing_config.use_program(&pio.common.load_program(&timing_program.program), &[]);
-    let target_frequency = 1_000_000 / timing_program.public_defines.MICROS_PER_TICK;
-    let clock_divider = (clk_sys_freq() as f64) / (target_frequency as f64);
-    timing_config.clock_divider = FixedU32::<U8>::checked_from_num(clock_divider).unwrap();
-    timing_config.shift_out = ShiftConfig {
-        direction: embassy_rp::pio::ShiftDirection::Left,
-        auto_fill: true,
-        threshold: 32,
+    fn apply(&self, stream: StreamConfig) -> StreamConfig {
+        let brightness = if self.on { self.brightness } else { 0 };
+        let stream = match self.color {
+            Some(color) => stream.with_color(color),
+            None => stream,
+        };
+
+        stream.scaled(brightness)
+    }
+}
+
+impl core::fmt::Display for LightState {
+    /// Formats the state the way `from_str` parses it
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let on = if self.on { "ON" } else { "OFF" };
+        core::write!(f, "{},{}", on, self.brightness)?;
+        if let Some(Color(r, g, b)) = self.color {
+            core::write!(f, ",{},{},{}", r, g, b)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for LightState {
+    type Err = ();
+
+    /// Parses `<ON|OFF>,<brightness>[,<r>,<g>,<b>]`, e.g. `ON,128` or `ON,255,0,64,255`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, ',').map(str::trim);
+
+        let on = match parts.next() {
+            Some("ON") => true,
+            Some("OFF") => false,
+            _ => return Err(()),
+        };
+
+        let brightness = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let color = parts.next().map(str::parse).transpose()?;
+
+        Ok(LightState {
+            on,
+            brightness,
+            color,
+        })
+    }
+}
+
+/// Number of tasks that can follow the light state, besides status reports
+const MAX_LIGHT_RECEIVERS: usize = 1;
+
+/// Light state of the first zone, for status reports and outputs that don't go through the
+/// orchestrator
+static LIGHT: Watch<CriticalSectionRawMutex, Option<LightState>, MAX_LIGHT_RECEIVERS> =
+    Watch::new_with(None);
+
+/// Light state currently selected, `None` until it has been restored
+#[cfg_attr(not(feature = "udp_status"), allow(unused))]
+pub fn light_state() -> Option<LightState> {
+    LIGHT.try_get().flatten()
+}
+
+pub type LightReceiver =
+    watch::Receiver<'static, CriticalSectionRawMutex, Option<LightState>, MAX_LIGHT_RECEIVERS>;
+
+/// Observes the light state currently selected, see `Receiver::changed`
+#[cfg_attr(not(feature = "tunable_white"), allow(unused))]
+pub fn light_receiver() -> LightReceiver {
+    LIGHT
+        .receiver()
+        .expect("Too many tasks following the light state")
+}
+
+pub type LedCommandSender<'a> = Sender<'a, CriticalSectionRawMutex, LedCommand, 4>;
+pub type LedCommandReceiver<'a> = Receiver<'a, CriticalSectionRawMutex, LedCommand, 4>;
+
+/// Effect shown after booting
+const STARTUP_EFFECT: Effect = Effect::Droplets;
+
+/// Balances the LEDs of the RGB channels. Not applied in manual mode, so the raw output can be
+/// compared when calibrating.
+const COLOR_CORRECTION: ColorCorrection = ColorCorrection::IDENTITY;
+
+/// Gamma applied after booting, 1 leaves the colors unchanged. Can be changed at runtime through
+/// `picow/gamma/set`.
+const GAMMA: f32 = 1.;
+
+/// Maps the effect's colors to PWM duties after booting, after the gamma. Can be changed at
+/// runtime through `picow/dimming_curve/set`.
+const DIMMING_CURVE: DimmingCurve = DimmingCurve::Linear;
+
+/// Ticks of the timing program every burst is shown at least, extending bursts too short to be
+/// visible. 0 shows them as configured.
+const MIN_ON_TICKS: u32 = 0;
+
+/// How overlapping custom streams are combined, effects choose their own
+const MIX_MODE: MixMode = MixMode::Additive;
+
+/// Emits steps on a grid of this interval instead of at every change of a stream, so the DMA is
+/// paced evenly. `None` emits them exactly at the changes.
+const SAMPLE_INTERVAL: Option<Duration> = None;
+
+/// Shows built-in patterns instead of the effect while not connected to the broker: a fast blue
+/// blink while joining the network, and a slow red blink while disconnected.
+const STATUS_PATTERNS: bool = true;
+
+/// Restarts the animation on every rising edge of the sync input, so several devices sharing a
+/// trigger wire stay in phase. If disabled, the sync pin is left unused.
+///
+/// An edge interrupts the buffer being sent. The animation restarts once the first
+/// `SYNC_BUFFER_LENGTH` steps have been calculated again, which is the latency between the edge
+/// and the phase reset, plus the time it takes to wake the task. Edges while paused or in manual
+/// mode are ignored.
+const SYNC_INPUT: bool = false;
+
+/// Number of steps per buffer sent to the state machines
+const BUFFER_LENGTH: usize = 2048;
+
+/// Length of the first buffer after restarting the animation, kept short to restart quickly
+const SYNC_BUFFER_LENGTH: usize = 32;
+
+/// Time the LEDs take to fade out on shutdown, zero turns them off instantly. The animation keeps
+/// running while fading.
+const FADE_OUT_DURATION: Duration = Duration::from_millis(0);
+
+/// Number of steps the fade out is made of, each one dims the LEDs by a factor of √2
+const FADE_OUT_STEPS: u32 = 16;
+
+/// Counter wrap of the PWM slices, full brightness is `PWM_TOP + 1`
+const PWM_TOP: u16 = 254;
+
+/// Time a change of the brightness takes, zero applies it at once. Changes arriving in the
+/// meantime ramp on from the brightness shown at that moment, so a dragged slider is followed
+/// smoothly.
+const BRIGHTNESS_RAMP_DURATION: Duration = Duration::from_millis(300);
+
+/// Interval the PWM outputs are dimmed at during a brightness ramp
+const BRIGHTNESS_RAMP_STEP: Duration = Duration::from_millis(10);
+
+/// Time every color of the test pattern is shown for
+const TEST_PATTERN_STEP: Duration = Duration::from_secs(1);
+
+/// Color the light flashes in when identifying the device, shown regardless of its brightness
+const IDENTIFY_COLOR: Color = Color(255, 255, 255);
+
+/// Time the identify color is shown for
+const IDENTIFY_DURATION: Duration = Duration::from_secs(1);
+
+/// Number of steps calculated between yields to the executor. Higher values reduce scheduling
+/// overhead, lower values keep other tasks more responsive.
+const YIELD_BATCH_SIZE: usize = 16;
+
+#[derive(Clone, Copy)]
+enum Restart {
+    /// Starts the current animation over
+    Phase,
+    /// Switches to the streams of the current selection
+    Reconfigure,
+}
+
+impl Restart {
+    /// Starts the animation of `config` over, or replaces it with the one built by `reconfigure`.
+    /// The `pending` rest of a step split across buffers belongs to the old timeline.
+    fn apply<const N: usize>(
+        self,
+        config: &mut ColorStepIterator<N>,
+        pending: &mut Option<ColorStep>,
+        reconfigure: impl FnOnce() -> ColorStepIterator<N>,
+    ) {
+        match self {
+            Restart::Phase => config.reset(),
+            Restart::Reconfigure => *config = reconfigure(),
+        }
+        *pending = None;
+    }
+}
+
+/// Steps of swapping the config of a running zone, see `SWAP_SEQUENCE`
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum SwapStep {
+    /// Checks that no DMA transfer reads the buffers anymore
+    CheckDmaIdle,
+    /// Stops the state machines, dropping the steps of the old animation still queued in them
+    StopStateMachines,
+    /// Applies the `Restart` to the config
+    Reconfigure,
+    /// Calculates the first buffers of the new config
+    FillBuffers,
+    StartStateMachines,
+}
+
+/// Order the zones swap their config in. The buffers are only replaced once neither the DMA nor
+/// the state machines use them, and the state machines only start again once the new buffers are
+/// ready.
+const SWAP_SEQUENCE: [SwapStep; 5] = [
+    SwapStep::CheckDmaIdle,
+    SwapStep::StopStateMachines,
+    SwapStep::Reconfigure,
+    SwapStep::FillBuffers,
+    SwapStep::StartStateMachines,
+];
+
+const _: () = core::assert!(safe_swap_order(&SWAP_SEQUENCE));
+
+/// Whether `steps` is a complete swap that never touches the buffers while they're in use
+const fn safe_swap_order(steps: &[SwapStep]) -> bool {
+    // Where every step is, in the order they're declared in
+    let mut positions = [usize::MAX; 5];
+    let mut index = 0;
+    while index < steps.len() {
+        let step = steps[index] as usize;
+        if positions[step] != usize::MAX {
+            return false;
+        }
+        positions[step] = index;
+        index += 1;
+    }
+
+    // Without duplicates, five steps include every one of them
+    let [idle, stop, reconfigure, fill, start] = positions;
+    steps.len() == 5
+        && idle < reconfigure
+        && stop < reconfigure
+        && reconfigure < fill
+        && fill < start
+}
+
+/// What the animation has to do after a command has been applied to the selection
+enum Action {
+    None,
+    Restart(Restart),
+    /// Shows the overlay that has been started, leaving pause and manual mode
+    Overlay,
+}
+
+/// How the first zone feeds its PWM outputs, independent of what the selection shows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Playback {
+    /// The animation advances through the streams
+    Running,
+    /// The animation holds the frame it was at
+    Paused,
+    /// The PWM outputs show the given color, bypassing the animation and the color correction
+    Manual(Color),
+}
+
+impl Playback {
+    /// Applies `command` if it concerns the playback rather than the selection, and hands it back
+    /// otherwise. Pausing only affects a running animation, resuming leaves manual mode as well.
+    fn apply(&mut self, command: LedCommand) -> Option<LedCommand> {
+        match command {
+            LedCommand::Pause if *self == Playback::Running => *self = Playback::Paused,
+            LedCommand::Pause => {}
+            LedCommand::Resume => *self = Playback::Running,
+            LedCommand::Manual(color) => *self = Playback::Manual(color),
+            command => return Some(command),
+        }
+
+        None
+    }
+}
+
+/// Decides which streams are shown, and how
+struct Selection {
+    zone: Zone,
+    status: ConnectionStatus,
+    /// `None` until the light state has been restored, the LEDs stay dark until then
+    light: Option<LightState>,
+    /// Upper limit of the brightness while idle
+    idle_brightness: Option<u8>,
+    /// Shown unless a status pattern or an overlay is
+    effect: Effect,
+    /// Shown instead of the effect, if set
+    custom_streams: Option<Vec<StreamConfig, MAX_STREAMS>>,
+    /// Multiple of the normal speed the effect is played at
+    speed: f32,
+    /// Applies to the status patterns as well
+    gamma: Gamma,
+    /// Applies to the status patterns as well
+    dimming_curve: DimmingCurve,
+    /// Set until the given instant while the test pattern or the identify color is shown
+    overlay: Option<(Overlay, Instant)>,
+    /// Set while the brightness of the effect changes
+    ramp: Option<BrightnessRamp>,
+}
+
+/// Brightness change of the effect in progress. Rebuilding the streams restarts the animation,
+/// so they keep the brightness they were built with, and the PWM outputs are dimmed from it to
+/// the brightness shown at the moment.
+#[derive(Clone, Copy)]
+struct BrightnessRamp {
+    /// Brightness the streams are built with, at least the shown one
+    built: u8,
+    from: u8,
+    to: u8,
+    start: Instant,
+}
+
+impl BrightnessRamp {
+    fn end(&self) -> Instant {
+        self.start + BRIGHTNESS_RAMP_DURATION
+    }
+
+    /// Moves linearly from `from` to `to`
+    fn shown(&self, now: Instant) -> u8 {
+        let total = BRIGHTNESS_RAMP_DURATION.as_ticks().max(1) as i64;
+        let elapsed = (now.saturating_duration_since(self.start).as_ticks() as i64).min(total);
+        let (from, to) = (self.from as i64, self.to as i64);
+
+        (from + (to - from) * elapsed / total) as u8
+    }
+
+    /// Counter wrap of the PWM slices that dims the built brightness to the shown one
+    fn wrap(&self, now: Instant) -> u32 {
+        let shown = self.shown(now).max(1) as u32;
+
+        ((PWM_TOP as u32 + 1) * self.built as u32 / shown).max(PWM_TOP as u32 + 1)
+    }
+}
+
+/// Shown on top of everything else for a while, the selection underneath is kept as it is
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Overlay {
+    TestPattern,
+    Identify,
+}
+
+impl Overlay {
+    fn duration(self) -> Duration {
+        match self {
+            Overlay::TestPattern => TEST_PATTERN_STEP * 4,
+            Overlay::Identify => IDENTIFY_DURATION,
+        }
+    }
+
+    fn streams(self) -> Vec<StreamConfig, MAX_STREAMS> {
+        match self {
+            Overlay::TestPattern => test_pattern(),
+            Overlay::Identify => [StreamConfig::solid(IDENTIFY_COLOR)].into_iter().collect(),
+        }
+    }
+}
+
+impl Selection {
+    /// Shows the status pattern of joining the network until the light state is restored
+    fn new(zone: Zone) -> Self {
+        Selection {
+            zone,
+            status: ConnectionStatus::Joining,
+            light: None,
+            idle_brightness: None,
+            effect: STARTUP_EFFECT,
+            custom_streams: None,
+            speed: 1.,
+            gamma: Gamma::new(GAMMA),
+            dimming_curve: DIMMING_CURVE,
+            overlay: None,
+            ramp: None,
+        }
+    }
+
+    /// Applies `command` and reports the resulting state. Pausing, manual mode, stream dumps and
+    /// shutdowns concern the animation rather than the selection, so they're left to the caller.
+    fn apply(&mut self, command: LedCommand, sender: &MqttTxSender<'_>) -> Action {
+        let changed = match command {
+            LedCommand::Restart => return Action::Restart(Restart::Phase),
+            LedCommand::ReportEffect => {
+                report_effect(self.zone, self.effect_name(), sender);
+                false
+            }
+            LedCommand::ReportState => {
+                report_effect(self.zone, self.effect_name(), sender);
+                report_light(self.zone, self.light, sender);
+                false
+            }
+            LedCommand::NextEffect => {
+                let changed = self.next_effect();
+                report_effect(self.zone, self.effect_name(), sender);
+                report_light(self.zone, self.light, sender);
+                changed
+            }
+            LedCommand::SetStreams(streams) => {
+                let changed = self.set_custom_streams(streams);
+                report_effect(self.zone, self.effect_name(), sender);
+                changed
+            }
+            LedCommand::ToggleLight => {
+                let changed = self.toggle_light();
+                report_light(self.zone, self.light, sender);
+                changed
+            }
+            LedCommand::SwitchLight(on) => {
+                let changed = self.switch_light(on);
+                report_light(self.zone, self.light, sender);
+                changed
+            }
+            LedCommand::SetBrightness(brightness) => {
+                let changed = self.set_brightness(brightness);
+                report_light(self.zone, self.light, sender);
+                changed
+            }
+            LedCommand::AdjustBrightness(delta) => {
+                let changed = self.adjust_brightness(delta);
+                report_light(self.zone, self.light, sender);
+                changed
+            }
+            LedCommand::Status(status) => self.set_status(status),
+            LedCommand::SetLightState(light) => self.set_light(light),
+            LedCommand::Idle(brightness) => self.set_idle(brightness),
+            LedCommand::Speed(speed) => self.set_speed(speed),
+            LedCommand::Gamma(gamma) => self.set_gamma(gamma),
+            LedCommand::DimmingCurve(curve) => self.set_dimming_curve(curve),
+            LedCommand::TestPattern => {
+                self.start_overlay(Overlay::TestPattern);
+                return Action::Overlay;
+            }
+            LedCommand::Identify => {
+                self.start_overlay(Overlay::Identify);
+                return Action::Overlay;
+            }
+            LedCommand::Pause
+            | LedCommand::Resume
+            | LedCommand::Manual(_)
+            | LedCommand::DumpStreams
+            | LedCommand::Shutdown(_) => false,
+        };
+
+        if changed {
+            Action::Restart(Restart::Reconfigure)
+        } else {
+            Action::None
+        }
+    }
+
+    fn streams(&self) -> Vec<StreamConfig, MAX_STREAMS> {
+        if let Some((overlay, _)) = self.overlay {
+            return overlay.streams();
+        }
+
+        if let Some(pattern) = self.status_pattern() {
+            return pattern;
+        }
+
+        match self.light {
+            Some(light) => {
+                let brightness = match self.ramp {
+                    Some(ramp) => ramp.built,
+                    None => self.target_brightness().unwrap_or_default(),
+                };
+                let light = LightState {
+                    on: true,
+                    brightness,
+                    ..light
+                };
+
+                let streams = match &self.custom_streams {
+                    Some(streams) => streams.clone(),
+                    None => self.effect.streams(),
+                };
+
+                streams
+                    .into_iter()
+                    .map(|stream| light.apply(stream).with_speed(self.speed))
+                    .collect()
+            }
+            None => [StreamConfig::solid(Color::black())].into_iter().collect(),
+        }
+    }
+
+    fn mix_mode(&self) -> MixMode {
+        if self.overlay.is_some() || self.status_pattern().is_some() {
+            return MixMode::Additive;
+        }
+
+        match self.custom_streams {
+            Some(_) => MIX_MODE,
+            None => self.effect.mix_mode(),
+        }
+    }
+
+    fn status_pattern(&self) -> Option<Vec<StreamConfig, MAX_STREAMS>> {
+        STATUS_PATTERNS.then(|| self.status.pattern()).flatten()
+    }
+
+    /// Brightness of the effect once any ramp is done, `None` while it isn't shown
+    fn target_brightness(&self) -> Option<u8> {
+        if self.overlay.is_some() || self.status_pattern().is_some() {
+            return None;
+        }
+
+        let light = self.light?;
+        let brightness = if light.on { light.brightness } else { 0 };
+
+        Some(brightness.min(self.idle_brightness.unwrap_or(u8::MAX)))
+    }
+
+    fn shown_brightness(&self, now: Instant) -> Option<u8> {
+        match self.ramp {
+            Some(ramp) => Some(ramp.shown(now)),
+            None => self.target_brightness(),
+        }
+    }
+
+    /// Ramps from the brightness `shown` before changing the selection to the new target, starting
+    /// `now`.
+    /// Returns whether the streams have to be rebuilt, which during a ramp is only needed if they
+    /// are too dark for the target, or `restyled` in another way.
+    ///
+    /// Only the PWM slices of the first zone can be dimmed, the second one changes at once.
+    fn ramp_brightness(&mut self, shown: Option<u8>, restyled: bool, now: Instant) -> bool {
+        let built = self.ramp.map_or(shown, |ramp| Some(ramp.built));
+        let ramped = self.zone == Zone::First && BRIGHTNESS_RAMP_DURATION > Duration::from_ticks(0);
+
+        match (shown, built, self.target_brightness()) {
+            (Some(from), Some(built), Some(to)) if ramped && from != to => {
+                let rebuild = restyled || to > built;
+                self.ramp = Some(BrightnessRamp {
+                    built: if rebuild { from.max(to) } else { built },
+                    from,
+                    to,
+                    start: now,
+                });
+
+                rebuild
+            }
+            _ => {
+                self.ramp = None;
+                self.light.is_some() && self.status_pattern().is_none()
+            }
+        }
+    }
+
+    /// Ends the ramp, returning whether the streams have to be rebuilt at the target brightness
+    fn finish_ramp(&mut self) -> bool {
+        self.ramp.take().is_some_and(|ramp| ramp.to < ramp.built)
+    }
+
+    /// Returns whether the shown streams changed
+    fn set_status(&mut self, status: ConnectionStatus) -> bool {
+        if self.status == status {
+            return false;
+        }
+
+        info!("Connection status {}", status);
+        self.status = status;
+
+        // Every status shows a different pattern, or the effect. Patterns aren't dimmed.
+        if STATUS_PATTERNS {
+            self.ramp = None;
+        }
+        STATUS_PATTERNS
+    }
+
+    /// Returns whether the shown streams changed
+    fn set_custom_streams(&mut self, streams: Option<Vec<StreamConfig, MAX_STREAMS>>) -> bool {
+        if self.custom_streams.is_none() && streams.is_none() {
+            return false;
+        }
+        self.custom_streams = streams;
+        info!("Switching to {}", self.effect_name());
+
+        self.light.is_some() && self.status_pattern().is_none()
+    }
+
+    /// Name of the shown effect, `Custom` for custom streams
+    fn effect_name(&self) -> &'static str {
+        match self.custom_streams {
+            Some(_) => "Custom",
+            None => self.effect.name(),
+        }
+    }
+
+    /// Returns whether the shown streams have to be rebuilt, brightness changes are ramped
+    fn set_light(&mut self, light: LightState) -> bool {
+        let now = Instant::now();
+        let shown = self.shown_brightness(now);
+        let restyled = self
+            .light
+            .is_none_or(|previous| previous.color != light.color);
+        self.store_light(light);
+
+        self.ramp_brightness(shown, restyled, now)
+    }
+
+    fn store_light(&mut self, light: LightState) {
+        self.light = Some(light);
+        if self.zone == Zone::First {
+            LIGHT.sender().send(Some(light));
+        }
+    }
+
+    /// Returns whether the shown streams have to be rebuilt
+    fn toggle_light(&mut self) -> bool {
+        match self.light {
+            Some(light) => self.set_light(LightState {
+                on: !light.on,
+                ..light
+            }),
+            None => false,
+        }
+    }
+
+    /// Returns whether the shown streams have to be rebuilt
+    fn switch_light(&mut self, on: bool) -> bool {
+        match self.light {
+            Some(light) if light.on != on => self.set_light(LightState { on, ..light }),
+            _ => false,
+        }
+    }
+
+    /// Returns whether the shown streams have to be rebuilt
+    fn set_brightness(&mut self, brightness: u8) -> bool {
+        match self.light {
+            Some(light) if light.brightness != brightness => self.set_light(LightState {
+                brightness,
+                ..light
+            }),
+            _ => false,
+        }
+    }
+
+    /// Returns whether the shown streams have to be rebuilt
+    fn adjust_brightness(&mut self, delta: i16) -> bool {
+        match self.light {
+            Some(light) => self.set_light(LightState {
+                on: light.on || delta > 0,
+                brightness: (light.brightness as i16 + delta).clamp(0, u8::MAX as i16) as u8,
+                ..light
+            }),
+            None => false,
+        }
+    }
+
+    /// Returns whether the shown streams changed
+    fn next_effect(&mut self) -> bool {
+        let next = EFFECTS
+            .iter()
+            .position(|effect| *effect == self.effect)
+            .map_or(0, |index| (index + 1) % EFFECTS.len());
+        self.effect = EFFECTS[next];
+        self.custom_streams = None;
+        info!("Switching to effect {}", self.effect.name());
+
+        // Brightness commands after switching override it again
+        if let (Some(light), Some(brightness)) = (self.light, self.effect.default_brightness()) {
+            let now = Instant::now();
+            let shown = self.shown_brightness(now);
+            self.store_light(LightState {
+                brightness,
+                ..light
+            });
+            _ = self.ramp_brightness(shown, true, now);
+        }
+
+        self.light.is_some() && self.status_pattern().is_none()
+    }
+
+    /// Returns whether the shown streams have to be rebuilt, brightness changes are ramped
+    fn set_idle(&mut self, brightness: Option<u8>) -> bool {
+        let changed = self.idle_brightness != brightness;
+        let now = Instant::now();
+        let shown = self.shown_brightness(now);
+        self.idle_brightness = brightness;
+
+        changed && self.ramp_brightness(shown, false, now)
+    }
+
+    /// Returns whether the shown streams changed
+    fn set_speed(&mut self, speed: f32) -> bool {
+        let speed = speed.clamp(*SPEED_RANGE.start(), *SPEED_RANGE.end());
+        let changed = self.speed != speed;
+        self.speed = speed;
+
+        changed && self.light.is_some() && self.status_pattern().is_none()
+    }
+
+    /// Shows the overlay from its beginning, even if it's already shown
+    fn start_overlay(&mut self, overlay: Overlay) {
+        self.overlay = Some((overlay, Instant::now() + overlay.duration()));
+        self.ramp = None;
+    }
+
+    /// Returns whether the shown streams changed
+    fn set_gamma(&mut self, gamma: f32) -> bool {
+        let gamma = gamma.clamp(*GAMMA_RANGE.start(), *GAMMA_RANGE.end());
+        if self.gamma.gamma() == gamma {
+            return false;
+        }
+
+        self.gamma = Gamma::new(gamma);
+        true
+    }
+
+    /// Returns whether the shown streams changed
+    fn set_dimming_curve(&mut self, curve: DimmingCurve) -> bool {
+        let changed = self.dimming_curve != curve;
+        self.dimming_curve = curve;
+
+        changed
+    }
+}
+
+/// Frequency a timing program taking `cycles_per_tick` has to run at for ticks of
+/// `micros_per_tick`, and the clock divider to reach it from `clk_hz`. `None` if the divider is
+/// outside of what the PIO supports.
+pub fn compute_timing(
+    clk_hz: u32,
+    micros_per_tick: i32,
+    cycles_per_tick: u32,
+) -> Option<(u32, FixedU32<U8>)> {
+    let target_frequency = 1_000_000_u32
+        .checked_div(micros_per_tick.try_into().ok()?)?
+        .checked_mul(cycles_per_tick)?;
+    let clock_divider = (clk_hz as f64) / (target_frequency as f64);
+    if !(1.0..=65536.0).contains(&clock_divider) {
+        return None;
+    }
+
+    Some((
+        target_frequency,
+        FixedU32::<U8>::checked_from_num(clock_divider)?,
+    ))
+}
+
+/// Step of a fade out over `duration` that `started` before `now`, `None` once it's done
+fn fade_out_step(duration: Duration, started: Instant, now: Instant) -> Option<u32> {
+    let step = match duration.as_ticks() {
+        0 => FADE_OUT_STEPS,
+        total => {
+            let elapsed = now.saturating_duration_since(started).as_ticks().min(total);
+            (elapsed * FADE_OUT_STEPS as u64 / total) as u32
+        }
     };
-    timing_config.shift_in = ShiftConfig {
-        direction: embassy_rp::pio::ShiftDirection::Left,
-        auto_fill: true,
-        threshold: 16,
+
+    (step < FADE_OUT_STEPS).then_some(step)
+}
+
+/// Counter wrap of the PWM slices dimming the LEDs to `step` of the fade out. Every second step
+/// doubles the wrap, the ones in between multiply it by ~√2.
+fn fade_out_wrap(step: u32) -> u32 {
+    ((PWM_TOP as u32 + 1) << (step / 2)) * [128, 181][step as usize % 2] / 128
+}
+
+/// Compare values of the red, green and blue PWM outputs showing `color`. At the counter wrap of
+/// `PWM_TOP + 1`, every level of a component is a duty cycle of `level / 255`.
+fn pwm_duties(color: Color) -> [u16; 3] {
+    color.as_array().map(u16::from)
+}
+
+/// Steps of the streams of `selection`, for a timing program with the given tick length and
+/// overhead per step
+fn stream_config(
+    selection: &Selection,
+    micros_per_tick: i32,
+    tick_overhead: i32,
+) -> ColorStepIterator<MAX_STREAMS> {
+    let config =
+        stream::Config::<MAX_STREAMS>::new(&selection.streams(), micros_per_tick, tick_overhead)
+            .with_color_correction(COLOR_CORRECTION)
+            .with_gamma(selection.gamma.clone())
+            .with_dimming_curve(selection.dimming_curve)
+            .with_mix_mode(selection.mix_mode());
+    match SAMPLE_INTERVAL {
+        Some(interval) => config.with_sample_interval(interval),
+        None => config,
+    }
+    .with_min_on_time(MIN_ON_TICKS)
+    .into_iter()
+}
+
+/// The dump is dropped if the MQTT queue is full, instead of stalling the animation
+fn dump_streams<const N: usize>(config: &ColorStepIterator<N>, sender: &MqttTxSender<'_>) {
+    let payload = match config.config().to_json::<MAX_PAYLOAD_LENGTH>() {
+        Ok(payload) => payload,
+        Err(_) => {
+            warn!("Failed to serialize the stream configuration");
+            return;
+        }
     };
 
-    let mut pwm_config = pwm::Config::default();
-    pwm_config.enable = true;
-    pwm_config.top = 254;
-
-    let pwm_slice_red = p.red_slice.number();
-    let pwm = Pwm::new_output_a(p.red_slice, p.red_pin, pwm_config.clone());
-    core::mem::forget(pwm);
-
-    sync_pio_to_pwm(
-        [*p.dma_pwm_red_a.into(), *p.dma_pwm_red_b.into()],
-        pwm_slice_red,
-        1,
-        0,
-    );
-
-    let pwm_slice_green = p.green_slice.number();
-    let pwm = Pwm::new_output_a(p.green_slice, p.green_pin, pwm_config.clone());
-    core::mem::forget(pwm);
-
-    sync_pio_to_pwm(
-        [*p.dma_pwm_green_a.into(), *p.dma_pwm_green_b.into()],
-        pwm_slice_green,
-        1,
-        1,
-    );
-
-    let pwm_slice_blue = p.blue_slice.number();
-    let pwm = Pwm::new_output_a(p.blue_slice, p.blue_pin, pwm_config.clone());
-    core::mem::forget(pwm);
-
-    sync_pio_to_pwm(
-        [*p.dma_pwm_blue_a.into(), *p.dma_pwm_blue_b.into()],
-        pwm_slice_blue,
-        1,
-        2,
-    );
-
-    pio.sm0.set_config(&timing_config);
-    pio.sm0.set_enable(true);
-
-    pio.sm1.set_config(&timing_config);
-    pio.sm1.set_enable(true);
-
-    pio.sm2.set_config(&timing_config);
-    pio.sm2.set_enable(true);
-
-    pio.irq_flags.set_all(0);
-
-    let mut config = stream::Config::new(
-        &[
-            StreamConfig::new(Color(255, 0, 0), Hz(60.), Duration::from_millis(3), None),
-            StreamConfig::new(
-                Color(0, 255, 255),
-                Hz(60.5),
-                Duration::from_millis(3),
-                Some(Duration::from_millis(500)),
-            ),
-            StreamConfig::new(
-                Color(0, 255, 00),
-                Hz(59.5),
-                Duration::from_millis(3),
-                Some(Duration::from_millis(2500)),
-            ),
-        ],
-        timing_program.public_defines.MICROS_PER_TICK,
-        timing_program.public_defines.TICK_OVERHEAD,
-    )
-    .into_iter();
+    let packet = TxPacket::PublishWithProperties {
+        qospid: mqttrs::QosPid::AtMostOnce,
+        retain: false,
+        topic_name: String::try_from("picow/debug/streams").unwrap(),
+        payload: payload.into_bytes(),
+        properties: PublishProperties {
+            content_type: Some("application/json"),
+            ..Default::default()
+        },
+    };
 
-    let (mut red, mut green, mut blue) = calculate_next_buffer::<_, 2048>(&mut config).await;
+    if sender.try_send(packet).is_err() {
+        warn!("MQTT queue is full, dropping the stream dump");
+    }
+}
 
-    loop {
-        info!("Loop");
+fn report_effect(zone: Zone, name: &'static str, sender: &MqttTxSender<'_>) {
+    let packet = TxPacket::Publish {
+        qospid: mqttrs::QosPid::AtMostOnce,
+        retain: true,
+        topic_name: zone.effect_state_topic(),
+        payload: name.as_bytes(),
+    };
 
-        let ((r, g, b), _, _, _) = join4(
-            calculate_next_buffer(&mut config),
-            pio.sm0.tx().dma_push(p.dma_pio_red.reborrow(), &red, false),
-            pio.sm1
-                .tx()
-                .dma_push(p.dma_pio_green.reborrow(), &green, false),
-            pio.sm2
-                .tx()
-                .dma_push(p.dma_pio_blue.reborrow(), &blue, false),
-        )
-        .await;
+    if sender.try_send(packet).is_err() {
+        warn!("MQTT queue is full, dropping the effect state");
+    }
+}
 
-        red = r;
-        green = g;
-        blue = b;
+/// Nothing is published before the light state has been restored
+fn report_light(zone: Zone, light: Option<LightState>, sender: &MqttTxSender<'_>) {
+    let Some(light) = light else {
+        return;
+    };
+
+    let mut payload = String::<MAX_PAYLOAD_LENGTH>::new();
+    _ = core::write!(payload, "{}", light);
+
+    let packet = TxPacket::PublishOwned {
+        qospid: mqttrs::QosPid::AtMostOnce,
+        retain: true,
+        topic_name: String::try_from(zone.light_state_topic()).unwrap(),
+        payload: payload.into_bytes(),
+    };
+
+    if sender.try_send(packet).is_err() {
+        warn!("MQTT queue is full, dropping the light state");
+    }
+}
+
+/// Every color is on for one step, the whole sequence is shown once before the previous streams
+/// are restored
+fn test_pattern() -> Vec<StreamConfig, MAX_STREAMS> {
+    let colors = [
+        Color(255, 0, 0),
+        Color(0, 255, 0),
+        Color(0, 0, 255),
+        Color(255, 255, 255),
+    ];
+    let period = TEST_PATTERN_STEP * colors.len() as u32;
+    let group = TimingGroup::new(Hz(1e6 / period.as_micros() as f32), None);
+
+    colors
+        .into_iter()
+        .zip(0..)
+        .map(|(color, index)| group.stream(color, TEST_PATTERN_STEP, TEST_PATTERN_STEP * index))
+        .collect()
+}
+
+/// Never returns without a deadline
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => Timer::at(deadline).await,
+        None => core::future::pending().await,
     }
 }
 
+/// Calculates the next `length` steps, at most `BUFFER_SIZE`
 async fn calculate_next_buffer<const NUM_STREAMS: usize, const BUFFER_SIZE: usize>(
     config: &mut ColorStepIterator<NUM_STREAMS>,
+    pending: &mut Option<ColorStep>,
+    length: usize,
+    yield_batch_size: usize,
 ) -> (
     Vec<u32, BUFFER_SIZE>,
     Vec<u32, BUFFER_SIZE>,
@@ -163,55 +995,716 @@ async fn calculate_next_buffer<const NUM_STREAMS: usize, const BUFFER_SIZE: usiz
     let mut green = Vec::new();
     let mut blue = Vec::new();
 
-    while !red.is_full() {
-        let next = config.next().unwrap();
+    while red.len() < length.min(BUFFER_SIZE) {
+        // Delays that don't fit into 24 bits are emitted as several steps. Only one step is
+        // pushed per iteration, the rest is carried over, possibly into the next buffer.
+        let mut next = pending.take().unwrap_or_else(|| config.next().unwrap());
+        if let Some(step) = next.split_off(config.tick_overhead()) {
+            *pending = Some(next);
+            next = step;
+        }
 
-        red.push(next.encode_red()).unwrap();
-        green.push(next.encode_green()).unwrap();
-        blue.push(next.encode_blue()).unwrap();
+        let [r, g, b] = next.encode();
+        red.push(r).unwrap();
+        green.push(g).unwrap();
+        blue.push(b).unwrap();
 
-        yield_now().await;
+        if red.len() % yield_batch_size == 0 {
+            yield_now().await;
+        }
     }
 
-    return (red, green, blue);
+    (red, green, blue)
 }
 
-fn sync_pio_to_pwm(dmas: [AnyChannel; 2], pwm_slice: usize, pio_number: u8, sm: u8) {
-    let raw_pwm = pac::PWM.ch(pwm_slice);
+#[cfg(test)]
+mod tests {
+    use core::task::{Context, Poll, Waker};
+    use embassy_futures::block_on;
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+    use embassy_time::{Duration, Instant};
+    use heapless::Vec;
+
+    use super::{
+        Action, BRIGHTNESS_RAMP_DURATION, BrightnessRamp, ConnectionStatus, EFFECTS,
+        FADE_OUT_STEPS, GAMMA_RANGE, IDENTIFY_COLOR, IDENTIFY_DURATION, LedCommand, LightState,
+        Overlay, Playback, Restart, SPEED_RANGE, SWAP_SEQUENCE, Selection, SwapStep,
+        TEST_PATTERN_STEP, Zone, calculate_next_buffer, compute_timing, fade_out_step,
+        fade_out_wrap, pwm_duties, safe_swap_order, stream_config,
+    };
+    use crate::{
+        effects::Effect,
+        mqtt::TxPacket,
+        stream::{Color, ColorStep, Config, Hz, StreamConfig},
+    };
+
+    #[test]
+    fn light_states_round_trip_through_their_payload() {
+        let states = [
+            LightState::DEFAULT,
+            LightState {
+                on: false,
+                brightness: 0,
+                color: None,
+            },
+            LightState {
+                on: true,
+                brightness: 128,
+                color: Some(Color(0, 64, 255)),
+            },
+        ];
+
+        for state in states {
+            let payload = std::format!("{state}");
+            assert_eq!(payload.parse(), Ok(state));
+        }
+        assert_eq!(std::format!("{}", states[2]), "ON,128,0,64,255");
+    }
+
+    #[test]
+    fn next_effect_cycles_and_wraps() {
+        let mut selection = Selection::new(Zone::Second);
+        let start = EFFECTS
+            .iter()
+            .position(|effect| *effect == selection.effect)
+            .unwrap();
+
+        for offset in 1..=EFFECTS.len() {
+            selection.next_effect();
+            assert!(selection.effect == EFFECTS[(start + offset) % EFFECTS.len()]);
+        }
+        assert!(selection.effect == EFFECTS[start]);
+    }
+
+    #[test]
+    fn toggling_switches_the_light_on_and_off() {
+        let mut selection = Selection::new(Zone::Second);
+        selection.set_status(ConnectionStatus::Connected);
+        // Nothing to toggle before the light state is restored
+        assert!(!selection.toggle_light());
+
+        selection.set_light(LightState::DEFAULT);
+        assert!(selection.toggle_light());
+        assert_eq!(
+            selection.light,
+            Some(LightState {
+                on: false,
+                ..LightState::DEFAULT
+            })
+        );
+        assert!(selection.toggle_light());
+        assert_eq!(selection.light, Some(LightState::DEFAULT));
+    }
+
+    #[test]
+    fn manual_commands_set_the_exact_duties() {
+        let mut playback = Playback::Running;
+
+        assert!(
+            playback
+                .apply(LedCommand::Manual(Color(255, 128, 1)))
+                .is_none()
+        );
+        assert_eq!(playback, Playback::Manual(Color(255, 128, 1)));
+        assert_eq!(pwm_duties(Color(255, 128, 1)), [255, 128, 1]);
+        assert_eq!(pwm_duties(Color::black()), [0, 0, 0]);
+
+        // Another color replaces the shown one, pausing doesn't leave manual mode
+        assert!(
+            playback
+                .apply(LedCommand::Manual(Color(0, 0, 255)))
+                .is_none()
+        );
+        assert!(playback.apply(LedCommand::Pause).is_none());
+        assert_eq!(playback, Playback::Manual(Color(0, 0, 255)));
+
+        // Commands for the selection are handed back
+        assert!(matches!(
+            playback.apply(LedCommand::ToggleLight),
+            Some(LedCommand::ToggleLight)
+        ));
+        assert!(playback.apply(LedCommand::Resume).is_none());
+        assert_eq!(playback, Playback::Running);
+    }
+
+    #[test]
+    fn pausing_holds_the_frame_and_resuming_continues_it() {
+        let streams = [
+            StreamConfig::new(Color(255, 0, 0), Hz(100.), Duration::from_millis(2), None),
+            StreamConfig::new(Color(0, 0, 255), Hz(30.), Duration::from_millis(5), None),
+        ];
+        let buffer = |config: &mut _, pending: &mut _, length| {
+            block_on(calculate_next_buffer::<2, 32>(config, pending, length, 4))
+        };
+        let mut config = Config::<2>::new(&streams, 1, 0).into_iter();
+        let mut pending = None;
+        let before = buffer(&mut config, &mut pending, 16);
+
+        let mut playback = Playback::Running;
+        assert!(playback.apply(LedCommand::Pause).is_none());
+        assert_eq!(playback, Playback::Paused);
+        // Pausing again doesn't change anything, and the selection still gets its commands
+        assert!(playback.apply(LedCommand::Pause).is_none());
+        assert!(playback.apply(LedCommand::NextEffect).is_some());
+        assert_eq!(playback, Playback::Paused);
+
+        // No buffers are calculated while paused, so the outputs keep the last step of `before`
+        assert!(playback.apply(LedCommand::Resume).is_none());
+        assert_eq!(playback, Playback::Running);
+        let after = buffer(&mut config, &mut pending, 16);
+
+        // Resuming continues with the steps that would have followed without the pause
+        let mut config = Config::<2>::new(&streams, 1, 0).into_iter();
+        let (red, green, blue) = buffer(&mut config, &mut None, 32);
+        assert_eq!([&before.0[..], &after.0[..]].concat(), red.as_slice());
+        assert_eq!([&before.1[..], &after.1[..]].concat(), green.as_slice());
+        assert_eq!([&before.2[..], &after.2[..]].concat(), blue.as_slice());
+    }
+
+    #[test]
+    fn fade_outs_dim_step_by_step_until_done() {
+        let duration = Duration::from_millis(1600);
+        let started = Instant::from_secs(10);
+        let at = |millis| fade_out_step(duration, started, started + Duration::from_millis(millis));
+
+        assert_eq!(at(0), Some(0));
+        assert_eq!(at(99), Some(0));
+        assert_eq!(at(100), Some(1));
+        assert_eq!(at(799), Some(7));
+        assert_eq!(at(1599), Some(FADE_OUT_STEPS - 1));
+        assert_eq!(at(1600), None);
+        assert_eq!(at(60_000), None);
+        // Fading instantly is done right away
+        assert_eq!(
+            fade_out_step(Duration::from_ticks(0), started, started),
+            None
+        );
+
+        // Full brightness first, then every step dims by ~√2
+        assert_eq!(fade_out_wrap(0), 255);
+        assert_eq!(fade_out_wrap(1), 360);
+        assert_eq!(fade_out_wrap(2), 510);
+        assert_eq!(fade_out_wrap(7), 2884);
+        for step in 1..FADE_OUT_STEPS {
+            assert!(fade_out_wrap(step) > fade_out_wrap(step - 1));
+        }
+        assert_eq!(fade_out_wrap(FADE_OUT_STEPS - 2), 255 << 7);
+    }
+
+    /// A selection of the second zone showing a single custom stream at full brightness
+    fn custom_selection() -> Selection {
+        let mut selection = Selection::new(Zone::Second);
+        selection.set_status(ConnectionStatus::Connected);
+        selection.set_light(LightState::DEFAULT);
+        let stream = StreamConfig::new(Color(255, 0, 0), Hz(100.), Duration::from_millis(2), None);
+        selection.set_custom_streams(Some([stream].into_iter().collect()));
+        selection
+    }
+
+    #[test]
+    fn speed_scales_the_shown_streams() {
+        let mut selection = custom_selection();
+        let loop_period =
+            |selection: &Selection| stream_config(selection, 1, 0).config().loop_period();
+        assert_eq!(loop_period(&selection), Duration::from_millis(10));
+
+        assert!(selection.set_speed(2.));
+        assert_eq!(loop_period(&selection), Duration::from_millis(5));
+        let config = stream_config(&selection, 1, 0);
+        // The burst shrinks along with the period
+        assert_eq!(
+            config.config().color_at(Instant::from_micros(999)),
+            Color(255, 0, 0)
+        );
+        assert_eq!(
+            config.config().color_at(Instant::from_millis(1)),
+            Color::black()
+        );
+        // Setting the same speed again keeps the animation going
+        assert!(!selection.set_speed(2.));
 
-    let treq_sel = pac::dma::vals::TreqSel::from(pio_number * 8 + sm + 4);
+        assert!(selection.set_speed(1000.));
+        assert_eq!(selection.speed, *SPEED_RANGE.end());
+        assert!(selection.set_speed(0.));
+        assert_eq!(selection.speed, *SPEED_RANGE.start());
+    }
+
+    #[test]
+    fn gamma_is_applied_to_the_shown_streams() {
+        let mut selection = custom_selection();
+        selection.set_brightness(128);
+        let shown = |selection: &Selection| {
+            stream_config(selection, 1, 0)
+                .config()
+                .color_at(Instant::MIN)
+        };
+        assert_eq!(shown(&selection), Color(128, 0, 0));
 
-    let [first_dma, second_dma] = dmas;
-    let r = first_dma.regs();
+        // (128 / 255) ^ 2 * 255 is 64.25
+        assert!(selection.set_gamma(2.));
+        assert_eq!(shown(&selection), Color(64, 0, 0));
+        assert!(!selection.set_gamma(2.));
 
-    r.write_addr().write_value(raw_pwm.cc().as_ptr() as u32);
-    r.read_addr()
-        .write_value(pac::PIO1.rxf(sm as usize).as_ptr() as u32);
-    r.trans_count().write_value(u32::MAX);
-    r.al1_ctrl().write(|val| {
-        let mut w = CtrlTrig(0);
-        w.set_treq_sel(treq_sel);
-        w.set_data_size(u16::size());
-        w.set_chain_to(second_dma.number());
-        w.set_incr_read(false);
-        w.set_incr_write(false);
-        w.set_en(true);
+        // Clamped to the range, 3 gives 32.25
+        assert!(selection.set_gamma(10.));
+        assert_eq!(selection.gamma.gamma(), *GAMMA_RANGE.end());
+        assert_eq!(shown(&selection), Color(32, 0, 0));
+        assert!(selection.set_gamma(0.));
+        assert_eq!(shown(&selection), Color(128, 0, 0));
+    }
 
-        *val = w.0;
-    });
+    #[test]
+    fn test_patterns_cycle_through_every_channel() {
+        let mut selection = custom_selection();
+        selection.set_brightness(10);
+        selection.start_overlay(Overlay::TestPattern);
 
-    let r = second_dma.regs();
+        // Shown at full brightness, regardless of the light
+        let config = stream_config(&selection, 1, 0);
+        let colors = [0, 1, 2, 3].map(|step| {
+            let start = Instant::MIN + TEST_PATTERN_STEP * step;
+            let end = start + TEST_PATTERN_STEP - Duration::from_micros(1);
+            assert_eq!(
+                config.config().color_at(start),
+                config.config().color_at(end)
+            );
+            config.config().color_at(start)
+        });
+        assert_eq!(
+            colors,
+            [
+                Color(255, 0, 0),
+                Color(0, 255, 0),
+                Color(0, 0, 255),
+                Color(255, 255, 255)
+            ]
+        );
+        let (_, end) = selection.overlay.unwrap();
+        assert!(end - Instant::now() <= TEST_PATTERN_STEP * 4);
+
+        // Once it's over, the previous streams are back
+        selection.overlay = None;
+        let config = stream_config(&selection, 1, 0);
+        assert_eq!(config.config().color_at(Instant::MIN), Color(10, 0, 0));
+    }
+
+    /// Applies `command` to `selection`, returning the published state as topics and payloads
+    fn apply(selection: &mut Selection, command: LedCommand) -> std::vec::Vec<(String, String)> {
+        let channel = Channel::<CriticalSectionRawMutex, TxPacket, 10>::new();
+        selection.apply(command, &channel.sender());
+
+        let mut published = std::vec::Vec::new();
+        while let Ok(packet) = channel.try_receive() {
+            let (topic, payload) = match packet {
+                TxPacket::Publish {
+                    topic_name,
+                    payload,
+                    retain: true,
+                    ..
+                } => (topic_name.into(), payload.to_vec()),
+                TxPacket::PublishOwned {
+                    topic_name,
+                    payload,
+                    retain: true,
+                    ..
+                } => (topic_name.as_str().into(), payload.to_vec()),
+                _ => panic!("Expected a retained publish"),
+            };
+            published.push((topic, String::from_utf8(payload).unwrap()));
+        }
+        published
+    }
+
+    #[test]
+    fn configs_are_only_swapped_while_the_buffers_are_unused() {
+        use SwapStep::*;
+
+        assert!(safe_swap_order(&SWAP_SEQUENCE));
+        // Where the DMA check goes relative to stopping doesn't matter
+        assert!(safe_swap_order(&[
+            StopStateMachines,
+            CheckDmaIdle,
+            Reconfigure,
+            FillBuffers,
+            StartStateMachines
+        ]));
+
+        for unsafe_order in [
+            // Buffers replaced while the DMA may still read them
+            [
+                Reconfigure,
+                CheckDmaIdle,
+                StopStateMachines,
+                FillBuffers,
+                StartStateMachines,
+            ],
+            // Old steps still queued in the state machines
+            [
+                CheckDmaIdle,
+                Reconfigure,
+                StopStateMachines,
+                FillBuffers,
+                StartStateMachines,
+            ],
+            // The buffers of the old config
+            [
+                CheckDmaIdle,
+                StopStateMachines,
+                FillBuffers,
+                Reconfigure,
+                StartStateMachines,
+            ],
+            // Started without buffers
+            [
+                CheckDmaIdle,
+                StopStateMachines,
+                Reconfigure,
+                StartStateMachines,
+                FillBuffers,
+            ],
+            // Never checked
+            [
+                StopStateMachines,
+                StopStateMachines,
+                Reconfigure,
+                FillBuffers,
+                StartStateMachines,
+            ],
+        ] {
+            assert!(!safe_swap_order(&unsafe_order), "{unsafe_order:?}");
+        }
+        assert!(!safe_swap_order(&SWAP_SEQUENCE[..4]));
+    }
+
+    #[test]
+    fn state_requests_report_the_effect_and_the_light() {
+        let mut selection = custom_selection();
+        let state = [
+            ("picow/zone2/effect/state".into(), "Custom".into()),
+            (
+                "picow/zone2/light/state".into(),
+                LightState::DEFAULT.to_string(),
+            ),
+        ];
 
-    r.write_addr().write_value(raw_pwm.cc().as_ptr() as u32);
-    r.read_addr()
-        .write_value(pac::PIO1.rxf(sm as usize).as_ptr() as u32);
-    r.trans_count().write_value(u32::MAX);
-    r.ctrl_trig().write(|w| {
-        w.set_treq_sel(treq_sel);
-        w.set_data_size(u16::size());
-        w.set_chain_to(first_dma.number());
-        w.set_incr_read(false);
-        w.set_incr_write(false);
-        w.set_en(true);
-    });
+        // Asking again gives the same answer, without touching the animation
+        for _ in 0..2 {
+            let channel = Channel::<CriticalSectionRawMutex, TxPacket, 10>::new();
+            assert!(matches!(
+                selection.apply(LedCommand::ReportState, &channel.sender()),
+                Action::None
+            ));
+            assert_eq!(apply(&mut selection, LedCommand::ReportState), state);
+        }
+    }
+
+    #[test]
+    fn effect_names_are_reported_on_every_change() {
+        let mut selection = Selection::new(Zone::First);
+        let effect = |name: &str| ("picow/effect/state".into(), name.into());
+
+        assert_eq!(
+            apply(&mut selection, LedCommand::ReportEffect),
+            [effect(selection.effect.name())]
+        );
+        // Switching reports the light as well, which isn't there until it's been restored
+        let published = apply(&mut selection, LedCommand::NextEffect);
+        assert_eq!(published, [effect(selection.effect.name())]);
+
+        let stream = StreamConfig::solid(Color(255, 0, 0));
+        let custom = LedCommand::SetStreams(Some([stream].into_iter().collect()));
+        assert_eq!(apply(&mut selection, custom), [effect("Custom")]);
+        assert_eq!(
+            apply(&mut selection, LedCommand::SetStreams(None)),
+            [effect(selection.effect.name())]
+        );
+
+        // The second zone reports to its own topic
+        let mut second = Selection::new(Zone::Second);
+        assert_eq!(
+            apply(&mut second, LedCommand::ReportEffect),
+            [(
+                "picow/zone2/effect/state".into(),
+                second.effect.name().into()
+            )]
+        );
+    }
+
+    #[test]
+    fn effects_switch_to_their_default_brightness() {
+        let mut selection = Selection::new(Zone::Second);
+        selection.set_status(ConnectionStatus::Connected);
+        selection.set_light(LightState {
+            brightness: 40,
+            ..LightState::DEFAULT
+        });
+        let brightness = |selection: &Selection| selection.light.unwrap().brightness;
+
+        while !matches!(selection.effect, Effect::Confetti { .. }) {
+            // Effects without a default keep the brightness
+            assert_eq!(brightness(&selection), 40);
+            selection.next_effect();
+        }
+        assert_eq!(brightness(&selection), u8::MAX);
+
+        // Until the brightness is set explicitly
+        assert!(selection.set_brightness(40));
+        assert_eq!(brightness(&selection), 40);
+        selection.next_effect();
+        assert_eq!(brightness(&selection), 40);
+        assert!(selection.light.unwrap().on);
+    }
+
+    #[test]
+    fn identifying_flashes_and_restores_the_brightness() {
+        let mut selection = custom_selection();
+        selection.set_brightness(10);
+        let shown = |selection: &Selection| {
+            stream_config(selection, 1, 0)
+                .config()
+                .color_at(Instant::MIN)
+        };
+
+        selection.start_overlay(Overlay::Identify);
+        assert_eq!(shown(&selection), IDENTIFY_COLOR);
+        assert_eq!(selection.target_brightness(), None);
+        let (_, end) = selection.overlay.unwrap();
+        assert!(end - Instant::now() <= IDENTIFY_DURATION);
+
+        // The light state is left alone, so it's shown again afterwards
+        selection.overlay = None;
+        assert_eq!(selection.light.unwrap().brightness, 10);
+        assert_eq!(selection.target_brightness(), Some(10));
+        assert_eq!(shown(&selection), Color(10, 0, 0));
+    }
+
+    fn ramp(from: u8, to: u8) -> BrightnessRamp {
+        BrightnessRamp {
+            built: from.max(to),
+            from,
+            to,
+            start: Instant::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn ramps_progress_monotonically() {
+        for (from, to) in [(0, 255), (200, 10), (7, 8)] {
+            let ramp = ramp(from, to);
+            let mut previous = from;
+            for millis in (0..=BRIGHTNESS_RAMP_DURATION.as_millis()).step_by(10) {
+                let shown = ramp.shown(ramp.start + Duration::from_millis(millis));
+                assert!(shown.abs_diff(to) <= previous.abs_diff(to));
+                assert!(shown.abs_diff(from) >= previous.abs_diff(from));
+                previous = shown;
+            }
+        }
+    }
+
+    #[test]
+    fn ramps_converge_to_their_target() {
+        let ramp = ramp(200, 10);
+
+        assert_eq!(ramp.shown(ramp.start), 200);
+        assert_eq!(ramp.shown(ramp.start + BRIGHTNESS_RAMP_DURATION / 2), 105);
+        assert_eq!(ramp.shown(ramp.end()), 10);
+        assert_eq!(ramp.shown(ramp.end() + Duration::from_secs(60)), 10);
+        // Before the start, e.g. if the ramp was set up late, it shows where it starts
+        assert_eq!(ramp.shown(ramp.start - Duration::from_millis(1)), 200);
+    }
+
+    #[test]
+    fn retargeted_ramps_start_from_the_shown_brightness() {
+        let light = |brightness| LightState {
+            brightness,
+            ..LightState::DEFAULT
+        };
+        let mut selection = Selection::new(Zone::First);
+        selection.set_status(ConnectionStatus::Connected);
+        selection.light = Some(light(0));
+
+        let start = Instant::from_secs(10);
+        let shown = selection.shown_brightness(start);
+        selection.light = Some(light(200));
+        assert!(selection.ramp_brightness(shown, false, start));
+
+        let midway = start + BRIGHTNESS_RAMP_DURATION / 2;
+        assert_eq!(selection.shown_brightness(midway), Some(100));
+        let shown = selection.shown_brightness(midway);
+        selection.light = Some(light(50));
+        // The streams are bright enough already, dimming them is left to the PWM slices
+        assert!(!selection.ramp_brightness(shown, false, midway));
+
+        assert_eq!(selection.shown_brightness(midway), Some(100));
+        let ramp = selection.ramp.unwrap();
+        assert_eq!((ramp.built, ramp.from, ramp.to), (200, 100, 50));
+        assert_eq!(
+            selection.shown_brightness(midway + BRIGHTNESS_RAMP_DURATION),
+            Some(50)
+        );
+    }
+
+    #[test]
+    fn restored_state_colors_and_dims_the_effect() {
+        let mut selection = Selection::new(Zone::Second);
+        selection.set_status(ConnectionStatus::Connected);
+        let color = Color(0, 64, 255);
+        assert!(selection.set_light(LightState {
+            on: true,
+            brightness: 128,
+            color: Some(color),
+        }));
+
+        let streams = selection.streams();
+        assert_eq!(streams.len(), selection.effect.streams().len());
+        let dimmed = color.scaled(128);
+        assert!(streams.iter().all(|stream| stream.color() == dimmed));
+        // The first stream of the effect starts right away
+        let config = stream_config(&selection, 1, 0);
+        assert_eq!(config.config().color_at(Instant::MIN), dimmed);
+    }
+
+    #[test]
+    fn phase_restarts_start_the_timeline_over() {
+        // The gap after every burst is longer than a step can be, so it's split across buffers
+        let streams = [StreamConfig::new(
+            Color(255, 0, 0),
+            Hz(0.01),
+            Duration::from_millis(1),
+            None,
+        )];
+        let fresh = || Config::<1>::new(&streams, 1, 0).into_iter();
+        let buffer = |config: &mut _, pending: &mut _, length| {
+            block_on(calculate_next_buffer::<1, 8>(config, pending, length, 4))
+        };
+        let (expected, _, _) = buffer(&mut fresh(), &mut None, 8);
+
+        let mut config = fresh();
+        let mut pending = None;
+        buffer(&mut config, &mut pending, 3);
+        assert!(pending.is_some());
+
+        // A trigger edge restarts the phase
+        Restart::Phase.apply(&mut config, &mut pending, || unreachable!());
+        assert!(pending.is_none());
+        let (red, _, _) = buffer(&mut config, &mut pending, 8);
+        assert_eq!(red, expected);
+    }
+
+    /// Consecutive words with the same color, as `(color, words, total delay)`
+    fn runs(words: &[u32]) -> Vec<(u32, usize, u32), 16> {
+        let mut runs = Vec::<(u32, usize, u32), 16>::new();
+        for word in words {
+            let (color, delay) = (word >> 24, word & ColorStep::MAX_DELAY);
+            match runs.last_mut() {
+                Some((last, count, total)) if *last == color => {
+                    *count += 1;
+                    *total += delay;
+                }
+                _ => runs.push((color, 1, delay)).unwrap(),
+            }
+        }
+        runs
+    }
+
+    #[test]
+    fn long_delays_are_spread_over_buffers() {
+        // On for a second, then off for 99 seconds
+        let streams = [StreamConfig::new(
+            Color(255, 0, 0),
+            Hz(0.01),
+            Duration::from_secs(1),
+            None,
+        )];
+        let mut config = Config::<1>::new(&streams, 1, 0).into_iter();
+        let mut pending = None;
+
+        let mut red = std::vec::Vec::new();
+        for _ in 0..4 {
+            let (buffer, _, _) = block_on(calculate_next_buffer::<1, 4>(
+                &mut config,
+                &mut pending,
+                4,
+                2,
+            ));
+            assert_eq!(buffer.len(), 4);
+            red.extend_from_slice(&buffer);
+        }
+
+        let runs = runs(&red);
+        let on = runs.iter().position(|&(color, _, _)| color == 255).unwrap();
+        // The first step only leads up to the start of the burst
+        assert_eq!((runs[on].0, runs[on].2), (255, 1_000_000));
+        // 99 seconds take six steps of at most MAX_DELAY ticks
+        assert_eq!(runs[on + 1], (0, 6, 99_000_000));
+    }
+
+    /// Polls `future` to completion, returning how often it yielded and its output
+    fn yields<F: Future>(future: F) -> (usize, F::Output) {
+        let mut future = core::pin::pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+
+        let mut count = 0;
+        loop {
+            match future.as_mut().poll(&mut context) {
+                Poll::Ready(output) => return (count, output),
+                Poll::Pending => count += 1,
+            }
+        }
+    }
+
+    #[test]
+    fn buffers_yield_after_every_batch() {
+        let streams = [StreamConfig::new(
+            Color(255, 0, 0),
+            Hz(100.),
+            Duration::from_millis(1),
+            None,
+        )];
+        let mut config = Config::<1>::new(&streams, 1, 0).into_iter();
+        let mut pending = None;
+
+        for (length, batch, expected) in [(10, 3, 3), (9, 3, 3), (2, 3, 0), (16, 1, 16)] {
+            let (count, (red, _, _)) = yields(calculate_next_buffer::<1, 16>(
+                &mut config,
+                &mut pending,
+                length,
+                batch,
+            ));
+            assert_eq!(red.len(), length);
+            assert_eq!(count, expected, "{length} steps in batches of {batch}");
+        }
+    }
+
+    #[test]
+    fn timing_at_the_default_clock() {
+        let (frequency, divider) = compute_timing(125_000_000, 64, 1).unwrap();
+        assert_eq!(frequency, 15_625);
+        assert_eq!(divider.to_num::<f64>(), 8000.);
+
+        // A PWM period of the second zone per tick
+        let (frequency, divider) = compute_timing(125_000_000, 64, 264).unwrap();
+        assert_eq!(frequency, 4_125_000);
+        assert!((divider.to_num::<f64>() - 125e6 / 4_125_000.).abs() < 1. / 256.);
+    }
+
+    #[test]
+    fn dividers_below_one_are_rejected() {
+        // Slightly too slow a clock for 1 MHz
+        assert_eq!(compute_timing(999_999, 1, 1), None);
+        assert!(compute_timing(1_000_000, 1, 1).is_some());
+    }
+
+    #[test]
+    fn dividers_beyond_the_fixed_point_range_are_rejected() {
+        assert_eq!(compute_timing(u32::MAX, 1_000_000, 1), None);
+        assert!(compute_timing(65_536, 1_000_000, 1).is_some());
+    }
+
+    #[test]
+    fn empty_ticks_are_rejected() {
+        assert_eq!(compute_timing(125_000_000, 0, 1), None);
+        assert_eq!(compute_timing(125_000_000, -64, 1), None);
+        assert_eq!(compute_timing(125_000_000, 64, 0), None);
+    }
 }
@@ -0,0 +1,348 @@
+use core::pin::pin;
+
+use defmt::*;
+use embassy_futures::{
+    join::join,
+    select::{Either3, select3},
+};
+use embassy_rp::{
+    clocks::clk_sys_freq,
+    gpio::Level,
+    peripherals::PIO0,
+    pio::{Config, Direction, FifoJoin, Pin, ShiftConfig, ShiftDirection, StateMachine},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use pio::pio_asm;
+
+use super::{
+    Action, LedCommand, LedCommandReceiver, Restart, SWAP_SEQUENCE, SYNC_BUFFER_LENGTH, Selection,
+    SwapStep, YIELD_BATCH_SIZE, Zone, calculate_next_buffer, compute_timing, stream_config,
+    wait_until,
+    zone::{PIO_PWM_OVERHEAD, PIO_PWM_PERIOD, PIO_PWM_STEP_OVERHEAD, pio_pwm_step},
+};
+use crate::{
+    event_log,
+    mqtt::MqttTxSender,
+    peripherals::{SecondZonePeripherals, SparePio},
+};
+
+/// Number of steps per buffer of the second zone. The CPU copies them into the FIFOs, so shorter
+/// buffers than `BUFFER_LENGTH` only cost a little more overhead.
+const SECOND_ZONE_BUFFER_LENGTH: usize = 256;
+
+/// Shows the streams of the second zone. Every state machine generates the PWM signal of a color
+/// itself, one period per tick, so the zone doesn't need any PWM slices or DMA channels.
+///
+/// Manual mode, the fade out, brightness ramps and the sync input are left to the first zone.
+#[embassy_executor::task]
+pub async fn orchestrate_second_zone(
+    pio: SparePio,
+    p: SecondZonePeripherals,
+    commands: LedCommandReceiver<'static>,
+    sender: MqttTxSender<'static>,
+) {
+    let SparePio {
+        mut common,
+        sm1,
+        sm2,
+        sm3,
+    } = pio;
+
+    // Reads the words of `pio_pwm_step`, then runs the periods with their loop counts. Stays
+    // on or off if a count is 0.
+    let pwm_program = pio_asm! {
+        r#"
+            .define public MICROS_PER_TICK 64
+            .define public TICK_OVERHEAD 1
+            .define public PERIOD_OVERHEAD 9
+            .define public STEP_OVERHEAD 3
+            .side_set 1 opt
+
+        .wrap_target
+            pull block
+            mov y, osr
+            pull block
+            mov isr, osr
+            pull block
+        first:
+            out x, 16
+            jmp !x turn_off
+        stay_on:
+            jmp x-- stay_on side 1
+        turn_off:
+            out x, 16
+            jmp !x end_period
+        stay_off:
+            jmp x-- stay_off side 0
+        end_period:
+            jmp y-- period
+        .wrap
+        period:
+            mov osr, isr
+            jmp first
+        "#
+    };
+    core::assert_eq!(
+        pwm_program.public_defines.PERIOD_OVERHEAD as u32,
+        PIO_PWM_OVERHEAD
+    );
+    core::assert_eq!(
+        pwm_program.public_defines.STEP_OVERHEAD as u32,
+        PIO_PWM_STEP_OVERHEAD
+    );
+
+    let clock = clk_sys_freq();
+    let Some((target_frequency, clock_divider)) = compute_timing(
+        clock,
+        pwm_program.public_defines.MICROS_PER_TICK,
+        PIO_PWM_PERIOD,
+    ) else {
+        error!(
+            "Can't run the PWM program at {} us per period from a {} Hz system clock",
+            pwm_program.public_defines.MICROS_PER_TICK, clock
+        );
+        return;
+    };
+    info!(
+        "PWM program of the second zone runs at {} Hz, clock divider {}",
+        target_frequency,
+        clock_divider.to_num::<f32>()
+    );
+
+    let loaded = common.load_program(&pwm_program.program);
+    let pins = [
+        common.make_pio_pin(p.red_pin),
+        common.make_pio_pin(p.green_pin),
+        common.make_pio_pin(p.blue_pin),
+    ];
+    // The state machines only differ in the pin they drive
+    let configs = pins.each_ref().map(|pin| {
+        let mut config = Config::default();
+        config.use_program(&loaded, &[pin]);
+        config.clock_divider = clock_divider;
+        config.shift_out = ShiftConfig {
+            direction: ShiftDirection::Right,
+            auto_fill: false,
+            threshold: 32,
+        };
+        config.fifo_join = FifoJoin::TxOnly;
+        config
+    });
+
+    let mut outputs = Outputs {
+        red: sm1,
+        green: sm2,
+        blue: sm3,
+        pins,
+        configs,
+    };
+    outputs.init();
+
+    let make_config = |selection: &Selection| {
+        stream_config(
+            selection,
+            pwm_program.public_defines.MICROS_PER_TICK,
+            pwm_program.public_defines.TICK_OVERHEAD,
+        )
+    };
+
+    let mut selection = Selection::new(Zone::Second);
+    let mut config = make_config(&selection);
+
+    let mut pending = None;
+    let (mut red, mut green, mut blue) = calculate_next_buffer::<_, SECOND_ZONE_BUFFER_LENGTH>(
+        &mut config,
+        &mut pending,
+        SECOND_ZONE_BUFFER_LENGTH,
+        YIELD_BATCH_SIZE,
+    )
+    .await;
+
+    let mut paused = false;
+    let mut pending_restart: Option<Restart> = None;
+
+    loop {
+        if let Some(restart) = pending_restart.take() {
+            for step in SWAP_SEQUENCE {
+                match step {
+                    // The CPU feeds the state machines, and it's right here
+                    SwapStep::CheckDmaIdle => {}
+                    SwapStep::StopStateMachines => outputs.stop(),
+                    SwapStep::Reconfigure => {
+                        restart.apply(&mut config, &mut pending, || make_config(&selection))
+                    }
+                    SwapStep::FillBuffers => {
+                        (red, green, blue) = calculate_next_buffer(
+                            &mut config,
+                            &mut pending,
+                            SYNC_BUFFER_LENGTH,
+                            YIELD_BATCH_SIZE,
+                        )
+                        .await
+                    }
+                    SwapStep::StartStateMachines => outputs.start(),
+                }
+            }
+        }
+
+        let result = {
+            let mut transfer = pin!(join(
+                calculate_next_buffer(
+                    &mut config,
+                    &mut pending,
+                    SECOND_ZONE_BUFFER_LENGTH,
+                    YIELD_BATCH_SIZE
+                ),
+                outputs.push(&red, &green, &blue),
+            ));
+
+            // Pausing only takes effect once the current buffer has been sent, like in the first
+            // zone
+            loop {
+                match select3(
+                    transfer.as_mut(),
+                    commands.receive(),
+                    wait_until(selection.overlay.map(|(_, end)| end)),
+                )
+                .await
+                {
+                    Either3::First(buffers) => break Ok(buffers),
+                    Either3::Second(LedCommand::Pause) => paused = true,
+                    Either3::Second(LedCommand::Resume) => paused = false,
+                    Either3::Second(LedCommand::Shutdown(stopped)) => {
+                        break Err(Interruption::Shutdown(stopped));
+                    }
+                    // Only the first zone has a manual mode, and stream dumps are only sent to it
+                    Either3::Second(LedCommand::Manual(_) | LedCommand::DumpStreams) => {}
+                    Either3::Second(command) => match selection.apply(command, &sender) {
+                        Action::None => {}
+                        Action::Restart(restart) => break Err(Interruption::Restart(restart)),
+                        Action::Overlay => {
+                            paused = false;
+                            break Err(Interruption::Restart(Restart::Reconfigure));
+                        }
+                    },
+                    Either3::Third(()) => {
+                        selection.overlay = None;
+                        break Err(Interruption::Restart(Restart::Reconfigure));
+                    }
+                }
+            }
+        };
+
+        let ((r, g, b), ()) = match result {
+            Ok(buffers) => buffers,
+            Err(Interruption::Restart(restart)) => {
+                pending_restart = Some(restart);
+                continue;
+            }
+            Err(Interruption::Shutdown(stopped)) => {
+                return shutdown(&mut outputs, stopped);
+            }
+        };
+
+        red = r;
+        green = g;
+        blue = b;
+
+        // Without new data the state machines stall at the beginning of a step, holding the
+        // level of the last one
+        while paused {
+            match commands.receive().await {
+                LedCommand::Resume => paused = false,
+                LedCommand::Pause | LedCommand::Manual(_) | LedCommand::DumpStreams => {}
+                LedCommand::Shutdown(stopped) => return shutdown(&mut outputs, stopped),
+                command => match selection.apply(command, &sender) {
+                    Action::None => {}
+                    // A new config starts from the beginning as well
+                    Action::Restart(Restart::Phase) => {
+                        pending_restart.get_or_insert(Restart::Phase);
+                    }
+                    Action::Restart(Restart::Reconfigure) => {
+                        pending_restart = Some(Restart::Reconfigure)
+                    }
+                    Action::Overlay => {
+                        paused = false;
+                        pending_restart = Some(Restart::Reconfigure);
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Reason for aborting the buffer being sent
+enum Interruption {
+    Restart(Restart),
+    Shutdown(&'static Signal<CriticalSectionRawMutex, ()>),
+}
+
+fn shutdown(outputs: &mut Outputs<'_>, stopped: &Signal<CriticalSectionRawMutex, ()>) {
+    outputs.stop();
+
+    info!("Second LED zone shut down");
+    event_log::record("Second LED zone shut down");
+    stopped.signal(());
+}
+
+/// State machines of the second zone, one per color, along with their pins and configs
+struct Outputs<'d> {
+    red: StateMachine<'d, PIO0, 1>,
+    green: StateMachine<'d, PIO0, 2>,
+    blue: StateMachine<'d, PIO0, 3>,
+    pins: [Pin<'d, PIO0>; 3],
+    configs: [Config<'d, PIO0>; 3],
+}
+
+impl Outputs<'_> {
+    /// Makes the pins outputs and starts the state machines
+    fn init(&mut self) {
+        self.red.set_pin_dirs(Direction::Out, &[&self.pins[0]]);
+        self.green.set_pin_dirs(Direction::Out, &[&self.pins[1]]);
+        self.blue.set_pin_dirs(Direction::Out, &[&self.pins[2]]);
+        self.start();
+    }
+
+    /// Restarts stopped state machines from the beginning of the program
+    fn start(&mut self) {
+        self.red.set_config(&self.configs[0]);
+        self.green.set_config(&self.configs[1]);
+        self.blue.set_config(&self.configs[2]);
+        self.red.restart();
+        self.green.restart();
+        self.blue.restart();
+        self.red.set_enable(true);
+        self.green.set_enable(true);
+        self.blue.set_enable(true);
+    }
+
+    /// Stops the state machines, discarding the queued steps, and turns the LEDs off. They'd
+    /// keep the level of the last period otherwise.
+    fn stop(&mut self) {
+        self.red.set_enable(false);
+        self.green.set_enable(false);
+        self.blue.set_enable(false);
+        self.red.clear_fifos();
+        self.green.clear_fifos();
+        self.blue.clear_fifos();
+        self.red.set_pins(Level::Low, &[&self.pins[0]]);
+        self.green.set_pins(Level::Low, &[&self.pins[1]]);
+        self.blue.set_pins(Level::Low, &[&self.pins[2]]);
+    }
+
+    /// Feeds the steps to the state machines, waiting while their FIFOs are full. The colors
+    /// are fed step by step, so none of them runs ahead by more than its FIFO holds.
+    async fn push(&mut self, red: &[u32], green: &[u32], blue: &[u32]) {
+        for ((&r, &g), &b) in red.iter().zip(green).zip(blue) {
+            for word in pio_pwm_step(r) {
+                self.red.tx().wait_push(word).await;
+            }
+            for word in pio_pwm_step(g) {
+                self.green.tx().wait_push(word).await;
+            }
+            for word in pio_pwm_step(b) {
+                self.blue.tx().wait_push(word).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use embassy_time::Duration;
+use heapless::Vec;
+
+use crate::{
+    effects::MAX_STREAMS,
+    stream::{Color, Hz, StreamConfig},
+};
+
+/// State of the connection to the MQTT broker, as far as it's visible on the LEDs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConnectionStatus {
+    /// Joining the WiFi network
+    Joining,
+    /// On the network, but not connected to the broker
+    Disconnected,
+    Connected,
+}
+
+impl ConnectionStatus {
+    /// Built-in pattern shown instead of the user's effect, `None` if the effect is shown
+    pub fn pattern(self) -> Option<Vec<StreamConfig, MAX_STREAMS>> {
+        let blink = |color, frequency, on_time| {
+            [StreamConfig::new(color, frequency, on_time, None)]
+                .into_iter()
+                .collect()
+        };
+
+        match self {
+            ConnectionStatus::Joining => {
+                Some(blink(Color(0, 0, 255), Hz(2.), Duration::from_millis(100)))
+            }
+            ConnectionStatus::Disconnected => {
+                Some(blink(Color(255, 0, 0), Hz(0.5), Duration::from_secs(1)))
+            }
+            ConnectionStatus::Connected => None,
+        }
+    }
+
+    /// How long the onboard LED stays on and off while blinking, `None` if it's on constantly
+    pub fn onboard_blink(self) -> Option<Duration> {
+        match self {
+            ConnectionStatus::Joining | ConnectionStatus::Disconnected => {
+                Some(Duration::from_secs(1))
+            }
+            ConnectionStatus::Connected => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionStatus;
+
+    #[test]
+    fn onboard_led_blinks_until_connected() {
+        assert!(ConnectionStatus::Joining.onboard_blink().is_some());
+        assert!(ConnectionStatus::Disconnected.onboard_blink().is_some());
+        assert_eq!(ConnectionStatus::Connected.onboard_blink(), None);
+    }
+
+    #[test]
+    fn effect_is_shown_once_connected() {
+        assert!(ConnectionStatus::Joining.pattern().is_some());
+        assert!(ConnectionStatus::Disconnected.pattern().is_some());
+        assert!(ConnectionStatus::Connected.pattern().is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use super::PWM_TOP;
+
+/// Group of LEDs with its own light state and streams
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Zone {
+    /// Driven by PIO1 and the PWM slices. Manual mode, the fade out, brightness ramps and the sync
+    /// input only apply to it.
+    First,
+    /// Driven by the state machines of PIO0 the WiFi driver leaves free, with the `second_zone`
+    /// feature
+    Second,
+}
+
+impl Zone {
+    /// Zones the firmware drives
+    pub const ALL: &[Zone] = if cfg!(feature = "second_zone") {
+        &[Zone::First, Zone::Second]
+    } else {
+        &[Zone::First]
+    };
+
+    /// Name of the light entity in Home Assistant
+    pub const fn name(self) -> &'static str {
+        match self {
+            Zone::First => "Light",
+            Zone::Second => "Light 2",
+        }
+    }
+
+    /// Distinguishes the entities of the zones, within the device
+    pub const fn id(self) -> &'static str {
+        match self {
+            Zone::First => "light",
+            Zone::Second => "light2",
+        }
+    }
+
+    /// Turns the light on or off with `ON` or `OFF`
+    pub const fn light_topic(self) -> &'static str {
+        match self {
+            Zone::First => "picow/light/set",
+            Zone::Second => "picow/zone2/light/set",
+        }
+    }
+
+    /// Sets the brightness of the light, from 0 to 255
+    pub const fn brightness_topic(self) -> &'static str {
+        match self {
+            Zone::First => "picow/light/brightness/set",
+            Zone::Second => "picow/zone2/light/brightness/set",
+        }
+    }
+
+    /// Retained light state, see `LightState`
+    pub const fn light_state_topic(self) -> &'static str {
+        match self {
+            Zone::First => "picow/light/state",
+            Zone::Second => "picow/zone2/light/state",
+        }
+    }
+
+    /// Retained name of the shown effect
+    pub const fn effect_state_topic(self) -> &'static str {
+        match self {
+            Zone::First => "picow/effect/state",
+            Zone::Second => "picow/zone2/effect/state",
+        }
+    }
+
+    /// Retained custom streams
+    pub const fn config_topic(self) -> &'static str {
+        match self {
+            Zone::First => "picow/config",
+            Zone::Second => "picow/zone2/config",
+        }
+    }
+
+    /// The zone the topic belongs to, if it's one of the zone topics picked by `topic`
+    pub fn of_topic(topic: &str, zone_topic: fn(Zone) -> &'static str) -> Option<Zone> {
+        [Zone::First, Zone::Second]
+            .into_iter()
+            .find(|&zone| zone_topic(zone) == topic)
+    }
+
+    /// All topics the zone publishes retained messages to
+    pub const fn retained_topics(self) -> [&'static str; 3] {
+        [
+            self.effect_state_topic(),
+            self.light_state_topic(),
+            self.config_topic(),
+        ]
+    }
+}
+
+/// Cycles of a PWM period of the second zone besides the on and off loops, see
+/// `pio_pwm_cycles`
+#[cfg_attr(not(feature = "second_zone"), allow(unused))]
+pub const PIO_PWM_OVERHEAD: u32 = 9;
+
+/// Cycles the PWM program of the second zone takes to read a step, beyond those of a period
+#[cfg_attr(not(feature = "second_zone"), allow(unused))]
+pub const PIO_PWM_STEP_OVERHEAD: u32 = 3;
+
+/// Cycles of the PIO program for every PWM period of the second zone, which is one tick
+#[cfg_attr(not(feature = "second_zone"), allow(unused))]
+pub const PIO_PWM_PERIOD: u32 = PWM_TOP as u32 + 1 + PIO_PWM_OVERHEAD;
+
+/// Loop counts the PWM program of the second zone stays on and off for within a period, packed
+/// as `on | off << 16`, to match the duty of `level` on the PWM slices of the first zone.
+///
+/// A period takes `on + off + PIO_PWM_OVERHEAD` cycles, of which the output is on for `on + 3`.
+/// A count of 0 skips its loop, so the output stays off for level 0 and on for full brightness.
+/// Skipping a loop saves one cycle, that's added to the other one. Below full brightness, the
+/// output is on for at least four cycles and off for at least seven, which the dimmest and
+/// brightest few levels are rounded to.
+#[cfg_attr(not(feature = "second_zone"), allow(unused))]
+pub fn pio_pwm_cycles(level: u8) -> u32 {
+    let loops = PIO_PWM_PERIOD - PIO_PWM_OVERHEAD;
+    let (on, off) = match level as u32 {
+        0 => (0, loops + 1),
+        level if level > PWM_TOP as u32 => (loops + 1, 0),
+        level => {
+            let high = (level * PIO_PWM_PERIOD + PWM_TOP as u32 / 2) / (PWM_TOP as u32 + 1);
+            let on = high.saturating_sub(3).clamp(1, loops - 1);
+            (on, loops - on)
+        }
+    };
+
+    on | off << 16
+}
+
+/// Words the PWM program of the second zone reads for an encoded step of the streams: the
+/// number of additional periods the step lasts, and the loop counts of `pio_pwm_cycles` for its
+/// first period and the others. The first one is shortened by `PIO_PWM_STEP_OVERHEAD`, taken from
+/// the longer loop, so every step lasts a whole number of periods.
+#[cfg_attr(not(feature = "second_zone"), allow(unused))]
+pub fn pio_pwm_step(step: u32) -> [u32; 3] {
+    let delay = step & crate::stream::ColorStep::MAX_DELAY;
+    let cycles = pio_pwm_cycles((step >> 24) as u8);
+    let first = if cycles & 0xFFFF > cycles >> 16 {
+        cycles - PIO_PWM_STEP_OVERHEAD
+    } else {
+        cycles - (PIO_PWM_STEP_OVERHEAD << 16)
+    };
+
+    [delay, first, cycles]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PIO_PWM_PERIOD, Zone, pio_pwm_cycles, pio_pwm_step};
+    use crate::led_orchestrator::PWM_TOP;
+
+    /// Runs the PWM program for a step with the given level and delay, from reading it until
+    /// it's done, starting with the output off. Returns the cycles taken and the ones the
+    /// output was on for.
+    fn simulate(level: u8, delay: u32) -> (u32, u32) {
+        let [periods, first, rest] = pio_pwm_step((level as u32) << 24 | delay);
+        let (mut cycles, mut on, mut high) = (0, 0, false);
+        let mut run = |count: u32, level: Option<bool>| {
+            high = level.unwrap_or(high);
+            cycles += count;
+            if high {
+                on += count;
+            }
+        };
+
+        // pull, mov, pull, mov, pull
+        run(5, None);
+        for period in 0..=periods {
+            let counts = if period == 0 {
+                first
+            } else {
+                // mov osr, isr and the jump back
+                run(2, None);
+                rest
+            };
+            let (on_loops, off_loops) = (counts & 0xFFFF, counts >> 16);
+            // out, jmp !x
+            run(2, None);
+            if on_loops > 0 {
+                run(on_loops + 1, Some(true));
+            }
+            run(2, None);
+            if off_loops > 0 {
+                run(off_loops + 1, Some(false));
+            }
+            // jmp y--
+            run(1, None);
+        }
+
+        (cycles, on)
+    }
+
+    /// Cycles the output is on for in a period after the first one of a step
+    fn on_cycles(level: u8) -> u32 {
+        simulate(level, 1).1 - simulate(level, 0).1
+    }
+
+    #[test]
+    fn every_step_lasts_whole_periods() {
+        for level in 0..=u8::MAX {
+            for delay in 0..3 {
+                assert_eq!(
+                    simulate(level, delay).0,
+                    (delay + 1) * PIO_PWM_PERIOD,
+                    "level {level}, delay {delay}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn extremes_dont_toggle_the_output() {
+        assert_eq!(simulate(0, 4).1, 0);
+        assert_eq!(on_cycles(u8::MAX), PIO_PWM_PERIOD);
+        assert_eq!(pio_pwm_cycles(u8::MAX) >> 16, 0);
+    }
+
+    #[test]
+    fn duty_follows_the_level() {
+        for level in 4..=248 {
+            let expected = level as f32 / (PWM_TOP as f32 + 1.);
+            let duty = on_cycles(level) as f32 / PIO_PWM_PERIOD as f32;
+            assert!(
+                (duty - expected).abs() <= 0.5 / PIO_PWM_PERIOD as f32,
+                "level {level}"
+            );
+        }
+        assert_eq!(on_cycles(1), 4);
+        assert_eq!(on_cycles(PWM_TOP as u8), PIO_PWM_PERIOD - 7);
+    }
+
+    #[test]
+    fn steps_keep_their_delay() {
+        let [delay, _, cycles] = pio_pwm_step(128 << 24 | 1000);
+
+        assert_eq!(delay, 1000);
+        assert_eq!(cycles, pio_pwm_cycles(128));
+    }
+
+    #[test]
+    fn topics_are_mapped_back_to_their_zone() {
+        assert_eq!(
+            Zone::of_topic("picow/zone2/light/set", Zone::light_topic),
+            Some(Zone::Second)
+        );
+        assert_eq!(
+            Zone::of_topic("picow/config", Zone::config_topic),
+            Some(Zone::First)
+        );
+        assert_eq!(Zone::of_topic("picow/config", Zone::light_topic), None);
+    }
+
+    #[test]
+    fn zones_have_distinct_topics() {
+        let [first, second] = [Zone::First, Zone::Second].map(Zone::retained_topics);
+
+        assert!(first.iter().all(|topic| !second.contains(topic)));
+        assert_ne!(Zone::First.id(), Zone::Second.id());
+    }
+}
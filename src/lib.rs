@@ -0,0 +1,8 @@
+//! Parts of the firmware that don't depend on the RP2040, so they can also be built for the host,
+//! e.g. to fuzz them with the `std` feature
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod stream;
+#[cfg(test)]
+mod test_logger;
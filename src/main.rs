@@ -1,98 +1,826 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+// The host only builds the tests, which leaves everything that's only used by the firmware unused
+#![cfg_attr(not(target_os = "none"), allow(dead_code, unused_imports))]
 
+#[cfg(feature = "udp_status")]
+mod broadcast;
+mod effects;
+mod event_log;
+mod input;
 mod led_orchestrator;
 mod mqtt;
 mod network;
 mod peripherals;
-mod stream;
+#[cfg(target_os = "none")]
+mod power;
+mod temperature;
+#[cfg(test)]
+mod test_logger;
+#[cfg(feature = "tunable_white")]
+mod white;
 
+use core::fmt::Write;
 use defmt::*;
-use embassy_executor::Spawner;
-use embassy_rp::{self, pac::SIO};
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_futures::select::{Either3, select3};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
 use embassy_sync::channel::Channel;
-use embassy_sync::pubsub::{PubSubChannel, WaitResult};
-use embassy_time::{Instant, Timer};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::pubsub::WaitResult;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Ticker, Timer, with_deadline, with_timeout};
+use heapless::{String, Vec};
 use indoc::indoc;
+use jungbrunnen::stream::{self, DimmingCurve};
 use static_cell::StaticCell;
 
-use crate::led_orchestrator::orchestrate_leds;
+use crate::effects::{EFFECTS, MAX_STREAMS};
+use crate::led_orchestrator::{
+    ConnectionStatus, GAMMA_RANGE, LedCommand, LedCommandSender, LightState, SPEED_RANGE, Zone,
+};
 use crate::mqtt::{
-    ConnectionOptions, Credentials, MqttRunner, MqttRxSubscriber, MqttTxSender, RxPacket,
-    SubscribeTopic, TxPacket, mqtt_heartbeat, mqtt_task,
+    Availability, Command, ConnectionOptions, Credentials, DEBUG_REQUESTS, Keepalive, LastWill,
+    MAX_PAYLOAD_LENGTH, MAX_TOPIC_LENGTH, MqttPrioritySender, MqttRunner, MqttRxChannel,
+    MqttRxSubscriber, MqttTxSender, PRIORITY_CAPACITY, RxPacket, TxPacket, Unit, announce_sensors,
+    clear_sensors, connection_receiver, lagged_packets, mqtt_heartbeat, mqtt_task, publish_sensor,
+    publish_topic, record_lag, wait_connected,
+};
+use crate::network::{DhcpFallback, DhcpOptions, RoamingOptions};
+#[cfg(target_os = "none")]
+use {
+    crate::led_orchestrator::orchestrate_leds,
+    crate::network::{BoardConfig, Cyw43, dhcp_retry_task, network_task, roam, wifi_task},
+    crate::peripherals::{
+        AssignedResources, ButtonPeripherals, EncoderPeripherals, LedPeripherals, PowerPeripherals,
+        SecondZonePeripherals, TemperaturePeripherals, WhitePeripherals, WifiPeripherals,
+        critical_section_lock_held, release_critical_section_lock,
+    },
+    crate::power::WakeSource,
+    crate::temperature::TemperatureSensor,
+    defmt_rtt as _,
+    embassy_executor::Spawner,
+    panic_probe as _,
+};
+
+fn autodiscovery_topic(device_id: &str) -> Result<String<MAX_TOPIC_LENGTH>, core::fmt::Error> {
+    publish_topic(&["homeassistant/device", device_id, "config"]).map_err(|_| core::fmt::Error)
+}
+
+fn autodiscovery_packet(device_id: &str) -> Result<TxPacket, core::fmt::Error> {
+    let topic_name = autodiscovery_topic(device_id)?;
+
+    let mut payload = String::<MAX_PAYLOAD_LENGTH>::new();
+    core::write!(
+        payload,
+        indoc! {r#"{{
+            "device": {{
+                "identifiers": ["{device_id}"],
+                "name": "PicoW",
+                "model": "Rasperry Pi Pico W",
+                "manufacturer": "Raspberry Pi"
+            }},
+            "origin": {{
+                "name": "Test"
+            }},
+            "availability_topic": "{availability}",
+            "components": {{
+                "speed": {{
+                    "platform": "number",
+                    "name": "Speed",
+                    "unique_id": "{device_id}_speed",
+                    "command_topic": "picow/speed/set",
+                    "min": {min_speed},
+                    "max": {max_speed},
+                    "step": 0.1
+                }},
+                "gamma": {{
+                    "platform": "number",
+                    "name": "Gamma",
+                    "unique_id": "{device_id}_gamma",
+                    "command_topic": "picow/gamma/set",
+                    "min": {min_gamma},
+                    "max": {max_gamma},
+                    "step": 0.1
+                }}
+            }}
+        }}"#},
+        device_id = device_id,
+        availability = AVAILABILITY.topic,
+        min_speed = SPEED_RANGE.start(),
+        max_speed = SPEED_RANGE.end(),
+        min_gamma = GAMMA_RANGE.start(),
+        max_gamma = GAMMA_RANGE.end(),
+    )?;
+
+    Ok(TxPacket::PublishOwned {
+        qospid: mqttrs::QosPid::AtMostOnce,
+        retain: false,
+        topic_name,
+        payload: payload.into_bytes(),
+    })
+}
+
+fn light_discovery_topic(
+    device_id: &str,
+    zone: Zone,
+) -> Result<String<MAX_TOPIC_LENGTH>, core::fmt::Error> {
+    publish_topic(&["homeassistant/light", device_id, zone.id(), "config"])
+        .map_err(|_| core::fmt::Error)
+}
+
+/// Light entity of `zone`, attached to the device. Its state and brightness are read from the
+/// light state topic of the zone.
+fn light_discovery_packet(device_id: &str, zone: Zone) -> Result<TxPacket, core::fmt::Error> {
+    let topic_name = light_discovery_topic(device_id, zone)?;
+
+    let mut payload = String::<MAX_PAYLOAD_LENGTH>::new();
+    core::write!(
+        payload,
+        r#"{{"name":"{name}","unique_id":"{device_id}_{id}","device":{{"identifiers":["{device_id}"]}},"availability_topic":"{availability}","command_topic":"{command}","brightness_command_topic":"{brightness}","state_topic":"{state}","state_value_template":"{{{{ value.split(',')[0] }}}}","brightness_state_topic":"{state}","brightness_value_template":"{{{{ value.split(',')[1] }}}}"}}"#,
+        name = zone.name(),
+        id = zone.id(),
+        availability = AVAILABILITY.topic,
+        command = zone.light_topic(),
+        brightness = zone.brightness_topic(),
+        state = zone.light_state_topic(),
+    )?;
+
+    Ok(TxPacket::PublishOwned {
+        qospid: mqttrs::QosPid::AtMostOnce,
+        retain: false,
+        topic_name,
+        payload: payload.into_bytes(),
+    })
+}
+
+fn dimming_curve_discovery_topic(
+    device_id: &str,
+) -> Result<String<MAX_TOPIC_LENGTH>, core::fmt::Error> {
+    publish_topic(&["homeassistant/select", device_id, "dimming_curve", "config"])
+        .map_err(|_| core::fmt::Error)
+}
+
+/// Select entity of the dimming curve, announced on its own since the device config has no room
+/// left for it
+fn dimming_curve_discovery_packet(device_id: &str) -> Result<TxPacket, core::fmt::Error> {
+    let topic_name = dimming_curve_discovery_topic(device_id)?;
+
+    let mut payload = String::<MAX_PAYLOAD_LENGTH>::new();
+    core::write!(
+        payload,
+        r#"{{"name":"Dimming curve","unique_id":"{device_id}_dimming_curve","device":{{"identifiers":["{device_id}"]}},"availability_topic":"{availability}","command_topic":"picow/dimming_curve/set","options":["#,
+        availability = AVAILABILITY.topic,
+    )?;
+    for (index, curve) in DimmingCurve::ALL.iter().enumerate() {
+        let separator = if index > 0 { "," } else { "" };
+        core::write!(payload, r#"{separator}"{}""#, curve.name())?;
+    }
+    payload.push_str("]}").map_err(|_| core::fmt::Error)?;
+
+    Ok(TxPacket::PublishOwned {
+        qospid: mqttrs::QosPid::AtMostOnce,
+        retain: false,
+        topic_name,
+        payload: payload.into_bytes(),
+    })
+}
+
+/// Channels driven by the device, the RGB ones and the white ones of the `tunable_white` feature
+const CHANNELS: &[&str] = if cfg!(feature = "tunable_white") {
+    &["red", "green", "blue", "warm_white", "cool_white"]
+} else {
+    &["red", "green", "blue"]
+};
+
+/// Describes what the firmware was built with, so a controller can adapt to it
+fn capabilities_packet() -> Result<TxPacket, core::fmt::Error> {
+    let mut payload = String::<MAX_PAYLOAD_LENGTH>::new();
+    payload
+        .push_str(r#"{"channels":["#)
+        .map_err(|_| core::fmt::Error)?;
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        let separator = if index > 0 { "," } else { "" };
+        core::write!(payload, r#"{separator}"{channel}""#)?;
+    }
+
+    core::write!(payload, r#"],"max_streams":{MAX_STREAMS},"effects":["#)?;
+    for (index, effect) in EFFECTS.iter().enumerate() {
+        let separator = if index > 0 { "," } else { "" };
+        core::write!(payload, r#"{separator}"{}""#, effect.name())?;
+    }
+    payload.push_str("]}").map_err(|_| core::fmt::Error)?;
+
+    Ok(TxPacket::PublishOwned {
+        qospid: mqttrs::QosPid::AtMostOnce,
+        retain: true,
+        topic_name: String::try_from("picow/capabilities").map_err(|_| core::fmt::Error)?,
+        payload: payload.into_bytes(),
+    })
+}
+
+const AVAILABILITY: Availability = Availability {
+    topic: "picow/availability",
+    interval: Duration::from_secs(5 * 60),
 };
-use crate::network::{Cyw43, network_task, wifi_task};
-use crate::peripherals::{AssignedResources, LedPeripherals, WifiPeripherals};
 
-use {defmt_rtt as _, panic_probe as _};
+/// Host name or IPv4 literal of the MQTT broker. Literals are used as they are, without DNS.
+const MQTT_BROKER: &str = "homeassistant";
+
+/// Publishes the uptime to this topic at the interval, for setups that watch publishes rather
+/// than the broker's view of the connection. `None` disables it.
+const KEEPALIVE: Option<Keepalive> = None;
+
+/// Every topic the device publishes retained messages to, besides the autodiscovery configs and
+/// the topics of the zones
+const RETAINED_TOPICS: &[&str] = &[AVAILABILITY.topic, "picow/capabilities"];
+
+/// Topics of the second zone. The first zone's are subscribed along with the other commands.
+#[cfg(feature = "second_zone")]
+const SECOND_ZONE_TOPICS: &[&str] = &[
+    Zone::Second.light_topic(),
+    Zone::Second.brightness_topic(),
+    Zone::Second.light_state_topic(),
+    Zone::Second.config_topic(),
+];
+
+/// Port the status is broadcast to with the `udp_status` feature
+#[cfg(feature = "udp_status")]
+const STATUS_BROADCAST_PORT: u16 = 4210;
+
+/// Resends the autodiscovery configs at this interval while connected, so Home Assistant picks
+/// them up again after losing them, e.g. when it restarted. `None` only sends them after
+/// connecting.
+const DISCOVERY_REFRESH_INTERVAL: Option<Duration> = Some(Duration::from_secs(15 * 60));
+
+/// When to resend the autodiscovery configs after sending them at `sent`
+fn next_discovery_refresh(sent: Instant) -> Instant {
+    DISCOVERY_REFRESH_INTERVAL.map_or(Instant::MAX, |interval| sent + interval)
+}
 
 #[embassy_executor::task]
 async fn mqtt_autodiscovery_task(
     mut subscriber: MqttRxSubscriber<'static>,
-    sender: MqttTxSender<'static>,
+    sender: MqttPrioritySender<'static>,
+    device_id: &'static str,
 ) {
+    let mut next_refresh = Instant::MAX;
+
     loop {
-        let command = subscriber.next_message().await;
+        let Ok(command) = with_deadline(next_refresh, subscriber.next_message()).await else {
+            // Nothing is sent while disconnected, there's no deadline then
+            announce(&sender, device_id).await;
+            // Counted from the deadline, so the refreshes don't drift
+            next_refresh = next_discovery_refresh(next_refresh);
+            continue;
+        };
+
         let command = match command {
             WaitResult::Lagged(num) => {
                 warn!("Lagged {} messages behind!", num);
+                record_lag(num);
                 continue;
             }
             WaitResult::Message(command) => command,
         };
 
-        if let RxPacket::Connected = command {
+        match command {
+            RxPacket::Connected { session_present } => {
+                // A resumed session still has the subscriptions of the last connection
+                if !session_present {
+                    sender
+                        .send(TxPacket::Subscribe(&[
+                            "picow/light/set",
+                            "picow/light/brightness/set",
+                            "picow/sleep/set",
+                            "picow/pause/set",
+                            "picow/light/state",
+                        ]))
+                        .await;
+                    // A single SUBSCRIBE holds at most five topics
+                    sender
+                        .send(TxPacket::Subscribe(&["picow/discovery/clear"]))
+                        .await;
+                }
+
+                announce(&sender, device_id).await;
+                next_refresh = next_discovery_refresh(Instant::now());
+            }
+            RxPacket::Command {
+                command: Command::ClearDiscovery,
+                retained: false,
+            } => {
+                clear_discovery(&sender, device_id).await;
+                // Otherwise the refresh would add the device again
+                next_refresh = Instant::MAX;
+            }
+            RxPacket::Disconnected | RxPacket::ConnectionGivenUp => next_refresh = Instant::MAX,
+            RxPacket::Command { .. } => {}
+        }
+    }
+}
+
+/// Sends the autodiscovery configs of the device, the light of every zone, the dimming curve and
+/// all sensors
+async fn announce(sender: &MqttPrioritySender<'_>, device_id: &str) {
+    match autodiscovery_packet(device_id) {
+        Ok(autodiscovery) => sender.send(autodiscovery).await,
+        Err(_) => warn!("Autodiscovery config exceeds the payload buffer"),
+    }
+
+    for &zone in Zone::ALL {
+        match light_discovery_packet(device_id, zone) {
+            Ok(light) => sender.send(light).await,
+            Err(_) => warn!("Light config exceeds the payload buffer"),
+        }
+    }
+
+    match dimming_curve_discovery_packet(device_id) {
+        Ok(select) => sender.send(select).await,
+        Err(_) => warn!("Dimming curve config exceeds the payload buffer"),
+    }
+
+    if announce_sensors(sender, device_id).await.is_err() {
+        warn!("Failed to announce sensors");
+    }
+}
+
+/// Removes the device from Home Assistant by clearing its autodiscovery configs and retained
+/// topics with empty retained publishes. It's announced again after the next connect.
+async fn clear_discovery(sender: &MqttPrioritySender<'_>, device_id: &str) {
+    match autodiscovery_topic(device_id) {
+        Ok(topic_name) => {
+            sender
+                .send(TxPacket::PublishOwned {
+                    qospid: mqttrs::QosPid::AtMostOnce,
+                    retain: true,
+                    topic_name,
+                    payload: Vec::new(),
+                })
+                .await
+        }
+        Err(_) => warn!("Autodiscovery topic exceeds the topic buffer"),
+    }
+
+    for &zone in Zone::ALL {
+        match light_discovery_topic(device_id, zone) {
+            Ok(topic_name) => {
+                sender
+                    .send(TxPacket::PublishOwned {
+                        qospid: mqttrs::QosPid::AtMostOnce,
+                        retain: true,
+                        topic_name,
+                        payload: Vec::new(),
+                    })
+                    .await
+            }
+            Err(_) => warn!("Light config topic exceeds the topic buffer"),
+        }
+    }
+
+    match dimming_curve_discovery_topic(device_id) {
+        Ok(topic_name) => {
             sender
-                .send(TxPacket::Subscribe(&[
-                    SubscribeTopic {
-                        qos: mqttrs::QoS::AtMostOnce,
-                        topic_path: "picow/light/set",
-                    },
-                    SubscribeTopic {
-                        qos: mqttrs::QoS::AtMostOnce,
-                        topic_path: "picow/light/brightness/set",
-                    },
-                ]))
-                .await;
-
-            let autodiscovery = TxPacket::Publish {
+                .send(TxPacket::PublishOwned {
+                    qospid: mqttrs::QosPid::AtMostOnce,
+                    retain: true,
+                    topic_name,
+                    payload: Vec::new(),
+                })
+                .await
+        }
+        Err(_) => warn!("Dimming curve config topic exceeds the topic buffer"),
+    }
+
+    if clear_sensors(sender, device_id).await.is_err() {
+        warn!("Failed to clear sensors");
+    }
+
+    let zone_topics = Zone::ALL.iter().flat_map(|zone| zone.retained_topics());
+    for topic_name in RETAINED_TOPICS.iter().copied().chain(zone_topics) {
+        sender
+            .send(TxPacket::Publish {
                 qospid: mqttrs::QosPid::AtMostOnce,
-                topic_name: "homeassistant/device/picow/config",
-                payload: indoc! {
-                r#"{
-                        "device": {
-                            "identifiers": ["picow"],
-                            "name": "PicoW",
-                            "model": "Rasperry Pi Pico W",
-                            "manufacturer": "Raspberry Pi"
-                        },
-                        "origin": {
-                            "name": "Test"
-                        },
-                        "components": {
-                        }
-                    }"#
+                retain: true,
+                topic_name,
+                payload: &[],
+            })
+            .await;
+    }
+}
+
+/// Dims the light after this long without any command, like a screensaver. `None` keeps the
+/// brightness unchanged.
+const IDLE_TIMEOUT: Option<Duration> = None;
+/// Brightness the light is dimmed to while idle, 0 turns it off
+const IDLE_BRIGHTNESS: u8 = 32;
+
+/// Time since the last command, deciding when the light is dimmed for being idle
+struct IdleTimer {
+    /// `None` never dims the light
+    timeout: Option<Duration>,
+    last_command: Instant,
+    idle: bool,
+}
+
+impl IdleTimer {
+    fn new(timeout: Option<Duration>, now: Instant) -> Self {
+        Self {
+            timeout,
+            last_command: now,
+            idle: false,
+        }
+    }
+
+    /// Instant the light is dimmed at unless a command arrives before, `None` while it's idle
+    /// already
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout
+            .filter(|_| !self.idle)
+            .map(|timeout| self.last_command.saturating_add(timeout))
+    }
+
+    /// Dims the light once the deadline passed
+    fn expire(&mut self) -> LedCommand {
+        self.idle = true;
+        LedCommand::Idle(Some(IDLE_BRIGHTNESS))
+    }
+
+    /// Restarts the timeout on a command received `now`, returning the command restoring the
+    /// brightness if the light was dimmed
+    fn command(&mut self, now: Instant) -> Option<LedCommand> {
+        self.last_command = now;
+        core::mem::take(&mut self.idle).then_some(LedCommand::Idle(None))
+    }
+}
+
+/// Command queue of every zone, in the order of `Zone::ALL`
+type LedSenders = [LedCommandSender<'static>; Zone::ALL.len()];
+
+/// Sends `command` to every zone
+async fn broadcast(leds: &LedSenders, command: LedCommand) {
+    for sender in leds {
+        sender.send(command.clone()).await;
+    }
+}
+
+/// Sends `command` to `zone`, dropping it if the firmware doesn't drive the zone
+async fn send_to(leds: &LedSenders, zone: Zone, command: LedCommand) {
+    if let Some(sender) = leds.get(zone as usize) {
+        sender.send(command).await;
+    }
+}
+
+/// Subscribes to the commands handled by `led_command_task`. They go through the priority
+/// queue, so a backlog of state publishes doesn't keep the commands from arriving.
+async fn subscribe_led_commands(priority: &MqttPrioritySender<'_>) {
+    priority
+        .send(TxPacket::Subscribe(&[
+            "picow/manual/set",
+            DEBUG_REQUESTS,
+            "picow/restart/set",
+            "picow/speed/set",
+            "picow/gamma/set",
+        ]))
+        .await;
+    // A single SUBSCRIBE holds at most five topics
+    priority
+        .send(TxPacket::Subscribe(&[
+            "picow/test_pattern/set",
+            "picow/manual/raw/set",
+            "picow/config",
+            "picow/get",
+            "picow/reconnect",
+        ]))
+        .await;
+    priority
+        .send(TxPacket::Subscribe(&[
+            "picow/identify/set",
+            "picow/dimming_curve/set",
+        ]))
+        .await;
+    #[cfg(feature = "second_zone")]
+    priority.send(TxPacket::Subscribe(SECOND_ZONE_TOPICS)).await;
+}
+
+#[embassy_executor::task]
+async fn led_command_task(
+    mut subscriber: MqttRxSubscriber<'static>,
+    leds: LedSenders,
+    sender: MqttTxSender<'static>,
+    priority: MqttPrioritySender<'static>,
+) -> ! {
+    let mut idle = IdleTimer::new(IDLE_TIMEOUT, Instant::now());
+
+    loop {
+        let message = match idle.deadline() {
+            Some(deadline) => with_deadline(deadline, subscriber.next_message()).await,
+            None => Ok(subscriber.next_message().await),
+        };
+
+        let Ok(message) = message else {
+            broadcast(&leds, idle.expire()).await;
+            continue;
+        };
+
+        let command = match message {
+            WaitResult::Lagged(num) => {
+                warn!("Lagged {} messages behind!", num);
+                record_lag(num);
+                continue;
+            }
+            WaitResult::Message(RxPacket::Connected { session_present }) => {
+                broadcast(&leds, LedCommand::Status(ConnectionStatus::Connected)).await;
+                broadcast(&leds, LedCommand::ReportEffect).await;
+                if !session_present {
+                    subscribe_led_commands(&priority).await;
                 }
-                .as_bytes(),
-            };
+                continue;
+            }
+            WaitResult::Message(RxPacket::Command { command, retained }) => {
+                if retained && command.is_one_shot() {
+                    warn!("Ignoring a retained one-shot command");
+                    continue;
+                }
+
+                if let Some(restore) = idle.command(Instant::now()) {
+                    broadcast(&leds, restore).await;
+                }
+
+                command
+            }
+            WaitResult::Message(RxPacket::Disconnected | RxPacket::ConnectionGivenUp) => {
+                broadcast(&leds, LedCommand::Status(ConnectionStatus::Disconnected)).await;
+                continue;
+            }
+        };
+
+        // Manual mode and the stream dump only concern the first zone
+        match command {
+            Command::Pause(true) => broadcast(&leds, LedCommand::Pause).await,
+            Command::Pause(false) => broadcast(&leds, LedCommand::Resume).await,
+            Command::Manual(None) => send_to(&leds, Zone::First, LedCommand::Resume).await,
+            Command::Manual(Some(color)) => {
+                send_to(&leds, Zone::First, LedCommand::Manual(color)).await
+            }
+            Command::DumpStreams => send_to(&leds, Zone::First, LedCommand::DumpStreams).await,
+            Command::Restart => broadcast(&leds, LedCommand::Restart).await,
+            Command::Speed(speed) => broadcast(&leds, LedCommand::Speed(speed)).await,
+            Command::Gamma(gamma) => broadcast(&leds, LedCommand::Gamma(gamma)).await,
+            Command::DimmingCurve(curve) => broadcast(&leds, LedCommand::DimmingCurve(curve)).await,
+            Command::TestPattern => broadcast(&leds, LedCommand::TestPattern).await,
+            Command::Identify => broadcast(&leds, LedCommand::Identify).await,
+            Command::Streams(zone, streams) => {
+                send_to(&leds, zone, LedCommand::SetStreams(streams)).await
+            }
+            Command::Light(zone, on) => send_to(&leds, zone, LedCommand::SwitchLight(on)).await,
+            Command::Brightness(zone, brightness) => {
+                send_to(&leds, zone, LedCommand::SetBrightness(brightness)).await
+            }
+            Command::Reconnect => sender.send(TxPacket::Reconnect).await,
+            Command::GetState => {
+                // Only reachable while connected, so the device is online
+                sender
+                    .send(TxPacket::Publish {
+                        qospid: mqttrs::QosPid::AtMostOnce,
+                        retain: true,
+                        topic_name: AVAILABILITY.topic,
+                        payload: b"online",
+                    })
+                    .await;
+                broadcast(&leds, LedCommand::ReportState).await;
+            }
+            Command::DumpEvents => {
+                let payload = event_log::dump::<MAX_PAYLOAD_LENGTH>();
+                sender
+                    .send(TxPacket::PublishOwned {
+                        qospid: mqttrs::QosPid::AtMostOnce,
+                        retain: false,
+                        topic_name: String::try_from("picow/debug/events").unwrap(),
+                        payload: payload.into_bytes(),
+                    })
+                    .await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Waits for the broker to replay the retained light state of every zone after connecting. Falls
+/// back to the default state for zones without one, or if the broker can't be reached in time.
+async fn restore_light_states(
+    mut subscriber: MqttRxSubscriber<'_>,
+) -> [LightState; Zone::ALL.len()] {
+    /// Time the network and broker get to accept the connection after booting
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+    /// Time the broker gets to send retained messages after connecting
+    const RESTORE_TIMEOUT: Duration = Duration::from_secs(2);
+
+    if with_timeout(CONNECT_TIMEOUT, wait_connected())
+        .await
+        .is_err()
+    {
+        info!("Not connected to the broker, using default light state");
+        return [LightState::DEFAULT; Zone::ALL.len()];
+    }
+
+    let mut restored = [None; Zone::ALL.len()];
+    // Zones without a retained state wait until the timeout
+    _ = with_timeout(
+        RESTORE_TIMEOUT,
+        collect_light_states(&mut subscriber, &mut restored),
+    )
+    .await;
+
+    with_defaults(restored)
+}
 
-            sender.send(autodiscovery).await;
+/// Records the retained light states received until every zone has one. States that aren't
+/// retained are live echoes of the device's own reports, not replays.
+async fn collect_light_states(
+    subscriber: &mut MqttRxSubscriber<'_>,
+    restored: &mut [Option<LightState>; Zone::ALL.len()],
+) {
+    while restored.contains(&None) {
+        if let WaitResult::Message(RxPacket::Command {
+            command: Command::LightState(zone, state),
+            retained: true,
+        }) = subscriber.next_message().await
+            && let Some(restored) = restored.get_mut(zone as usize)
+        {
+            *restored = Some(state);
         }
     }
 }
 
+/// The restored light state of every zone, or the default for zones without one
+fn with_defaults(restored: [Option<LightState>; Zone::ALL.len()]) -> [LightState; Zone::ALL.len()] {
+    core::array::from_fn(|index| match restored[index] {
+        Some(state) => {
+            info!("Restored light state of {}", Zone::ALL[index]);
+            state
+        }
+        None => {
+            info!(
+                "No retained light state of {}, using default",
+                Zone::ALL[index]
+            );
+            LightState::DEFAULT
+        }
+    })
+}
+
+/// Restores the light states, in its own task so the status LED shows the connection meanwhile.
+/// The effect isn't shown until the previous state is known, instead of briefly showing the
+/// default.
+#[embassy_executor::task]
+async fn light_state_task(subscriber: MqttRxSubscriber<'static>, leds: LedSenders) {
+    let light_states = restore_light_states(subscriber).await;
+    for (sender, light_state) in leds.iter().zip(light_states) {
+        sender.send(LedCommand::SetLightState(light_state)).await;
+    }
+}
+
+#[cfg(target_os = "none")]
+#[embassy_executor::task]
+async fn mqtt_diagnostics_task(
+    sender: MqttTxSender<'static>,
+    device_id: &'static str,
+    mut temperature: TemperatureSensor,
+) -> ! {
+    let mut ticker = Ticker::every(Duration::from_secs(60));
+
+    loop {
+        ticker.next().await;
+
+        let uptime = Instant::now().as_secs() as u32;
+        if publish_sensor(&sender, device_id, "uptime", uptime, Unit::Seconds)
+            .await
+            .is_err()
+        {
+            warn!("Failed to publish uptime");
+        }
+
+        if publish_sensor(
+            &sender,
+            device_id,
+            "rx_lagged",
+            lagged_packets(),
+            Unit::None,
+        )
+        .await
+        .is_err()
+        {
+            warn!("Failed to publish lagged packets");
+        }
+
+        match temperature.read().await {
+            Some(celsius) => {
+                if publish_sensor(&sender, device_id, "temperature", celsius, Unit::Celsius)
+                    .await
+                    .is_err()
+                {
+                    warn!("Failed to publish the temperature");
+                }
+            }
+            None => warn!("Failed to read the temperature"),
+        }
+    }
+}
+
+/// Reads a push button on the button pin to switch effects and turn the light on or off. If
+/// disabled, the button pin is left unused.
+const BUTTON_INPUT: bool = false;
+
+/// Reads a rotary encoder on the encoder pins to change the brightness. If disabled, the encoder
+/// pins are left unused.
+const ENCODER_INPUT: bool = false;
+
+/// Whether the onboard LED shows the connection to the MQTT broker, independent of the strobe
+const ONBOARD_STATUS_LED: bool = true;
+
+/// Rejoins the network when the signal stays weak and another AP with the same SSID is stronger,
+/// see `RoamingOptions`. Every check scans for the APs, which briefly takes the radio off the
+/// channel, so it's only worth it with several APs.
+const ROAMING: bool = false;
+
+/// Blinks the onboard LED while connecting to the broker, keeps it on once connected
+#[cfg(target_os = "none")]
+async fn onboard_status_led<S>(cyw43: &Mutex<NoopRawMutex, Cyw43<'_, S>>) -> ! {
+    let mut connected = connection_receiver();
+    let mut status = ConnectionStatus::Disconnected;
+    let mut on = true;
+
+    loop {
+        cyw43.lock().await.set_onboard_led(on).await;
+
+        let change = match status.onboard_blink() {
+            Some(duration) => with_timeout(duration, connected.changed()).await.ok(),
+            None => Some(connected.changed().await),
+        };
+
+        match change {
+            Some(true) => status = ConnectionStatus::Connected,
+            Some(false) => status = ConnectionStatus::Disconnected,
+            None => {}
+        }
+        on = status.onboard_blink().is_none() || !on;
+    }
+}
+
+#[cfg(target_os = "none")]
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    let locked_state = SIO.spinlock_st();
-    info!("Spinlock state {:b}", locked_state.read());
-
-    SIO.spinlock(31).write_value(1);
+    let locked_state = release_critical_section_lock();
+    if critical_section_lock_held(locked_state) {
+        warn!(
+            "Released the critical section lock, still held from before the reset, spinlock state {:b}",
+            locked_state
+        );
+    }
 
     let p = embassy_rp::init(Default::default());
     let p = split_resources!(p);
 
-    let (cyw43, runner) = Cyw43::new(p.wifi).await;
+    static MQTT_TX_CHANNEL: StaticCell<Channel<CriticalSectionRawMutex, TxPacket, 10>> =
+        StaticCell::new();
+    let tx_channel = MQTT_TX_CHANNEL.init(Channel::new());
+    static MQTT_PRIORITY_CHANNEL: StaticCell<
+        Channel<CriticalSectionRawMutex, TxPacket, PRIORITY_CAPACITY>,
+    > = StaticCell::new();
+    let priority_channel = MQTT_PRIORITY_CHANNEL.init(Channel::new());
+
+    static LED_COMMAND_CHANNEL: StaticCell<Channel<CriticalSectionRawMutex, LedCommand, 4>> =
+        StaticCell::new();
+    let led_channel = LED_COMMAND_CHANNEL.init(Channel::new());
+    #[cfg(feature = "second_zone")]
+    static SECOND_ZONE_CHANNEL: StaticCell<Channel<CriticalSectionRawMutex, LedCommand, 4>> =
+        StaticCell::new();
+    #[cfg(feature = "second_zone")]
+    let second_zone_channel = SECOND_ZONE_CHANNEL.init(Channel::new());
+    // In the order of `Zone::ALL`
+    let led_senders: LedSenders = [
+        led_channel.sender(),
+        #[cfg(feature = "second_zone")]
+        second_zone_channel.sender(),
+    ];
+
+    // Started right away, so the status pattern is visible while joining the network
+    spawner.must_spawn(orchestrate_leds(
+        p.led,
+        led_channel.receiver(),
+        tx_channel.sender(),
+    ));
+
+    #[cfg_attr(not(feature = "second_zone"), allow(unused_variables))]
+    let (cyw43, runner, spare_pio) = Cyw43::new(p.wifi, BoardConfig::default()).await;
+
+    #[cfg(feature = "second_zone")]
+    spawner.must_spawn(led_orchestrator::orchestrate_second_zone(
+        spare_pio,
+        p.second_zone,
+        second_zone_channel.receiver(),
+        tx_channel.sender(),
+    ));
 
     spawner.must_spawn(wifi_task(runner));
 
@@ -106,47 +834,454 @@ async fn main(spawner: Spawner) {
 
     let ssid = env!("WIFI_SSID");
     let password = env!("WIFI_PASSWORD");
-    let cyw43 = cyw43.join(ssid, password).await;
+    let mut cyw43 = cyw43
+        .join(
+            ssid,
+            password,
+            DhcpOptions {
+                fallback: Some(DhcpFallback::LinkLocal),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Failed to establish network connection");
+    if let Some(retry) = cyw43.dhcp_retry() {
+        spawner.must_spawn(dhcp_retry_task(retry));
+    }
+
+    led_channel
+        .send(LedCommand::Status(ConnectionStatus::Disconnected))
+        .await;
+
+    #[cfg(feature = "udp_status")]
+    spawner.must_spawn(broadcast::status_broadcast_task(
+        cyw43.stack(),
+        broadcast::BroadcastOptions {
+            target: embassy_net::IpEndpoint::new(
+                embassy_net::Ipv4Address::new(255, 255, 255, 255).into(),
+                STATUS_BROADCAST_PORT,
+            ),
+            interval: Duration::from_secs(10),
+        },
+    ));
+
+    static DEVICE_ID: StaticCell<String<12>> = StaticCell::new();
+    let device_id = DEVICE_ID
+        .init(cyw43.mac_address().to_topic_string())
+        .as_str();
 
     let mqtt_runner = MqttRunner::new(
         cyw43.stack(),
         ConnectionOptions {
-            address: mqtt::ServerAddress::HostName("homeassistant"),
+            address: mqtt::ServerAddress::parse(MQTT_BROKER),
             client_id: "picow",
+            client_id_suffix: Some(cyw43.mac_address()),
             credentials: Credentials {
                 username: "picow",
                 password: "picow".as_bytes(),
             }
             .into(),
+            tcp_timeout: Duration::from_secs(60),
+            tcp_keep_alive: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(45),
+            keep_alive: Duration::from_secs(60),
+            clean_session: true,
+            command_qos: mqttrs::QoS::AtMostOnce,
+            max_reconnect_attempts: None,
+            ping_when_idle_only: false,
+            pin_address: false,
+            last_will: Some(LastWill {
+                topic: AVAILABILITY.topic,
+                message: b"offline",
+                qos: mqttrs::QoS::AtLeastOnce,
+                retain: true,
+            }),
+            mqtt5: None,
         },
     );
 
-    static MQTT_TX_CHANNEL: StaticCell<Channel<CriticalSectionRawMutex, TxPacket, 10>> =
-        StaticCell::new();
-
-    let tx_channel = MQTT_TX_CHANNEL.init(Channel::new());
-
-    static MQTT_RX_CHANNEL: StaticCell<
-        PubSubChannel<CriticalSectionRawMutex, RxPacket, 10, 10, 1>,
-    > = StaticCell::new();
-    let rx_channel = MQTT_RX_CHANNEL.init(PubSubChannel::new());
+    static MQTT_RX_CHANNEL: StaticCell<MqttRxChannel> = StaticCell::new();
+    let rx_channel = MQTT_RX_CHANNEL.init(MqttRxChannel::new());
 
+    // Must stay within MAX_RX_SUBSCRIBERS
     let autodiscovery_subscriber = rx_channel.subscriber().unwrap();
+    let mut power_subscriber = rx_channel.subscriber().unwrap();
+    let led_command_subscriber = rx_channel.subscriber().unwrap();
+    let restore_subscriber = rx_channel.subscriber().unwrap();
+    #[cfg(feature = "tunable_white")]
+    let white_subscriber = rx_channel.subscriber().unwrap();
 
     spawner.must_spawn(mqtt_task(
         mqtt_runner,
         tx_channel.receiver(),
+        priority_channel.receiver(),
         rx_channel.publisher().unwrap(),
     ));
-    spawner.must_spawn(mqtt_heartbeat(tx_channel.sender()));
+    spawner.must_spawn(mqtt_heartbeat(
+        tx_channel.sender(),
+        Some(AVAILABILITY),
+        KEEPALIVE,
+    ));
+    spawner.must_spawn(mqtt_diagnostics_task(
+        tx_channel.sender(),
+        device_id,
+        TemperatureSensor::new(p.temperature),
+    ));
     spawner.must_spawn(mqtt_autodiscovery_task(
         autodiscovery_subscriber,
+        priority_channel.sender(),
+        device_id,
+    ));
+
+    // Retained, so sending it once is enough. It's queued until the first connection.
+    match capabilities_packet() {
+        Ok(capabilities) => tx_channel.send(capabilities).await,
+        Err(_) => warn!("Capabilities exceed the payload buffer"),
+    }
+
+    if BUTTON_INPUT {
+        spawner.must_spawn(input::button_task(p.button, led_channel.sender()));
+    }
+    if ENCODER_INPUT {
+        spawner.must_spawn(input::encoder_task(p.encoder, led_channel.sender()));
+    }
+
+    spawner.must_spawn(led_command_task(
+        led_command_subscriber,
+        led_senders,
         tx_channel.sender(),
+        priority_channel.sender(),
     ));
 
-    spawner.must_spawn(orchestrate_leds(p.led));
+    spawner.must_spawn(light_state_task(restore_subscriber, led_senders));
 
-    loop {
-        Timer::at(Instant::MAX).await
+    #[cfg(feature = "tunable_white")]
+    spawner.must_spawn(white::white_task(
+        p.white,
+        white_subscriber,
+        tx_channel.sender(),
+    ));
+
+    /// Used when a sleep command doesn't specify a duration
+    const DEFAULT_WAKE_SOURCE: WakeSource = WakeSource::Pin;
+
+    // Retained sleep commands are ignored, otherwise the device would go back to sleep every
+    // time it reconnects
+    let sleep_requested = async {
+        loop {
+            if let WaitResult::Message(RxPacket::Command {
+                command: Command::Sleep(duration),
+                retained: false,
+            }) = power_subscriber.next_message().await
+            {
+                break duration.map_or(DEFAULT_WAKE_SOURCE, WakeSource::Timer);
+            }
+        }
+    };
+
+    // Both share the driver, which is only locked for a moment to set the LED, or for a scan
+    let cyw43 = Mutex::<NoopRawMutex, _>::new(cyw43);
+    let status_led = async {
+        if ONBOARD_STATUS_LED {
+            onboard_status_led(&cyw43).await
+        }
+        core::future::pending().await
+    };
+    let roaming = async {
+        if ROAMING {
+            roam(&cyw43, ssid, password, RoamingOptions::default()).await
+        }
+        core::future::pending().await
+    };
+
+    let wake = match select3(sleep_requested, status_led, roaming).await {
+        Either3::First(wake) => wake,
+        Either3::Second(never) | Either3::Third(never) => never,
+    };
+    let mut cyw43 = cyw43.into_inner();
+
+    info!("Entering sleep");
+    event_log::record("Entering sleep");
+
+    static LEDS_STOPPED: [Signal<CriticalSectionRawMutex, ()>; Zone::ALL.len()] =
+        [const { Signal::new() }; Zone::ALL.len()];
+    for (sender, stopped) in led_senders.iter().zip(&LEDS_STOPPED) {
+        sender.send(LedCommand::Shutdown(stopped)).await;
+    }
+    for stopped in &LEDS_STOPPED {
+        stopped.wait().await;
+    }
+
+    #[cfg(feature = "tunable_white")]
+    {
+        static WHITE_STOPPED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+        white::SHUTDOWN.signal(&WHITE_STOPPED);
+        WHITE_STOPPED.wait().await;
+    }
+
+    // The last will isn't published after a DISCONNECT
+    tx_channel
+        .send(TxPacket::Publish {
+            qospid: mqttrs::QosPid::AtMostOnce,
+            retain: true,
+            topic_name: AVAILABILITY.topic,
+            payload: b"offline",
+        })
+        .await;
+    tx_channel.send(TxPacket::Disconnect).await;
+    // Give the runner some time to flush the DISCONNECT before the radio is turned off
+    Timer::after_millis(500).await;
+
+    cyw43.set_onboard_led(false).await;
+    cyw43.control.leave().await;
+    cyw43
+        .control
+        .set_power_management(cyw43::PowerManagementMode::SuperSave)
+        .await;
+
+    power::sleep(p.power, wake).await
+}
+
+/// The firmware only runs on the RP2040
+#[cfg(not(target_os = "none"))]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::{block_on, poll_once, select::select};
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+    use embassy_time::{Duration, Instant};
+
+    use super::{
+        DISCOVERY_REFRESH_INTERVAL, IDLE_BRIGHTNESS, IdleTimer, RETAINED_TOPICS, Zone,
+        capabilities_packet, clear_discovery, collect_light_states, dimming_curve_discovery_packet,
+        light_discovery_packet, light_discovery_topic, next_discovery_refresh, with_defaults,
+    };
+    use crate::led_orchestrator::{LedCommand, LightState};
+    use crate::mqtt::{Command, MqttRxChannel, PRIORITY_CAPACITY, RxPacket, TxPacket};
+    use crate::stream::Color;
+
+    const RETAINED: LightState = LightState {
+        on: true,
+        brightness: 128,
+        color: Some(Color(0, 64, 255)),
+    };
+
+    /// Light states restored from `received`, the default for zones the broker didn't replay
+    fn restored(received: &[RxPacket]) -> [LightState; Zone::ALL.len()] {
+        let channel = MqttRxChannel::new();
+        let mut subscriber = channel.subscriber().unwrap();
+        let publisher = channel.publisher().unwrap();
+        for packet in received {
+            publisher.publish_immediate(packet.clone());
+        }
+
+        let mut restored = [None; Zone::ALL.len()];
+        // Waits for the remaining zones once the received packets are handled, until the timeout
+        let collected = poll_once(collect_light_states(&mut subscriber, &mut restored));
+        assert_eq!(collected.is_ready(), !restored.contains(&None));
+
+        with_defaults(restored)
+    }
+
+    fn light_state(zone: Zone, retained: bool) -> RxPacket {
+        RxPacket::Command {
+            command: Command::LightState(zone, RETAINED),
+            retained,
+        }
+    }
+
+    #[test]
+    fn retained_light_states_are_restored() {
+        let states = restored(&[light_state(Zone::First, true)]);
+
+        assert_eq!(states[0], RETAINED);
+        assert!(
+            states[1..]
+                .iter()
+                .all(|state| *state == LightState::DEFAULT)
+        );
+    }
+
+    #[test]
+    fn zones_without_a_retained_state_use_the_default() {
+        // A live echo of a report isn't a replay of the retained state
+        let states = restored(&[light_state(Zone::First, false)]);
+
+        assert!(states.iter().all(|state| *state == LightState::DEFAULT));
+    }
+
+    /// Topic and payload of the autodiscovery config of a zone
+    fn discovery(zone: Zone) -> (std::string::String, std::string::String) {
+        match light_discovery_packet("28cdc1000000", zone) {
+            Ok(TxPacket::PublishOwned {
+                topic_name,
+                payload,
+                ..
+            }) => (
+                topic_name.as_str().to_owned(),
+                std::str::from_utf8(&payload).unwrap().to_owned(),
+            ),
+            _ => panic!("no autodiscovery config for {zone:?}"),
+        }
+    }
+
+    #[test]
+    fn dimming_curves_are_offered_as_a_select() {
+        let Ok(TxPacket::PublishOwned {
+            topic_name,
+            payload,
+            ..
+        }) = dimming_curve_discovery_packet("28cdc1000000")
+        else {
+            panic!("no autodiscovery config for the dimming curve");
+        };
+        let payload = std::str::from_utf8(&payload).unwrap();
+
+        assert_eq!(
+            topic_name,
+            "homeassistant/select/28cdc1000000/dimming_curve/config"
+        );
+        assert!(payload.contains(r#""command_topic":"picow/dimming_curve/set""#));
+        assert!(payload.ends_with(r#""options":["linear","logarithmic","cie1931"]}"#));
+    }
+
+    #[test]
+    fn zones_are_announced_as_separate_lights() {
+        let (first_topic, first) = discovery(Zone::First);
+        let (second_topic, second) = discovery(Zone::Second);
+
+        assert_eq!(first_topic, "homeassistant/light/28cdc1000000/light/config");
+        assert_eq!(
+            second_topic,
+            "homeassistant/light/28cdc1000000/light2/config"
+        );
+        assert!(first.contains(r#""command_topic":"picow/light/set""#));
+        assert!(first.contains(r#""brightness_command_topic":"picow/light/brightness/set""#));
+        assert!(second.contains(r#""unique_id":"28cdc1000000_light2""#));
+        assert!(second.contains(r#""state_topic":"picow/zone2/light/state""#));
+    }
+
+    #[test]
+    fn lights_are_dimmed_after_the_timeout() {
+        let start = Instant::from_secs(100);
+        let mut idle = IdleTimer::new(Some(Duration::from_secs(30)), start);
+        assert_eq!(idle.deadline(), Some(start + Duration::from_secs(30)));
+
+        // Every command pushes the deadline out
+        assert!(idle.command(start + Duration::from_secs(20)).is_none());
+        assert_eq!(idle.deadline(), Some(start + Duration::from_secs(50)));
+
+        assert!(matches!(
+            idle.expire(),
+            LedCommand::Idle(Some(brightness)) if brightness == IDLE_BRIGHTNESS
+        ));
+        // Nothing more to wait for while idle
+        assert_eq!(idle.deadline(), None);
+    }
+
+    #[test]
+    fn commands_restore_the_brightness() {
+        let start = Instant::from_secs(100);
+        let mut idle = IdleTimer::new(Some(Duration::from_secs(30)), start);
+        idle.expire();
+
+        let now = start + Duration::from_secs(90);
+        assert!(matches!(idle.command(now), Some(LedCommand::Idle(None))));
+        assert_eq!(idle.deadline(), Some(now + Duration::from_secs(30)));
+        // Only the first command after dimming restores it
+        assert!(idle.command(now).is_none());
+    }
+
+    #[test]
+    fn lights_without_a_timeout_are_never_dimmed() {
+        let mut idle = IdleTimer::new(None, Instant::from_secs(100));
+
+        assert_eq!(idle.deadline(), None);
+        assert!(idle.command(Instant::from_secs(200)).is_none());
+        assert_eq!(idle.deadline(), None);
+    }
+
+    #[test]
+    fn capabilities_describe_the_build() {
+        let Ok(TxPacket::PublishOwned {
+            topic_name,
+            payload,
+            retain: true,
+            ..
+        }) = capabilities_packet()
+        else {
+            panic!("Expected a retained publish");
+        };
+
+        let channels = if cfg!(feature = "tunable_white") {
+            r#""red","green","blue","warm_white","cool_white""#
+        } else {
+            r#""red","green","blue""#
+        };
+        assert_eq!(topic_name, "picow/capabilities");
+        assert_eq!(
+            std::str::from_utf8(&payload).unwrap(),
+            std::format!(
+                r#"{{"channels":[{channels}],"max_streams":8,"effects":["Droplets","Chase","Confetti","Sweep","Solid"]}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn clearing_the_discovery_empties_the_retained_topics() {
+        let channel = Channel::<CriticalSectionRawMutex, TxPacket, PRIORITY_CAPACITY>::new();
+        let mut sent = std::vec::Vec::new();
+        block_on(select(clear_discovery(&channel.sender(), "picow"), async {
+            loop {
+                sent.push(channel.receive().await);
+            }
+        }));
+        while let Ok(packet) = channel.try_receive() {
+            sent.push(packet);
+        }
+
+        let cleared: std::vec::Vec<std::string::String> = sent
+            .into_iter()
+            .map(|packet| match packet {
+                TxPacket::Publish {
+                    topic_name,
+                    payload: &[],
+                    retain: true,
+                    ..
+                } => topic_name.into(),
+                TxPacket::PublishOwned {
+                    topic_name,
+                    payload,
+                    retain: true,
+                    ..
+                } if payload.is_empty() => topic_name.as_str().into(),
+                _ => panic!("Expected an empty retained publish"),
+            })
+            .collect();
+
+        let retained = Zone::ALL
+            .iter()
+            .flat_map(|zone| zone.retained_topics())
+            .chain(RETAINED_TOPICS.iter().copied());
+        for topic in retained {
+            assert!(cleared.iter().any(|cleared| cleared == topic), "{topic}");
+        }
+        for &zone in Zone::ALL {
+            let config = light_discovery_topic("picow", zone).unwrap();
+            assert!(cleared.iter().any(|cleared| cleared == config.as_str()));
+        }
+    }
+
+    #[test]
+    fn discovery_refreshes_follow_each_other() {
+        let sent = Instant::from_secs(100);
+        let next = next_discovery_refresh(sent);
+
+        assert_eq!(next, sent + DISCOVERY_REFRESH_INTERVAL.unwrap());
+        assert_eq!(
+            next_discovery_refresh(next),
+            sent + DISCOVERY_REFRESH_INTERVAL.unwrap() * 2
+        );
     }
 }
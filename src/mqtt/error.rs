@@ -1,20 +1,38 @@
-use core::num::ParseIntError;
+use core::num::{ParseFloatError, ParseIntError};
+
+use super::topic::TopicError;
+use crate::stream::ConfigError;
 
 pub(crate) type Result<T> = core::result::Result<T, MqttError>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, defmt::Format)]
 pub(crate) enum MqttError {
     Generic,
-    TcpError,
+    ConnectionReset,
+    /// The broker sent a packet it must not send at that point
+    ProtocolViolation,
+    /// The connection was dropped on request, to connect again
+    ReconnectRequested,
+    /// Nothing was received from the broker within the read timeout
+    ReadTimeout,
+    /// The TCP connection ended up at another endpoint than the one it was opened to
+    UnexpectedEndpoint,
+    ConnectionGivenUp,
     ConnectError,
     DnsError,
     EncodeError,
     DecodeError,
+    PayloadTooLarge,
+    TooManySensors,
+    InvalidTopic(TopicError),
+    InvalidConfig(ConfigError),
 }
 
 impl From<embassy_net::tcp::Error> for MqttError {
-    fn from(_value: embassy_net::tcp::Error) -> Self {
-        Self::TcpError
+    fn from(value: embassy_net::tcp::Error) -> Self {
+        match value {
+            embassy_net::tcp::Error::ConnectionReset => Self::ConnectionReset,
+        }
     }
 }
 
@@ -42,6 +60,30 @@ impl From<ParseIntError> for MqttError {
     }
 }
 
+impl From<ParseFloatError> for MqttError {
+    fn from(_value: ParseFloatError) -> Self {
+        Self::DecodeError
+    }
+}
+
+impl From<core::fmt::Error> for MqttError {
+    fn from(_value: core::fmt::Error) -> Self {
+        Self::EncodeError
+    }
+}
+
+impl From<TopicError> for MqttError {
+    fn from(value: TopicError) -> Self {
+        Self::InvalidTopic(value)
+    }
+}
+
+impl From<ConfigError> for MqttError {
+    fn from(value: ConfigError) -> Self {
+        Self::InvalidConfig(value)
+    }
+}
+
 impl From<()> for MqttError {
     fn from(_value: ()) -> Self {
         Self::Generic
@@ -0,0 +1,105 @@
+//! A scripted socket, to run sessions in the host tests
+
+use std::{collections::VecDeque, vec::Vec};
+
+use mqttrs::Packet;
+
+use super::{
+    error::{MqttError, Result},
+    socket::{MqttSocket, RxBuffer, encode_packet},
+};
+
+/// What the next read from the broker returns
+pub(crate) enum Read {
+    /// Received bytes, which don't have to be whole packets
+    Bytes(Vec<u8>),
+    Eof,
+    Fail(MqttError),
+}
+
+/// Hands out the scripted reads in order, and records everything sent. Once the script is
+/// exhausted, reads never complete.
+#[derive(Default)]
+pub(crate) struct MockSocket {
+    pub reads: VecDeque<Read>,
+    pub sent: Vec<Vec<u8>>,
+    pub closed: bool,
+    pub aborted: bool,
+    /// Speaks MQTT 5 instead of 3.1.1
+    pub mqtt5: bool,
+}
+
+impl MockSocket {
+    pub fn new(reads: impl IntoIterator<Item = Read>) -> Self {
+        Self {
+            reads: reads.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The packets sent so far
+    pub fn sent_packets(&self) -> Vec<Packet<'_>> {
+        self.sent
+            .iter()
+            .map(|bytes| mqttrs::decode_slice(bytes).unwrap().unwrap())
+            .collect()
+    }
+}
+
+/// Encodes `packet` the way the broker would send it
+pub(crate) fn encode(packet: &Packet<'_>) -> Vec<u8> {
+    let mut buffer = [0; 2048];
+    let size = mqttrs::encode_slice(packet, &mut buffer).unwrap();
+    buffer[..size].to_vec()
+}
+
+/// A scripted read of a single packet
+pub(crate) fn receive(packet: &Packet<'_>) -> Read {
+    Read::Bytes(encode(packet))
+}
+
+impl MqttSocket for MockSocket {
+    async fn send_packet_with(&mut self, packet: &Packet<'_>, properties: &[u8]) -> Result<()> {
+        let mut buffer = [0; 2048];
+        let size = encode_packet(packet, properties, self.mqtt5, &mut buffer)?;
+        self.sent.push(buffer[..size].to_vec());
+        Ok(())
+    }
+
+    async fn read_packet<'s, const N: usize>(
+        &mut self,
+        buffer: &'s mut RxBuffer<N>,
+    ) -> Result<Option<Packet<'s>>> {
+        buffer.discard_consumed();
+
+        let length = loop {
+            if let Some(length) = buffer.next_packet()? {
+                break length;
+            }
+
+            match self.reads.pop_front() {
+                Some(Read::Bytes(bytes)) => {
+                    buffer.space()[..bytes.len()].copy_from_slice(&bytes);
+                    buffer.received(bytes.len());
+                }
+                Some(Read::Eof) => return Ok(None),
+                Some(Read::Fail(err)) => return Err(err),
+                None => core::future::pending().await,
+            }
+        };
+
+        buffer.take_packet(length, self.mqtt5).map(Some)
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+    }
+
+    fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
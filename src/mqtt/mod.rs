@@ -1,55 +1,377 @@
-use core::str::FromStr;
+use core::{fmt::Write, ops::ControlFlow, str::FromStr};
+use defmt::*;
 use error::{MqttError, Result};
 
-use embassy_futures::select::{Either, select};
-use embassy_net::{IpAddress, IpEndpoint, Stack, tcp::TcpSocket};
+use embassy_futures::select::{Either4, select4};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack, tcp::TcpSocket};
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
     channel::{Receiver, Sender},
-    pubsub::{Publisher, Subscriber},
+    pubsub::{PubSubChannel, Publisher, Subscriber},
+    watch::{self, Watch},
+};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use heapless::{String, Vec};
+use mqttrs::{Connect, ConnectReturnCode, Packet, Pid, Protocol, Publish, Subscribe};
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::{
+    effects::MAX_STREAMS,
+    event_log,
+    led_orchestrator::{GAMMA_RANGE, LightState, SPEED_RANGE, Zone},
+    network::MacAddress,
+    stream::{self, Color, DimmingCurve, StreamConfig},
 };
-use embassy_time::{Duration, Ticker};
-use mqttrs::{Connect, Packet, Pid, Protocol, Publish, Subscribe};
 
 mod error;
+#[cfg(test)]
+mod mock;
+mod sensor;
 mod socket;
+mod topic;
+mod v5;
 
-use socket::MqttSocket;
+pub use sensor::{Unit, announce_sensors, clear_sensors, publish_sensor};
+use socket::{MqttConnection, MqttSocket, RxBuffer};
+use topic::TopicError;
+pub use topic::{DEBUG_REQUESTS, publish_topic};
+pub use v5::{ConnectProperties, PublishProperties};
 
+pub const MAX_TOPIC_LENGTH: usize = 128;
+/// Large enough for the autodiscovery config, which grows with every entity
+pub const MAX_PAYLOAD_LENGTH: usize = 1024;
+/// Size of the socket, encode and packet buffers of the runner, every packet must fit into them
+const BUFFER_SIZE: usize = 2048;
+/// Fixed header, topic length and packet identifier of a PUBLISH, at most
+const PUBLISH_OVERHEAD: usize = 5 + 2 + 2;
+// Packets are encoded as a whole, so a payload that doesn't fit would only fail at runtime
+const _: () = core::assert!(
+    PUBLISH_OVERHEAD + 2 + v5::MAX_PROPERTIES_LENGTH + MAX_TOPIC_LENGTH + MAX_PAYLOAD_LENGTH
+        <= BUFFER_SIZE
+);
+/// Longest accepted payload of a received command. Larger ones are rejected as a whole.
+pub const MAX_COMMAND_LENGTH: usize = 64;
+/// Longest accepted stream config, anything that fits into the packet buffer. The config topics
+/// of the zones are the only commands with a JSON payload.
+const MAX_CONFIG_LENGTH: usize = BUFFER_SIZE - PUBLISH_OVERHEAD - Zone::First.config_topic().len();
+/// Longest client id every MQTT 3.1.1 broker has to accept
+pub const MAX_CLIENT_ID_LENGTH: usize = 23;
+/// Number of packet ids remembered per QoS level to detect redelivered publishes
+const PID_HISTORY_LENGTH: usize = 8;
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone)]
 pub enum RxPacket {
-    Connected,
+    /// `session_present` is set if the broker resumed a persistent session, in which case the
+    /// subscriptions of the last connection are still active
+    Connected { session_present: bool },
+    /// The connection was lost or couldn't be established, the runner keeps trying
+    Disconnected,
+    /// The runner stopped after `max_reconnect_attempts` failed attempts in a row. No further
+    /// events follow.
+    ConnectionGivenUp,
+    /// `retained` is set if the command was replayed by the broker from a retained message,
+    /// rather than being sent by a user just now
+    Command { command: Command, retained: bool },
 }
 
-pub struct SubscribeTopic {
-    pub qos: mqttrs::QoS,
-    pub topic_path: &'static str,
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+pub enum Command {
+    /// Request to enter low-power sleep, optionally waking up after the given duration
+    Sleep(Option<Duration>),
+    /// Freezes (`true`) or continues (`false`) the LED animation
+    Pause(bool),
+    /// Color temperature of the white channels in mireds
+    #[cfg_attr(not(feature = "tunable_white"), allow(unused))]
+    ColorTemperature(u16),
+    /// Shows a constant color on the RGB channels (`Some`), or returns to the animation (`None`)
+    Manual(Option<Color>),
+    /// Request to publish the active stream configuration
+    DumpStreams,
+    /// Request to publish the recorded events
+    DumpEvents,
+    /// Multiple of the normal speed the effect is played at
+    Speed(f32),
+    /// Gamma applied to the colors of the effect
+    Gamma(f32),
+    /// Curve mapping the colors to PWM duties
+    DimmingCurve(DimmingCurve),
+    /// Request to show the test pattern once
+    TestPattern,
+    /// Request to flash the light to locate the device
+    Identify,
+    /// Starts the LED animation over from its beginning
+    Restart,
+    /// Last on-state of the light of a zone, usually replayed from the retained state topic
+    LightState(Zone, LightState),
+    /// Turns the light of a zone on (`true`) or off
+    Light(Zone, bool),
+    /// Brightness of the light of a zone
+    Brightness(Zone, u8),
+    /// Removes the device from Home Assistant by clearing its retained topics
+    ClearDiscovery,
+    /// Drops the MQTT connection and connects again, for troubleshooting
+    Reconnect,
+    /// Request to publish the light state, effect and availability right away
+    GetState,
+    /// Streams shown on a zone instead of the built-in effect, `None` returns to it. Usually
+    /// replayed from the retained config topic.
+    Streams(Zone, Option<Vec<StreamConfig, MAX_STREAMS>>),
+}
+
+impl Command {
+    /// Whether the command triggers an action rather than setting some state. These are only
+    /// carried out when sent just now, a retained one would repeat on every connect.
+    pub fn is_one_shot(&self) -> bool {
+        matches!(
+            self,
+            Command::Sleep(_)
+                | Command::TestPattern
+                | Command::Identify
+                | Command::Restart
+                | Command::ClearDiscovery
+                | Command::Reconnect
+        )
+    }
 }
 
-#[allow(unused)]
+#[allow(unused, clippy::large_enum_variant)]
 pub enum TxPacket {
-    Subscribe(&'static [SubscribeTopic]),
+    /// Subscribes to the command topics with `ConnectionOptions::command_qos`
+    Subscribe(&'static [&'static str]),
     Publish {
         qospid: mqttrs::QosPid,
+        retain: bool,
         topic_name: &'static str,
         payload: &'static [u8],
     },
+    PublishOwned {
+        qospid: mqttrs::QosPid,
+        retain: bool,
+        topic_name: String<MAX_TOPIC_LENGTH>,
+        payload: Vec<u8, MAX_PAYLOAD_LENGTH>,
+    },
+    /// Like `PublishOwned`, with MQTT 5 properties. They're left out when connected with 3.1.1.
+    PublishWithProperties {
+        qospid: mqttrs::QosPid,
+        retain: bool,
+        topic_name: String<MAX_TOPIC_LENGTH>,
+        payload: Vec<u8, MAX_PAYLOAD_LENGTH>,
+        properties: PublishProperties<'static>,
+    },
     Pingreq,
+    /// Gracefully closes the connection. The runner won't reconnect afterwards.
+    Disconnect,
+    /// Aborts the connection and connects again, to exercise the reconnect path on demand. The
+    /// broker sees a lost connection, so it publishes the last will.
+    Reconnect,
+}
+
+/// Queued packets sent after every received one, at most
+const MAX_TX_PER_RX: usize = 2;
+
+/// Number of tasks that can wait for the connection at the same time
+const MAX_CONNECTION_WAITERS: usize = 4;
+
+/// Whether the runner is connected to the broker, i.e. the CONNACK has been processed
+static CONNECTED: Watch<CriticalSectionRawMutex, bool, MAX_CONNECTION_WAITERS> =
+    Watch::new_with(false);
+
+/// Resolves once connected to the broker, right away if already connected.
+///
+/// Unlike the `Connected` event, this doesn't depend on a subscriber having been registered
+/// before the connection was established.
+pub async fn wait_connected() {
+    let mut receiver = CONNECTED
+        .receiver()
+        .expect("Too many tasks waiting for the MQTT connection");
+
+    receiver.get_and(|connected| *connected).await;
+}
+
+pub type ConnectionReceiver =
+    watch::Receiver<'static, CriticalSectionRawMutex, bool, MAX_CONNECTION_WAITERS>;
+
+/// Observes whether the runner is connected to the broker, see `Receiver::changed`
+pub fn connection_receiver() -> ConnectionReceiver {
+    CONNECTED
+        .receiver()
+        .expect("Too many tasks waiting for the MQTT connection")
 }
 
 pub type MqttTxSender<'a> = Sender<'a, CriticalSectionRawMutex, TxPacket, 10>;
 pub type MqttTxReceiver<'a> = Receiver<'a, CriticalSectionRawMutex, TxPacket, 10>;
 
-pub type MqttRxPublisher<'a> = Publisher<'a, CriticalSectionRawMutex, RxPacket, 10, 10, 1>;
-pub type MqttRxSubscriber<'a> = Subscriber<'a, CriticalSectionRawMutex, RxPacket, 10, 10, 1>;
+/// Number of packets the priority queue holds
+pub const PRIORITY_CAPACITY: usize = 4;
+
+/// Queue that's always sent before the normal one, for the subscriptions and autodiscovery
+/// configs, so they aren't held up by routine traffic filling the normal queue
+pub type MqttPrioritySender<'a> = Sender<'a, CriticalSectionRawMutex, TxPacket, PRIORITY_CAPACITY>;
+pub type MqttPriorityReceiver<'a> =
+    Receiver<'a, CriticalSectionRawMutex, TxPacket, PRIORITY_CAPACITY>;
+
+/// Number of received packets buffered for the slowest subscriber
+pub const RX_CAPACITY: usize = 10;
+/// Number of tasks that can subscribe to received packets. Every subscriber sees every packet.
+/// One that falls more than `RX_CAPACITY` packets behind misses the oldest ones and gets a
+/// `WaitResult::Lagged`, so a slow task never holds up the others.
+pub const MAX_RX_SUBSCRIBERS: usize = 10;
+
+/// Total number of packets missed by lagging subscribers
+static LAGGED_PACKETS: AtomicU32 = AtomicU32::new(0);
+
+/// Counts packets a subscriber missed, to be called on `WaitResult::Lagged`. The total saturates
+/// instead of wrapping around.
+pub fn record_lag(missed: u64) {
+    let missed = missed.try_into().unwrap_or(u32::MAX);
+    _ = LAGGED_PACKETS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+        Some(total.saturating_add(missed))
+    });
+}
+
+/// Total number of packets missed by lagging subscribers since booting. If this keeps growing,
+/// `RX_CAPACITY` is too small or a subscriber too slow.
+pub fn lagged_packets() -> u32 {
+    LAGGED_PACKETS.load(Ordering::Relaxed)
+}
+
+pub type MqttRxChannel =
+    PubSubChannel<CriticalSectionRawMutex, RxPacket, RX_CAPACITY, MAX_RX_SUBSCRIBERS, 1>;
+pub type MqttRxPublisher<'a> =
+    Publisher<'a, CriticalSectionRawMutex, RxPacket, RX_CAPACITY, MAX_RX_SUBSCRIBERS, 1>;
+pub type MqttRxSubscriber<'a> =
+    Subscriber<'a, CriticalSectionRawMutex, RxPacket, RX_CAPACITY, MAX_RX_SUBSCRIBERS, 1>;
+
+/// Interval of the PINGREQs sent by `mqtt_heartbeat`
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Retained topic telling whether the device is reachable. The heartbeat publishes `online` to it,
+/// `offline` should be set as last will.
+#[derive(Clone, Copy)]
+pub struct Availability {
+    pub topic: &'static str,
+    /// `online` is published after connecting and then repeated at this interval, in case the
+    /// retained message got lost
+    pub interval: Duration,
+}
+
+/// Application level keepalive, which unlike PINGREQ also shows that publishing works end to end
+#[derive(Clone, Copy)]
+pub struct Keepalive {
+    pub topic: &'static str,
+    /// Published as is, `None` publishes the milliseconds since boot instead
+    pub payload: Option<&'static [u8]>,
+    pub interval: Duration,
+}
 
+/// Sends a PINGREQ every `HEARTBEAT_INTERVAL`, unless the runner drops it as redundant, keeps the
+/// availability topic up to date and publishes the application keepalive, if given
 #[embassy_executor::task]
-pub async fn mqtt_heartbeat(sender: MqttTxSender<'static>) -> ! {
-    let mut ticker = Ticker::every(Duration::from_secs(30));
+pub async fn mqtt_heartbeat(
+    sender: MqttTxSender<'static>,
+    availability: Option<Availability>,
+    keepalive: Option<Keepalive>,
+) -> ! {
+    let mut ticker = Ticker::every(HEARTBEAT_INTERVAL);
+    let mut connected = connection_receiver();
+    let mut schedule = HeartbeatSchedule::new(availability, keepalive);
 
     loop {
-        ticker.next().await;
-        sender.send(TxPacket::Pingreq).await;
+        let packet = match select4(
+            ticker.next(),
+            connected.changed(),
+            Timer::at(schedule.next_availability),
+            Timer::at(schedule.next_keepalive),
+        )
+        .await
+        {
+            Either4::First(()) => Some(TxPacket::Pingreq),
+            Either4::Second(connected) => {
+                schedule.connection_changed(connected, Instant::now());
+                None
+            }
+            // Sent at most once per interval, so this can't flood the queue
+            Either4::Third(()) => schedule.availability(Instant::now()),
+            Either4::Fourth(()) => schedule.keepalive(Instant::now()),
+        };
+
+        if let Some(packet) = packet {
+            sender.send(packet).await;
+        }
+    }
+}
+
+/// When `mqtt_heartbeat` publishes the availability and the keepalive next. Both are only sent
+/// while connected.
+struct HeartbeatSchedule {
+    availability: Option<Availability>,
+    keepalive: Option<Keepalive>,
+    next_availability: Instant,
+    next_keepalive: Instant,
+}
+
+impl HeartbeatSchedule {
+    fn new(availability: Option<Availability>, keepalive: Option<Keepalive>) -> Self {
+        Self {
+            availability,
+            keepalive,
+            next_availability: Instant::MAX,
+            next_keepalive: Instant::MAX,
+        }
+    }
+
+    /// Publishes both right after connecting, and stops them while disconnected
+    fn connection_changed(&mut self, connected: bool, now: Instant) {
+        let next = if connected { now } else { Instant::MAX };
+        self.next_availability = next;
+        self.next_keepalive = next;
+    }
+
+    /// The availability to publish once `next_availability` is reached
+    fn availability(&mut self, now: Instant) -> Option<TxPacket> {
+        let Some(availability) = self.availability else {
+            self.next_availability = Instant::MAX;
+            return None;
+        };
+
+        self.next_availability = now + availability.interval;
+        Some(TxPacket::Publish {
+            qospid: mqttrs::QosPid::AtMostOnce,
+            retain: true,
+            topic_name: availability.topic,
+            payload: b"online",
+        })
+    }
+
+    /// The keepalive to publish once `next_keepalive` is reached
+    fn keepalive(&mut self, now: Instant) -> Option<TxPacket> {
+        let Some(keepalive) = self.keepalive else {
+            self.next_keepalive = Instant::MAX;
+            return None;
+        };
+
+        self.next_keepalive = now + keepalive.interval;
+        Some(match keepalive.payload {
+            Some(payload) => TxPacket::Publish {
+                qospid: mqttrs::QosPid::AtMostOnce,
+                retain: false,
+                topic_name: keepalive.topic,
+                payload,
+            },
+            None => {
+                let mut uptime = String::<20>::new();
+                _ = core::write!(uptime, "{}", now.as_millis());
+                TxPacket::PublishOwned {
+                    qospid: mqttrs::QosPid::AtMostOnce,
+                    retain: false,
+                    // Only too long topics don't fit, which would be a programming error
+                    topic_name: unwrap!(String::from_str(keepalive.topic)),
+                    payload: unwrap!(Vec::from_slice(uptime.as_bytes())),
+                }
+            }
+        })
     }
 }
 
@@ -57,32 +379,204 @@ pub async fn mqtt_heartbeat(sender: MqttTxSender<'static>) -> ! {
 pub async fn mqtt_task(
     runner: MqttRunner<'static>,
     receiver: MqttTxReceiver<'static>,
+    priority: MqttPriorityReceiver<'static>,
     sender: MqttRxPublisher<'static>,
 ) -> ! {
-    runner.run(receiver, sender).await.unwrap();
+    if let Err(err) = runner.run(receiver, priority, sender).await {
+        error!("MQTT runner stopped: {}", err);
+    }
 
-    unreachable!("MQTT runner should never exit")
+    // The runner only returns after a requested disconnect or giving up, stay idle until the
+    // device resets
+    loop {
+        Timer::at(Instant::MAX).await
+    }
 }
 
 pub struct MqttRunner<'a> {
     stack: Stack<'a>,
+    client_id: String<MAX_CLIENT_ID_LENGTH>,
+    rx_buffer: [u8; BUFFER_SIZE],
+    tx_buffer: [u8; BUFFER_SIZE],
+    encode_buffer: [u8; BUFFER_SIZE],
+    session: Session<'a>,
+}
+
+/// Everything the runner keeps across connections, apart from the buffers the TCP socket borrows
+struct Session<'a> {
     options: ConnectionOptions<'a>,
-    rx_buffer: [u8; 2048],
-    tx_buffer: [u8; 2048],
+    packet_buffer: RxBuffer<BUFFER_SIZE>,
+    inbound: InboundPids,
+    /// Connection attempts that failed since the last successful one
+    failed_attempts: u32,
+    /// Used instead of resolving the host name, see `ConnectionOptions::pin_address`
+    pinned_address: Option<IpAddress>,
+}
+
+/// Ids of received publishes, so redeliveries by the broker aren't passed on twice
+struct InboundPids {
+    /// QoS 1 publishes that have been acknowledged recently
+    acknowledged: PidHistory<PID_HISTORY_LENGTH>,
+    /// QoS 2 publishes that haven't been released yet
+    unreleased: PidHistory<PID_HISTORY_LENGTH>,
+}
+
+impl InboundPids {
+    fn clear(&mut self) {
+        self.acknowledged.clear();
+        self.unreleased.clear();
+    }
+}
+
+/// Remembers the last `N` inserted ids, forgetting the oldest one when full
+struct PidHistory<const N: usize>(Vec<Pid, N>);
+
+impl<const N: usize> PidHistory<N> {
+    fn contains(&self, pid: Pid) -> bool {
+        self.0.contains(&pid)
+    }
+
+    fn insert(&mut self, pid: Pid) {
+        if self.contains(pid) {
+            return;
+        }
+
+        if self.0.is_full() {
+            self.0.remove(0);
+        }
+        // Can't fail, room has been made above
+        self.0.push(pid).unwrap();
+    }
+
+    fn remove(&mut self, pid: Pid) {
+        self.0.retain(|known| *known != pid);
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
 }
 
 pub struct ConnectionOptions<'a> {
     pub address: ServerAddress<'a>,
     pub client_id: &'a str,
+    /// Appends the last three bytes of this address to `client_id`, e.g. `picow-0a0b0c`, so devices
+    /// sharing a configuration don't disconnect each other from the broker
+    pub client_id_suffix: Option<MacAddress>,
     pub credentials: Option<Credentials<'a>>,
+    /// Time without any received data after which the TCP connection is considered dead
+    pub tcp_timeout: Duration,
+    /// Interval of TCP keep-alive packets, must be shorter than `tcp_timeout`
+    pub tcp_keep_alive: Duration,
+    /// Time without any packet from the broker after which the connection is considered dead.
+    /// Must be longer than `HEARTBEAT_INTERVAL`, since the PINGRESPs are all the broker sends on
+    /// an idle connection. Unlike `tcp_timeout`, this also catches a broker that still
+    /// acknowledges TCP segments but stopped responding.
+    pub read_timeout: Duration,
+    /// Keep alive announced in the CONNECT, in whole seconds up to `u16::MAX`. The broker drops
+    /// the connection if it gets no packet from the device for one and a half times as long.
+    /// Must be longer than `HEARTBEAT_INTERVAL`, which sends the PINGREQs keeping it alive.
+    pub keep_alive: Duration,
+    /// Starts a new session on every connection. Otherwise the broker keeps subscriptions and
+    /// undelivered messages while the device is disconnected. Retained messages are only
+    /// replayed when subscribing, so a resumed session doesn't get them again.
+    pub clean_session: bool,
+    /// QoS all command topics are subscribed with. Raising it makes the broker redeliver commands
+    /// that got lost, e.g. so an "off" isn't missed. Received publishes are acknowledged
+    /// accordingly.
+    pub command_qos: mqttrs::QoS,
+    /// Number of failed connection attempts in a row after which the runner gives up, `None` to
+    /// keep trying forever
+    pub max_reconnect_attempts: Option<u32>,
+    /// Drops the PINGREQs of `mqtt_heartbeat` while other packets keep the connection alive, as
+    /// the spec intends. The sent packets have to satisfy the `keep_alive`, and since the read
+    /// timeout relies on the broker's responses, it needs received ones too, see
+    /// `ping_redundant`.
+    pub ping_when_idle_only: bool,
+    /// Keeps connecting to the address the host name resolved to for the first successful
+    /// connection, so a persistent session isn't resumed on another broker behind the same name
+    pub pin_address: bool,
+    /// Message the broker publishes when the connection is lost without a DISCONNECT
+    pub last_will: Option<LastWill<'a>>,
+    /// Connects with MQTT 5 instead of 3.1.1, sending these properties with the CONNECT. This
+    /// lets publishes carry properties like a content type, see `TxPacket::PublishWithProperties`.
+    pub mqtt5: Option<ConnectProperties<'a>>,
+}
+
+impl ConnectionOptions<'_> {
+    /// Panics if the timeouts contradict each other, see their fields. They're constants of the
+    /// firmware, so this only fails for a broken build.
+    fn assert_consistent(&self) {
+        core::assert!(
+            self.tcp_keep_alive < self.tcp_timeout,
+            "TCP keep-alive must be shorter than the TCP timeout"
+        );
+        core::assert!(
+            self.read_timeout > HEARTBEAT_INTERVAL,
+            "Read timeout must be longer than the heartbeat interval"
+        );
+        core::assert!(
+            self.keep_alive > HEARTBEAT_INTERVAL,
+            "Keep alive must be longer than the heartbeat interval"
+        );
+    }
+}
+
+pub struct LastWill<'a> {
+    pub topic: &'a str,
+    pub message: &'a [u8],
+    pub qos: mqttrs::QoS,
+    /// Keeps the will on the broker, so clients that subscribe later still see it. Usually set for
+    /// availability topics, so the device shows up as offline until it publishes again.
+    pub retain: bool,
 }
 
-#[allow(unused)]
+#[derive(Clone, Copy)]
 pub enum ServerAddress<'a> {
     Ip(IpAddress),
+    /// Resolved through DNS on every connection attempt, unless it's an IP literal like
+    /// `192.168.1.10`, see `literal_address`
     HostName(&'a str),
 }
 
+impl<'a> ServerAddress<'a> {
+    /// An IPv4 literal as `Ip`, anything else as a host name
+    pub fn parse(address: &'a str) -> Self {
+        match Ipv4Address::from_str(address) {
+            Ok(ip) => ServerAddress::Ip(IpAddress::Ipv4(ip)),
+            Err(_) => ServerAddress::HostName(address),
+        }
+    }
+}
+
+/// The address of an IP literal, `None` for a host name that has to be resolved through DNS.
+/// IPv6 isn't enabled in the network stack, so IPv6 literals fail without a query.
+fn literal_address(name: &str) -> Option<Result<IpAddress>> {
+    if let Ok(ip) = Ipv4Address::from_str(name) {
+        return Some(Ok(IpAddress::Ipv4(ip)));
+    }
+    if core::net::Ipv6Addr::from_str(name).is_ok() {
+        warn!("IPv6 broker addresses aren't supported");
+        return Some(Err(MqttError::DnsError));
+    }
+
+    None
+}
+
+/// Resolves `address` with `dns_query`, which is only asked for host names
+async fn resolve(
+    address: ServerAddress<'_>,
+    dns_query: impl AsyncFnOnce(&str) -> Result<IpAddress>,
+) -> Result<IpAddress> {
+    match address {
+        ServerAddress::Ip(ip) => Ok(ip),
+        ServerAddress::HostName(name) => match literal_address(name) {
+            Some(literal) => literal,
+            None => dns_query(name).await,
+        },
+    }
+}
+
 pub struct Credentials<'a> {
     pub username: &'a str,
     pub password: &'a [u8],
@@ -90,50 +584,67 @@ pub struct Credentials<'a> {
 
 impl<'a: 'static> MqttRunner<'a> {
     pub fn new(stack: Stack<'a>, options: ConnectionOptions<'a>) -> Self {
+        options.assert_consistent();
+
         Self {
             stack,
-            options,
-            rx_buffer: [0; 2048],
-            tx_buffer: [0; 2048],
+            client_id: client_id(options.client_id, options.client_id_suffix),
+            rx_buffer: [0; BUFFER_SIZE],
+            tx_buffer: [0; BUFFER_SIZE],
+            encode_buffer: [0; BUFFER_SIZE],
+            session: Session::new(options),
         }
     }
 
     pub async fn run(
         mut self,
         receiver: MqttTxReceiver<'a>,
+        priority: MqttPriorityReceiver<'a>,
         publisher: MqttRxPublisher<'a>,
     ) -> Result<()> {
-        let address = MqttRunner::resolve_server_address(self.options.address, self.stack).await?;
+        loop {
+            let result = self.run_session(&receiver, &priority, &publisher).await;
+            CONNECTED.sender().send(false);
+
+            // Only a requested disconnect ends the runner, every error is retried. Even a broker
+            // that keeps sending garbage may be fixed in the meantime, and
+            // `max_reconnect_attempts` still limits the attempts.
+            let Err(err) = result else {
+                return Ok(());
+            };
+
+            self.session.fail(&err, &publisher)?;
+            warn!("MQTT connection failed ({}), reconnecting", err);
+            Timer::after_secs(1).await;
+        }
+    }
+
+    async fn run_session(
+        &mut self,
+        receiver: &MqttTxReceiver<'a>,
+        priority: &MqttPriorityReceiver<'a>,
+        publisher: &MqttRxPublisher<'a>,
+    ) -> Result<()> {
+        let address = match self.session.pinned_address {
+            Some(address) => address,
+            None => {
+                MqttRunner::resolve_server_address(self.session.options.address, self.stack).await?
+            }
+        };
         let mut socket = MqttRunner::connect(
             address,
             self.stack,
             &mut self.rx_buffer,
             &mut self.tx_buffer,
-            self.options.client_id,
-            self.options
-                .credentials
-                .as_ref()
-                .map(|credentials| credentials.username),
-            self.options
-                .credentials
-                .as_ref()
-                .map(|credentials| credentials.password),
+            &mut self.encode_buffer,
+            &self.client_id,
+            &self.session.options,
         )
         .await?;
 
-        let mut buf = [0; 2048];
-
-        loop {
-            let result = select(socket.read_packet(&mut buf), receiver.receive()).await;
-
-            match result {
-                Either::First(Ok(Some(packet))) => {
-                    MqttRunner::handle_receive(packet, &publisher).await?
-                }
-                Either::Second(packet) => MqttRunner::handle_transmit(&mut socket, packet).await?,
-                _ => {}
-            }
-        }
+        self.session
+            .serve(&mut socket, address, receiver, priority, publisher)
+            .await
     }
 
     async fn connect<'b, const R: usize, const T: usize>(
@@ -141,58 +652,176 @@ impl<'a: 'static> MqttRunner<'a> {
         stack: Stack<'b>,
         rx_buffer: &'b mut [u8; R],
         tx_buffer: &'b mut [u8; T],
+        encode_buffer: &'b mut [u8],
         client_id: &str,
-        username: Option<&str>,
-        password: Option<&[u8]>,
-    ) -> Result<TcpSocket<'b>> {
+        options: &ConnectionOptions<'_>,
+    ) -> Result<MqttConnection<'b>> {
         let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
-        socket.set_timeout(Some(Duration::from_secs(60)));
-        socket.set_keep_alive(Some(Duration::from_secs(30)));
-        socket.connect(IpEndpoint::new(address, 1883)).await?;
+        socket.set_timeout(Some(options.tcp_timeout));
+        socket.set_keep_alive(Some(options.tcp_keep_alive));
+        let endpoint = IpEndpoint::new(address, 1883);
+        socket.connect(endpoint).await?;
+        // The address may change between attempts if the host name resolves to several
+        let endpoint = verify_endpoint(endpoint, socket.remote_endpoint())?;
+        info!("TCP connection to {} established", endpoint);
+
+        let mut socket = MqttConnection::new(socket, encode_buffer, options.mqtt5.is_some());
+        MqttRunner::send_connect(&mut socket, client_id, options).await?;
+
+        Ok(socket)
+    }
+
+    /// Sends the CONNECT opening a session with `options`
+    async fn send_connect(
+        socket: &mut impl MqttSocket,
+        client_id: &str,
+        options: &ConnectionOptions<'_>,
+    ) -> Result<()> {
+        let properties = match &options.mqtt5 {
+            Some(properties) => properties.encode()?,
+            None => v5::Properties::new(),
+        };
 
         let connect = Connect {
+            // Turned into MQTT 5 while encoding, if enabled
             protocol: Protocol::MQTT311,
-            keep_alive: 60,
-            clean_session: true,
+            keep_alive: options.keep_alive.as_secs().min(u16::MAX as u64) as u16,
+            clean_session: options.clean_session,
             client_id,
-            last_will: None,
-            username,
-            password,
+            last_will: options.last_will.as_ref().map(|will| mqttrs::LastWill {
+                topic: will.topic,
+                message: will.message,
+                qos: will.qos,
+                retain: will.retain,
+            }),
+            username: options
+                .credentials
+                .as_ref()
+                .map(|credentials| credentials.username),
+            password: options
+                .credentials
+                .as_ref()
+                .map(|credentials| credentials.password),
         }
         .into();
 
-        socket.send_packet(&connect).await?;
+        socket.send_packet_with(&connect, &properties).await
+    }
 
-        Ok(socket)
+    /// Waits for the broker to accept the connection, returning whether it resumed a previous
+    /// session. Any other packet than CONNACK is a protocol violation, since nothing else may be
+    /// sent before it.
+    async fn await_connack<const N: usize>(
+        socket: &mut impl MqttSocket,
+        buffer: &mut RxBuffer<N>,
+    ) -> Result<bool> {
+        // Garbage instead of a CONNACK is treated like a failed attempt, so it's retried
+        let packet = socket.read_packet(buffer).await.map_err(|err| match err {
+            MqttError::DecodeError => MqttError::ConnectError,
+            err => err,
+        })?;
+
+        match packet {
+            Some(Packet::Connack(connack)) if connack.code == ConnectReturnCode::Accepted => {
+                Ok(connack.session_present)
+            }
+            Some(Packet::Connack(_)) => {
+                warn!("MQTT broker refused the connection");
+                event_log::record("MQTT broker refused the connection");
+                Err(MqttError::ConnectError)
+            }
+            Some(_) => {
+                warn!("Expected CONNACK as first packet from the MQTT broker");
+                Err(MqttError::ConnectError)
+            }
+            None => Err(MqttError::ConnectionReset),
+        }
     }
 
     async fn resolve_server_address(
         address: ServerAddress<'_>,
         stack: Stack<'a>,
     ) -> Result<IpAddress> {
-        match address {
-            ServerAddress::Ip(ip) => Ok(ip),
-            ServerAddress::HostName(name) => {
-                let server_address = stack
-                    .dns_query(name, embassy_net::dns::DnsQueryType::A)
-                    .await?;
+        resolve(address, async |name: &str| {
+            let server_address = stack
+                .dns_query(name, embassy_net::dns::DnsQueryType::A)
+                .await?;
 
-                Ok(*server_address.first().ok_or(MqttError::DnsError)?)
-            }
-        }
+            Ok(*server_address.first().ok_or(MqttError::DnsError)?)
+        })
+        .await
     }
 
-    async fn handle_receive(packet: Packet<'_>, publisher: &MqttRxPublisher<'_>) -> Result<()> {
+    /// Events are published without waiting for subscribers. If one of them falls behind, the
+    /// oldest event is dropped (and reported to it as lagged) instead of stalling the read loop,
+    /// which would eventually let the connection time out.
+    ///
+    /// Publishes with QoS 1 and 2 are acknowledged, otherwise the broker keeps redelivering them.
+    async fn handle_receive(
+        socket: &mut impl MqttSocket,
+        packet: Packet<'_>,
+        publisher: &MqttRxPublisher<'_>,
+        inbound: &mut InboundPids,
+    ) -> Result<()> {
         match packet {
-            Packet::Publish(Publish {
-                payload,
-                topic_name,
-                ..
-            }) => match (topic_name, core::str::from_utf8(payload)?) {
-                _ => {}
-            },
-            Packet::Connack(_) => {
-                publisher.publish(RxPacket::Connected).await;
+            Packet::Publish(publish) => {
+                let topic_name = publish.topic_name;
+                let qospid = publish.qospid;
+
+                // A QoS 2 publish is passed on when it first arrives, redeliveries before its
+                // release are only acknowledged again. QoS 1 redeliveries are marked with `dup`,
+                // they are dropped if the original has been acknowledged just before.
+                let duplicate = match qospid {
+                    mqttrs::QosPid::AtMostOnce => false,
+                    mqttrs::QosPid::AtLeastOnce(pid) => {
+                        publish.dup && inbound.acknowledged.contains(pid)
+                    }
+                    mqttrs::QosPid::ExactlyOnce(pid) => inbound.unreleased.contains(pid),
+                };
+
+                // A malformed command must not tear down the connection, so it's only logged
+                if duplicate {
+                    debug!("Ignoring redelivered publish on {}", topic_name);
+                } else if let Err(err) = MqttRunner::handle_command(publish, publisher) {
+                    warn!("Dropping command on {}: {}", topic_name, err);
+                }
+
+                match qospid {
+                    mqttrs::QosPid::AtMostOnce => {}
+                    mqttrs::QosPid::AtLeastOnce(pid) => {
+                        inbound.acknowledged.insert(pid);
+                        socket.send_packet(&Packet::Puback(pid)).await?
+                    }
+                    mqttrs::QosPid::ExactlyOnce(pid) => {
+                        inbound.unreleased.insert(pid);
+                        socket.send_packet(&Packet::Pubrec(pid)).await?
+                    }
+                }
+            }
+            Packet::Pubrel(pid) => {
+                inbound.unreleased.remove(pid);
+                socket.send_packet(&Packet::Pubcomp(pid)).await?
+            }
+            // The broker may grant a lower QoS than requested, which is fine, but commands on
+            // rejected topics would never arrive
+            Packet::Suback(suback)
+                if suback
+                    .return_codes
+                    .contains(&mqttrs::SubscribeReturnCodes::Failure) =>
+            {
+                warn!("Broker rejected a subscription");
+            }
+            // Only clients send these, and the broker acknowledges the connection once. Going
+            // on after them could announce the connection twice, so it's started over instead.
+            Packet::Connack(_)
+            | Packet::Connect(_)
+            | Packet::Subscribe(_)
+            | Packet::Unsubscribe(_)
+            | Packet::Pingreq
+            | Packet::Disconnect => {
+                warn!("Unexpected packet from the MQTT broker, resetting the connection");
+                event_log::record("MQTT protocol violation");
+                return Err(MqttError::ProtocolViolation);
             }
             _ => {}
         }
@@ -200,15 +829,157 @@ impl<'a: 'static> MqttRunner<'a> {
         Ok(())
     }
 
-    async fn handle_transmit(socket: &mut TcpSocket<'_>, packet: TxPacket) -> Result<()> {
+    fn handle_command(publish: Publish<'_>, publisher: &MqttRxPublisher<'_>) -> Result<()> {
+        // Would otherwise just not match any command, hiding the broken broker traffic
+        if publish.topic_name.is_empty() {
+            return Err(TopicError::Empty.into());
+        }
+        let max_length = if Zone::of_topic(publish.topic_name, Zone::config_topic).is_some() {
+            MAX_CONFIG_LENGTH
+        } else {
+            MAX_COMMAND_LENGTH
+        };
+        if publish.payload.len() > max_length {
+            return Err(MqttError::PayloadTooLarge);
+        }
+
+        let Some(command) = MqttRunner::parse_command(publish.topic_name, publish.payload)? else {
+            return Ok(());
+        };
+
+        publisher.publish_immediate(RxPacket::Command {
+            command,
+            retained: publish.retain,
+        });
+
+        Ok(())
+    }
+
+    /// `None` if the topic isn't a command. Payloads of topics ending in `/raw/set` are binary,
+    /// all others are UTF-8 text.
+    fn parse_command(topic: &str, payload: &[u8]) -> Result<Option<Command>> {
+        if topic == "picow/manual/raw/set" {
+            // <r><g><b> as one byte each, or nothing to return to the animation
+            return match *payload {
+                [] => Ok(Some(Command::Manual(None))),
+                [r, g, b] => Ok(Some(Command::Manual(Some(Color(r, g, b))))),
+                _ => Err(MqttError::DecodeError),
+            };
+        }
+
+        if let Some(command) = MqttRunner::parse_zone_command(topic, payload)? {
+            return Ok(Some(command));
+        }
+
+        let command = match (topic, core::str::from_utf8(payload)?) {
+            ("picow/sleep/set", "") => Command::Sleep(None),
+            ("picow/sleep/set", seconds) => {
+                Command::Sleep(Some(Duration::from_secs(seconds.trim().parse()?)))
+            }
+            ("picow/pause/set", "ON") => Command::Pause(true),
+            ("picow/pause/set", "OFF") => Command::Pause(false),
+            ("picow/light/color_temp/set", mireds) => {
+                Command::ColorTemperature(mireds.trim().parse()?)
+            }
+            ("picow/manual/set", "") => Command::Manual(None),
+            ("picow/manual/set", color) => Command::Manual(Some(color.parse()?)),
+            (topic, _) if topic::matches(DEBUG_REQUESTS, topic) => match topic::level(topic, 2) {
+                Some("streams") => Command::DumpStreams,
+                Some("events") => Command::DumpEvents,
+                _ => return Ok(None),
+            },
+            ("picow/restart/set", _) => Command::Restart,
+            ("picow/discovery/clear", _) => Command::ClearDiscovery,
+            ("picow/get", _) => Command::GetState,
+            ("picow/reconnect", _) => Command::Reconnect,
+            ("picow/test_pattern/set", _) => Command::TestPattern,
+            ("picow/identify/set", _) => Command::Identify,
+            ("picow/speed/set", speed) => {
+                let speed = speed.trim().parse()?;
+                if !SPEED_RANGE.contains(&speed) {
+                    return Err(MqttError::DecodeError);
+                }
+
+                Command::Speed(speed)
+            }
+            ("picow/gamma/set", gamma) => {
+                let gamma = gamma.trim().parse()?;
+                if !GAMMA_RANGE.contains(&gamma) {
+                    return Err(MqttError::DecodeError);
+                }
+
+                Command::Gamma(gamma)
+            }
+            ("picow/dimming_curve/set", curve) => Command::DimmingCurve(curve.trim().parse()?),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(command))
+    }
+
+    /// Commands on the topics of a zone, `None` if the topic belongs to no zone
+    fn parse_zone_command(topic: &str, payload: &[u8]) -> Result<Option<Command>> {
+        if let Some(zone) = Zone::of_topic(topic, Zone::config_topic) {
+            // Clearing the retained config returns to the built-in effect
+            let streams = match core::str::from_utf8(payload)?.trim() {
+                "" => None,
+                json => Some(stream::streams_from_json(json)?),
+            };
+            return Ok(Some(Command::Streams(zone, streams)));
+        }
+
+        let payload = core::str::from_utf8(payload)?;
+        let command = if let Some(zone) = Zone::of_topic(topic, Zone::light_topic) {
+            match payload.trim() {
+                "ON" => Command::Light(zone, true),
+                "OFF" => Command::Light(zone, false),
+                _ => return Err(MqttError::DecodeError),
+            }
+        } else if let Some(zone) = Zone::of_topic(topic, Zone::brightness_topic) {
+            Command::Brightness(zone, payload.trim().parse()?)
+        } else if let Some(zone) = Zone::of_topic(topic, Zone::light_state_topic) {
+            Command::LightState(zone, payload.parse()?)
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(command))
+    }
+
+    /// Sends a packet from the queue, `Break` if it closed the connection
+    async fn transmit_queued(
+        socket: &mut impl MqttSocket,
+        packet: TxPacket,
+        options: &ConnectionOptions<'_>,
+    ) -> Result<ControlFlow<()>> {
+        let disconnect = matches!(packet, TxPacket::Disconnect);
+        match MqttRunner::handle_transmit(socket, packet, options).await {
+            // Nothing was written, so the connection is still usable without the packet
+            Err(MqttError::PayloadTooLarge) => {}
+            result => result?,
+        }
+
+        if disconnect {
+            info!("Disconnected from MQTT broker");
+            return Ok(ControlFlow::Break(()));
+        }
+
+        Ok(ControlFlow::Continue(()))
+    }
+
+    async fn handle_transmit(
+        socket: &mut impl MqttSocket,
+        packet: TxPacket,
+        options: &ConnectionOptions<'_>,
+    ) -> Result<()> {
         match packet {
             TxPacket::Subscribe(topics) => {
                 let topics = topics
                     .iter()
                     .map(|topic| {
                         Ok(mqttrs::SubscribeTopic {
-                            qos: topic.qos,
-                            topic_path: heapless_07::String::from_str(topic.topic_path)?,
+                            qos: options.command_qos,
+                            topic_path: heapless_07::String::from_str(topic)?,
                         })
                     })
                     .collect::<Result<heapless_07::Vec<_, 5>>>()?;
@@ -221,25 +992,1564 @@ impl<'a: 'static> MqttRunner<'a> {
             }
             TxPacket::Publish {
                 qospid,
+                retain,
+                topic_name,
+                payload,
+            } => MqttRunner::publish(socket, qospid, retain, topic_name, payload, &[]).await?,
+            TxPacket::PublishOwned {
+                qospid,
+                retain,
+                topic_name,
+                payload,
+            } => MqttRunner::publish(socket, qospid, retain, &topic_name, &payload, &[]).await?,
+            TxPacket::PublishWithProperties {
+                qospid,
+                retain,
                 topic_name,
                 payload,
+                properties,
             } => {
-                socket
-                    .send_packet(
-                        &Publish {
-                            dup: false,
-                            retain: false,
-                            qospid,
-                            topic_name,
-                            payload,
-                        }
-                        .into(),
-                    )
+                let properties = properties.encode()?;
+                MqttRunner::publish(socket, qospid, retain, &topic_name, &payload, &properties)
                     .await?
             }
             TxPacket::Pingreq => socket.send_packet(&mqttrs::Packet::Pingreq).await?,
+            TxPacket::Disconnect => {
+                socket.send_packet(&mqttrs::Packet::Disconnect).await?;
+                socket.close();
+                socket.flush().await?;
+            }
+            TxPacket::Reconnect => {
+                info!("Reconnect requested, aborting the MQTT connection");
+                event_log::record("MQTT reconnect requested");
+                socket.abort();
+                socket.flush().await?;
+                return Err(MqttError::ReconnectRequested);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn publish(
+        socket: &mut impl MqttSocket,
+        qospid: mqttrs::QosPid,
+        retain: bool,
+        topic_name: &str,
+        payload: &[u8],
+        properties: &[u8],
+    ) -> Result<()> {
+        socket
+            .send_packet_with(
+                &Publish {
+                    dup: false,
+                    retain,
+                    qospid,
+                    topic_name,
+                    payload,
+                }
+                .into(),
+                properties,
+            )
+            .await
+    }
+}
+
+impl<'a> Session<'a> {
+    fn new(options: ConnectionOptions<'a>) -> Self {
+        Self {
+            options,
+            packet_buffer: RxBuffer::new(),
+            inbound: InboundPids {
+                acknowledged: PidHistory(Vec::new()),
+                unreleased: PidHistory(Vec::new()),
+            },
+            failed_attempts: 0,
+            pinned_address: None,
+        }
+    }
+
+    /// Counts the connection that ended with `err`, and tells the application whether it's
+    /// retried or the runner gives up
+    fn fail(&mut self, err: &MqttError, publisher: &MqttRxPublisher<'_>) -> Result<()> {
+        // A requested reconnect isn't a failure, so it can't make the runner give up
+        if !matches!(err, MqttError::ReconnectRequested) {
+            self.failed_attempts += 1;
+        }
+        if self
+            .options
+            .max_reconnect_attempts
+            .is_some_and(|max| self.failed_attempts >= max)
+        {
+            event_log::record("MQTT connection given up");
+            publisher.publish_immediate(RxPacket::ConnectionGivenUp);
+            return Err(MqttError::ConnectionGivenUp);
         }
 
+        event_log::record("MQTT connection failed");
+        publisher.publish_immediate(RxPacket::Disconnected);
         Ok(())
     }
+
+    /// Runs a connection to `address` from the CONNACK on, until it's closed or fails
+    async fn serve(
+        &mut self,
+        socket: &mut impl MqttSocket,
+        address: IpAddress,
+        receiver: &MqttTxReceiver<'_>,
+        priority: &MqttPriorityReceiver<'_>,
+        publisher: &MqttRxPublisher<'_>,
+    ) -> Result<()> {
+        self.packet_buffer.clear();
+        let session_present = MqttRunner::await_connack(socket, &mut self.packet_buffer).await?;
+
+        self.failed_attempts = 0;
+        if self.options.pin_address {
+            self.pinned_address = Some(address);
+        }
+        // Without a persistent session, the broker doesn't redeliver publishes of the last one
+        if !session_present {
+            self.inbound.clear();
+        }
+
+        event_log::record("MQTT connected");
+        CONNECTED.sender().send(true);
+        publisher.publish_immediate(RxPacket::Connected { session_present });
+
+        let mut last_received = Instant::now();
+        let mut last_sent = Instant::now();
+        loop {
+            // Polled in this order, so the priority queue goes first
+            let result = select4(
+                socket.read_packet(&mut self.packet_buffer),
+                priority.receive(),
+                receiver.receive(),
+                Timer::at(last_received + self.options.read_timeout),
+            )
+            .await;
+
+            match result {
+                Either4::First(Ok(Some(packet))) => {
+                    last_received = Instant::now();
+                    MqttRunner::handle_receive(socket, packet, publisher, &mut self.inbound)
+                        .await?;
+
+                    // The read is polled first, so a flood of inbound packets would otherwise
+                    // starve the queue. The other way around can't happen, since a read that's
+                    // ready always wins.
+                    for _ in 0..MAX_TX_PER_RX {
+                        let Ok(packet) = priority.try_receive().or_else(|_| receiver.try_receive())
+                        else {
+                            break;
+                        };
+                        if matches!(packet, TxPacket::Pingreq)
+                            && ping_redundant(
+                                &self.options,
+                                Instant::now(),
+                                last_sent,
+                                last_received,
+                            )
+                        {
+                            trace!("Skipping PINGREQ on a busy connection");
+                            continue;
+                        }
+                        if MqttRunner::transmit_queued(socket, packet, &self.options)
+                            .await?
+                            .is_break()
+                        {
+                            return Ok(());
+                        }
+                        last_sent = Instant::now();
+                    }
+                }
+                Either4::First(Ok(None)) => {
+                    warn!("MQTT broker closed the connection");
+                    return Err(MqttError::ConnectionReset);
+                }
+                Either4::First(Err(err)) => {
+                    // The stream can't be resynchronized after garbage, so reconnect either way
+                    warn!("Failed to read from the MQTT broker ({})", err);
+                    return Err(err);
+                }
+                Either4::Second(packet) | Either4::Third(packet) => {
+                    if matches!(packet, TxPacket::Pingreq)
+                        && ping_redundant(&self.options, Instant::now(), last_sent, last_received)
+                    {
+                        trace!("Skipping PINGREQ on a busy connection");
+                        continue;
+                    }
+                    if MqttRunner::transmit_queued(socket, packet, &self.options)
+                        .await?
+                        .is_break()
+                    {
+                        return Ok(());
+                    }
+                    last_sent = Instant::now();
+                }
+                Either4::Fourth(()) => {
+                    warn!("No packet from the MQTT broker within the read timeout");
+                    socket.abort();
+                    return Err(MqttError::ReadTimeout);
+                }
+            }
+        }
+    }
+}
+
+/// Whether a PINGREQ can be skipped with `ping_when_idle_only` at `now`. The last sent packet has
+/// to be recent enough that the keep alive can't run out before the next heartbeat, which then
+/// pings if the connection went quiet. The same goes for the last received packet and the read
+/// timeout.
+fn ping_redundant(
+    options: &ConnectionOptions<'_>,
+    now: Instant,
+    last_sent: Instant,
+    last_received: Instant,
+) -> bool {
+    // Half the slack each, leaving the other half for the round trip of that ping
+    let window = |timeout: Duration| {
+        timeout
+            .checked_sub(HEARTBEAT_INTERVAL)
+            .unwrap_or(Duration::MIN)
+            / 2
+    };
+
+    options.ping_when_idle_only
+        && now.saturating_duration_since(last_sent) < window(options.keep_alive)
+        && now.saturating_duration_since(last_received) < window(options.read_timeout)
+}
+
+/// Checks that the socket is connected to `expected`. The session state belongs to the broker the
+/// address was resolved for, so a connection that ended up anywhere else isn't used.
+fn verify_endpoint(expected: IpEndpoint, remote: Option<IpEndpoint>) -> Result<IpEndpoint> {
+    match remote {
+        Some(remote) if remote == expected => Ok(remote),
+        Some(remote) => {
+            warn!("Connected to {} instead of {}", remote, expected);
+            Err(MqttError::UnexpectedEndpoint)
+        }
+        // Closed again right away
+        None => Err(MqttError::ConnectionReset),
+    }
+}
+
+/// Builds the id sent to the broker. The configured prefix is cut off where necessary to keep the
+/// whole id, including the suffix, within `MAX_CLIENT_ID_LENGTH`.
+fn client_id(prefix: &str, suffix: Option<MacAddress>) -> String<MAX_CLIENT_ID_LENGTH> {
+    let suffix_length = if suffix.is_some() { 7 } else { 0 };
+
+    let mut end = prefix.len().min(MAX_CLIENT_ID_LENGTH - suffix_length);
+    while !prefix.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut client_id = String::new();
+    // Both parts fit, since their lengths have been checked above
+    client_id.push_str(&prefix[..end]).unwrap();
+    if let Some(MacAddress([.., a, b, c])) = suffix {
+        core::write!(client_id, "-{:02x}{:02x}{:02x}", a, b, c).unwrap();
+    }
+
+    client_id
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::{
+        pin::Pin,
+        sync::{Mutex, MutexGuard},
+        vec::Vec,
+    };
+
+    use embassy_futures::block_on;
+    use embassy_net::{IpAddress, IpEndpoint};
+    use embassy_sync::{
+        blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, pubsub::WaitResult,
+    };
+    use embassy_time::{Duration, Instant};
+    use mqttrs::{Connack, ConnectReturnCode, Packet, Pid, Publish, QoS, QosPid};
+
+    use super::{
+        Availability, CONNECTED, Command, ConnectionOptions, HEARTBEAT_INTERVAL, HeartbeatSchedule,
+        Keepalive, LastWill, MAX_CLIENT_ID_LENGTH, MqttRunner, MqttRxChannel, PRIORITY_CAPACITY,
+        PidHistory, PublishProperties, RX_CAPACITY, RxPacket, ServerAddress, Session, TxPacket,
+        client_id,
+        error::{MqttError, Result},
+        lagged_packets,
+        mock::{MockSocket, Read, encode, receive},
+        ping_redundant, record_lag, resolve,
+        topic::TopicError,
+        verify_endpoint, wait_connected,
+    };
+    use crate::{
+        led_orchestrator::Zone,
+        network::MacAddress,
+        stream::{Color, DimmingCurve},
+    };
+
+    static SESSIONS: Mutex<()> = Mutex::new(());
+
+    fn options() -> ConnectionOptions<'static> {
+        ConnectionOptions {
+            address: ServerAddress::HostName("broker"),
+            client_id: "test",
+            client_id_suffix: None,
+            credentials: None,
+            tcp_timeout: Duration::from_secs(60),
+            tcp_keep_alive: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(3600),
+            keep_alive: Duration::from_secs(3600),
+            clean_session: true,
+            command_qos: QoS::AtMostOnce,
+            max_reconnect_attempts: None,
+            ping_when_idle_only: false,
+            pin_address: false,
+            last_will: None,
+            mqtt5: None,
+        }
+    }
+
+    #[test]
+    fn consistent_options_are_accepted() {
+        options().assert_consistent();
+    }
+
+    #[test]
+    #[should_panic(expected = "TCP keep-alive must be shorter")]
+    fn tcp_keep_alives_are_shorter_than_the_timeout() {
+        ConnectionOptions {
+            tcp_keep_alive: Duration::from_secs(60),
+            ..options()
+        }
+        .assert_consistent();
+    }
+
+    #[test]
+    #[should_panic(expected = "Read timeout must be longer")]
+    fn read_timeouts_are_longer_than_the_heartbeat() {
+        ConnectionOptions {
+            read_timeout: HEARTBEAT_INTERVAL,
+            ..options()
+        }
+        .assert_consistent();
+    }
+
+    #[test]
+    #[should_panic(expected = "Keep alive must be longer")]
+    fn keep_alives_are_longer_than_the_heartbeat() {
+        ConnectionOptions {
+            keep_alive: HEARTBEAT_INTERVAL,
+            ..options()
+        }
+        .assert_consistent();
+    }
+
+    fn connack() -> Read {
+        receive(&Packet::Connack(Connack {
+            session_present: false,
+            code: ConnectReturnCode::Accepted,
+        }))
+    }
+
+    /// Sessions publish the connection state globally, so they run one at a time
+    fn exclusive() -> MutexGuard<'static, ()> {
+        let guard = SESSIONS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        CONNECTED.sender().send(false);
+        guard
+    }
+
+    /// The queues of a session, as set up by `mqtt_task`
+    struct Queues {
+        transmit: Channel<CriticalSectionRawMutex, TxPacket, 10>,
+        priority: Channel<CriticalSectionRawMutex, TxPacket, PRIORITY_CAPACITY>,
+        received: MqttRxChannel,
+    }
+
+    impl Queues {
+        fn new() -> Self {
+            Self {
+                transmit: Channel::new(),
+                priority: Channel::new(),
+                received: MqttRxChannel::new(),
+            }
+        }
+
+        async fn serve(&self, session: &mut Session<'_>, socket: &mut MockSocket) -> Result<()> {
+            session
+                .serve(
+                    socket,
+                    IpAddress::v4(127, 0, 0, 1),
+                    &self.transmit.receiver(),
+                    &self.priority.receiver(),
+                    &self.received.publisher().unwrap(),
+                )
+                .await
+        }
+    }
+
+    /// Serves a session over `socket` with `queued` waiting to be sent, until it ends
+    fn serve(socket: &mut MockSocket, queued: Vec<TxPacket>) -> (Result<()>, Vec<RxPacket>) {
+        serve_with(options(), socket, queued)
+    }
+
+    /// Like `serve`, with the given options
+    fn serve_with(
+        options: ConnectionOptions<'static>,
+        socket: &mut MockSocket,
+        queued: Vec<TxPacket>,
+    ) -> (Result<()>, Vec<RxPacket>) {
+        let _exclusive = exclusive();
+        let queues = Queues::new();
+        let mut subscriber = queues.received.subscriber().unwrap();
+        for packet in queued {
+            queues.transmit.try_send(packet).ok().unwrap();
+        }
+
+        let result = block_on(queues.serve(&mut Session::new(options), socket));
+
+        let mut events = Vec::new();
+        while let Some(event) = subscriber.try_next_message_pure() {
+            events.push(event);
+        }
+        (result, events)
+    }
+
+    #[test]
+    fn availability_is_republished_while_connected() {
+        let availability = Availability {
+            topic: "picow/availability",
+            interval: Duration::from_secs(300),
+        };
+        let mut schedule = HeartbeatSchedule::new(Some(availability), None);
+        assert_eq!(schedule.next_availability, Instant::MAX);
+
+        let connected = Instant::from_secs(10);
+        schedule.connection_changed(true, connected);
+        assert_eq!(schedule.next_availability, connected);
+        for published in [connected, connected + availability.interval] {
+            let packet = schedule.availability(published);
+            assert!(matches!(
+                packet,
+                Some(TxPacket::Publish {
+                    retain: true,
+                    topic_name: "picow/availability",
+                    payload: b"online",
+                    ..
+                })
+            ));
+            assert_eq!(
+                schedule.next_availability,
+                published + availability.interval
+            );
+        }
+
+        schedule.connection_changed(false, Instant::from_secs(1000));
+        assert_eq!(schedule.next_availability, Instant::MAX);
+        // The keepalive isn't configured, so it's never due
+        schedule.connection_changed(true, Instant::from_secs(2000));
+        assert!(schedule.keepalive(Instant::from_secs(2000)).is_none());
+        assert_eq!(schedule.next_keepalive, Instant::MAX);
+    }
+
+    #[test]
+    fn keepalives_carry_the_configured_payload() {
+        let keepalive = Keepalive {
+            topic: "picow/keepalive",
+            payload: Some(b"alive"),
+            interval: Duration::from_secs(60),
+        };
+        let mut schedule = HeartbeatSchedule::new(None, Some(keepalive));
+        let now = Instant::from_secs(10);
+        schedule.connection_changed(true, now);
+
+        assert!(matches!(
+            schedule.keepalive(now),
+            Some(TxPacket::Publish {
+                retain: false,
+                topic_name: "picow/keepalive",
+                payload: b"alive",
+                ..
+            })
+        ));
+        assert_eq!(schedule.next_keepalive, now + keepalive.interval);
+
+        // Without a payload, the uptime is published
+        let mut schedule = HeartbeatSchedule::new(
+            None,
+            Some(Keepalive {
+                payload: None,
+                ..keepalive
+            }),
+        );
+        let Some(TxPacket::PublishOwned {
+            topic_name,
+            payload,
+            retain: false,
+            ..
+        }) = schedule.keepalive(Instant::from_millis(12345))
+        else {
+            panic!("Expected the uptime");
+        };
+        assert_eq!(topic_name, "picow/keepalive");
+        assert_eq!(payload, b"12345");
+    }
+
+    #[test]
+    fn runners_give_up_after_failed_attempts_in_a_row() {
+        let _exclusive = exclusive();
+        let queues = Queues::new();
+        let mut subscriber = queues.received.subscriber().unwrap();
+        let fail = |session: &mut Session<'_>, err| {
+            session.fail(&err, &queues.received.publisher().unwrap())
+        };
+        let mut session = Session::new(ConnectionOptions {
+            max_reconnect_attempts: Some(3),
+            ..options()
+        });
+
+        // Ends every session, either before or after the CONNACK
+        let attempt = |session: &mut Session<'_>, connected: bool| {
+            let reset = Read::Fail(MqttError::ConnectionReset);
+            let mut socket = match connected {
+                true => MockSocket::new([connack(), reset]),
+                false => MockSocket::new([reset]),
+            };
+            let err = block_on(queues.serve(session, &mut socket)).unwrap_err();
+            fail(session, err)
+        };
+
+        assert!(attempt(&mut session, false).is_ok());
+        assert!(attempt(&mut session, false).is_ok());
+        // Reconnects on request don't count
+        assert!(fail(&mut session, MqttError::ReconnectRequested).is_ok());
+        // The CONNACK starts the count over, the session failing later is the first failure
+        assert!(attempt(&mut session, true).is_ok());
+        assert!(attempt(&mut session, false).is_ok());
+        assert!(matches!(
+            attempt(&mut session, false),
+            Err(MqttError::ConnectionGivenUp)
+        ));
+
+        let mut events = Vec::new();
+        while let Some(event) = subscriber.try_next_message_pure() {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 7);
+        assert!(matches!(events[3], RxPacket::Connected { .. }));
+        assert!(matches!(events[5], RxPacket::Disconnected));
+        assert!(matches!(events[6], RxPacket::ConnectionGivenUp));
+    }
+
+    /// Brightness of a received brightness command, to tell packets apart
+    fn brightness(result: Option<WaitResult<RxPacket>>) -> Option<u8> {
+        match result {
+            Some(WaitResult::Message(RxPacket::Command {
+                command: Command::Brightness(_, brightness),
+                ..
+            })) => Some(brightness),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn slow_subscribers_dont_hold_up_the_others() {
+        let channel = MqttRxChannel::new();
+        let mut fast = channel.subscriber().unwrap();
+        let mut slow = channel.subscriber().unwrap();
+        let publisher = channel.publisher().unwrap();
+
+        let count = RX_CAPACITY as u8 + 3;
+        for value in 0..count {
+            publisher.publish_immediate(RxPacket::Command {
+                command: Command::Brightness(Zone::First, value),
+                retained: false,
+            });
+            assert_eq!(brightness(fast.try_next_message()), Some(value));
+        }
+
+        // The slow one misses the oldest packets, but gets the rest in order
+        assert!(matches!(
+            slow.try_next_message(),
+            Some(WaitResult::Lagged(3))
+        ));
+        for value in 3..count {
+            assert_eq!(brightness(slow.try_next_message()), Some(value));
+        }
+        assert!(slow.try_next_message().is_none());
+    }
+
+    #[test]
+    fn full_subscribers_dont_stall_reads() {
+        let _exclusive = exclusive();
+        let queues = Queues::new();
+        // Never drained while the session runs
+        let mut stalled = queues.received.subscriber().unwrap();
+
+        let count = RX_CAPACITY as u16 + 5;
+        let mut reads = std::vec![connack()];
+        reads.extend((1..=count).map(|n| pause(QosPid::AtLeastOnce(pid(n)), false)));
+        reads.push(Read::Eof);
+        let mut socket = MockSocket::new(reads);
+
+        let result = block_on(queues.serve(&mut Session::new(options()), &mut socket));
+
+        // Every publish was read and acknowledged up to the EOF
+        assert!(matches!(result, Err(MqttError::ConnectionReset)));
+        assert_eq!(socket.sent_packets().len(), count as usize);
+        // The CONNACK event and the oldest commands were dropped for the stalled subscriber
+        assert!(matches!(
+            stalled.try_next_message(),
+            Some(WaitResult::Lagged(6))
+        ));
+    }
+
+    #[test]
+    fn queued_packets_are_sent_between_continuous_reads() {
+        let mut socket = MockSocket::new([
+            connack(),
+            pause(QosPid::AtLeastOnce(pid(1)), false),
+            pause(QosPid::AtLeastOnce(pid(2)), false),
+            pause(QosPid::AtLeastOnce(pid(3)), false),
+            Read::Eof,
+        ]);
+        let state = || TxPacket::Publish {
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "picow/effect",
+            payload: b"rainbow",
+        };
+
+        let (result, _) = serve(&mut socket, std::vec![state(), state(), state()]);
+
+        assert!(matches!(result, Err(MqttError::ConnectionReset)));
+        // Every read is followed by up to `MAX_TX_PER_RX`, two, queued packets
+        let sent: Vec<_> = socket
+            .sent_packets()
+            .iter()
+            .map(|packet| match packet {
+                Packet::Puback(pid) => std::format!("ack {}", pid.get()),
+                Packet::Publish(_) => "state".into(),
+                _ => panic!("Unexpected packet"),
+            })
+            .collect();
+        assert_eq!(sent, ["ack 1", "state", "state", "ack 2", "state", "ack 3"]);
+    }
+
+    #[test]
+    fn reads_time_out_while_sending() {
+        // The broker goes quiet right after the CONNACK, with the deadline already reached
+        let mut socket = MockSocket::new([connack()]);
+        let options = ConnectionOptions {
+            read_timeout: Duration::from_ticks(0),
+            ..options()
+        };
+        let state = TxPacket::Publish {
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "picow/effect",
+            payload: b"rainbow",
+        };
+
+        let (result, _) = serve_with(options, &mut socket, std::vec![state]);
+
+        // Sending doesn't count as hearing from the broker
+        assert!(matches!(result, Err(MqttError::ReadTimeout)));
+        assert!(socket.aborted);
+        assert!(matches!(socket.sent_packets()[..], [Packet::Publish(_)]));
+    }
+
+    #[test]
+    fn lagging_subscribers_count_the_missed_packets() {
+        let channel = MqttRxChannel::new();
+        let mut subscriber = channel.subscriber().unwrap();
+        let publisher = channel.publisher().unwrap();
+        for _ in 0..RX_CAPACITY + 5 {
+            publisher.publish_immediate(RxPacket::Disconnected);
+        }
+
+        let before = lagged_packets();
+        let Some(WaitResult::Lagged(missed)) = subscriber.try_next_message() else {
+            panic!("Expected the subscriber to lag");
+        };
+        record_lag(missed);
+        assert_eq!(lagged_packets() - before, 5);
+
+        // Saturates instead of wrapping
+        record_lag(u64::MAX);
+        assert_eq!(lagged_packets(), u32::MAX);
+        record_lag(1);
+        assert_eq!(lagged_packets(), u32::MAX);
+    }
+
+    /// The CONNECT opening a session with `options`
+    fn connect_sent(options: &ConnectionOptions<'_>) -> MockSocket {
+        let mut socket = MockSocket::new([]);
+        block_on(MqttRunner::send_connect(&mut socket, "test", options)).unwrap();
+        socket
+    }
+
+    #[test]
+    fn clean_sessions_are_subscribed_again() {
+        for clean_session in [true, false] {
+            let options = ConnectionOptions {
+                clean_session,
+                ..options()
+            };
+            let socket = connect_sent(&options);
+            assert!(matches!(
+                socket.sent_packets()[..],
+                [Packet::Connect(ref connect)] if connect.clean_session == clean_session
+            ));
+
+            // A broker keeping the session only resumes it if the device asked for that
+            let mut socket = MockSocket::new([
+                receive(&Packet::Connack(Connack {
+                    session_present: !clean_session,
+                    code: ConnectReturnCode::Accepted,
+                })),
+                Read::Eof,
+            ]);
+            let (_, events) = serve_with(options, &mut socket, Vec::new());
+
+            // Tasks only subscribe again without the session, which holds the subscriptions
+            assert!(matches!(
+                events[..],
+                [RxPacket::Connected { session_present }, ..] if session_present != clean_session
+            ));
+        }
+    }
+
+    #[test]
+    fn last_wills_are_flagged_in_the_connect() {
+        let with_will = ConnectionOptions {
+            last_will: Some(LastWill {
+                topic: "picow/availability",
+                message: b"offline",
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            ..options()
+        };
+
+        let socket = connect_sent(&with_will);
+        // After the fixed header, the protocol name and level: retained will with QoS 1, and a
+        // clean session
+        assert_eq!(socket.sent[0][9], 0b0010_1110);
+        let [Packet::Connect(connect)] = &socket.sent_packets()[..] else {
+            panic!("Expected a CONNECT");
+        };
+        let will = connect.last_will.as_ref().unwrap();
+        assert_eq!(will.topic, "picow/availability");
+        assert_eq!(will.message, b"offline");
+        assert_eq!((will.qos, will.retain), (QoS::AtLeastOnce, true));
+
+        // Without a will, only the clean session is flagged
+        let socket = connect_sent(&options());
+        assert_eq!(socket.sent[0][9], 0b0000_0010);
+    }
+
+    fn poll<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn connected_once_the_connack_is_processed() {
+        let _exclusive = exclusive();
+        let queues = Queues::new();
+        let mut connected = pin!(wait_connected());
+
+        // The last byte of the CONNACK is still missing
+        let mut socket = MockSocket::new([Read::Bytes(std::vec![0x20, 0x02, 0])]);
+        let mut session = Session::new(options());
+        assert!(poll(pin!(queues.serve(&mut session, &mut socket))).is_pending());
+        assert!(poll(connected.as_mut()).is_pending());
+
+        let mut socket = MockSocket::new([connack()]);
+        let mut session = pin!(queues.serve(&mut session, &mut socket));
+        assert!(poll(connected.as_mut()).is_pending());
+        assert!(poll(session.as_mut()).is_pending());
+        assert!(poll(connected.as_mut()).is_ready());
+    }
+
+    #[test]
+    fn retained_states_are_told_apart_from_live_echoes() {
+        let state = |retain| {
+            receive(&Packet::Publish(Publish {
+                dup: false,
+                qospid: QosPid::AtMostOnce,
+                retain,
+                topic_name: "picow/light/state",
+                payload: b"ON,64",
+            }))
+        };
+        let mut socket = MockSocket::new([connack(), state(true), state(false), Read::Eof]);
+
+        let (result, events) = serve(&mut socket, Vec::new());
+
+        assert!(matches!(result, Err(MqttError::ConnectionReset)));
+        let retained: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                RxPacket::Command {
+                    command: Command::LightState(Zone::First, state),
+                    retained,
+                } if state.brightness == 64 => Some(*retained),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(retained, [true, false]);
+    }
+
+    #[test]
+    fn second_connacks_end_the_session() {
+        let mut socket = MockSocket::new([connack(), connack(), pause(QosPid::AtMostOnce, false)]);
+
+        let (result, events) = serve(&mut socket, Vec::new());
+
+        assert!(matches!(result, Err(MqttError::ProtocolViolation)));
+        // Announced only once, and nothing after the violation is read
+        assert!(matches!(
+            events[..],
+            [RxPacket::Connected {
+                session_present: false
+            }]
+        ));
+        assert_eq!(socket.reads.len(), 1);
+    }
+
+    #[test]
+    fn eof_ends_the_session() {
+        let mut socket = MockSocket::new([connack(), Read::Eof]);
+
+        let (result, events) = serve(&mut socket, Vec::new());
+
+        assert!(matches!(result, Err(MqttError::ConnectionReset)));
+        assert!(matches!(
+            events[..],
+            [RxPacket::Connected {
+                session_present: false
+            }]
+        ));
+    }
+
+    #[test]
+    fn read_errors_end_the_session() {
+        let mut socket = MockSocket::new([connack(), Read::Fail(MqttError::DecodeError)]);
+
+        let (result, _) = serve(&mut socket, Vec::new());
+
+        assert!(matches!(result, Err(MqttError::DecodeError)));
+    }
+
+    #[test]
+    fn oversized_packets_dont_end_the_session() {
+        let mut publish = std::vec![0x30, 0xA0, 0x1F, 0, 1, b'a'];
+        publish.resize(2 + 2 + 0xFA0, 0);
+        let mut socket = MockSocket::new([connack()]);
+        socket.reads.extend(
+            publish
+                .chunks(1000)
+                .map(|chunk| Read::Bytes(chunk.to_vec())),
+        );
+        socket.reads.extend([receive(&Packet::Pingresp), Read::Eof]);
+
+        let (result, _) = serve(&mut socket, Vec::new());
+
+        assert!(matches!(result, Err(MqttError::ConnectionReset)));
+        assert!(socket.reads.is_empty());
+    }
+
+    #[test]
+    fn oversized_publishes_dont_end_the_session() {
+        static PAYLOAD: [u8; super::BUFFER_SIZE] = [0; super::BUFFER_SIZE];
+        let oversized = TxPacket::Publish {
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a",
+            payload: &PAYLOAD,
+        };
+        let next = TxPacket::Publish {
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a",
+            payload: b"b",
+        };
+        let mut socket = MockSocket::new([connack()]);
+
+        let (result, _) = serve(&mut socket, std::vec![oversized, next, TxPacket::Reconnect]);
+
+        // Skipped, the connection goes on with the next one
+        assert!(matches!(result, Err(MqttError::ReconnectRequested)));
+        assert!(matches!(
+            socket.sent_packets()[..],
+            [Packet::Publish(Publish { payload: b"b", .. })]
+        ));
+    }
+
+    #[test]
+    fn reset_between_packets_doesnt_corrupt_the_next_session() {
+        let _exclusive = exclusive();
+        let queues = Queues::new();
+        let mut subscriber = queues.received.subscriber().unwrap();
+        let mut session = Session::new(options());
+        let pause = encode(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "picow/pause/set",
+            payload: b"ON",
+        }));
+
+        // The reset hits after one command and half of the next
+        let mut socket = MockSocket::new([
+            connack(),
+            Read::Bytes(pause.clone()),
+            Read::Bytes(pause[..4].to_vec()),
+            Read::Fail(MqttError::ConnectionReset),
+        ]);
+        let result = block_on(queues.serve(&mut session, &mut socket));
+        assert!(matches!(result, Err(MqttError::ConnectionReset)));
+
+        let mut socket = MockSocket::new([connack(), Read::Bytes(pause), Read::Eof]);
+        let result = block_on(queues.serve(&mut session, &mut socket));
+        assert!(matches!(result, Err(MqttError::ConnectionReset)));
+        assert!(socket.reads.is_empty());
+
+        let events: Vec<_> = core::iter::from_fn(|| subscriber.try_next_message_pure()).collect();
+        assert!(matches!(
+            events[..],
+            [
+                RxPacket::Connected { .. },
+                RxPacket::Command {
+                    command: Command::Pause(true),
+                    ..
+                },
+                RxPacket::Connected { .. },
+                RxPacket::Command {
+                    command: Command::Pause(true),
+                    ..
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn connections_to_other_endpoints_are_rejected() {
+        let expected = IpEndpoint::new(IpAddress::v4(192, 168, 1, 2), 1883);
+
+        assert!(matches!(
+            verify_endpoint(expected, Some(expected)),
+            Ok(endpoint) if endpoint == expected
+        ));
+        for remote in [
+            IpEndpoint::new(IpAddress::v4(192, 168, 1, 3), 1883),
+            IpEndpoint::new(IpAddress::v4(192, 168, 1, 2), 8883),
+        ] {
+            assert!(matches!(
+                verify_endpoint(expected, Some(remote)),
+                Err(MqttError::UnexpectedEndpoint)
+            ));
+        }
+        assert!(matches!(
+            verify_endpoint(expected, None),
+            Err(MqttError::ConnectionReset)
+        ));
+    }
+
+    #[test]
+    fn client_id_ends_with_the_mac_address() {
+        let mac_address = MacAddress([0x28, 0xCD, 0xC1, 0x0A, 0x0B, 0x0C]);
+
+        assert_eq!(client_id("picow", Some(mac_address)), "picow-0a0b0c");
+        assert_eq!(client_id("picow", None), "picow");
+    }
+
+    #[test]
+    fn long_client_id_prefixes_are_cut_to_fit_the_suffix() {
+        let prefix = "a".repeat(MAX_CLIENT_ID_LENGTH);
+        let mac_address = MacAddress([0; 6]);
+
+        let id = client_id(&prefix, Some(mac_address));
+        assert_eq!(id.len(), MAX_CLIENT_ID_LENGTH);
+        assert!(id.ends_with("-000000"));
+        assert_eq!(client_id(&prefix, None).len(), MAX_CLIENT_ID_LENGTH);
+    }
+
+    #[test]
+    fn client_id_prefixes_are_cut_between_characters() {
+        // Two bytes each, the limit falls in the middle of one
+        let prefix = "ä".repeat(MAX_CLIENT_ID_LENGTH);
+
+        let id = client_id(&prefix, Some(MacAddress([0; 6])));
+        assert!(id.len() <= MAX_CLIENT_ID_LENGTH);
+        assert!(id.starts_with("ää") && id.ends_with("-000000"));
+    }
+
+    /// A pause command, as the broker sends it with `qospid`
+    fn pause(qospid: QosPid, dup: bool) -> Read {
+        receive(&Packet::Publish(Publish {
+            dup,
+            qospid,
+            retain: false,
+            topic_name: "picow/pause/set",
+            payload: b"ON",
+        }))
+    }
+
+    fn pid(pid: u16) -> Pid {
+        Pid::try_from(pid).unwrap()
+    }
+
+    fn commands(events: &[RxPacket]) -> usize {
+        events
+            .iter()
+            .filter(|event| matches!(event, RxPacket::Command { .. }))
+            .count()
+    }
+
+    #[test]
+    fn subscriptions_use_the_command_qos() {
+        for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+            let options = ConnectionOptions {
+                command_qos: qos,
+                ..options()
+            };
+            let mut socket = MockSocket::new([connack()]);
+            let subscribe = TxPacket::Subscribe(&["picow/light/set", "picow/get"]);
+
+            let (result, _) = serve_with(
+                options,
+                &mut socket,
+                std::vec![subscribe, TxPacket::Reconnect],
+            );
+
+            assert!(matches!(result, Err(MqttError::ReconnectRequested)));
+            let sent = socket.sent_packets();
+            let [Packet::Subscribe(subscribe)] = &sent[..] else {
+                panic!("Expected a single SUBSCRIBE");
+            };
+            let topics: Vec<_> = subscribe
+                .topics
+                .iter()
+                .map(|topic| (topic.topic_path.as_str(), topic.qos))
+                .collect();
+            assert_eq!(topics, [("picow/light/set", qos), ("picow/get", qos)]);
+        }
+    }
+
+    #[test]
+    fn subscriptions_overtake_a_full_queue() {
+        let _exclusive = exclusive();
+        let queues = Queues::new();
+        for _ in 0..9 {
+            queues
+                .transmit
+                .try_send(TxPacket::Publish {
+                    qospid: QosPid::AtMostOnce,
+                    retain: false,
+                    topic_name: "picow/effect",
+                    payload: b"rainbow",
+                })
+                .ok()
+                .unwrap();
+        }
+        queues.transmit.try_send(TxPacket::Reconnect).ok().unwrap();
+        assert!(queues.transmit.is_full());
+        queues
+            .priority
+            .try_send(TxPacket::Subscribe(&["picow/get"]))
+            .ok()
+            .unwrap();
+
+        let mut socket = MockSocket::new([connack()]);
+        let result = block_on(queues.serve(&mut Session::new(options()), &mut socket));
+
+        assert!(matches!(result, Err(MqttError::ReconnectRequested)));
+        let sent = socket.sent_packets();
+        assert_eq!(sent.len(), 10);
+        assert!(matches!(sent[0], Packet::Subscribe(_)));
+        assert!(
+            sent[1..]
+                .iter()
+                .all(|packet| matches!(packet, Packet::Publish(_)))
+        );
+    }
+
+    #[test]
+    fn qos1_publishes_are_acknowledged() {
+        let mut socket = MockSocket::new([
+            connack(),
+            pause(QosPid::AtLeastOnce(pid(7)), false),
+            Read::Eof,
+        ]);
+
+        let (_, events) = serve(&mut socket, Vec::new());
+
+        assert_eq!(commands(&events), 1);
+        assert!(matches!(socket.sent_packets()[..], [Packet::Puback(sent)] if sent == pid(7)));
+    }
+
+    #[test]
+    fn mqtt5_sessions_drop_received_properties() {
+        // Accepted with a receive maximum, then a pause command with a content type
+        let mut publish = std::vec![0x32, 0, 0, 15];
+        publish.extend_from_slice(b"picow/pause/set");
+        publish.extend_from_slice(&[0, 7, 7, 0x03, 0, 4]);
+        publish.extend_from_slice(b"textON");
+        publish[1] = publish.len() as u8 - 2;
+        let mut socket = MockSocket::new([
+            Read::Bytes(std::vec![0x20, 6, 0, 0, 3, 0x21, 0, 20]),
+            Read::Bytes(publish),
+            Read::Eof,
+        ]);
+        socket.mqtt5 = true;
+
+        let (_, events) = serve(&mut socket, Vec::new());
+
+        assert_eq!(commands(&events), 1);
+        assert_eq!(socket.sent, [[0x40, 2, 0, 7]]);
+    }
+
+    #[test]
+    fn mqtt5_sessions_send_publish_properties() {
+        let publish = TxPacket::PublishWithProperties {
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a/b".try_into().unwrap(),
+            payload: heapless::Vec::from_slice(b"{}").unwrap(),
+            properties: PublishProperties {
+                content_type: Some("application/json"),
+                ..Default::default()
+            },
+        };
+        let mut socket = MockSocket::new([connack()]);
+        socket.mqtt5 = true;
+
+        let (result, _) = serve(&mut socket, std::vec![publish, TxPacket::Reconnect]);
+
+        assert!(matches!(result, Err(MqttError::ReconnectRequested)));
+        let mut expected = std::vec![0x30, 27, 0, 3, b'a', b'/', b'b', 19, 0x03, 0, 16];
+        expected.extend_from_slice(b"application/json{}");
+        assert_eq!(socket.sent, [expected]);
+    }
+
+    #[test]
+    fn qos2_publishes_are_received_and_completed() {
+        let mut socket = MockSocket::new([
+            connack(),
+            pause(QosPid::ExactlyOnce(pid(9)), false),
+            receive(&Packet::Pubrel(pid(9))),
+            Read::Eof,
+        ]);
+
+        let (_, events) = serve(&mut socket, Vec::new());
+
+        assert_eq!(commands(&events), 1);
+        assert!(matches!(
+            socket.sent_packets()[..],
+            [Packet::Pubrec(received), Packet::Pubcomp(completed)]
+                if received == pid(9) && completed == pid(9)
+        ));
+    }
+
+    #[test]
+    fn redeliveries_of_acknowledged_publishes_are_ignored() {
+        let mut socket = MockSocket::new([
+            connack(),
+            pause(QosPid::AtLeastOnce(pid(7)), false),
+            pause(QosPid::AtLeastOnce(pid(7)), true),
+            Read::Eof,
+        ]);
+
+        let (_, events) = serve(&mut socket, Vec::new());
+
+        assert_eq!(commands(&events), 1);
+        // Still acknowledged, or the broker would keep trying
+        assert_eq!(socket.sent_packets().len(), 2);
+    }
+
+    #[test]
+    fn redeliveries_of_unknown_publishes_are_passed_on() {
+        // The original got lost, e.g. with the previous connection
+        let mut socket = MockSocket::new([
+            connack(),
+            pause(QosPid::AtLeastOnce(pid(3)), false),
+            pause(QosPid::AtLeastOnce(pid(4)), true),
+            Read::Eof,
+        ]);
+
+        let (_, events) = serve(&mut socket, Vec::new());
+
+        assert_eq!(commands(&events), 2);
+    }
+
+    #[test]
+    fn unreleased_qos2_publishes_are_passed_on_once() {
+        let mut socket = MockSocket::new([
+            connack(),
+            pause(QosPid::ExactlyOnce(pid(9)), false),
+            pause(QosPid::ExactlyOnce(pid(9)), true),
+            receive(&Packet::Pubrel(pid(9))),
+            // After the release, the id may be used for a new publish
+            pause(QosPid::ExactlyOnce(pid(9)), false),
+            Read::Eof,
+        ]);
+
+        let (_, events) = serve(&mut socket, Vec::new());
+
+        assert_eq!(commands(&events), 2);
+    }
+
+    #[test]
+    fn pid_history_forgets_the_oldest() {
+        let mut history = PidHistory::<2>(heapless::Vec::new());
+        history.insert(pid(1));
+        history.insert(pid(2));
+        // Already known ids don't take up another slot
+        history.insert(pid(1));
+        assert!(history.contains(pid(1)) && history.contains(pid(2)));
+
+        history.insert(pid(3));
+        assert!(!history.contains(pid(1)));
+        assert!(history.contains(pid(2)) && history.contains(pid(3)));
+
+        history.remove(pid(2));
+        assert!(!history.contains(pid(2)));
+    }
+
+    /// Handles a received publish of `payload` to `topic`, returning the command it produced
+    fn command(topic: &str, payload: &[u8]) -> Result<Option<Command>> {
+        let received = MqttRxChannel::new();
+        let mut subscriber = received.subscriber().unwrap();
+        let publish = Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: topic,
+            payload,
+        };
+
+        MqttRunner::handle_command(publish, &received.publisher().unwrap())?;
+
+        Ok(match subscriber.try_next_message_pure() {
+            Some(RxPacket::Command { command, .. }) => Some(command),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn readme_config_is_applied() {
+        let json = br#"{"streams":[{"color":[255,0,0],"hz":60,"burst_us":3000,"offset_us":0}]}"#;
+        assert!(json.len() > super::MAX_COMMAND_LENGTH);
+
+        let Ok(Some(Command::Streams(Zone::First, Some(streams)))) = command("picow/config", json)
+        else {
+            panic!("config not parsed");
+        };
+
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].color(), Color(255, 0, 0));
+    }
+
+    #[test]
+    fn raw_manual_colors_are_binary() {
+        // Not valid UTF-8, which the raw topic doesn't need
+        let payload = [0xff, 0x80, 0x00];
+        assert!(matches!(
+            command("picow/manual/raw/set", &payload),
+            Ok(Some(Command::Manual(Some(Color(255, 128, 0)))))
+        ));
+        assert!(matches!(
+            command("picow/manual/raw/set", b""),
+            Ok(Some(Command::Manual(None)))
+        ));
+        assert!(matches!(
+            command("picow/manual/raw/set", &payload[..2]),
+            Err(MqttError::DecodeError)
+        ));
+        // Text topics still reject it
+        assert!(command("picow/manual/set", &payload).is_err());
+
+        // Delivered as received by a running session
+        let mut socket = MockSocket::new([
+            connack(),
+            receive(&Packet::Publish(Publish {
+                dup: false,
+                qospid: QosPid::AtMostOnce,
+                retain: false,
+                topic_name: "picow/manual/raw/set",
+                payload: &payload,
+            })),
+            Read::Eof,
+        ]);
+        let (_, events) = serve(&mut socket, Vec::new());
+        assert!(matches!(
+            events[1],
+            RxPacket::Command {
+                command: Command::Manual(Some(Color(255, 128, 0))),
+                retained: false
+            }
+        ));
+    }
+
+    #[test]
+    fn publishes_without_a_topic_are_dropped() {
+        assert!(matches!(
+            command("", b"ON"),
+            Err(MqttError::InvalidTopic(TopicError::Empty))
+        ));
+
+        // The session goes on with the next publish
+        let mut socket = MockSocket::new([
+            connack(),
+            receive(&Packet::Publish(Publish {
+                dup: false,
+                qospid: QosPid::AtLeastOnce(pid(3)),
+                retain: false,
+                topic_name: "",
+                payload: b"ON",
+            })),
+            pause(QosPid::AtMostOnce, false),
+            Read::Eof,
+        ]);
+        let (result, events) = serve(&mut socket, Vec::new());
+
+        assert!(matches!(result, Err(MqttError::ConnectionReset)));
+        assert_eq!(commands(&events), 1);
+        // Still acknowledged, or the broker would keep redelivering it
+        assert!(matches!(socket.sent_packets()[..], [Packet::Puback(sent)] if sent == pid(3)));
+    }
+
+    #[test]
+    fn lights_are_switched_per_zone() {
+        assert!(matches!(
+            command("picow/light/set", b"ON"),
+            Ok(Some(Command::Light(Zone::First, true)))
+        ));
+        assert!(matches!(
+            command("picow/zone2/light/set", b"OFF\n"),
+            Ok(Some(Command::Light(Zone::Second, false)))
+        ));
+        assert!(matches!(
+            command("picow/zone2/light/set", b"on"),
+            Err(MqttError::DecodeError)
+        ));
+    }
+
+    #[test]
+    fn brightness_is_set_per_zone() {
+        assert!(matches!(
+            command("picow/light/brightness/set", b"128"),
+            Ok(Some(Command::Brightness(Zone::First, 128)))
+        ));
+        assert!(matches!(
+            command("picow/zone2/light/brightness/set", b" 7 "),
+            Ok(Some(Command::Brightness(Zone::Second, 7)))
+        ));
+        assert!(command("picow/light/brightness/set", b"256").is_err());
+    }
+
+    #[test]
+    fn state_requests_ignore_the_payload() {
+        for payload in [&b""[..], b"ON", b"{}"] {
+            assert!(matches!(
+                command("picow/get", payload),
+                Ok(Some(Command::GetState))
+            ));
+        }
+    }
+
+    #[test]
+    fn zones_have_their_own_state_and_config() {
+        assert!(matches!(
+            command("picow/zone2/light/state", b"ON,64"),
+            Ok(Some(Command::LightState(Zone::Second, state))) if state.brightness == 64
+        ));
+        assert!(matches!(
+            command("picow/zone2/config", b""),
+            Ok(Some(Command::Streams(Zone::Second, None)))
+        ));
+    }
+
+    #[test]
+    fn sleep_wakes_up_after_the_given_seconds() {
+        assert!(matches!(
+            command("picow/sleep/set", b""),
+            Ok(Some(Command::Sleep(None)))
+        ));
+        assert!(matches!(
+            command("picow/sleep/set", b" 90\n"),
+            Ok(Some(Command::Sleep(Some(duration)))) if duration == Duration::from_secs(90)
+        ));
+        assert!(command("picow/sleep/set", b"-1").is_err());
+        assert!(command("picow/sleep/set", b"soon").is_err());
+
+        let Ok(Some(sleep)) = command("picow/sleep/set", b"") else {
+            panic!("sleep not parsed");
+        };
+        assert!(sleep.is_one_shot());
+    }
+
+    #[test]
+    fn dimming_curves_are_selected_by_name() {
+        assert!(matches!(
+            command("picow/dimming_curve/set", b"cie1931\n"),
+            Ok(Some(Command::DimmingCurve(DimmingCurve::Cie1931)))
+        ));
+        assert!(command("picow/dimming_curve/set", b"gamma").is_err());
+    }
+
+    #[test]
+    fn long_commands_are_rejected() {
+        let payload = [b'1'; super::MAX_COMMAND_LENGTH + 1];
+
+        assert!(matches!(
+            command("picow/speed/set", &payload),
+            Err(MqttError::PayloadTooLarge)
+        ));
+    }
+
+    #[test]
+    fn reconnect_starts_a_new_session() {
+        let Ok(Some(command)) = command("picow/reconnect", b"") else {
+            panic!("reconnect not parsed");
+        };
+        assert!(command.is_one_shot());
+
+        let _exclusive = exclusive();
+        let queues = Queues::new();
+        let mut subscriber = queues.received.subscriber().unwrap();
+        let mut session = Session::new(options());
+        queues.transmit.try_send(TxPacket::Reconnect).ok().unwrap();
+
+        let mut socket = MockSocket::new([connack()]);
+        let result = block_on(queues.serve(&mut session, &mut socket));
+        assert!(matches!(result, Err(MqttError::ReconnectRequested)));
+        assert!(socket.aborted);
+
+        let mut socket = MockSocket::new([connack(), Read::Eof]);
+        let result = block_on(queues.serve(&mut session, &mut socket));
+        assert!(matches!(result, Err(MqttError::ConnectionReset)));
+
+        let connects = core::iter::from_fn(|| subscriber.try_next_message_pure())
+            .filter(|event| matches!(event, RxPacket::Connected { .. }))
+            .count();
+        assert_eq!(connects, 2);
+    }
+
+    #[test]
+    fn pings_are_only_skipped_within_both_windows() {
+        let options = ConnectionOptions {
+            ping_when_idle_only: true,
+            keep_alive: Duration::from_secs(60),
+            read_timeout: Duration::from_secs(90),
+            ..options()
+        };
+        let now = Instant::from_secs(1000);
+        let ago = |seconds| now - Duration::from_secs(seconds);
+
+        // The windows are 15 seconds from the keep alive, and 30 from the read timeout
+        assert!(ping_redundant(&options, now, ago(14), ago(29)));
+        assert!(!ping_redundant(&options, now, ago(16), ago(0)));
+        assert!(!ping_redundant(&options, now, ago(0), ago(31)));
+
+        let options = ConnectionOptions {
+            ping_when_idle_only: false,
+            ..options
+        };
+        assert!(!ping_redundant(&options, now, now, now));
+    }
+
+    #[test]
+    fn pings_are_skipped_on_busy_connections() {
+        let options = ConnectionOptions {
+            ping_when_idle_only: true,
+            ..options()
+        };
+        let mut socket = MockSocket::new([connack()]);
+
+        let (result, _) = serve_with(
+            options,
+            &mut socket,
+            std::vec![TxPacket::Pingreq, TxPacket::Reconnect],
+        );
+
+        // The CONNACK was just received, and the keep alive is far off
+        assert!(matches!(result, Err(MqttError::ReconnectRequested)));
+        assert!(socket.sent.is_empty());
+    }
+
+    #[test]
+    fn pings_are_sent_on_idle_connections() {
+        // Without any slack, the connection counts as idle right away
+        let options = ConnectionOptions {
+            ping_when_idle_only: true,
+            keep_alive: HEARTBEAT_INTERVAL,
+            ..options()
+        };
+        let mut socket = MockSocket::new([connack()]);
+
+        let (result, _) = serve_with(
+            options,
+            &mut socket,
+            std::vec![TxPacket::Pingreq, TxPacket::Reconnect],
+        );
+
+        assert!(matches!(result, Err(MqttError::ReconnectRequested)));
+        assert!(matches!(socket.sent_packets()[..], [Packet::Pingreq]));
+    }
+
+    /// Resolves `address`, returning the result and the names DNS was asked for
+    fn resolved(address: ServerAddress<'_>) -> (Result<IpAddress>, Vec<std::string::String>) {
+        let mut queries = Vec::new();
+        let result = block_on(resolve(address, async |name: &str| {
+            queries.push(name.to_owned());
+            Ok(IpAddress::v4(10, 0, 0, 1))
+        }));
+
+        (result, queries)
+    }
+
+    #[test]
+    fn ip_literals_skip_dns() {
+        let (result, queries) = resolved(ServerAddress::HostName("192.168.1.10"));
+        assert_eq!(result.ok(), Some(IpAddress::v4(192, 168, 1, 10)));
+        assert!(queries.is_empty());
+
+        let (result, queries) = resolved(ServerAddress::HostName("fe80::1"));
+        assert!(matches!(result, Err(MqttError::DnsError)));
+        assert!(queries.is_empty());
+
+        assert!(matches!(
+            ServerAddress::parse("10.1.2.3"),
+            ServerAddress::Ip(ip) if ip == IpAddress::v4(10, 1, 2, 3)
+        ));
+    }
+
+    #[test]
+    fn host_names_are_resolved_through_dns() {
+        let (result, queries) = resolved(ServerAddress::parse("homeassistant"));
+
+        assert_eq!(result.ok(), Some(IpAddress::v4(10, 0, 0, 1)));
+        assert_eq!(queries, ["homeassistant"]);
+        // Not a literal, even though it starts like one
+        let (_, queries) = resolved(ServerAddress::HostName("192.168.1.10.example"));
+        assert_eq!(queries.len(), 1);
+    }
+
+    #[test]
+    fn only_actions_are_one_shot() {
+        assert!(Command::Restart.is_one_shot());
+        assert!(Command::ClearDiscovery.is_one_shot());
+        assert!(Command::Identify.is_one_shot());
+        assert!(Command::TestPattern.is_one_shot());
+        assert!(!Command::Pause(true).is_one_shot());
+        assert!(!Command::Streams(Zone::First, None).is_one_shot());
+    }
 }
@@ -0,0 +1,300 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::{String, Vec};
+
+use super::error::{MqttError, Result};
+use super::{
+    MAX_PAYLOAD_LENGTH, MAX_TOPIC_LENGTH, MqttPrioritySender, MqttTxSender, TxPacket, publish_topic,
+};
+
+/// Maximum number of distinct sensors that can be published
+pub const MAX_SENSORS: usize = 8;
+
+#[derive(Clone, Copy)]
+pub enum SensorValue {
+    Float(f32),
+    Int(i64),
+}
+
+impl From<f32> for SensorValue {
+    fn from(value: f32) -> Self {
+        Self::Float(value)
+    }
+}
+
+impl From<i32> for SensorValue {
+    fn from(value: i32) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+impl From<u32> for SensorValue {
+    fn from(value: u32) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    None,
+    Seconds,
+    Celsius,
+}
+
+impl Unit {
+    fn unit_of_measurement(self) -> Option<&'static str> {
+        match self {
+            Unit::None => None,
+            Unit::Seconds => Some("s"),
+            Unit::Celsius => Some("°C"),
+        }
+    }
+
+    fn device_class(self) -> Option<&'static str> {
+        match self {
+            Unit::None => None,
+            Unit::Seconds => Some("duration"),
+            Unit::Celsius => Some("temperature"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Sensor {
+    name: &'static str,
+    unit: Unit,
+}
+
+static SENSORS: Mutex<CriticalSectionRawMutex, RefCell<Vec<Sensor, MAX_SENSORS>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Publishes `value` to `<device_id>/sensor/<name>/state`.
+///
+/// The first time a sensor is published, its autodiscovery config is sent as well, attaching the
+/// sensor to the device identified by `device_id`.
+pub async fn publish_sensor(
+    sender: &MqttTxSender<'_>,
+    device_id: &str,
+    name: &'static str,
+    value: impl Into<SensorValue>,
+    unit: Unit,
+) -> Result<()> {
+    let sensor = Sensor { name, unit };
+
+    // Everything is built before registering, so a sensor that can't be published isn't
+    // announced either
+    let topic_name = state_topic(device_id, name)?;
+    let mut payload = String::<MAX_PAYLOAD_LENGTH>::new();
+    match value.into() {
+        SensorValue::Float(value) => write!(payload, "{}", value)?,
+        SensorValue::Int(value) => write!(payload, "{}", value)?,
+    }
+
+    if !registered(name) {
+        let discovery = discovery_packet(device_id, &sensor)?;
+        if register(sensor)? {
+            sender.send(discovery).await;
+        }
+    }
+
+    sender
+        .send(TxPacket::PublishOwned {
+            qospid: mqttrs::QosPid::AtMostOnce,
+            retain: false,
+            topic_name,
+            payload: payload.into_bytes(),
+        })
+        .await;
+
+    Ok(())
+}
+
+/// Resends the autodiscovery config of every sensor published so far
+pub async fn announce_sensors(sender: &MqttPrioritySender<'_>, device_id: &str) -> Result<()> {
+    let sensors = SENSORS.lock(|sensors| sensors.borrow().clone());
+
+    for sensor in &sensors {
+        sender.send(discovery_packet(device_id, sensor)?).await;
+    }
+
+    Ok(())
+}
+
+/// Clears the autodiscovery config of every sensor published so far, removing them from Home
+/// Assistant
+pub async fn clear_sensors(sender: &MqttPrioritySender<'_>, device_id: &str) -> Result<()> {
+    let sensors = SENSORS.lock(|sensors| sensors.borrow().clone());
+
+    for sensor in &sensors {
+        sender
+            .send(TxPacket::PublishOwned {
+                qospid: mqttrs::QosPid::AtMostOnce,
+                retain: true,
+                topic_name: discovery_topic(device_id, sensor)?,
+                payload: Vec::new(),
+            })
+            .await;
+    }
+
+    Ok(())
+}
+
+fn registered(name: &str) -> bool {
+    SENSORS.lock(|sensors| sensors.borrow().iter().any(|known| known.name == name))
+}
+
+/// Returns whether the sensor wasn't known before
+fn register(sensor: Sensor) -> Result<bool> {
+    SENSORS.lock(|sensors| {
+        let mut sensors = sensors.borrow_mut();
+        if sensors.iter().any(|known| known.name == sensor.name) {
+            return Ok(false);
+        }
+
+        sensors
+            .push(sensor)
+            .map(|_| true)
+            .map_err(|_| MqttError::TooManySensors)
+    })
+}
+
+/// Unique per device, so the sensors of several devices don't mix on the same broker
+fn state_topic(device_id: &str, name: &str) -> Result<String<MAX_TOPIC_LENGTH>> {
+    Ok(publish_topic(&[device_id, "sensor", name, "state"])?)
+}
+
+fn discovery_topic(device_id: &str, sensor: &Sensor) -> Result<String<MAX_TOPIC_LENGTH>> {
+    Ok(publish_topic(&[
+        "homeassistant/sensor",
+        device_id,
+        sensor.name,
+        "config",
+    ])?)
+}
+
+fn discovery_packet(device_id: &str, sensor: &Sensor) -> Result<TxPacket> {
+    let topic_name = discovery_topic(device_id, sensor)?;
+
+    let mut payload = String::<MAX_PAYLOAD_LENGTH>::new();
+    write!(
+        payload,
+        r#"{{"name":"{name}","unique_id":"{device_id}_{name}","state_topic":"{topic}","device":{{"identifiers":["{device_id}"]}}"#,
+        name = sensor.name,
+        topic = state_topic(device_id, sensor.name)?,
+    )?;
+    if let Some(unit) = sensor.unit.unit_of_measurement() {
+        write!(payload, r#","unit_of_measurement":"{}""#, unit)?;
+    }
+    if let Some(device_class) = sensor.unit.device_class() {
+        write!(payload, r#","device_class":"{}""#, device_class)?;
+    }
+    payload.push('}').map_err(|_| MqttError::EncodeError)?;
+
+    Ok(TxPacket::PublishOwned {
+        qospid: mqttrs::QosPid::AtMostOnce,
+        retain: false,
+        topic_name,
+        payload: payload.into_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+
+    use embassy_futures::block_on;
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+
+    use super::{Sensor, Unit, discovery_packet, publish_sensor, registered, state_topic};
+    use crate::mqtt::{MqttError, TxPacket};
+
+    /// Publishes a sensor of the device `28cdc1000001`, returning the result and the sent packets
+    /// as their topics and payloads
+    fn publish(
+        name: &'static str,
+        value: impl Into<super::SensorValue>,
+    ) -> (Result<(), MqttError>, Vec<(String, String)>) {
+        let channel = Channel::<CriticalSectionRawMutex, TxPacket, 10>::new();
+        let result = block_on(publish_sensor(
+            &channel.sender(),
+            "28cdc1000001",
+            name,
+            value,
+            Unit::None,
+        ));
+
+        let mut sent = Vec::new();
+        while let Ok(TxPacket::PublishOwned {
+            topic_name,
+            payload,
+            ..
+        }) = channel.try_receive()
+        {
+            let payload = str::from_utf8(&payload).unwrap().to_owned();
+            sent.push((topic_name.as_str().to_owned(), payload));
+        }
+        (result, sent)
+    }
+
+    #[test]
+    fn extreme_values_are_published_in_full() {
+        let (result, sent) = publish("test_extreme_int", i32::MIN);
+        assert!(result.is_ok());
+        // The discovery config comes first
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].1, "-2147483648");
+
+        let (result, sent) = publish("test_extreme_int", f32::MAX);
+        assert!(result.is_ok());
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, std::format!("{}", f32::MAX));
+    }
+
+    #[test]
+    fn sensors_without_room_for_their_topic_arent_registered() {
+        let name = "a_sensor_name_far_too_long_for_the_topic_buffer_of_the_state_topic_\
+            and_the_discovery_topic_of_the_device";
+
+        let (result, sent) = publish(name, 1);
+        assert!(result.is_err());
+        assert!(sent.is_empty());
+        assert!(!registered(name));
+    }
+
+    #[test]
+    fn state_topic_is_per_device() {
+        assert_eq!(
+            state_topic("28cdc1000001", "uptime").unwrap(),
+            "28cdc1000001/sensor/uptime/state"
+        );
+    }
+
+    #[test]
+    fn discovery_refers_to_the_state_topic() {
+        let sensor = Sensor {
+            name: "temperature",
+            unit: Unit::Celsius,
+        };
+
+        let TxPacket::PublishOwned {
+            topic_name,
+            payload,
+            ..
+        } = discovery_packet("28cdc1000001", &sensor).unwrap()
+        else {
+            panic!("not an owned publish");
+        };
+
+        assert_eq!(
+            topic_name,
+            "homeassistant/sensor/28cdc1000001/temperature/config"
+        );
+        let payload = str::from_utf8(&payload).unwrap();
+        assert!(payload.contains(r#""state_topic":"28cdc1000001/sensor/temperature/state""#));
+        assert!(payload.contains(r#""unique_id":"28cdc1000001_temperature""#));
+        assert!(payload.contains(r#""unit_of_measurement":"°C""#));
+        assert!(payload.contains(r#""device_class":"temperature""#));
+    }
+}
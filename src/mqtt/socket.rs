@@ -1,26 +1,345 @@
+use defmt::*;
 use embassy_net::tcp::TcpSocket;
 use mqttrs::Packet;
 
-use super::error::{MqttError, Result};
+use super::{
+    error::{MqttError, Result},
+    v5,
+};
 
 pub(crate) trait MqttSocket {
-    async fn send_packet(&mut self, packet: &Packet<'_>) -> Result<()>;
-    async fn read_packet<'s>(&mut self, buf: &'s mut [u8]) -> Result<Option<Packet<'s>>>;
+    /// Sends a packet with MQTT 5 `properties`, an encoded property list. They're dropped when
+    /// connected with 3.1.1.
+    async fn send_packet_with(&mut self, packet: &Packet<'_>, properties: &[u8]) -> Result<()>;
+    async fn send_packet(&mut self, packet: &Packet<'_>) -> Result<()> {
+        self.send_packet_with(packet, &[]).await
+    }
+    async fn read_packet<'s, const N: usize>(
+        &mut self,
+        buffer: &'s mut RxBuffer<N>,
+    ) -> Result<Option<Packet<'s>>>;
+    /// Closes the connection once everything written has been sent
+    fn close(&mut self);
+    /// Drops the connection without closing it, the broker sees it as lost
+    fn abort(&mut self);
+    /// Waits until everything written has been sent, or the connection is gone
+    async fn flush(&mut self) -> Result<()>;
 }
 
-impl<'a> MqttSocket for TcpSocket<'a> {
-    async fn send_packet(&mut self, packet: &Packet<'_>) -> Result<()> {
-        let mut buf = [0; 2048];
-        let size = mqttrs::encode_slice(packet, &mut buf).map_err(|_| MqttError::EncodeError)?;
+/// Accumulates received bytes until a full packet is available, so packets split across
+/// multiple TCP reads are decoded correctly
+pub(crate) struct RxBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    consumed: usize,
+    /// Bytes of a packet too large for the buffer that are still to be dropped as they arrive
+    skipping: usize,
+}
+
+impl<const N: usize> RxBuffer<N> {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            consumed: 0,
+            skipping: 0,
+        }
+    }
+
+    /// Drops all buffered bytes. Must be called whenever a new connection is established, since
+    /// partial packets from the previous connection will never be completed.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.consumed = 0;
+        self.skipping = 0;
+    }
+
+    /// Free room at the end, to receive into
+    pub fn space(&mut self) -> &mut [u8] {
+        &mut self.buf[self.len..]
+    }
 
-        self.write(&buf[0..size]).await.map(|_| ())?;
+    /// Appends `count` bytes received into `space`
+    pub fn received(&mut self, count: usize) {
+        self.len += count;
+    }
+
+    /// Length of the first packet, once it has been received completely. Packets larger than the
+    /// buffer are dropped as they arrive instead, their length keeps the stream in sync. A broken
+    /// length can't be skipped, so it clears the buffer and fails.
+    pub fn next_packet(&mut self) -> Result<Option<usize>> {
+        loop {
+            self.consumed = self.skipping.min(self.len);
+            self.skipping -= self.consumed;
+            self.discard_consumed();
+
+            let length = match self.packet_length() {
+                Ok(Some(length)) => length,
+                Ok(None) => return Ok(None),
+                Err(err) => {
+                    self.clear();
+                    return Err(err);
+                }
+            };
+            if length <= N {
+                return Ok((length <= self.len).then_some(length));
+            }
+
+            warn!(
+                "Skipping a packet of {} bytes, larger than the buffer of {}",
+                length, N
+            );
+            self.skipping = length;
+        }
+    }
+
+    /// Decodes the first packet, of a `length` returned by `next_packet`, received with MQTT 5 if
+    /// `mqtt5` is set. It's dropped from the buffer with the next read.
+    pub fn take_packet(&mut self, length: usize, mqtt5: bool) -> Result<Packet<'_>> {
+        self.consumed = length;
+
+        let length = if mqtt5 {
+            v5::downgrade(&mut self.buf[..length])?
+        } else {
+            length
+        };
+        mqttrs::decode_slice(&self.buf[..length])
+            .map_err(|_| MqttError::DecodeError)?
+            .ok_or(MqttError::DecodeError)
+    }
+
+    pub fn discard_consumed(&mut self) {
+        self.buf.copy_within(self.consumed..self.len, 0);
+        self.len -= self.consumed;
+        self.consumed = 0;
+    }
+
+    /// Length of the first packet in the buffer, once its fixed header has been received
+    fn packet_length(&self) -> Result<Option<usize>> {
+        let buf = &self.buf[..self.len];
+        let mut remaining_length = 0;
+
+        for (pos, byte) in buf.iter().skip(1).take(4).enumerate() {
+            remaining_length |= (*byte as usize & 0x7F) << (pos * 7);
+
+            if byte & 0x80 == 0 {
+                return Ok(Some(2 + pos + remaining_length));
+            }
+        }
+
+        if buf.len() >= 5 {
+            Err(MqttError::DecodeError)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Encodes `packet` into `buffer` for MQTT 5 if `mqtt5` is set, otherwise for 3.1.1 without the
+/// `properties`. Returns the encoded length, or `PayloadTooLarge` if it doesn't fit.
+pub(crate) fn encode_packet(
+    packet: &Packet<'_>,
+    properties: &[u8],
+    mqtt5: bool,
+    buffer: &mut [u8],
+) -> Result<usize> {
+    let size = mqttrs::encode_slice(packet, buffer).map_err(|err| match err {
+        mqttrs::Error::WriteZero => MqttError::PayloadTooLarge,
+        _ => MqttError::EncodeError,
+    });
+    let size = match size {
+        Ok(size) if mqtt5 => v5::upgrade(buffer, size, properties),
+        size => size,
+    };
+
+    if let Err(MqttError::PayloadTooLarge) = size {
+        warn!("Packet exceeds the encode buffer of {} bytes", buffer.len());
+    }
+    size
+}
+
+/// A TCP socket together with the buffer packets are encoded into before sending. The buffer is
+/// reused for every packet, which keeps it off the stack.
+pub(crate) struct MqttConnection<'a> {
+    pub socket: TcpSocket<'a>,
+    encode_buffer: &'a mut [u8],
+    /// Whether the connection speaks MQTT 5 instead of 3.1.1
+    mqtt5: bool,
+}
+
+impl<'a> MqttConnection<'a> {
+    pub fn new(socket: TcpSocket<'a>, encode_buffer: &'a mut [u8], mqtt5: bool) -> Self {
+        Self {
+            socket,
+            encode_buffer,
+            mqtt5,
+        }
+    }
+}
+
+impl<'a> MqttSocket for MqttConnection<'a> {
+    /// Fails with `PayloadTooLarge` if the packet doesn't fit into the encode buffer
+    async fn send_packet_with(&mut self, packet: &Packet<'_>, properties: &[u8]) -> Result<()> {
+        let size = encode_packet(packet, properties, self.mqtt5, self.encode_buffer)?;
+
+        self.socket
+            .write(&self.encode_buffer[0..size])
+            .await
+            .map(|_| ())?;
 
         Ok(())
     }
 
-    async fn read_packet<'s>(&mut self, buf: &'s mut [u8]) -> Result<Option<Packet<'s>>> {
-        let count = self.read(buf).await?;
+    /// Returns `None` if the connection was closed by the remote.
+    ///
+    /// This is cancel safe, already received bytes stay in the buffer.
+    async fn read_packet<'s, const N: usize>(
+        &mut self,
+        buffer: &'s mut RxBuffer<N>,
+    ) -> Result<Option<Packet<'s>>> {
+        buffer.discard_consumed();
+
+        let length = loop {
+            if let Some(length) = buffer.next_packet()? {
+                break length;
+            }
+
+            let count = self.socket.read(buffer.space()).await?;
+            if count == 0 {
+                return Ok(None);
+            }
+
+            buffer.received(count);
+        };
+
+        buffer.take_packet(length, self.mqtt5).map(Some)
+    }
+
+    fn close(&mut self) {
+        self.socket.close();
+    }
+
+    fn abort(&mut self) {
+        self.socket.abort();
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Ok(self.socket.flush().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttrs::{Packet, Publish, QosPid};
+
+    use super::{RxBuffer, encode_packet};
+    use crate::mqtt::error::MqttError;
+
+    fn publish(payload: &[u8]) -> Packet<'_> {
+        Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a",
+            payload,
+        })
+    }
+
+    fn receive<const N: usize>(buffer: &mut RxBuffer<N>, bytes: &[u8]) {
+        buffer.space()[..bytes.len()].copy_from_slice(bytes);
+        buffer.received(bytes.len());
+    }
+
+    #[test]
+    fn split_packets_are_reassembled() {
+        let mut buffer = RxBuffer::<16>::new();
+
+        // A PUBLISH to `a` with payload `xy`, and the start of a PINGRESP
+        receive(&mut buffer, &[0x30, 5, 0]);
+        assert_eq!(buffer.next_packet().unwrap(), None);
+        receive(&mut buffer, &[1, b'a', b'x', b'y', 0xD0]);
+
+        assert_eq!(buffer.next_packet().unwrap(), Some(7));
+        let mqttrs::Packet::Publish(publish) = buffer.take_packet(7, false).unwrap() else {
+            panic!("not a publish");
+        };
+        assert_eq!((publish.topic_name, publish.payload), ("a", &b"xy"[..]));
+
+        buffer.discard_consumed();
+        assert_eq!(buffer.next_packet().unwrap(), None);
+        receive(&mut buffer, &[0]);
+        assert_eq!(buffer.next_packet().unwrap(), Some(2));
+    }
+
+    #[test]
+    fn clear_drops_partial_packets() {
+        let mut buffer = RxBuffer::<16>::new();
+
+        receive(&mut buffer, &[0x30, 5, 0, 1]);
+        assert_eq!(buffer.next_packet().unwrap(), None);
+        buffer.clear();
+
+        receive(&mut buffer, &[0xD0, 0]);
+        assert_eq!(buffer.next_packet().unwrap(), Some(2));
+        assert!(matches!(
+            buffer.take_packet(2, false).unwrap(),
+            mqttrs::Packet::Pingresp
+        ));
+    }
+
+    #[test]
+    fn oversized_packets_are_skipped() {
+        let mut buffer = RxBuffer::<8>::new();
+
+        // A PUBLISH with 10 bytes remaining, arriving across reads, then a PINGRESP
+        receive(&mut buffer, &[0x30, 10, 0, 1, b'a', 1, 2, 3]);
+        assert_eq!(buffer.next_packet().unwrap(), None);
+        receive(&mut buffer, &[4, 5, 6, 7, 0xD0, 0]);
+
+        assert_eq!(buffer.next_packet().unwrap(), Some(2));
+        assert!(matches!(
+            buffer.take_packet(2, false).unwrap(),
+            mqttrs::Packet::Pingresp
+        ));
+    }
+
+    #[test]
+    fn oversized_publishes_arent_encoded() {
+        let mut buffer = [0; 16];
+
+        // Fixed header, topic and payload take 17 bytes
+        let result = encode_packet(&publish(&[7; 12]), &[], false, &mut buffer);
+        assert!(matches!(result, Err(MqttError::PayloadTooLarge)));
+        let result = encode_packet(&publish(&[7; 11]), &[], false, &mut buffer);
+        assert_eq!(result.unwrap(), 16);
+    }
+
+    #[test]
+    fn packets_fill_larger_buffers() {
+        let mut buffer = [0; 4096];
+        let payload: std::vec::Vec<u8> = (0..3000).map(|n| n as u8).collect();
+
+        // Fixed header with a two byte remaining length, topic and payload
+        let size = encode_packet(&publish(&payload), &[], false, &mut buffer).unwrap();
+        assert_eq!(size, 3 + 3 + 3000);
+        let Ok(Some(Packet::Publish(decoded))) = mqttrs::decode_slice(&buffer[..size]) else {
+            panic!("not a publish");
+        };
+        assert_eq!(decoded.payload, &payload[..]);
+
+        // MQTT 5 adds the length of the properties
+        let size = encode_packet(&publish(&payload), &[], true, &mut buffer).unwrap();
+        assert_eq!(size, 3 + 3 + 1 + 3000);
+    }
+
+    #[test]
+    fn broken_length_clears_the_buffer() {
+        let mut buffer = RxBuffer::<8>::new();
+
+        receive(&mut buffer, &[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]);
+        assert!(matches!(buffer.next_packet(), Err(MqttError::DecodeError)));
 
-        mqttrs::decode_slice(&buf[0..count]).map_err(|_| MqttError::DecodeError)
+        receive(&mut buffer, &[0xD0, 0]);
+        assert_eq!(buffer.next_packet().unwrap(), Some(2));
     }
 }
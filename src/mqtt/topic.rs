@@ -0,0 +1,155 @@
+use heapless::String;
+
+/// Filter matching all debug requests, `picow/debug/<what>/get`
+pub const DEBUG_REQUESTS: &str = "picow/debug/+/get";
+
+/// Whether `topic` matches the subscription `filter`. `+` matches exactly one level, `#` at the
+/// end of the filter matches any number of levels, including none.
+///
+/// Topics starting with `$` are reserved for the broker and never match a filter starting with a
+/// wildcard.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return filter_levels.next().is_none(),
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Level `index` of `topic`, counted from 0
+pub fn level(topic: &str, index: usize) -> Option<&str> {
+    topic.split('/').nth(index)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TopicError {
+    /// The topic has no levels at all, which MQTT doesn't allow
+    Empty,
+    /// A level is empty, e.g. `picow//state`
+    EmptyLevel,
+    /// Wildcards are only allowed in subscriptions
+    Wildcard,
+    TooLong,
+}
+
+/// Topic to publish to, made of `levels` joined with exactly one `/`. A level may consist of
+/// several levels itself, and slashes at its start or end are ignored, so e.g. a prefix may end
+/// with one.
+pub fn publish_topic<const N: usize>(levels: &[&str]) -> Result<String<N>, TopicError> {
+    if levels.is_empty() {
+        return Err(TopicError::Empty);
+    }
+
+    let mut topic = String::new();
+
+    for level in levels {
+        let level = level.trim_matches('/');
+        if level.is_empty() || level.split('/').any(str::is_empty) {
+            return Err(TopicError::EmptyLevel);
+        }
+        if level.contains(['+', '#']) {
+            return Err(TopicError::Wildcard);
+        }
+
+        if !topic.is_empty() {
+            topic.push('/').map_err(|_| TopicError::TooLong)?;
+        }
+        topic.push_str(level).map_err(|_| TopicError::TooLong)?;
+    }
+
+    Ok(topic)
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::String;
+
+    use super::{DEBUG_REQUESTS, TopicError, level, matches, publish_topic};
+
+    fn topic(levels: &[&str]) -> Result<String<32>, TopicError> {
+        publish_topic(levels)
+    }
+
+    #[test]
+    fn plus_matches_a_single_level() {
+        assert!(matches("picow/light/+/set", "picow/light/color/set"));
+        assert!(matches(DEBUG_REQUESTS, "picow/debug/streams/get"));
+        assert!(!matches("picow/light/+/set", "picow/light/set"));
+        assert!(!matches("picow/light/+/set", "picow/light/a/b/set"));
+        assert!(!matches("picow/light/+", "picow/light/color/set"));
+        // An empty level is still a level
+        assert!(matches("picow/+/set", "picow//set"));
+    }
+
+    #[test]
+    fn hash_matches_any_number_of_levels() {
+        assert!(matches("picow/#", "picow/light/color/set"));
+        assert!(matches("picow/#", "picow/light"));
+        assert!(matches("picow/#", "picow"));
+        assert!(matches("#", "picow/light"));
+        assert!(!matches("picow/#", "other/light"));
+        // Only allowed as the last level
+        assert!(!matches("picow/#/set", "picow/light/set"));
+    }
+
+    #[test]
+    fn exact_filters_match_only_themselves() {
+        assert!(matches("picow/pause/set", "picow/pause/set"));
+        assert!(!matches("picow/pause/set", "picow/pause/set/more"));
+        assert!(!matches("picow/pause/set", "picow/pause"));
+    }
+
+    #[test]
+    fn reserved_topics_dont_match_leading_wildcards() {
+        assert!(!matches("#", "$SYS/uptime"));
+        assert!(!matches("+/uptime", "$SYS/uptime"));
+        assert!(matches("$SYS/#", "$SYS/uptime"));
+    }
+
+    #[test]
+    fn levels_are_joined_with_one_slash() {
+        assert_eq!(
+            topic(&["picow", "light", "set"]).unwrap(),
+            "picow/light/set"
+        );
+        assert_eq!(topic(&["picow/", "light/set"]).unwrap(), "picow/light/set");
+        assert_eq!(topic(&["/picow/", "/light/"]).unwrap(), "picow/light");
+    }
+
+    #[test]
+    fn empty_levels_are_rejected() {
+        assert_eq!(topic(&[]), Err(TopicError::Empty));
+        assert_eq!(topic(&["picow", "", "set"]), Err(TopicError::EmptyLevel));
+        assert_eq!(topic(&["picow", "/"]), Err(TopicError::EmptyLevel));
+        assert_eq!(topic(&["picow//light", "set"]), Err(TopicError::EmptyLevel));
+    }
+
+    #[test]
+    fn wildcards_cant_be_published_to() {
+        assert_eq!(topic(&["picow", "+", "set"]), Err(TopicError::Wildcard));
+        assert_eq!(topic(&["picow/#"]), Err(TopicError::Wildcard));
+    }
+
+    #[test]
+    fn long_topics_are_rejected() {
+        assert_eq!(topic(&["picow", &"a".repeat(27)]), Err(TopicError::TooLong));
+        assert_eq!(topic(&["picow", &"a".repeat(26)]).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn levels_are_counted_from_zero() {
+        assert_eq!(level("picow/debug/streams/get", 2), Some("streams"));
+        assert_eq!(level("picow/debug", 2), None);
+    }
+}
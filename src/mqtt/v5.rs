@@ -0,0 +1,592 @@
+//! MQTT 5 on top of the 3.1.1 codec of mqttrs.
+//!
+//! Most packets only differ from 3.1.1 by the properties MQTT 5 added to them. Packets to send are
+//! encoded by mqttrs and get their properties inserted, received ones have them removed before
+//! mqttrs decodes them. Only CONNECT and PUBLISH are sent with properties, received properties
+//! are dropped.
+
+use core::ops::Range;
+
+use heapless::Vec;
+
+use super::error::{MqttError, Result};
+
+/// Longest property list sent with a packet. It's preceded by its length, 2 bytes at most.
+pub const MAX_PROPERTIES_LENGTH: usize = 256;
+
+pub type Properties = Vec<u8, MAX_PROPERTIES_LENGTH>;
+
+/// A name and value the broker passes on unchanged
+pub type UserProperty<'a> = (&'a str, &'a str);
+
+// Packet types, the upper bits of the first byte
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const DISCONNECT: u8 = 14;
+const AUTH: u8 = 15;
+
+// Property ids
+const MESSAGE_EXPIRY: u8 = 0x02;
+const CONTENT_TYPE: u8 = 0x03;
+const SESSION_EXPIRY: u8 = 0x11;
+const USER_PROPERTY: u8 = 0x26;
+
+/// Offset of the properties in the variable header of a CONNECT, after the protocol name, level,
+/// flags and keep alive
+const CONNECT_PROPERTIES: usize = 2 + 4 + 1 + 1 + 2;
+const PROTOCOL_LEVEL: usize = 6;
+const CONNECT_FLAGS: usize = 7;
+const WILL_FLAG: u8 = 0x04;
+
+#[derive(Clone, Copy, Default)]
+pub struct ConnectProperties<'a> {
+    /// Seconds the broker keeps the session after the connection is closed. Without it, the
+    /// session ends with the connection, even without `clean_session`.
+    pub session_expiry: Option<u32>,
+    pub user_properties: &'a [UserProperty<'a>],
+}
+
+impl ConnectProperties<'_> {
+    pub fn encode(&self) -> Result<Properties> {
+        let mut properties = Properties::new();
+        if let Some(seconds) = self.session_expiry {
+            write_u32(&mut properties, SESSION_EXPIRY, seconds)?;
+        }
+        write_user_properties(&mut properties, self.user_properties)?;
+
+        Ok(properties)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct PublishProperties<'a> {
+    /// Seconds after which the broker drops the message if it hasn't been delivered, also if
+    /// it's retained
+    pub message_expiry: Option<u32>,
+    /// MIME type of the payload, e.g. `application/json`
+    pub content_type: Option<&'a str>,
+    pub user_properties: &'a [UserProperty<'a>],
+}
+
+impl PublishProperties<'_> {
+    pub fn encode(&self) -> Result<Properties> {
+        let mut properties = Properties::new();
+        if let Some(seconds) = self.message_expiry {
+            write_u32(&mut properties, MESSAGE_EXPIRY, seconds)?;
+        }
+        if let Some(content_type) = self.content_type {
+            properties
+                .push(CONTENT_TYPE)
+                .map_err(|_| MqttError::PayloadTooLarge)?;
+            write_string(&mut properties, content_type)?;
+        }
+        write_user_properties(&mut properties, self.user_properties)?;
+
+        Ok(properties)
+    }
+}
+
+/// Turns the first `length` bytes of `buffer`, a packet encoded for 3.1.1, into MQTT 5. A CONNECT
+/// or PUBLISH gets `properties`, other packets with properties get an empty list. Returns the new
+/// length, or `PayloadTooLarge` if it doesn't fit into `buffer` anymore.
+pub fn upgrade(buffer: &mut [u8], length: usize, properties: &[u8]) -> Result<usize> {
+    let (header_length, body_length) = fixed_header(&buffer[..length])?;
+    let body = &buffer[header_length..length];
+
+    let mut list = Vec::<u8, { MAX_PROPERTIES_LENGTH + 2 }>::new();
+    write_length(&mut list, properties.len())?;
+    list.extend_from_slice(properties)
+        .map_err(|_| MqttError::PayloadTooLarge)?;
+    const EMPTY: &[u8] = &[0];
+
+    let mut inserts = Vec::<(usize, &[u8]), 2>::new();
+    match buffer[0] >> 4 {
+        CONNECT => {
+            if body.len() < CONNECT_PROPERTIES + 2 {
+                return Err(MqttError::EncodeError);
+            }
+            inserts.push((CONNECT_PROPERTIES, &list[..])).unwrap();
+            // The will gets properties of its own, in front of its topic after the client id
+            if body[CONNECT_FLAGS] & WILL_FLAG != 0 {
+                let client_id = CONNECT_PROPERTIES + 2 + read_u16(body, CONNECT_PROPERTIES)?;
+                inserts.push((client_id, EMPTY)).unwrap();
+            }
+            buffer[header_length + PROTOCOL_LEVEL] = 5;
+        }
+        PUBLISH => inserts
+            .push((publish_properties(buffer[0], body)?, &list[..]))
+            .unwrap(),
+        SUBSCRIBE | UNSUBSCRIBE => inserts.push((2, EMPTY)).unwrap(),
+        // The rest is the same in both versions, acknowledgements without a reason code mean
+        // success
+        _ => return Ok(length),
+    }
+
+    insert(buffer, header_length, body_length, &inserts)
+}
+
+/// Turns a received MQTT 5 packet into 3.1.1, in place. Returns the new length.
+pub fn downgrade(packet: &mut [u8]) -> Result<usize> {
+    let (header_length, body_length) = fixed_header(packet)?;
+    let first_byte = packet[0];
+    let body = &mut packet[header_length..];
+
+    let mut keep = Vec::<Range<usize>, 2>::new();
+    match first_byte >> 4 {
+        CONNACK => {
+            let reason = body.get_mut(1).ok_or(MqttError::DecodeError)?;
+            *reason = connect_return_code(*reason);
+            keep.push(0..2).unwrap();
+        }
+        PUBLISH => {
+            let start = publish_properties(first_byte, body)?;
+            let end = skip_properties(body, start)?;
+            keep.push(0..start).unwrap();
+            keep.push(end..body_length).unwrap();
+        }
+        // Only the packet id, the reason code is dropped
+        PUBACK | PUBREC | PUBREL | PUBCOMP | UNSUBACK => keep.push(0..2).unwrap(),
+        SUBACK => {
+            let end = skip_properties(body, 2)?;
+            // MQTT 5 has reasons for failures of its own, 3.1.1 only a single code
+            for code in &mut body[end..body_length] {
+                if *code >= 0x80 {
+                    *code = 0x80;
+                }
+            }
+            keep.push(0..2).unwrap();
+            keep.push(end..body_length).unwrap();
+        }
+        // Sent with a reason code by the broker
+        DISCONNECT => {}
+        // Only used for extended authentication, which isn't requested
+        AUTH => return Err(MqttError::DecodeError),
+        _ => return Ok(packet.len()),
+    }
+
+    if keep
+        .iter()
+        .any(|range| range.start > range.end || range.end > body_length)
+    {
+        return Err(MqttError::DecodeError);
+    }
+    remove(packet, header_length, &keep)
+}
+
+/// Length of the fixed header and remaining length of `packet`, which has to be complete
+fn fixed_header(packet: &[u8]) -> Result<(usize, usize)> {
+    let (body_length, length_bytes) = read_length(packet, 1)?;
+    let header_length = 1 + length_bytes;
+    if header_length + body_length != packet.len() {
+        return Err(MqttError::DecodeError);
+    }
+
+    Ok((header_length, body_length))
+}
+
+/// Offset of the properties in the body of a PUBLISH, after the topic and packet id
+fn publish_properties(first_byte: u8, body: &[u8]) -> Result<usize> {
+    let qos = (first_byte >> 1) & 0b11;
+    let offset = 2 + read_u16(body, 0)? + if qos > 0 { 2 } else { 0 };
+    if offset > body.len() {
+        return Err(MqttError::DecodeError);
+    }
+
+    Ok(offset)
+}
+
+/// End of the property list starting at `offset`
+fn skip_properties(body: &[u8], offset: usize) -> Result<usize> {
+    let (length, length_bytes) = read_length(body, offset)?;
+    let end = offset + length_bytes + length;
+    if end > body.len() {
+        return Err(MqttError::DecodeError);
+    }
+
+    Ok(end)
+}
+
+/// Maps the reason code of a CONNACK to the closest return code of 3.1.1
+fn connect_return_code(reason: u8) -> u8 {
+    match reason {
+        0x00 => 0,
+        // Unsupported protocol version
+        0x84 => 1,
+        // Client identifier not valid
+        0x85 => 2,
+        // Bad user name or password
+        0x86 => 4,
+        // Not authorized, banned
+        0x87 | 0x8A => 5,
+        _ => 3,
+    }
+}
+
+/// Inserts blocks of bytes at offsets of the body, in ascending order
+fn insert(
+    buffer: &mut [u8],
+    header_length: usize,
+    body_length: usize,
+    inserts: &[(usize, &[u8])],
+) -> Result<usize> {
+    let inserted: usize = inserts.iter().map(|(_, bytes)| bytes.len()).sum();
+    let mut header = Vec::<u8, 5>::new();
+    header.push(buffer[0]).unwrap();
+    write_length(&mut header, body_length + inserted)?;
+    let length = header.len() + body_length + inserted;
+    if length > buffer.len() {
+        return Err(MqttError::PayloadTooLarge);
+    }
+
+    // Everything only moves towards the end, so the last part goes first
+    let mut end = body_length;
+    let mut shift = header.len() + inserted;
+    for (offset, bytes) in inserts.iter().rev() {
+        buffer.copy_within(header_length + offset..header_length + end, offset + shift);
+        shift -= bytes.len();
+        buffer[offset + shift..offset + shift + bytes.len()].copy_from_slice(bytes);
+        end = *offset;
+    }
+    buffer.copy_within(header_length..header_length + end, shift);
+    buffer[..header.len()].copy_from_slice(&header);
+
+    Ok(length)
+}
+
+/// Keeps the ranges of the body, in ascending order, and drops the rest
+fn remove(packet: &mut [u8], header_length: usize, keep: &[Range<usize>]) -> Result<usize> {
+    let body_length = keep.iter().map(Range::len).sum();
+    let mut header = Vec::<u8, 5>::new();
+    header.push(packet[0]).unwrap();
+    write_length(&mut header, body_length)?;
+
+    // The header only gets shorter and everything moves towards the start, so nothing that's
+    // still needed is overwritten
+    packet[..header.len()].copy_from_slice(&header);
+    let mut position = header.len();
+    for range in keep {
+        packet.copy_within(
+            header_length + range.start..header_length + range.end,
+            position,
+        );
+        position += range.len();
+    }
+
+    Ok(position)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<usize> {
+    match bytes.get(offset..offset + 2) {
+        Some(&[high, low]) => Ok(u16::from_be_bytes([high, low]) as usize),
+        _ => Err(MqttError::DecodeError),
+    }
+}
+
+/// Reads a variable byte integer, returning it and the number of bytes it took
+fn read_length(bytes: &[u8], offset: usize) -> Result<(usize, usize)> {
+    let mut value = 0;
+    for (index, byte) in bytes.iter().skip(offset).take(4).enumerate() {
+        value |= (*byte as usize & 0x7F) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Ok((value, index + 1));
+        }
+    }
+
+    Err(MqttError::DecodeError)
+}
+
+fn write_length<const N: usize>(out: &mut Vec<u8, N>, mut value: usize) -> Result<()> {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let byte = if value > 0 { byte | 0x80 } else { byte };
+        out.push(byte).map_err(|_| MqttError::PayloadTooLarge)?;
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn write_u32(out: &mut Properties, id: u8, value: u32) -> Result<()> {
+    out.push(id).map_err(|_| MqttError::PayloadTooLarge)?;
+    out.extend_from_slice(&value.to_be_bytes())
+        .map_err(|_| MqttError::PayloadTooLarge)
+}
+
+fn write_string(out: &mut Properties, value: &str) -> Result<()> {
+    let length = u16::try_from(value.len()).map_err(|_| MqttError::PayloadTooLarge)?;
+    out.extend_from_slice(&length.to_be_bytes())
+        .map_err(|_| MqttError::PayloadTooLarge)?;
+    out.extend_from_slice(value.as_bytes())
+        .map_err(|_| MqttError::PayloadTooLarge)
+}
+
+fn write_user_properties(out: &mut Properties, properties: &[UserProperty<'_>]) -> Result<()> {
+    for (name, value) in properties {
+        out.push(USER_PROPERTY)
+            .map_err(|_| MqttError::PayloadTooLarge)?;
+        write_string(out, name)?;
+        write_string(out, value)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use mqttrs::{
+        Connack, ConnectReturnCode, LastWill, Packet, Pid, Protocol, Publish, QoS, QosPid,
+        SubscribeReturnCodes,
+    };
+
+    use super::{ConnectProperties, PublishProperties, downgrade};
+    use crate::mqtt::{error::MqttError, socket::encode_packet};
+
+    /// Encodes `packet` for MQTT 5
+    fn encode(packet: &Packet<'_>, properties: &[u8]) -> Vec<u8> {
+        let mut buffer = [0; 512];
+        let size = encode_packet(packet, properties, true, &mut buffer).unwrap();
+        buffer[..size].to_vec()
+    }
+
+    fn publish(qospid: QosPid) -> Packet<'static> {
+        Packet::Publish(Publish {
+            dup: false,
+            qospid,
+            retain: false,
+            topic_name: "a/b",
+            payload: b"{}",
+        })
+    }
+
+    /// Decodes a packet the broker sent with MQTT 5
+    fn decode(bytes: &mut [u8]) -> Packet<'_> {
+        let length = downgrade(bytes).unwrap();
+        mqttrs::decode_slice(&bytes[..length]).unwrap().unwrap()
+    }
+
+    #[test]
+    fn publish_has_a_content_type() {
+        let properties = PublishProperties {
+            content_type: Some("application/json"),
+            ..Default::default()
+        }
+        .encode()
+        .unwrap();
+
+        let mut expected = std::vec![0x30, 27, 0, 3, b'a', b'/', b'b', 19, 0x03, 0, 16];
+        expected.extend_from_slice(b"application/json{}");
+        assert_eq!(encode(&publish(QosPid::AtMostOnce), &properties), expected);
+    }
+
+    #[test]
+    fn publish_properties_follow_the_packet_id() {
+        let properties = PublishProperties {
+            message_expiry: Some(300),
+            user_properties: &[("k", "v")],
+            ..Default::default()
+        }
+        .encode()
+        .unwrap();
+        let pid = Pid::try_from(0x1234).unwrap();
+
+        let expected = std::vec![
+            0x32, 22, 0, 3, b'a', b'/', b'b', 0x12, 0x34, 12, 0x02, 0, 0, 0x01, 0x2C, 0x26, 0, 1,
+            b'k', 0, 1, b'v', b'{', b'}'
+        ];
+        assert_eq!(
+            encode(&publish(QosPid::AtLeastOnce(pid)), &properties),
+            expected
+        );
+    }
+
+    #[test]
+    fn publish_without_properties_has_an_empty_list() {
+        assert_eq!(
+            encode(&publish(QosPid::AtMostOnce), &[]),
+            [0x30, 8, 0, 3, b'a', b'/', b'b', 0, b'{', b'}']
+        );
+    }
+
+    #[test]
+    fn properties_are_dropped_for_311() {
+        let properties = PublishProperties {
+            content_type: Some("text/plain"),
+            ..Default::default()
+        }
+        .encode()
+        .unwrap();
+        let mut buffer = [0; 64];
+
+        let size = encode_packet(
+            &publish(QosPid::AtMostOnce),
+            &properties,
+            false,
+            &mut buffer,
+        );
+        assert_eq!(
+            &buffer[..size.unwrap()],
+            [0x30, 7, 0, 3, b'a', b'/', b'b', b'{', b'}']
+        );
+    }
+
+    #[test]
+    fn connect_announces_version_5() {
+        let properties = ConnectProperties {
+            session_expiry: Some(3600),
+            ..Default::default()
+        }
+        .encode()
+        .unwrap();
+        let connect = Packet::Connect(mqttrs::Connect {
+            protocol: Protocol::MQTT311,
+            keep_alive: 60,
+            client_id: "id",
+            clean_session: true,
+            last_will: Some(LastWill {
+                topic: "w",
+                message: b"x",
+                qos: QoS::AtMostOnce,
+                retain: false,
+            }),
+            username: None,
+            password: None,
+        });
+
+        let bytes = encode(&connect, &properties);
+
+        let mut expected = std::vec![0x10, 27, 0, 4, b'M', b'Q', b'T', b'T', 5, 0x06, 0, 60];
+        // Session expiry, then the client id and the will with its own empty properties
+        expected.extend_from_slice(&[5, 0x11, 0, 0, 0x0E, 0x10, 0, 2, b'i', b'd', 0]);
+        expected.extend_from_slice(&[0, 1, b'w', 0, 1, b'x']);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn subscribe_gets_empty_properties() {
+        let mut topics = heapless_07::Vec::new();
+        topics
+            .push(mqttrs::SubscribeTopic {
+                topic_path: heapless_07::String::from("t"),
+                qos: QoS::AtLeastOnce,
+            })
+            .unwrap();
+        let subscribe = Packet::Subscribe(mqttrs::Subscribe {
+            pid: Pid::try_from(1).unwrap(),
+            topics,
+        });
+
+        assert_eq!(encode(&subscribe, &[]), [0x82, 7, 0, 1, 0, 0, 1, b't', 1]);
+    }
+
+    #[test]
+    fn acknowledgements_are_unchanged() {
+        let puback = Packet::Puback(Pid::try_from(7).unwrap());
+
+        assert_eq!(encode(&puback, &[]), [0x40, 2, 0, 7]);
+        assert_eq!(encode(&Packet::Pingreq, &[]), [0xC0, 0]);
+    }
+
+    #[test]
+    fn packets_that_dont_fit_are_rejected() {
+        let properties = PublishProperties {
+            content_type: Some("application/json"),
+            ..Default::default()
+        }
+        .encode()
+        .unwrap();
+        let mut buffer = [0; 16];
+
+        assert!(matches!(
+            encode_packet(&publish(QosPid::AtMostOnce), &properties, true, &mut buffer),
+            Err(MqttError::PayloadTooLarge)
+        ));
+    }
+
+    #[test]
+    fn connack_properties_are_dropped() {
+        // Accepted, with a topic alias maximum of 10
+        let mut connack = [0x20, 6, 0, 0, 3, 0x22, 0, 10];
+
+        assert!(matches!(
+            decode(&mut connack),
+            Packet::Connack(Connack {
+                session_present: false,
+                code: ConnectReturnCode::Accepted
+            })
+        ));
+    }
+
+    #[test]
+    fn connack_reasons_are_mapped() {
+        let mut connack = [0x20, 3, 0, 0x86, 0];
+
+        assert!(matches!(
+            decode(&mut connack),
+            Packet::Connack(Connack {
+                code: ConnectReturnCode::BadUsernamePassword,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn received_publish_properties_are_dropped() {
+        let mut publish = std::vec![0x32, 0, 0, 1, b't', 0, 5, 3, 0x03, 0, 0, b'o', b'n'];
+        publish[1] = publish.len() as u8 - 2;
+
+        let Packet::Publish(publish) = decode(&mut publish) else {
+            panic!("not a publish");
+        };
+        assert_eq!((publish.topic_name, publish.payload), ("t", &b"on"[..]));
+        assert_eq!(
+            publish.qospid,
+            QosPid::AtLeastOnce(Pid::try_from(5).unwrap())
+        );
+    }
+
+    #[test]
+    fn suback_failures_are_mapped() {
+        let mut suback = [0x90, 5, 0, 1, 0, 0x01, 0x87];
+
+        let Packet::Suback(suback) = decode(&mut suback) else {
+            panic!("not a suback");
+        };
+        assert_eq!(
+            suback.return_codes[..],
+            [
+                SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+                SubscribeReturnCodes::Failure
+            ]
+        );
+    }
+
+    #[test]
+    fn acknowledgement_reasons_are_dropped() {
+        // No matching subscribers, with an empty property list
+        let mut puback = [0x40, 4, 0, 7, 0x10, 0];
+
+        assert!(matches!(decode(&mut puback), Packet::Puback(pid) if pid.get() == 7));
+    }
+
+    #[test]
+    fn truncated_properties_are_rejected() {
+        let mut publish = [0x30, 6, 0, 1, b't', 9, 0x03, 0];
+
+        assert!(matches!(
+            downgrade(&mut publish),
+            Err(MqttError::DecodeError)
+        ));
+    }
+}
@@ -0,0 +1,410 @@
+use core::{convert::Infallible, str::FromStr};
+
+use cyw43::{Control, JoinOptions, ScanOptions, ScanType};
+use cyw43_pio::{DEFAULT_CLOCK_DIVIDER, PioSpi};
+use defmt::*;
+use embassy_net::{Config, ConfigV4, DhcpConfig, Stack, StackResources, StaticConfigV4};
+use embassy_rp::{
+    bind_interrupts,
+    clocks::RoscRng,
+    dma::Channel,
+    gpio::{Level, Output},
+    peripherals::{DMA_CH9, PIO0},
+    pio::{InterruptHandler as PioInterruptHandler, Pio},
+};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use embedded_hal::digital::{ErrorType, OutputPin};
+use heapless::{String, Vec};
+use state::{Initialized, Joined, Uninitialized, WithStack};
+use static_cell::StaticCell;
+
+use super::{
+    AccessPoint, AddressSource, BoardConfig, DHCP_RETRY_TIMEOUT, DhcpOptions, MacAddress, Roaming,
+    RoamingOptions, await_lease,
+    error::{NetworkError, Result},
+    join_retry_delay, set_address_source,
+};
+use crate::{
+    event_log,
+    peripherals::{SparePio, WIFI_DMA_CHANNEL, WifiPeripherals},
+};
+
+/// APs kept from a scan, the weakest ones are dropped if there are more
+const MAX_ACCESS_POINTS: usize = 8;
+
+bind_interrupts!(struct Irqs {
+    PIO0_IRQ_0 => PioInterruptHandler<PIO0>;
+});
+
+#[embassy_executor::task]
+pub async fn wifi_task(runner: WiFiRunner<PIO0, 0, DMA_CH9>) -> ! {
+    runner.run().await;
+}
+
+#[embassy_executor::task]
+pub async fn network_task(mut runner: NetworkRunner) -> ! {
+    runner.run().await;
+}
+
+#[embassy_executor::task]
+pub async fn dhcp_retry_task(retry: DhcpRetry) {
+    retry.run().await;
+}
+
+/// Rejoins `ssid` when the current AP stays weak, so the firmware picks a stronger one, see
+/// `Roaming`. The driver is only locked while scanning or joining.
+pub async fn roam<'a: 'static>(
+    cyw43: &Mutex<NoopRawMutex, Cyw43<'a, Joined<'a>>>,
+    ssid: &str,
+    password: &str,
+    options: RoamingOptions,
+) -> ! {
+    let mut roaming = Roaming::new(options);
+
+    loop {
+        Timer::after(options.scan_interval).await;
+
+        let mut cyw43 = cyw43.lock().await;
+        let access_points = cyw43.scan(ssid).await;
+        if roaming.update(Instant::now(), &access_points) {
+            info!("Signal of the AP is weak, rejoining {}", ssid);
+            event_log::record("WiFi roaming");
+            cyw43.control.leave().await;
+            join_network(&mut cyw43.control, ssid, password).await;
+        }
+    }
+}
+
+/// Retries until the network has been joined. If several APs share the SSID, the firmware picks
+/// the strongest one.
+async fn join_network(control: &mut Control<'_>, ssid: &str, password: &str) {
+    let mut failed_attempts = 0;
+    loop {
+        let join_options = JoinOptions::new(password.as_bytes());
+        match control.join(ssid, join_options).await {
+            Ok(_) => return,
+            Err(err) => {
+                info!("Join failed with status={}", err.status);
+                Timer::after(join_retry_delay(failed_attempts, RoscRng.next_u32())).await;
+                failed_attempts += 1;
+            }
+        }
+    }
+}
+
+mod state {
+    use cyw43::NetDriver;
+    use embassy_net::{DhcpConfig, Stack};
+
+    use super::DhcpRetry;
+
+    pub struct Uninitialized<'a> {
+        pub(super) net_device: NetDriver<'a>,
+    }
+
+    pub struct Initialized<'a> {
+        pub(super) net_device: NetDriver<'a>,
+    }
+
+    pub struct WithStack<'a> {
+        pub(super) stack: Stack<'a>,
+        pub(super) dhcp_config: DhcpConfig,
+    }
+
+    pub struct Joined<'a> {
+        pub(super) stack: Stack<'a>,
+        pub(super) dhcp_retry: Option<DhcpRetry>,
+    }
+}
+
+/// Power pin of the WiFi chip. The driver sets it high to power the chip, which drives it low
+/// instead on boards with `BoardConfig::pwr_active_low`.
+pub struct PowerPin {
+    pin: Output<'static>,
+    board: BoardConfig,
+}
+
+impl PowerPin {
+    fn power(&mut self, powered: bool) {
+        self.pin
+            .set_level(Level::from(self.board.pwr_high(powered)));
+    }
+}
+
+impl ErrorType for PowerPin {
+    type Error = Infallible;
+}
+
+impl OutputPin for PowerPin {
+    fn set_low(&mut self) -> core::result::Result<(), Infallible> {
+        self.power(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> core::result::Result<(), Infallible> {
+        self.power(true);
+        Ok(())
+    }
+}
+
+pub struct Cyw43<'a, S> {
+    pub control: Control<'a>,
+    state: S,
+}
+
+pub type WiFiRunner<PIO, const SM: usize, DMA> =
+    cyw43::Runner<'static, PowerPin, PioSpi<'static, PIO, SM, DMA>>;
+
+pub type NetworkRunner = embassy_net::Runner<'static, cyw43::NetDriver<'static>>;
+
+impl<S> Cyw43<'_, S> {
+    /// Turns the onboard LED, which is wired to the WiFi chip rather than the RP2040, on or off
+    pub async fn set_onboard_led(&mut self, on: bool) {
+        self.control.gpio_set(0, on).await;
+    }
+}
+
+impl<'a: 'static> Cyw43<'a, Uninitialized<'a>> {
+    pub async fn new(
+        p: WifiPeripherals,
+        board: BoardConfig,
+    ) -> (
+        Cyw43<'a, Uninitialized<'a>>,
+        WiFiRunner<PIO0, 0, DMA_CH9>,
+        SparePio,
+    ) {
+        #[cfg(feature = "dev_firmware")]
+        let firmware = unsafe { core::slice::from_raw_parts(0x1010_0000 as *const u8, 231077) };
+
+        #[cfg(not(feature = "dev_firmware"))]
+        let firmware: &[u8] = include_bytes!("../../cyw43-firmware/43439A0.bin");
+
+        info!("Initializing PIO");
+        core::assert_eq!(p.dma.number(), WIFI_DMA_CHANNEL);
+
+        // Keeps the chip off until the driver starts it
+        let pwr = PowerPin {
+            pin: Output::new(p.pwr, Level::from(board.pwr_high(false))),
+            board,
+        };
+        let cs = Output::new(p.cs, Level::from(board.cs_high));
+        let Pio {
+            mut common,
+            irq0,
+            sm0,
+            sm1,
+            sm2,
+            sm3,
+            ..
+        } = Pio::new(p.pio, Irqs);
+        let spi = PioSpi::new(
+            &mut common,
+            sm0,
+            DEFAULT_CLOCK_DIVIDER,
+            irq0,
+            cs,
+            p.dio,
+            p.clk,
+            p.dma,
+        );
+
+        info!("Initializing cyw43 driver");
+
+        static STATE: StaticCell<cyw43::State> = StaticCell::new();
+        let state = STATE.init(cyw43::State::new());
+        let (net_device, control, runner) = cyw43::new(state, pwr, spi, firmware).await;
+
+        (
+            Cyw43 {
+                control,
+                state: Uninitialized { net_device },
+            },
+            runner,
+            SparePio {
+                common,
+                sm1,
+                sm2,
+                sm3,
+            },
+        )
+    }
+
+    pub async fn init(mut self) -> Cyw43<'a, Initialized<'a>> {
+        #[cfg(feature = "dev_firmware")]
+        let clm = unsafe { core::slice::from_raw_parts(0x1014_0000 as *const u8, 984) };
+
+        #[cfg(not(feature = "dev_firmware"))]
+        let clm: &[u8] = include_bytes!("../../cyw43-firmware/43439A0_clm.bin");
+
+        info!("Initializing control");
+        self.control.init(clm).await;
+
+        info!("Setting power management");
+        self.control
+            .set_power_management(cyw43::PowerManagementMode::PowerSave)
+            .await;
+
+        Cyw43 {
+            control: self.control,
+            state: Initialized {
+                net_device: self.state.net_device,
+            },
+        }
+    }
+}
+
+impl<'a: 'static> Cyw43<'a, Initialized<'a>> {
+    pub async fn init_stack(self, client_name: &str) -> (Cyw43<'a, WithStack<'a>>, NetworkRunner) {
+        let seed = RoscRng.next_u64();
+
+        let mut dhcp_config = DhcpConfig::default();
+        let str = String::from_str(client_name);
+        dhcp_config.hostname = Some(str.unwrap());
+
+        let net_config = Config::dhcpv4(dhcp_config.clone());
+        static RESOURCES: StaticCell<StackResources<16>> = StaticCell::new();
+
+        let (stack, runner) = embassy_net::new(
+            self.state.net_device,
+            net_config,
+            RESOURCES.init(StackResources::new()),
+            seed,
+        );
+
+        let mac_addr = stack.hardware_address();
+        info!("Hardware configured. MAC Address is {}", mac_addr);
+
+        (
+            Cyw43 {
+                control: self.control,
+                state: WithStack { stack, dhcp_config },
+            },
+            runner,
+        )
+    }
+}
+
+impl<'a: 'static> Cyw43<'a, WithStack<'a>> {
+    /// Joins the network, retrying until it succeeds. The firmware stays with the AP it picked,
+    /// see `roam` to move to a stronger one later.
+    pub async fn join(
+        mut self,
+        ssid: &str,
+        password: &str,
+        dhcp: DhcpOptions,
+    ) -> Result<Cyw43<'a, Joined<'a>>> {
+        info!("Trying to join {}", ssid);
+        join_network(&mut self.control, ssid, password).await;
+        info!("Joined network {}!", ssid);
+
+        let stack = self.state.stack;
+
+        let fallback = await_lease(
+            stack.wait_config_up(),
+            &dhcp,
+            stack.hardware_address().into(),
+        )
+        .await?;
+        let (address_source, dhcp_retry) = match fallback {
+            None => (AddressSource::Dhcp, None),
+            Some((config, source)) => {
+                stack.set_config_v4(ConfigV4::Static(config.clone()));
+                let retry = dhcp.retry_interval.map(|interval| DhcpRetry {
+                    stack,
+                    dhcp_config: self.state.dhcp_config,
+                    fallback: config,
+                    interval,
+                });
+                (source, retry)
+            }
+        };
+        set_address_source(address_source);
+
+        match stack.config_v4() {
+            Some(a) => info!("IP address is {} ({})", a.address, address_source),
+            None => return Err(NetworkError::NoAddress),
+        };
+
+        Ok(Cyw43 {
+            control: self.control,
+            state: Joined { stack, dhcp_retry },
+        })
+    }
+}
+
+impl<'a: 'static> Cyw43<'a, Joined<'a>> {
+    pub fn stack(&self) -> Stack<'a> {
+        self.state.stack
+    }
+
+    /// Keeps asking for a DHCP lease in the background if the fallback address is used, to be run
+    /// in `dhcp_retry_task`. `None` if the address came from DHCP, or retrying is disabled.
+    pub fn dhcp_retry(&mut self) -> Option<DhcpRetry> {
+        self.state.dhcp_retry.take()
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.state.stack.hardware_address().into()
+    }
+
+    /// APs broadcasting `ssid`, at most `MAX_ACCESS_POINTS` of the strongest ones
+    async fn scan(&mut self, ssid: &str) -> Vec<AccessPoint, MAX_ACCESS_POINTS> {
+        let mut options = ScanOptions::default();
+        options.ssid = String::try_from(ssid).ok();
+        // Probing is faster than waiting for beacons, so the radio is back on the channel sooner
+        options.scan_type = ScanType::Active;
+
+        let mut access_points = Vec::<AccessPoint, MAX_ACCESS_POINTS>::new();
+        let mut scanner = self.control.scan(options).await;
+        while let Some(bss) = scanner.next().await {
+            let found = AccessPoint {
+                bssid: bss.bssid,
+                rssi: bss.rssi,
+            };
+
+            // An AP may be reported once per beacon or probe response
+            if let Some(known) = access_points.iter_mut().find(|ap| ap.bssid == found.bssid) {
+                known.rssi = known.rssi.max(found.rssi);
+            } else if let Err(found) = access_points.push(found) {
+                let weakest = access_points.iter_mut().min_by_key(|ap| ap.rssi).unwrap();
+                if found.rssi > weakest.rssi {
+                    *weakest = found;
+                }
+            }
+        }
+
+        access_points
+    }
+}
+
+/// Returns to DHCP once a lease can be acquired, see `Cyw43::dhcp_retry`
+pub struct DhcpRetry {
+    stack: Stack<'static>,
+    dhcp_config: DhcpConfig,
+    fallback: StaticConfigV4,
+    interval: Duration,
+}
+
+impl DhcpRetry {
+    pub async fn run(self) {
+        loop {
+            Timer::after(self.interval).await;
+
+            self.stack
+                .set_config_v4(ConfigV4::Dhcp(self.dhcp_config.clone()));
+            if with_timeout(DHCP_RETRY_TIMEOUT, self.stack.wait_config_up())
+                .await
+                .is_ok()
+            {
+                info!("DHCP lease received, leaving the fallback address");
+                set_address_source(AddressSource::Dhcp);
+                return;
+            }
+
+            // The lease may still arrive later, but the fallback address is reachable right away
+            self.stack
+                .set_config_v4(ConfigV4::Static(self.fallback.clone()));
+        }
+    }
+}
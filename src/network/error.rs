@@ -0,0 +1,7 @@
+pub(crate) type Result<T> = core::result::Result<T, NetworkError>;
+
+#[derive(Debug, Clone)]
+pub(crate) enum NetworkError {
+    DhcpTimeout,
+    NoAddress,
+}
@@ -1,201 +1,469 @@
-use core::str::FromStr;
+use core::{cell::Cell, fmt::Write};
 
-use cyw43::{Control, JoinOptions};
-use cyw43_pio::{DEFAULT_CLOCK_DIVIDER, PioSpi};
 use defmt::*;
-use embassy_net::{Config, DhcpConfig, Stack, StackResources};
-use embassy_rp::{
-    bind_interrupts,
-    clocks::RoscRng,
-    gpio::{Level, Output},
-    peripherals::{DMA_CH9, PIO0},
-    pio::{InterruptHandler as PioInterruptHandler, Pio},
-};
-use embassy_time::{Duration, Timer, with_timeout};
-use heapless::String;
-use state::{Initialized, Joined, Uninitialized, WithStack};
-use static_cell::StaticCell;
+use embassy_net::{HardwareAddress, Ipv4Address, Ipv4Cidr, StaticConfigV4};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, with_timeout};
+use error::{NetworkError, Result};
+use heapless::{String, Vec};
+
+#[cfg(target_os = "none")]
+pub use driver::{Cyw43, dhcp_retry_task, network_task, roam, wifi_task};
+
+#[cfg(target_os = "none")]
+mod driver;
+mod error;
+
+pub struct DhcpOptions {
+    pub timeout: Duration,
+    pub fallback: Option<DhcpFallback>,
+    /// How often to ask for a lease again while on the fallback address. Every attempt takes the
+    /// device off the network for up to `DHCP_RETRY_TIMEOUT`. `None` keeps the fallback address
+    /// until the next reboot.
+    pub retry_interval: Option<Duration>,
+}
 
-use crate::peripherals::WifiPeripherals;
+impl Default for DhcpOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            fallback: None,
+            retry_interval: Some(Duration::from_secs(300)),
+        }
+    }
+}
 
-bind_interrupts!(struct Irqs {
-    PIO0_IRQ_0 => PioInterruptHandler<PIO0>;
-});
+/// Wiring of the WiFi chip, for boards that differ from the Pico W
+#[derive(Clone, Copy)]
+pub struct BoardConfig {
+    /// The chip is powered while its power pin is low, instead of high
+    pub pwr_active_low: bool,
+    /// Initial level of the SPI chip select, `true` for high. The chip has to start deselected.
+    pub cs_high: bool,
+}
 
-#[embassy_executor::task]
-pub async fn wifi_task(runner: WiFiRunner<PIO0, 0, DMA_CH9>) -> ! {
-    runner.run().await;
+impl Default for BoardConfig {
+    /// Wiring of the Pico W
+    fn default() -> Self {
+        Self {
+            pwr_active_low: false,
+            cs_high: true,
+        }
+    }
 }
 
-#[embassy_executor::task]
-pub async fn network_task(mut runner: NetworkRunner) -> ! {
-    runner.run().await;
+impl BoardConfig {
+    /// Level of the power pin, `true` for high, that powers the chip on or off
+    pub fn pwr_high(self, powered: bool) -> bool {
+        powered != self.pwr_active_low
+    }
+}
+
+/// Time a later attempt gets to receive a DHCP lease, before returning to the fallback address
+const DHCP_RETRY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address configuration used when no DHCP lease could be acquired in time
+#[allow(unused)]
+pub enum DhcpFallback {
+    /// A link-local address in 169.254.0.0/16, derived from the MAC address
+    LinkLocal,
+    Static(StaticConfigV4),
 }
 
-mod state {
-    use cyw43::NetDriver;
-    use embassy_net::Stack;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// Formats the address as lowercase hex digits without separators, e.g. `28cdc1000001`, which
+    /// is safe to use in MQTT topics and Home Assistant ids
+    pub fn to_topic_string(self) -> String<12> {
+        let mut string = String::new();
+        for byte in self.0 {
+            // Can't overflow, 6 bytes always format to exactly 12 characters
+            core::write!(string, "{:02x}", byte).unwrap();
+        }
 
-    pub struct Uninitialized<'a> {
-        pub(super) net_device: NetDriver<'a>,
+        string
     }
+}
 
-    pub struct Initialized<'a> {
-        pub(super) net_device: NetDriver<'a>,
+impl From<HardwareAddress> for MacAddress {
+    fn from(value: HardwareAddress) -> Self {
+        match value {
+            HardwareAddress::Ethernet(address) => MacAddress(address.0),
+            #[allow(unreachable_patterns)]
+            _ => MacAddress([0; 6]),
+        }
     }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum AddressSource {
+    Dhcp,
+    LinkLocal,
+    Static,
+}
+
+impl AddressSource {
+    #[cfg_attr(not(feature = "udp_status"), allow(unused))]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AddressSource::Dhcp => "dhcp",
+            AddressSource::LinkLocal => "link_local",
+            AddressSource::Static => "static",
+        }
+    }
+}
+
+static ADDRESS_SOURCE: Mutex<CriticalSectionRawMutex, Cell<Option<AddressSource>>> =
+    Mutex::new(Cell::new(None));
 
-    pub struct WithStack<'a> {
-        pub(super) stack: Stack<'a>,
+/// Where the current address came from, `None` until the network has been joined
+#[cfg_attr(not(feature = "udp_status"), allow(unused))]
+pub fn address_source() -> Option<AddressSource> {
+    ADDRESS_SOURCE.lock(Cell::get)
+}
+
+fn set_address_source(source: AddressSource) {
+    ADDRESS_SOURCE.lock(|shared| shared.set(Some(source)));
+}
+
+/// Waits up to `dhcp.timeout` for `lease`. Returns the address to fall back to if it didn't
+/// arrive in time, or `None` if it did.
+async fn await_lease(
+    lease: impl Future<Output = ()>,
+    dhcp: &DhcpOptions,
+    mac_address: MacAddress,
+) -> Result<Option<(StaticConfigV4, AddressSource)>> {
+    if with_timeout(dhcp.timeout, lease).await.is_ok() {
+        return Ok(None);
     }
 
-    pub struct Joined<'a> {
-        pub(super) stack: Stack<'a>,
+    warn!(
+        "No DHCP lease received after {} seconds",
+        dhcp.timeout.as_secs()
+    );
+
+    match &dhcp.fallback {
+        Some(DhcpFallback::LinkLocal) => Ok(Some((
+            link_local_config(mac_address),
+            AddressSource::LinkLocal,
+        ))),
+        Some(DhcpFallback::Static(config)) => Ok(Some((config.clone(), AddressSource::Static))),
+        None => Err(NetworkError::DhcpTimeout),
     }
 }
 
-pub struct Cyw43<'a, S> {
-    pub control: Control<'a>,
-    state: S,
+/// Delay after the first failed join, doubled after every further one
+const JOIN_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Upper limit of the delay between join attempts
+const MAX_JOIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Exponential backoff with jitter: a delay between half and all of the backed-off one, so
+/// devices rebooting together after a power outage don't hit the AP in lockstep
+fn join_retry_delay(failed_attempts: u32, random: u32) -> Duration {
+    let backoff = JOIN_RETRY_DELAY
+        .as_millis()
+        .saturating_mul(1 << failed_attempts.min(16))
+        .min(MAX_JOIN_RETRY_DELAY.as_millis());
+    let jitter = random as u64 % (backoff / 2 + 1);
+
+    Duration::from_millis(backoff / 2 + jitter)
 }
 
-pub type WiFiRunner<PIO, const SM: usize, DMA> =
-    cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO, SM, DMA>>;
+/// When to move to a stronger AP with the same SSID, see `roam`
+#[derive(Clone, Copy)]
+pub struct RoamingOptions {
+    /// Signal strength in dBm below which the current AP is considered weak
+    pub threshold: i16,
+    /// How long the signal has to stay below `threshold` before roaming
+    pub dwell_time: Duration,
+    /// Time between scans. Every scan takes the radio off the channel for a moment.
+    pub scan_interval: Duration,
+    /// How many dB stronger another AP has to be, so the device doesn't hop between two similar
+    /// ones
+    pub min_improvement: i16,
+}
 
-pub type NetworkRunner = embassy_net::Runner<'static, cyw43::NetDriver<'static>>;
+impl Default for RoamingOptions {
+    fn default() -> Self {
+        Self {
+            threshold: -75,
+            dwell_time: Duration::from_secs(60),
+            scan_interval: Duration::from_secs(15),
+            min_improvement: 8,
+        }
+    }
+}
 
-impl<'a: 'static> Cyw43<'a, Uninitialized<'a>> {
-    pub async fn new(
-        p: WifiPeripherals,
-    ) -> (Cyw43<'a, Uninitialized<'a>>, WiFiRunner<PIO0, 0, DMA_CH9>) {
-        #[cfg(feature = "dev_firmware")]
-        let firmware = unsafe { core::slice::from_raw_parts(0x1010_0000 as *const u8, 231077) };
+/// An AP found by a scan
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct AccessPoint {
+    pub bssid: [u8; 6],
+    /// Signal strength in dBm
+    pub rssi: i16,
+}
 
-        #[cfg(not(feature = "dev_firmware"))]
-        let firmware: &[u8] = include_bytes!("../../cyw43-firmware/43439A0.bin");
+/// Decides when to roam, from the APs seen by consecutive scans.
+///
+/// cyw43 doesn't tell which AP it joined. Since the firmware joins the strongest one, the
+/// strongest AP of the first scan after joining is taken as the current one.
+pub struct Roaming {
+    options: RoamingOptions,
+    current: Option<[u8; 6]>,
+    /// Since when the current AP has been weak
+    weak_since: Option<Instant>,
+}
 
-        info!("Initializing PIO");
+impl Roaming {
+    pub fn new(options: RoamingOptions) -> Self {
+        Self {
+            options,
+            current: None,
+            weak_since: None,
+        }
+    }
 
-        let pwr = Output::new(p.pwr, Level::Low);
-        let cs = Output::new(p.cs, Level::High);
-        let mut pio = Pio::new(p.pio, Irqs);
-        let spi = PioSpi::new(
-            &mut pio.common,
-            pio.sm0,
-            DEFAULT_CLOCK_DIVIDER,
-            pio.irq0,
-            cs,
-            p.dio,
-            p.clk,
-            p.dma,
-        );
+    /// Returns whether to rejoin, because the current AP has been weak for the dwell time and
+    /// another one is clearly stronger. The current AP is learned again from the next scan then.
+    pub fn update(&mut self, now: Instant, access_points: &[AccessPoint]) -> bool {
+        let Some(current) = self.current else {
+            self.current = strongest(access_points.iter()).map(|ap| ap.bssid);
+            return false;
+        };
 
-        info!("Initializing cyw43 driver");
+        // An AP that's no longer found at all is as weak as it gets
+        let rssi = access_points
+            .iter()
+            .find(|ap| ap.bssid == current)
+            .map_or(i16::MIN, |ap| ap.rssi);
+        if rssi >= self.options.threshold {
+            self.weak_since = None;
+            return false;
+        }
 
-        static STATE: StaticCell<cyw43::State> = StaticCell::new();
-        let state = STATE.init(cyw43::State::new());
-        let (net_device, control, runner) = cyw43::new(state, pwr, spi, firmware).await;
+        let weak_since = *self.weak_since.get_or_insert(now);
+        if now.saturating_duration_since(weak_since) < self.options.dwell_time {
+            return false;
+        }
 
-        (
-            Cyw43 {
-                control,
-                state: Uninitialized { net_device },
-            },
-            runner,
-        )
+        let others = access_points.iter().filter(|ap| ap.bssid != current);
+        match strongest(others) {
+            Some(other) if other.rssi >= rssi.saturating_add(self.options.min_improvement) => {
+                self.current = None;
+                self.weak_since = None;
+                true
+            }
+            _ => false,
+        }
     }
+}
 
-    pub async fn init(mut self) -> Cyw43<'a, Initialized<'a>> {
-        #[cfg(feature = "dev_firmware")]
-        let clm = unsafe { core::slice::from_raw_parts(0x1014_0000 as *const u8, 984) };
+fn strongest<'a>(access_points: impl Iterator<Item = &'a AccessPoint>) -> Option<&'a AccessPoint> {
+    access_points.max_by_key(|ap| ap.rssi)
+}
 
-        #[cfg(not(feature = "dev_firmware"))]
-        let clm: &[u8] = include_bytes!("../../cyw43-firmware/43439A0_clm.bin");
+/// Picks an address in 169.254.1.0 - 169.254.254.255 based on the MAC address, so it stays the
+/// same across reboots
+fn link_local_config(mac_address: MacAddress) -> StaticConfigV4 {
+    let [.., high, low] = mac_address.0;
 
-        info!("Initializing control");
-        self.control.init(clm).await;
+    StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(169, 254, 1 + high % 254, low), 16),
+        gateway: None,
+        dns_servers: Vec::new(),
+    }
+}
 
-        info!("Setting power management");
-        self.control
-            .set_power_management(cyw43::PowerManagementMode::PowerSave)
-            .await;
+#[cfg(test)]
+mod tests {
+    use core::future::{pending, ready};
 
-        Cyw43 {
-            control: self.control,
-            state: Initialized {
-                net_device: self.state.net_device,
-            },
+    use embassy_futures::block_on;
+    use embassy_time::{Duration, Instant};
+
+    use super::{
+        AccessPoint, AddressSource, BoardConfig, DhcpFallback, DhcpOptions, JOIN_RETRY_DELAY,
+        MAX_JOIN_RETRY_DELAY, MacAddress, Roaming, RoamingOptions, await_lease, join_retry_delay,
+    };
+    use crate::network::error::NetworkError;
+
+    const MAC_ADDRESS: MacAddress = MacAddress([0x28, 0xCD, 0xC1, 0x00, 0x0A, 0xFF]);
+
+    #[test]
+    fn power_pins_are_inverted_on_request() {
+        let pico_w = BoardConfig::default();
+        assert!(!pico_w.pwr_high(false));
+        assert!(pico_w.pwr_high(true));
+        assert!(pico_w.cs_high);
+
+        let inverted = BoardConfig {
+            pwr_active_low: true,
+            ..pico_w
+        };
+        assert!(inverted.pwr_high(false));
+        assert!(!inverted.pwr_high(true));
+    }
+
+    fn options(fallback: Option<DhcpFallback>) -> DhcpOptions {
+        DhcpOptions {
+            timeout: Duration::from_ticks(0),
+            fallback,
+            retry_interval: None,
         }
     }
-}
 
-impl<'a: 'static> Cyw43<'a, Initialized<'a>> {
-    pub async fn init_stack(self, client_name: &str) -> (Cyw43<'a, WithStack<'a>>, NetworkRunner) {
-        let seed = RoscRng.next_u64();
+    #[test]
+    fn lease_in_time_needs_no_fallback() {
+        let fallback = block_on(await_lease(
+            ready(()),
+            &options(Some(DhcpFallback::LinkLocal)),
+            MAC_ADDRESS,
+        ));
 
-        let mut dhcp_config = DhcpConfig::default();
-        let str = String::from_str(client_name);
-        dhcp_config.hostname = Some(str.unwrap());
+        assert!(matches!(fallback, Ok(None)));
+    }
 
-        let net_config = Config::dhcpv4(dhcp_config);
-        static RESOURCES: StaticCell<StackResources<16>> = StaticCell::new();
+    #[test]
+    fn timeout_falls_back_to_link_local() {
+        let fallback = block_on(await_lease(
+            pending(),
+            &options(Some(DhcpFallback::LinkLocal)),
+            MAC_ADDRESS,
+        ));
 
-        let (stack, runner) = embassy_net::new(
-            self.state.net_device,
-            net_config,
-            RESOURCES.init(StackResources::new()),
-            seed,
-        );
+        let Ok(Some((config, AddressSource::LinkLocal))) = fallback else {
+            panic!("no link-local fallback");
+        };
+        assert_eq!(config.address.address().octets(), [169, 254, 11, 0xFF]);
+    }
 
-        let mac_addr = stack.hardware_address();
-        info!("Hardware configured. MAC Address is {}", mac_addr);
+    #[test]
+    fn timeout_without_fallback_fails() {
+        let fallback = block_on(await_lease(pending(), &options(None), MAC_ADDRESS));
 
-        (
-            Cyw43 {
-                control: self.control,
-                state: WithStack { stack },
-            },
-            runner,
-        )
+        assert!(matches!(fallback, Err(NetworkError::DhcpTimeout)));
     }
-}
 
-impl<'a: 'static> Cyw43<'a, WithStack<'a>> {
-    pub async fn join(mut self, ssid: &str, password: &str) -> Cyw43<'a, Joined<'a>> {
-        info!("Trying to join {}", ssid);
+    const RANDOM: [u32; 5] = [0, 1, 12345, 0x8000_0000, u32::MAX];
 
-        loop {
-            let join_options = JoinOptions::new(password.as_bytes());
-            match self.control.join(ssid, join_options).await {
-                Ok(_) => break,
-                Err(err) => {
-                    info!("Join failed with status={}", err.status);
-                    Timer::after_millis(500).await;
-                }
+    #[test]
+    fn join_retry_delay_stays_within_bounds() {
+        for failed_attempts in 0..40 {
+            let backoff =
+                (JOIN_RETRY_DELAY * (1 << failed_attempts.min(16))).min(MAX_JOIN_RETRY_DELAY);
+
+            for random in RANDOM {
+                let delay = join_retry_delay(failed_attempts, random);
+                assert!(delay >= backoff / 2 && delay <= backoff);
             }
         }
+    }
 
-        info!("Joined network {}!", ssid);
+    #[test]
+    fn join_retry_delay_backs_off() {
+        let first = RANDOM.map(|random| join_retry_delay(0, random));
+        assert!(first.iter().all(|delay| *delay <= JOIN_RETRY_DELAY));
 
-        let stack = self.state.stack;
+        let later = RANDOM.map(|random| join_retry_delay(10, random));
+        assert!(later.iter().all(|delay| *delay >= MAX_JOIN_RETRY_DELAY / 2));
+    }
 
-        with_timeout(Duration::from_secs(60), stack.wait_config_up())
-            .await
-            .expect("Failed to establish network connection after 60 seconds");
+    #[test]
+    fn join_retry_delay_varies_with_the_rng() {
+        let delays = RANDOM.map(|random| join_retry_delay(3, random));
 
-        match stack.config_v4() {
-            Some(a) => info!("IP address is {}", a.address),
-            None => core::panic!("No IP address received from DHCP"),
-        };
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
 
-        Cyw43 {
-            control: self.control,
-            state: Joined { stack },
-        }
+    const JOINED: [u8; 6] = [1; 6];
+    const OTHER: [u8; 6] = [2; 6];
+
+    fn scan(joined: i16, other: i16) -> [AccessPoint; 2] {
+        [
+            AccessPoint {
+                bssid: JOINED,
+                rssi: joined,
+            },
+            AccessPoint {
+                bssid: OTHER,
+                rssi: other,
+            },
+        ]
+    }
+
+    fn at(secs: u64) -> Instant {
+        Instant::from_secs(secs)
+    }
+
+    /// Has learned the current AP from a first scan at 0s
+    fn roaming() -> Roaming {
+        let mut roaming = Roaming::new(RoamingOptions {
+            threshold: -75,
+            dwell_time: Duration::from_secs(30),
+            scan_interval: Duration::from_secs(10),
+            min_improvement: 8,
+        });
+        assert!(!roaming.update(at(0), &scan(-60, -70)));
+        roaming
+    }
+
+    #[test]
+    fn roams_after_being_weak_for_the_dwell_time() {
+        let mut roaming = roaming();
+
+        assert!(!roaming.update(at(10), &scan(-80, -60)));
+        assert!(!roaming.update(at(20), &scan(-82, -60)));
+        assert!(!roaming.update(at(39), &scan(-80, -60)));
+        assert!(roaming.update(at(40), &scan(-81, -60)));
+    }
+
+    #[test]
+    fn recovering_signal_restarts_the_dwell_time() {
+        let mut roaming = roaming();
+
+        assert!(!roaming.update(at(10), &scan(-80, -60)));
+        assert!(!roaming.update(at(30), &scan(-70, -60)));
+        assert!(!roaming.update(at(40), &scan(-80, -60)));
+        assert!(!roaming.update(at(60), &scan(-80, -60)));
+        assert!(roaming.update(at(70), &scan(-80, -60)));
+    }
+
+    #[test]
+    fn stays_without_a_clearly_stronger_ap() {
+        let mut roaming = roaming();
+
+        assert!(!roaming.update(at(10), &scan(-80, -75)));
+        assert!(!roaming.update(at(50), &scan(-80, -75)));
+        // Once there is one, it roams right away
+        assert!(roaming.update(at(60), &scan(-80, -72)));
+    }
+
+    #[test]
+    fn a_lost_ap_is_weak() {
+        let mut roaming = roaming();
+        let others = [AccessPoint {
+            bssid: OTHER,
+            rssi: -85,
+        }];
+
+        assert!(!roaming.update(at(10), &others));
+        assert!(roaming.update(at(40), &others));
+    }
+
+    #[test]
+    fn the_ap_is_learned_again_after_roaming() {
+        let mut roaming = roaming();
+        assert!(!roaming.update(at(10), &scan(-80, -60)));
+        assert!(roaming.update(at(40), &scan(-80, -60)));
+
+        // Now joined to the other one, the first AP being weak doesn't matter
+        assert!(!roaming.update(at(50), &scan(-90, -60)));
+        assert!(!roaming.update(at(100), &scan(-90, -60)));
     }
-}
 
-impl<'a: 'static> Cyw43<'a, Joined<'a>> {
-    pub fn stack(&self) -> Stack<'a> {
-        self.state.stack
+    #[test]
+    fn mac_address_formats_as_lowercase_hex() {
+        assert_eq!(MAC_ADDRESS.to_topic_string(), "28cdc1000aff");
     }
 }
@@ -0,0 +1,96 @@
+use assign_resources::assign_resources;
+use embassy_rp::{
+    Peri,
+    pac::SIO,
+    peripherals::{self, PIO0},
+    pio::{Common, StateMachine},
+};
+
+use super::{CRITICAL_SECTION_SPINLOCK, critical_section_lock_held};
+
+// Every color pin of the first LED zone has to be output A of its PWM slice, see `pwm_slice_of`.
+//
+// The DMA channels have to match `LED_DMA_CHANNELS` and `WIFI_DMA_CHANNEL`, which the drivers
+// check at startup. The macro moves every peripheral out of `Peripherals`, so assigning a channel
+// twice fails to compile, and the raw DMA registers are only accessed through the numbers of
+// channels owned this way.
+assign_resources! {
+  led: LedPeripherals {
+    pio: PIO1,
+    red_pin: PIN_4,
+    red_slice: PWM_SLICE2,
+    green_pin: PIN_6,
+    green_slice: PWM_SLICE3,
+    blue_pin: PIN_2,
+    blue_slice: PWM_SLICE1,
+    dma_pwm_red_a: DMA_CH0,
+    dma_pwm_red_b: DMA_CH1,
+    dma_pwm_green_a: DMA_CH2,
+    dma_pwm_green_b: DMA_CH3,
+    dma_pwm_blue_a: DMA_CH4,
+    dma_pwm_blue_b: DMA_CH5,
+    dma_pio_red: DMA_CH6,
+    dma_pio_green: DMA_CH7,
+    dma_pio_blue: DMA_CH8,
+    sync_pin: PIN_14,
+  },
+  wifi: WifiPeripherals {
+    pio: PIO0,
+    pwr: PIN_23,
+    cs: PIN_25,
+    dio: PIN_24,
+    clk: PIN_29,
+    dma: DMA_CH9,
+  },
+  second_zone: SecondZonePeripherals {
+    red_pin: PIN_10,
+    green_pin: PIN_11,
+    blue_pin: PIN_12,
+  },
+  white: WhitePeripherals {
+    warm_pin: PIN_8,
+    cool_pin: PIN_9,
+    slice: PWM_SLICE4,
+  },
+  power: PowerPeripherals {
+    wake_pin: PIN_15,
+  },
+  button: ButtonPeripherals {
+    pin: PIN_16,
+  },
+  encoder: EncoderPeripherals {
+    a_pin: PIN_17,
+    b_pin: PIN_18,
+  },
+  temperature: TemperaturePeripherals {
+    adc: ADC,
+    sensor: ADC_TEMP_SENSOR,
+  },
+}
+
+/// The parts of PIO0 the WiFi driver leaves free, it only runs on SM0. The second LED zone runs
+/// on them.
+#[cfg_attr(not(feature = "second_zone"), allow(unused))]
+pub struct SparePio {
+    pub common: Common<'static, PIO0>,
+    pub sm1: StateMachine<'static, PIO0, 1>,
+    pub sm2: StateMachine<'static, PIO0, 2>,
+    pub sm3: StateMachine<'static, PIO0, 3>,
+}
+
+/// Releases the spinlock backing critical sections, returning the state of all spinlocks before.
+///
+/// Spinlocks aren't reset by a soft reset, e.g. when flashing through a debug probe. If the
+/// reset happened inside a critical section, the lock is still held and the first critical
+/// section after boot would deadlock. Must be called before `embassy_rp::init`, while nothing
+/// can be inside a critical section yet.
+pub fn release_critical_section_lock() -> u32 {
+    let locked = SIO.spinlock_st().read();
+    SIO.spinlock(CRITICAL_SECTION_SPINLOCK).write_value(1);
+    debug_assert!(
+        !critical_section_lock_held(SIO.spinlock_st().read()),
+        "Critical section lock still held after releasing it"
+    );
+
+    locked
+}
@@ -1,31 +1,125 @@
-use assign_resources::assign_resources;
-use embassy_rp::{Peri, peripherals};
-
-assign_resources! {
-  led: LedPeripherals {
-    pio: PIO1,
-    red_pin: PIN_4,
-    red_slice: PWM_SLICE2,
-    green_pin: PIN_6,
-    green_slice: PWM_SLICE3,
-    blue_pin: PIN_2,
-    blue_slice: PWM_SLICE1,
-    dma_pwm_red_a: DMA_CH0,
-    dma_pwm_red_b: DMA_CH1,
-    dma_pwm_green_a: DMA_CH2,
-    dma_pwm_green_b: DMA_CH3,
-    dma_pwm_blue_a: DMA_CH4,
-    dma_pwm_blue_b: DMA_CH5,
-    dma_pio_red: DMA_CH6,
-    dma_pio_green: DMA_CH7,
-    dma_pio_blue: DMA_CH8,
-  },
-  wifi: WifiPeripherals {
-    pio: PIO0,
-    pwr: PIN_23,
-    cs: PIN_25,
-    dio: PIN_24,
-    clk: PIN_29,
-    dma: DMA_CH9,
-  },
+#[cfg(target_os = "none")]
+mod driver;
+
+#[cfg(target_os = "none")]
+pub use driver::{
+    AssignedResources, ButtonPeripherals, EncoderPeripherals, LedPeripherals, PowerPeripherals,
+    SecondZonePeripherals, SparePio, TemperaturePeripherals, WhitePeripherals, WifiPeripherals,
+    release_critical_section_lock,
+};
+
+/// Spinlock claimed by the `critical-section` implementation of embassy-rp
+pub const CRITICAL_SECTION_SPINLOCK: usize = 31;
+
+/// Whether the critical section lock is held in `state`, the state of all spinlocks. Before
+/// booting, it means a soft reset hit a critical section.
+pub const fn critical_section_lock_held(state: u32) -> bool {
+    state & (1 << CRITICAL_SECTION_SPINLOCK) != 0
+}
+
+/// Output of a PWM slice
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PwmChannel {
+    A,
+    B,
+}
+
+/// The PWM slice and output `gpio` is connected to. GPIO 2n is output A of slice n % 8, e.g.
+/// GPIO 4 and GPIO 20 for slice 2, and the odd pins are the B outputs.
+pub const fn pwm_slice_of(gpio: u8) -> (usize, PwmChannel) {
+    let channel = if gpio.is_multiple_of(2) {
+        PwmChannel::A
+    } else {
+        PwmChannel::B
+    };
+
+    ((gpio as usize / 2) % 8, channel)
+}
+
+/// DMA channels of the first LED zone, in the order of `LedPeripherals`: a ping-pong pair per
+/// color copying the PWM duties, then one per color feeding the timing program
+pub const LED_DMA_CHANNELS: [u8; 9] = [0, 1, 2, 3, 4, 5, 6, 7, 8];
+
+/// DMA channel of the WiFi driver. CH10 and CH11 of the 12 channels are left, the second LED
+/// zone doesn't need any.
+pub const WIFI_DMA_CHANNEL: u8 = 9;
+
+/// Every DMA channel in use
+const DMA_CHANNELS: [u8; LED_DMA_CHANNELS.len() + 1] = {
+    let mut channels = [WIFI_DMA_CHANNEL; LED_DMA_CHANNELS.len() + 1];
+    let mut index = 0;
+    while index < LED_DMA_CHANNELS.len() {
+        channels[index] = LED_DMA_CHANNELS[index];
+        index += 1;
+    }
+    channels
+};
+
+const _: () = core::assert!(
+    dma_channels_distinct(&DMA_CHANNELS),
+    "DMA channel assigned twice"
+);
+
+/// Whether no channel appears twice in `channels`
+const fn dma_channels_distinct(channels: &[u8]) -> bool {
+    let mut index = 0;
+    while index < channels.len() {
+        let mut other = index + 1;
+        while other < channels.len() {
+            if channels[index] == channels[other] {
+                return false;
+            }
+            other += 1;
+        }
+        index += 1;
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DMA_CHANNELS, PwmChannel, critical_section_lock_held, dma_channels_distinct, pwm_slice_of,
+    };
+
+    #[test]
+    fn only_spinlock_31_is_the_critical_section_lock() {
+        assert!(!critical_section_lock_held(0));
+        assert!(critical_section_lock_held(1 << 31));
+        assert!(critical_section_lock_held(u32::MAX));
+        assert!(!critical_section_lock_held(u32::MAX >> 1));
+    }
+
+    #[test]
+    fn pins_map_to_their_slice() {
+        assert_eq!(pwm_slice_of(4), (2, PwmChannel::A));
+        assert_eq!(pwm_slice_of(20), (2, PwmChannel::A));
+        assert_eq!(pwm_slice_of(9), (4, PwmChannel::B));
+        assert_eq!(pwm_slice_of(29), (6, PwmChannel::B));
+    }
+
+    #[test]
+    fn mismatched_pairings_are_detected() {
+        // The LED pins and slices of `LedPeripherals`
+        for (pin, slice) in [(4, 2), (6, 3), (2, 1)] {
+            assert_eq!(pwm_slice_of(pin), (slice, PwmChannel::A));
+        }
+        // Output B of slice 2, and output A of slice 3
+        assert_ne!(pwm_slice_of(5), (2, PwmChannel::A));
+        assert_ne!(pwm_slice_of(6), (2, PwmChannel::A));
+    }
+
+    #[test]
+    fn dma_assignment_has_no_conflicts() {
+        assert!(dma_channels_distinct(&DMA_CHANNELS));
+        assert!(dma_channels_distinct(&[]));
+    }
+
+    #[test]
+    fn conflicting_dma_channels_are_detected() {
+        // The WiFi driver on a channel of the LED zone
+        assert!(!dma_channels_distinct(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 6]));
+        assert!(!dma_channels_distinct(&[9, 9]));
+    }
 }
@@ -0,0 +1,42 @@
+use cortex_m::peripheral::SCB;
+use embassy_rp::{
+    clocks::dormant_sleep,
+    gpio::{DormantWakeConfig, Input, Pull},
+};
+use embassy_time::{Duration, Timer};
+
+use crate::peripherals::PowerPeripherals;
+
+#[derive(Clone, Copy)]
+pub enum WakeSource {
+    /// Idles until the duration elapsed. Clocks keep running, so this saves less power than `Pin`.
+    Timer(Duration),
+    /// Enters the `DORMANT` state until the wake pin is pulled low. All clocks are stopped.
+    Pin,
+}
+
+/// Puts the device to sleep until the wake source triggers, then resets it.
+///
+/// RAM contents and GPIO levels (including the PWM outputs, which are left low) are preserved
+/// while sleeping. Everything else that depends on a clock, most notably the timer and the
+/// network connection, is not, so the device resets on wake and initializes from scratch.
+///
+/// The LEDs and the network should be shut down before calling this.
+pub async fn sleep(p: PowerPeripherals, wake: WakeSource) -> ! {
+    match wake {
+        WakeSource::Timer(duration) => Timer::after(duration).await,
+        WakeSource::Pin => {
+            let mut pin = Input::new(p.wake_pin, Pull::Up);
+            let _wake = pin.dormant_wake(DormantWakeConfig {
+                edge_high: false,
+                edge_low: true,
+                level_high: false,
+                level_low: false,
+            });
+
+            dormant_sleep();
+        }
+    }
+
+    SCB::sys_reset()
+}
@@ -0,0 +1,15 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConfigError {
+    /// The burst of a stream is longer than its period
+    BurstExceedsPeriod,
+    /// The duty cycle of a stream isn't within `0 < duty <= 1`
+    InvalidDuty,
+    /// The frequency of a stream isn't positive, or its period rounds to zero
+    ZeroFrequency,
+    /// More streams than the config has room for
+    TooManyStreams,
+    /// A delay of the animation doesn't fit into the 32 bit tick counter
+    DelayOverflow,
+    /// The streams couldn't be parsed from JSON
+    MalformedJson,
+}
@@ -0,0 +1,414 @@
+use embassy_time::Duration;
+use heapless::Vec;
+
+use super::{Bpm, Color, ConfigError, Hz, StreamConfig};
+
+/// Nesting of arrays and objects skipped in unknown keys, deeper input is rejected
+const MAX_DEPTH: usize = 8;
+
+/// Parses the streams of a config in the format `Config::to_json` writes, e.g.
+/// `{"streams":[{"color":[255,0,0],"hz":60,"burst_us":3000,"offset_us":0}]}`.
+///
+/// `end_color`, `random_seed` and `offset_us` are optional. The frequency can be given as `bpm`
+/// instead of `hz`, and the burst as a `duty` cycle instead of `burst_us`. Unknown keys are
+/// ignored, so a dump of the active config can be fed back as is.
+pub fn streams_from_json<const N: usize>(json: &str) -> Result<Vec<StreamConfig, N>, ConfigError> {
+    let mut parser = Parser { json, position: 0 };
+    let mut streams = Vec::new();
+
+    parser.object(|parser, key| {
+        if key != "streams" {
+            return parser.skip_value(0);
+        }
+
+        parser.array(|parser| {
+            let stream = parser.stream()?;
+            streams
+                .push(stream)
+                .map_err(|_| ConfigError::TooManyStreams)
+        })
+    })?;
+
+    parser.skip_whitespace();
+    if parser.position != json.len() {
+        return Err(ConfigError::MalformedJson);
+    }
+
+    Ok(streams)
+}
+
+struct Parser<'a> {
+    json: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn stream(&mut self) -> Result<StreamConfig, ConfigError> {
+        let mut color = None;
+        let mut end_color = None;
+        let mut random_seed = None;
+        let mut frequency = None;
+        let mut burst = None;
+        let mut duty = None;
+        let mut offset = None;
+
+        self.object(|parser, key| {
+            match key {
+                "color" => color = Some(parser.color()?),
+                "end_color" => end_color = Some(parser.color()?),
+                "random_seed" => random_seed = Some(parser.number()?),
+                "hz" => frequency = Some(Hz(parser.number()?)),
+                "bpm" => frequency = Some(Bpm(parser.number()?).into()),
+                "burst_us" => burst = Some(Duration::from_micros(parser.number()?)),
+                "duty" => duty = Some(parser.number()?),
+                "offset_us" => offset = Some(Duration::from_micros(parser.number()?)),
+                _ => parser.skip_value(0)?,
+            }
+
+            Ok(())
+        })?;
+
+        let (Some(color), Some(frequency)) = (color, frequency) else {
+            return Err(ConfigError::MalformedJson);
+        };
+
+        let stream = match (burst, duty) {
+            (Some(burst), None) => StreamConfig::try_new(color, frequency, burst, offset)?,
+            (None, Some(duty)) => StreamConfig::try_new_with_duty(color, frequency, duty, offset)?,
+            _ => return Err(ConfigError::MalformedJson),
+        };
+        let stream = match end_color {
+            Some(end_color) => stream.with_gradient(end_color),
+            None => stream,
+        };
+
+        Ok(match random_seed {
+            Some(seed) => stream.with_random_colors(seed),
+            None => stream,
+        })
+    }
+
+    /// `[<r>,<g>,<b>]`
+    fn color(&mut self) -> Result<Color, ConfigError> {
+        let mut components = Vec::<u8, 3>::new();
+        self.array(|parser| {
+            let component = parser.number()?;
+            components
+                .push(component)
+                .map_err(|_| ConfigError::MalformedJson)
+        })?;
+
+        match *components {
+            [r, g, b] => Ok(Color(r, g, b)),
+            _ => Err(ConfigError::MalformedJson),
+        }
+    }
+
+    /// Calls `entry` with every key, which has to consume the value
+    fn object(
+        &mut self,
+        mut entry: impl FnMut(&mut Self, &'a str) -> Result<(), ConfigError>,
+    ) -> Result<(), ConfigError> {
+        self.expect(b'{')?;
+        if self.consume(b'}') {
+            return Ok(());
+        }
+
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            entry(self, key)?;
+
+            if !self.consume(b',') {
+                return self.expect(b'}');
+            }
+        }
+    }
+
+    /// Calls `element` for every element, which has to consume it
+    fn array(
+        &mut self,
+        mut element: impl FnMut(&mut Self) -> Result<(), ConfigError>,
+    ) -> Result<(), ConfigError> {
+        self.expect(b'[')?;
+        if self.consume(b']') {
+            return Ok(());
+        }
+
+        loop {
+            element(self)?;
+
+            if !self.consume(b',') {
+                return self.expect(b']');
+            }
+        }
+    }
+
+    /// Contents of a string, escapes are kept as they are
+    fn string(&mut self) -> Result<&'a str, ConfigError> {
+        self.expect(b'"')?;
+        let start = self.position;
+        let bytes = self.json.as_bytes();
+
+        while let Some(&byte) = bytes.get(self.position) {
+            match byte {
+                b'"' => {
+                    let string = &self.json[start..self.position];
+                    self.position += 1;
+                    return Ok(string);
+                }
+                b'\\' => self.position += 2,
+                _ => self.position += 1,
+            }
+        }
+
+        Err(ConfigError::MalformedJson)
+    }
+
+    fn number<T: core::str::FromStr>(&mut self) -> Result<T, ConfigError> {
+        self.skip_whitespace();
+        let start = self.position;
+        let length = self.json[start..]
+            .bytes()
+            .take_while(|byte| matches!(byte, b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E'))
+            .count();
+        self.position += length;
+
+        self.json[start..self.position]
+            .parse()
+            .map_err(|_| ConfigError::MalformedJson)
+    }
+
+    fn skip_value(&mut self, depth: usize) -> Result<(), ConfigError> {
+        if depth >= MAX_DEPTH {
+            return Err(ConfigError::MalformedJson);
+        }
+
+        self.skip_whitespace();
+        match self.json.as_bytes().get(self.position) {
+            Some(b'{') => self.object(|parser, _| parser.skip_value(depth + 1)),
+            Some(b'[') => self.array(|parser| parser.skip_value(depth + 1)),
+            Some(b'"') => self.string().map(|_| ()),
+            Some(b't' | b'f' | b'n') => {
+                let length = self.json[self.position..]
+                    .bytes()
+                    .take_while(u8::is_ascii_alphabetic)
+                    .count();
+                match &self.json[self.position..self.position + length] {
+                    "true" | "false" | "null" => {
+                        self.position += length;
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::MalformedJson),
+                }
+            }
+            _ => self.number::<f32>().map(|_| ()),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.position += self.json[self.position..]
+            .bytes()
+            .take_while(u8::is_ascii_whitespace)
+            .count();
+    }
+
+    /// Skips `byte` if it comes next
+    fn consume(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.json.as_bytes().get(self.position) == Some(&byte);
+        if found {
+            self.position += 1;
+        }
+
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), ConfigError> {
+        if self.consume(byte) {
+            Ok(())
+        } else {
+            Err(ConfigError::MalformedJson)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Duration;
+    use heapless::{String, Vec};
+
+    use super::streams_from_json;
+    use crate::stream::{
+        Color, ColorCorrection, Config, ConfigError, DimmingCurve, Gamma, Hz, MixMode, StreamConfig,
+    };
+
+    fn parse(json: &str) -> Result<Vec<StreamConfig, 4>, ConfigError> {
+        streams_from_json(json)
+    }
+
+    #[test]
+    fn readme_example_is_parsed() {
+        let streams =
+            parse(r#"{"streams":[{"color":[255,0,0],"hz":60,"burst_us":3000,"offset_us":0}]}"#)
+                .unwrap();
+
+        assert_eq!(streams.len(), 1);
+        let stream = &streams[0];
+        assert_eq!(stream.color, Color(255, 0, 0));
+        assert_eq!(stream.frequency.0, 60.);
+        assert_eq!(stream.burst_duration, Duration::from_micros(3000));
+        assert_eq!(stream.offset, Duration::from_ticks(0));
+        assert_eq!((stream.end_color, stream.random_seed), (None, None));
+    }
+
+    #[test]
+    fn dumps_can_be_fed_back() {
+        let config = Config::<2>::new(
+            &[
+                StreamConfig::new(Color(255, 0, 0), Hz(60.), Duration::from_micros(3000), None)
+                    .with_gradient(Color(0, 0, 255)),
+                StreamConfig::new(
+                    Color(10, 20, 30),
+                    Hz(2.5),
+                    Duration::from_millis(100),
+                    Some(Duration::from_millis(7)),
+                )
+                .with_random_colors(42),
+            ],
+            1,
+            0,
+        );
+        let json = config.to_json::<512>().unwrap();
+
+        let streams = parse(&json).unwrap();
+
+        assert_eq!(streams.len(), 2);
+        for (parsed, original) in streams.iter().zip(config.streams.iter()) {
+            assert_eq!(parsed.color, original.color);
+            assert_eq!(parsed.end_color, original.end_color);
+            assert_eq!(parsed.random_seed, original.random_seed);
+            assert_eq!(parsed.frequency.0, original.frequency.0);
+            assert_eq!(parsed.burst_duration, original.burst_duration);
+            assert_eq!(parsed.offset, original.offset);
+        }
+    }
+
+    #[test]
+    fn dumps_list_the_settings_and_every_stream() {
+        let config = Config::<2>::new(
+            &[
+                StreamConfig::new(Color(255, 0, 0), Hz(100.), Duration::from_millis(2), None)
+                    .with_random_colors(7),
+                StreamConfig::new(
+                    Color(0, 0, 255),
+                    Hz(50.),
+                    Duration::from_millis(5),
+                    Some(Duration::from_millis(1)),
+                )
+                .with_gradient(Color(0, 255, 0)),
+            ],
+            64,
+            5,
+        )
+        .with_color_correction(ColorCorrection {
+            r_scale: 255,
+            g_scale: 200,
+            b_scale: 180,
+        })
+        .with_gamma(Gamma::new(2.))
+        .with_dimming_curve(DimmingCurve::Cie1931)
+        .with_mix_mode(MixMode::Max);
+
+        assert_eq!(
+            config.to_json::<512>().unwrap(),
+            concat!(
+                r#"{"micros_per_tick":64,"tick_overhead":5,"correction":[255,200,180],"#,
+                r#""gamma":2,"dimming_curve":"cie1931","mix_mode":"max","loop_us":20000,"#,
+                r#""streams":[{"color":[255,0,0],"random_seed":7,"hz":100,"burst_us":2000,"#,
+                r#""offset_us":0},{"color":[0,0,255],"end_color":[0,255,0],"hz":50,"#,
+                r#""burst_us":5000,"offset_us":1000}]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_skipped() {
+        let json = r#" { "version" : 2, "meta": {"tags": ["a", {"b": null}], "ok": true},
+            "streams": [ {"name": "x", "color": [0, 255, 0], "hz": 1, "burst_us": 10} ] } "#;
+
+        let streams = parse(json).unwrap();
+
+        assert_eq!(streams[0].color, Color(0, 255, 0));
+    }
+
+    #[test]
+    fn broken_configs_are_rejected() {
+        for json in [
+            "",
+            r#"{"streams":[{"color":[255,0,0],"hz":60,"burst_us":3000}]} x"#,
+            r#"{"streams":[{"color":[256,0,0],"hz":60,"burst_us":3000}]}"#,
+            r#"{"streams":[{"color":[255,0],"hz":60,"burst_us":3000}]}"#,
+            r#"{"streams":[{"hz":60,"burst_us":3000}]}"#,
+            r#"{"streams":[{"color":[255,0,0],"burst_us":3000}]}"#,
+            r#"{"streams":[{"color":[255,0,0],"hz":60,"burst_us":3000}"#,
+            r#"{"deep":[[[[[[[[[[]]]]]]]]]],"streams":[]}"#,
+        ] {
+            assert_eq!(
+                parse(json).unwrap_err(),
+                ConfigError::MalformedJson,
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn too_many_streams_are_rejected() {
+        let stream = r#"{"color":[255,0,0],"hz":60,"burst_us":3000},"#;
+        let mut json = String::<512>::try_from(r#"{"streams":["#).unwrap();
+        for _ in 0..5 {
+            json.push_str(stream).unwrap();
+        }
+        json.pop();
+        json.push_str("]}").unwrap();
+
+        assert_eq!(parse(&json).unwrap_err(), ConfigError::TooManyStreams);
+    }
+
+    #[test]
+    fn tempo_and_duty_replace_hz_and_burst() {
+        let streams = parse(r#"{"streams":[{"color":[255,0,0],"bpm":120,"duty":0.1}]}"#).unwrap();
+
+        assert_eq!(
+            streams[0].frequency.as_duration(),
+            Duration::from_millis(500)
+        );
+        assert_eq!(streams[0].burst_duration, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn burst_is_given_once() {
+        assert_eq!(
+            parse(r#"{"streams":[{"color":[255,0,0],"hz":60,"burst_us":3000,"duty":0.1}]}"#)
+                .unwrap_err(),
+            ConfigError::MalformedJson
+        );
+        assert_eq!(
+            parse(r#"{"streams":[{"color":[255,0,0],"hz":60}]}"#).unwrap_err(),
+            ConfigError::MalformedJson
+        );
+    }
+
+    #[test]
+    fn invalid_tempo_and_duty_are_rejected() {
+        assert_eq!(
+            parse(r#"{"streams":[{"color":[255,0,0],"bpm":0,"duty":0.1}]}"#).unwrap_err(),
+            ConfigError::ZeroFrequency
+        );
+        assert_eq!(
+            parse(r#"{"streams":[{"color":[255,0,0],"hz":60,"duty":2}]}"#).unwrap_err(),
+            ConfigError::InvalidDuty
+        );
+    }
+}
@@ -1,5 +1,15 @@
+use core::{fmt::Write, str::FromStr};
+
 use embassy_time::{Duration, Instant};
-use heapless::Vec;
+use heapless::{String, Vec};
+
+mod error;
+mod json;
+#[cfg(all(test, feature = "std"))]
+mod proptests;
+
+pub use error::ConfigError;
+pub use json::streams_from_json;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Color(pub u8, pub u8, pub u8);
@@ -9,49 +19,445 @@ impl Color {
         Color(0, 0, 0)
     }
 
-    pub fn r(&self) -> u8 {
-        self.0
+    pub fn from_array([r, g, b]: [u8; 3]) -> Color {
+        Color(r, g, b)
+    }
+
+    pub fn as_array(&self) -> [u8; 3] {
+        [self.0, self.1, self.2]
+    }
+
+    /// Multiplies every component by `factor / 255`, rounded to the nearest integer
+    pub fn scaled(self, factor: u8) -> Color {
+        Color::lerp(Color::black(), self, factor)
     }
 
-    pub fn g(&self) -> u8 {
-        self.1
+    /// Color `t / 255` of the way from `a` to `b`, rounded to the nearest integer per component
+    pub fn lerp(a: Color, b: Color, t: u8) -> Color {
+        a.mix(b, t as u64, u8::MAX as u64)
     }
 
-    pub fn b(&self) -> u8 {
-        self.2
+    /// Iterates over the red, green and blue components, in that order
+    pub fn components(&self) -> impl Iterator<Item = u8> {
+        self.as_array().into_iter()
+    }
+
+    /// Color `numerator / denominator` of the way from `self` to `other`
+    fn mix(self, other: Color, numerator: u64, denominator: u64) -> Color {
+        let [from, to] = [self.as_array(), other.as_array()];
+        Color::from_array(core::array::from_fn(|i| {
+            let (from, to) = (from[i] as u64, to[i] as u64);
+            ((from * (denominator - numerator) + to * numerator + denominator / 2) / denominator)
+                as u8
+        }))
     }
 }
 
-#[derive(Clone, Copy)]
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+
+    a
+}
+
+/// Hashes the seed and the number of the burst, so any burst's color can be calculated without
+/// keeping state
+fn random_color(seed: u16, burst: u64) -> Color {
+    // Finalizer of MurmurHash3
+    let mut x = ((seed as u32) << 16) ^ (burst as u32).wrapping_mul(0x9E37_79B9);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85EB_CA6B);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xC2B2_AE35);
+    x ^= x >> 16;
+
+    let [r, g, b, _] = x.to_le_bytes();
+    Color(r, g, b)
+}
+
+fn scale(value: u8, factor: u8) -> u8 {
+    ((value as u16 * factor as u16 + 127) / 255) as u8
+}
+
+/// Per-channel factors balancing the perceived brightness of the LEDs, so white doesn't look
+/// tinted. Every channel is multiplied by its factor / 255.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorCorrection {
+    pub r_scale: u8,
+    pub g_scale: u8,
+    pub b_scale: u8,
+}
+
+impl ColorCorrection {
+    /// Leaves all channels unchanged
+    pub const IDENTITY: ColorCorrection = ColorCorrection {
+        r_scale: u8::MAX,
+        g_scale: u8::MAX,
+        b_scale: u8::MAX,
+    };
+
+    pub fn apply(&self, Color(r, g, b): Color) -> Color {
+        Color(
+            scale(r, self.r_scale),
+            scale(g, self.g_scale),
+            scale(b, self.b_scale),
+        )
+    }
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Lookup table mapping every component to `255 * (component / 255) ^ gamma`, so equal steps of
+/// the input look like equal steps of brightness
+#[derive(Clone)]
+pub struct Gamma {
+    gamma: f32,
+    table: [u8; 256],
+}
+
+impl Gamma {
+    /// Leaves all components unchanged
+    pub const IDENTITY: Gamma = Gamma {
+        gamma: 1.,
+        table: {
+            let mut table = [0; 256];
+            let mut i = 0;
+            while i < 256 {
+                table[i] = i as u8;
+                i += 1;
+            }
+            table
+        },
+    };
+
+    /// Panics unless `gamma` is positive
+    pub fn new(gamma: f32) -> Self {
+        core::assert!(gamma > 0.);
+
+        Self {
+            gamma,
+            table: core::array::from_fn(|i| {
+                (powf(i as f32 / 255., gamma) * 255. + 0.5).min(255.) as u8
+            }),
+        }
+    }
+
+    pub fn gamma(&self) -> f32 {
+        self.gamma
+    }
+
+    pub fn apply(&self, color: Color) -> Color {
+        Color::from_array(
+            color
+                .as_array()
+                .map(|component| self.table[component as usize]),
+        )
+    }
+}
+
+/// Leaves out the table, which follows from the gamma
+impl core::fmt::Debug for Gamma {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Gamma").field("gamma", &self.gamma).finish()
+    }
+}
+
+impl Default for Gamma {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Named curve mapping a component to its PWM duty
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DimmingCurve {
+    /// Leaves all components unchanged
+    Linear,
+    /// `256 ^ (component / 255) - 1`, very dim over most of the range
+    Logarithmic,
+    /// Inverse of the CIE 1931 lightness, so equal steps of the input look like equal steps of
+    /// brightness
+    Cie1931,
+}
+
+const LOGARITHMIC_TABLE: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = (exp2(i as f32 * 8. / 255.) - 1. + 0.5) as u8;
+        i += 1;
+    }
+    table
+};
+
+const CIE_1931_TABLE: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let lightness = i as f32 * 100. / 255.;
+        let luminance = if lightness <= 8. {
+            lightness / 903.3
+        } else {
+            let cube_root = (lightness + 16.) / 116.;
+            cube_root * cube_root * cube_root
+        };
+        table[i] = (luminance * 255. + 0.5) as u8;
+        i += 1;
+    }
+    table
+};
+
+impl DimmingCurve {
+    pub const ALL: [DimmingCurve; 3] = [
+        DimmingCurve::Linear,
+        DimmingCurve::Logarithmic,
+        DimmingCurve::Cie1931,
+    ];
+
+    pub fn map(self, component: u8) -> u8 {
+        match self {
+            DimmingCurve::Linear => component,
+            DimmingCurve::Logarithmic => LOGARITHMIC_TABLE[component as usize],
+            DimmingCurve::Cie1931 => CIE_1931_TABLE[component as usize],
+        }
+    }
+
+    pub fn apply(self, color: Color) -> Color {
+        Color::from_array(color.as_array().map(|component| self.map(component)))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DimmingCurve::Linear => "linear",
+            DimmingCurve::Logarithmic => "logarithmic",
+            DimmingCurve::Cie1931 => "cie1931",
+        }
+    }
+}
+
+impl FromStr for DimmingCurve {
+    type Err = ();
+
+    /// Parses the name of the curve, e.g. `cie1931`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DimmingCurve::ALL
+            .into_iter()
+            .find(|curve| curve.name() == s)
+            .ok_or(())
+    }
+}
+
+/// `base ^ exponent` for `base` in `0..=1`, accurate to about 1e-4, since there is no `powf` in
+/// `core`
+fn powf(base: f32, exponent: f32) -> f32 {
+    if base <= 0. {
+        return 0.;
+    }
+
+    exp2(exponent * log2(base))
+}
+
+fn log2(x: f32) -> f32 {
+    // x = m * 2^e with m in [1, 2)
+    let bits = x.to_bits();
+    let exponent = ((bits >> 23) & 0xFF) as i32 - 127;
+    let mantissa = f32::from_bits((bits & 0x007F_FFFF) | 0x3F80_0000);
+
+    // ln(m) = 2 * atanh(s), with s in [0, 1/3)
+    let s = (mantissa - 1.) / (mantissa + 1.);
+    let s2 = s * s;
+    let ln = 2. * s * (1. + s2 * (1. / 3. + s2 * (1. / 5. + s2 * (1. / 7.))));
+
+    exponent as f32 + ln * core::f32::consts::LOG2_E
+}
+
+/// Also used for the dimming tables, so it must stay `const`
+const fn exp2(x: f32) -> f32 {
+    if x < -126. {
+        return 0.;
+    }
+
+    // x = n + f with f in [0, 1)
+    let n = x as i32 - (x < (x as i32) as f32) as i32;
+    let y = (x - n as f32) * core::f32::consts::LN_2;
+
+    let mut term = 1.;
+    let mut sum = 1.;
+    let mut i = 1;
+    while i < 8 {
+        term *= y / i as f32;
+        sum += term;
+        i += 1;
+    }
+
+    sum * f32::from_bits(((n + 127) as u32) << 23)
+}
+
+impl FromStr for Color {
+    type Err = ();
+
+    /// Parses `<r>,<g>,<b>`, e.g. `255,0,64`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut components = s
+            .split(',')
+            .map(|component| component.trim().parse::<u8>().map_err(|_| ()));
+
+        match (
+            components.next(),
+            components.next(),
+            components.next(),
+            components.next(),
+        ) {
+            (Some(r), Some(g), Some(b), None) => Ok(Color(r?, g?, b?)),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct Hz(pub f32);
 
 impl Hz {
+    /// Period of the frequency. It's capped at 2^40 µs, about 12 days, which is far more than the
+    /// tick counter can time, so frequencies that aren't positive don't overflow before
+    /// `StreamConfig::validate` rejects them.
     pub fn as_duration(self) -> Duration {
-        Duration::from_micros((1e6 / self.0) as u64)
+        const MAX_PERIOD_MICROS: u64 = 1 << 40;
+
+        Duration::from_micros(((1e6 / self.0) as u64).min(MAX_PERIOD_MICROS))
     }
 }
 
+/// Beats per minute, for streams synced to music. Like a frequency, it has to be positive, which
+/// is checked when a stream is created from it.
 #[derive(Clone, Copy)]
+pub struct Bpm(pub f32);
+
+impl From<Bpm> for Hz {
+    fn from(value: Bpm) -> Self {
+        Hz(value.0 / 60.)
+    }
+}
+
+impl From<Hz> for Bpm {
+    fn from(value: Hz) -> Self {
+        Bpm(value.0 * 60.)
+    }
+}
+
+/// Number of steps a gradient is shown in over the burst
+pub const GRADIENT_STEPS: u64 = 8;
+
+/// Period of solid streams. Longer periods need fewer steps, but buffers take longer to play,
+/// which delays commands waiting for the current one.
+const SOLID_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug)]
 pub struct StreamConfig {
     color: Color,
+    /// If set, the burst sweeps from `color` to this one in `GRADIENT_STEPS` steps
+    end_color: Option<Color>,
+    /// If set, every burst is shown in a pseudo-random color derived from this seed and the
+    /// number of the burst, limited to `color` per channel
+    random_seed: Option<u16>,
     frequency: Hz,
     burst_duration: Duration,
     offset: Duration,
 }
 
 impl StreamConfig {
+    #[allow(unused)]
+    pub fn color(&self) -> Color {
+        self.color
+    }
+
+    /// Same stream, shown in a different solid color
+    pub fn with_color(self, color: Color) -> Self {
+        Self {
+            color,
+            end_color: None,
+            random_seed: None,
+            ..self
+        }
+    }
+
+    /// Same stream, sweeping from its color to `end_color` over every burst
+    pub fn with_gradient(self, end_color: Color) -> Self {
+        Self {
+            end_color: Some(end_color),
+            random_seed: None,
+            ..self
+        }
+    }
+
+    /// Same stream, showing a different pseudo-random color in every burst. The sequence only
+    /// depends on `seed`, so it's the same on every run. The stream's color is the upper limit of
+    /// every channel, white allows all colors.
+    pub fn with_random_colors(self, seed: u16) -> Self {
+        Self {
+            end_color: None,
+            random_seed: Some(seed),
+            ..self
+        }
+    }
+
+    /// Same stream, played `factor` times as fast. The burst and offset shrink along with the
+    /// period, so the shape of the pattern is kept.
+    pub fn with_speed(self, factor: f32) -> Self {
+        core::assert!(factor > 0.);
+
+        let scale = |duration: Duration| {
+            Duration::from_micros((duration.as_micros() as f32 / factor) as u64)
+        };
+        let frequency = Hz(self.frequency.0 * factor);
+
+        Self {
+            frequency,
+            burst_duration: scale(self.burst_duration).min(frequency.as_duration()),
+            offset: scale(self.offset),
+            ..self
+        }
+    }
+
+    /// Same stream, with all of its colors multiplied by `factor / 255`
+    pub fn scaled(self, factor: u8) -> Self {
+        Self {
+            color: self.color.scaled(factor),
+            end_color: self.end_color.map(|color| color.scaled(factor)),
+            ..self
+        }
+    }
+
     pub fn get_color_at_instant(&self, instant: Instant) -> Color {
         if instant < self.get_start() {
             return Color::black();
         }
 
         let period = self.frequency.as_duration();
-        if (instant.as_micros() - self.offset.as_micros()) % period.as_micros()
-            < self.burst_duration.as_micros()
-        {
-            self.color
-        } else {
-            Color::black()
+        let phase = (instant.as_micros() - self.offset.as_micros()) % period.as_micros();
+        if phase >= self.burst_duration.as_micros() {
+            return Color::black();
+        }
+
+        if let Some(seed) = self.random_seed {
+            let burst = (instant.as_micros() - self.offset.as_micros()) / period.as_micros();
+            let Color(r, g, b) = random_color(seed, burst);
+            let Color(max_r, max_g, max_b) = self.color;
+
+            return Color(scale(r, max_r), scale(g, max_g), scale(b, max_b));
+        }
+
+        match self.end_color {
+            None => self.color,
+            Some(end_color) => {
+                let step = self.gradient_step(phase);
+                self.color.mix(end_color, step, GRADIENT_STEPS - 1)
+            }
         }
     }
 
@@ -68,10 +474,20 @@ impl StreamConfig {
                         (instant.as_micros() - self.offset.as_micros()) % period.as_micros(),
                     );
 
+                    // Changes past the end of the timeline saturate at `Instant::MAX`, where the
+                    // iterator then stays
                     if phase < self.burst_duration {
-                        instant + self.burst_duration - phase
+                        let burst = self.burst_duration.as_micros();
+                        let change = match self.end_color {
+                            None => burst,
+                            Some(_) => {
+                                let step = self.gradient_step(phase.as_micros());
+                                ((step + 1) * burst).div_ceil(GRADIENT_STEPS)
+                            }
+                        };
+                        (instant - phase).saturating_add(Duration::from_micros(change))
                     } else {
-                        instant + period - phase
+                        (instant - phase).saturating_add(period)
                     }
                 }
             }
@@ -81,6 +497,30 @@ impl StreamConfig {
     fn get_start(&self) -> Instant {
         Instant::MIN + self.offset
     }
+
+    /// Step of the gradient shown `phase` microseconds into the burst
+    fn gradient_step(&self, phase: u64) -> u64 {
+        phase * GRADIENT_STEPS / self.burst_duration.as_micros()
+    }
+
+    /// Writes the stream as a JSON object, with durations in microseconds
+    pub fn write_json(&self, out: &mut impl Write) -> core::fmt::Result {
+        let Color(r, g, b) = self.color;
+        write!(out, r#"{{"color":[{},{},{}],"#, r, g, b)?;
+        if let Some(Color(r, g, b)) = self.end_color {
+            write!(out, r#""end_color":[{},{},{}],"#, r, g, b)?;
+        }
+        if let Some(seed) = self.random_seed {
+            write!(out, r#""random_seed":{},"#, seed)?;
+        }
+        write!(
+            out,
+            r#""hz":{},"burst_us":{},"offset_us":{}}}"#,
+            self.frequency.0,
+            self.burst_duration.as_micros(),
+            self.offset.as_micros()
+        )
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -90,37 +530,278 @@ pub struct ColorStep {
 }
 
 impl ColorStep {
-    pub fn encode_red(&self) -> u32 {
-        self.encode(|color| color.r())
+    /// Largest delay that fits into the 24 bits the PIO program reads for it
+    pub const MAX_DELAY: u32 = 0xFFFFFF;
+
+    /// Splits a step whose delay doesn't fit into 24 bits.
+    ///
+    /// Returns a step with the same color and the maximum delay, and leaves the remaining delay
+    /// in `self`. Since every step costs `tick_overhead` additional ticks, that overhead is
+    /// deducted from the remainder so the total wait stays the same.
+    pub fn split_off(&mut self, tick_overhead: u32) -> Option<ColorStep> {
+        if self.delay <= Self::MAX_DELAY {
+            return None;
+        }
+
+        self.delay = (self.delay - Self::MAX_DELAY).saturating_sub(tick_overhead);
+
+        Some(ColorStep {
+            color: self.color,
+            delay: Self::MAX_DELAY,
+        })
     }
 
-    pub fn encode_green(&self) -> u32 {
-        self.encode(|color| color.g())
+    /// Encodes the step for the red, green and blue PIO state machines, in that order
+    pub fn encode(&self) -> [u32; 3] {
+        self.color
+            .as_array()
+            .map(|component| (component as u32) << 24 | self.delay & Self::MAX_DELAY)
     }
+}
 
-    pub fn encode_blue(&self) -> u32 {
-        self.encode(|color| color.b())
+/// How the colors of overlapping streams are combined
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MixMode {
+    /// Components are summed up. If a sum exceeds 255, all components are scaled down evenly,
+    /// keeping the hue.
+    #[default]
+    Additive,
+    /// Every component is the maximum across all streams, so overlapping streams of the same hue
+    /// don't wash out to white
+    Max,
+}
+
+impl MixMode {
+    fn combine(self, mixed: u32, component: u8) -> u32 {
+        match self {
+            MixMode::Additive => mixed + component as u32,
+            MixMode::Max => mixed.max(component as u32),
+        }
     }
 
-    fn encode(&self, get_color_component: impl FnOnce(Color) -> u8) -> u32 {
-        (get_color_component(self.color) as u32) << 24 | self.delay & 0xFFFFFF
+    fn name(self) -> &'static str {
+        match self {
+            MixMode::Additive => "additive",
+            MixMode::Max => "max",
+        }
     }
 }
 
+#[derive(Debug)]
 pub struct Config<const N: usize> {
     streams: Vec<StreamConfig, N>,
     micros_per_tick: i32,
     tick_overhead: i32,
+    correction: ColorCorrection,
+    gamma: Gamma,
+    dimming_curve: DimmingCurve,
+    mix_mode: MixMode,
+    /// Emits steps on a fixed grid instead of at every change, if set
+    sample_interval: Option<Duration>,
+    /// Ticks a step with any color is shown at least
+    min_on_ticks: u32,
 }
 
 impl<const N: usize> Config<N> {
-    pub fn new(streams: &[StreamConfig; N], micros_per_tick: i32, tick_overhead: i32) -> Self {
-        Self {
-            streams: Vec::from_slice(streams).unwrap(),
+    /// Panics if there are more than `N` streams, see `try_new`
+    pub fn new(streams: &[StreamConfig], micros_per_tick: i32, tick_overhead: i32) -> Self {
+        Self::try_new(streams, micros_per_tick, tick_overhead).unwrap()
+    }
+
+    /// Like `new`, but fails instead of panicking if there are more than `N` streams, one of them
+    /// isn't valid, or a delay between two steps wouldn't fit into the tick counter, for streams
+    /// that aren't known at compile time
+    pub fn try_new(
+        streams: &[StreamConfig],
+        micros_per_tick: i32,
+        tick_overhead: i32,
+    ) -> Result<Self, ConfigError> {
+        streams.iter().try_for_each(StreamConfig::validate)?;
+
+        // No two steps are further apart than the offset and period of the slowest stream
+        let longest_delay = streams
+            .iter()
+            .map(|stream| (stream.offset + stream.frequency.as_duration()).as_micros())
+            .max()
+            .unwrap_or_default();
+        if longest_delay / micros_per_tick.max(1) as u64 > u32::MAX as u64 {
+            return Err(ConfigError::DelayOverflow);
+        }
+
+        Ok(Self {
+            streams: Vec::from_slice(streams).map_err(|_| ConfigError::TooManyStreams)?,
             micros_per_tick,
             tick_overhead,
+            correction: ColorCorrection::IDENTITY,
+            gamma: Gamma::IDENTITY,
+            dimming_curve: DimmingCurve::Linear,
+            mix_mode: MixMode::Additive,
+            sample_interval: None,
+            min_on_ticks: 0,
+        })
+    }
+
+    /// Time after which the animation repeats, the least common multiple of all stream periods.
+    ///
+    /// The animation only repeats once every stream has started, i.e. after the largest offset.
+    /// Periods are rounded to whole microseconds, so streams with incommensurate frequencies
+    /// result in huge loops. If the period doesn't fit into a `Duration`, it saturates at
+    /// `Duration::MAX`.
+    pub fn loop_period(&self) -> Duration {
+        let periods = self
+            .streams
+            .iter()
+            .map(|stream| stream.frequency.as_duration().as_micros())
+            .chain(self.sample_interval.map(|interval| interval.as_micros()));
+
+        periods
+            .filter(|period| *period > 0)
+            .try_fold(1_u64, |period, stream_period| {
+                (period / gcd(period, stream_period)).checked_mul(stream_period)
+            })
+            .map_or(Duration::MAX, Duration::from_micros)
+    }
+
+    /// Color shown at `instant` on the virtual timeline of the iterator, with all streams mixed
+    /// and the output corrections applied
+    pub fn color_at(&self, instant: Instant) -> Color {
+        let mix_mode = self.mix_mode;
+        let color = self
+            .streams
+            .iter()
+            .map(|stream| stream.get_color_at_instant(instant))
+            .fold([0_u32; 3], |mut mixed, color| {
+                mixed
+                    .iter_mut()
+                    .zip(color.components())
+                    .for_each(|(mixed, component)| *mixed = mix_mode.combine(*mixed, component));
+                mixed
+            });
+
+        let max = color.into_iter().max().unwrap_or_default();
+        let color = if max > 255 {
+            Color::from_array(color.map(|val| (val * 255 / max) as u8))
+        } else {
+            Color::from_array(color.map(|val| val as u8))
+        };
+        let color = self.dimming_curve.apply(self.gamma.apply(color));
+        self.correction.apply(color)
+    }
+
+    /// Serializes the config to JSON. Streams that don't fit into `L` bytes are left out, which is
+    /// marked by `"truncated":true`.
+    pub fn to_json<const L: usize>(&self) -> Result<String<L>, core::fmt::Error> {
+        /// Room kept for closing the streams array and the truncation marker
+        const TAIL: &str = r#"],"truncated":true}"#;
+
+        let ColorCorrection {
+            r_scale,
+            g_scale,
+            b_scale,
+        } = self.correction;
+
+        let mut json = String::<L>::new();
+        write!(
+            json,
+            r#"{{"micros_per_tick":{},"tick_overhead":{},"correction":[{},{},{}],"gamma":{},"dimming_curve":"{}","mix_mode":"{}","#,
+            self.micros_per_tick,
+            self.tick_overhead,
+            r_scale,
+            g_scale,
+            b_scale,
+            self.gamma.gamma(),
+            self.dimming_curve.name(),
+            self.mix_mode.name()
+        )?;
+        if let Some(interval) = self.sample_interval {
+            write!(json, r#""sample_us":{},"#, interval.as_micros())?;
+        }
+        write!(json, r#""loop_us":{},"#, self.loop_period().as_micros())?;
+        if self.min_on_ticks > 0 {
+            write!(json, r#""min_on_ticks":{},"#, self.min_on_ticks)?;
+        }
+        json.push_str(r#""streams":["#)
+            .map_err(|_| core::fmt::Error)?;
+
+        let mut truncated = false;
+        for (index, stream) in self.streams.iter().enumerate() {
+            let mut entry = String::<96>::new();
+            if index > 0 {
+                entry.push(',').map_err(|_| core::fmt::Error)?;
+            }
+            stream.write_json(&mut entry)?;
+
+            if json.len() + entry.len() + TAIL.len() > L {
+                truncated = true;
+                break;
+            }
+            json.push_str(&entry).map_err(|_| core::fmt::Error)?;
+        }
+
+        if truncated {
+            json.push_str(TAIL).map_err(|_| core::fmt::Error)?;
+        } else {
+            json.push_str("]}").map_err(|_| core::fmt::Error)?;
+        }
+
+        Ok(json)
+    }
+
+    /// Corrects the output for the given LEDs. It is applied last, after the streams have been
+    /// mixed, so it doesn't affect their relative brightness.
+    pub fn with_color_correction(self, correction: ColorCorrection) -> Self {
+        Self { correction, ..self }
+    }
+
+    /// Applied to the mixed color, before the color correction
+    pub fn with_gamma(self, gamma: Gamma) -> Self {
+        Self { gamma, ..self }
+    }
+
+    /// Applied to the mixed color after the gamma, before the color correction. Usually only one
+    /// of the two is used.
+    pub fn with_dimming_curve(self, dimming_curve: DimmingCurve) -> Self {
+        Self {
+            dimming_curve,
+            ..self
+        }
+    }
+
+    pub fn with_mix_mode(self, mix_mode: MixMode) -> Self {
+        Self { mix_mode, ..self }
+    }
+
+    /// Emits a step every `interval`, showing the mixed color at its start, instead of a step
+    /// for every change of a stream. All steps have the same delay, at the cost of shifting
+    /// changes to the next grid point.
+    pub fn with_sample_interval(self, interval: Duration) -> Self {
+        Self {
+            sample_interval: Some(interval),
+            ..self
         }
     }
+
+    /// Shows every step that isn't black for at least `ticks`, so short bursts at high frequencies
+    /// don't round down to invisible pulses. A step that would end sooner ends after that time
+    /// instead, or together with the next burst if that has started by then. The timeline itself
+    /// isn't shifted. With a sample interval, it's rounded up to whole intervals, so steps stay on the
+    /// grid.
+    pub fn with_min_on_time(self, ticks: u32) -> Self {
+        Self {
+            min_on_ticks: ticks,
+            ..self
+        }
+    }
+
+    /// Earliest time the step starting at `start` with `color` may end at
+    fn min_step_end(&self, start: Instant, color: Color) -> Instant {
+        if color == Color::black() {
+            return start;
+        }
+
+        let min_on_time = self.min_on_ticks as u64 * self.micros_per_tick.max(1) as u64;
+        start.saturating_add(Duration::from_micros(min_on_time))
+    }
 }
 
 impl<const N: usize> IntoIterator for Config<N> {
@@ -132,6 +813,11 @@ impl<const N: usize> IntoIterator for Config<N> {
     }
 }
 
+/// Calculates the steps of a config one after another.
+///
+/// Time is tracked on a virtual timeline starting at `Instant::MIN`, independent of when the steps
+/// are actually shown. The clock is never read, so the iterator behaves the same on every run and
+/// doesn't need a time driver.
 pub struct ColorStepIterator<const N: usize> {
     config: Config<N>,
     current_time: Option<Instant>,
@@ -145,12 +831,27 @@ impl<const N: usize> ColorStepIterator<N> {
         }
     }
 
-    fn get_next_time_after(&self, instant: Option<Instant>) -> Option<Instant> {
+    pub fn config(&self) -> &Config<N> {
+        &self.config
+    }
+
+    /// Restarts the animation from its beginning
+    pub fn reset(&mut self) {
+        self.current_time = None;
+    }
+
+    pub fn tick_overhead(&self) -> u32 {
+        self.config.tick_overhead as u32
+    }
+
+    /// Without any streams, the config stays black and steps are emitted every `SOLID_PERIOD`
+    fn get_next_time_after(&self, instant: Option<Instant>) -> Instant {
         self.config
             .streams
             .iter()
             .map(|stream| stream.get_next_change_after(instant))
             .min()
+            .unwrap_or_else(|| instant.unwrap_or(Instant::MIN).saturating_add(SOLID_PERIOD))
     }
 }
 
@@ -158,55 +859,722 @@ impl<const N: usize> Iterator for ColorStepIterator<N> {
     type Item = ColorStep;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let next_time = self.get_next_time_after(self.current_time);
-        let next_time = next_time.unwrap();
         let current_time = self.current_time.unwrap_or(Instant::MIN);
+        let color = self.config.color_at(current_time);
+        let min_end = self.config.min_step_end(current_time, color);
+        let next_time = match self.config.sample_interval {
+            Some(interval) => {
+                let mut next_time = current_time.saturating_add(interval);
+                while next_time < min_end {
+                    next_time = next_time.saturating_add(interval);
+                }
+                next_time
+            }
+            None => {
+                let next_time = self.get_next_time_after(self.current_time);
+                if next_time >= min_end {
+                    next_time
+                } else if self.config.color_at(min_end) == color {
+                    // The next burst has started by then, so they're merged
+                    self.get_next_time_after(Some(min_end))
+                } else {
+                    min_end
+                }
+            }
+        };
 
-        let color = self
-            .config
-            .streams
-            .iter()
-            .map(|stream| stream.get_color_at_instant(current_time))
-            .fold((0_u32, 0_u32, 0_u32), |sum, color| {
-                (
-                    sum.0 + color.r() as u32,
-                    sum.1 + color.g() as u32,
-                    sum.2 + color.b() as u32,
-                )
-            });
-
-        let max = color.0.max(color.1).max(color.2);
         let diff = next_time - current_time;
         let delay = ((diff.as_micros() / self.config.micros_per_tick as u64) as u32)
             .saturating_sub(self.config.tick_overhead as u32);
 
         self.current_time = Some(next_time);
 
-        let color = if max > 255 {
-            let normalize = |val| (val * 255 / max) as u8;
-            Color(normalize(color.0), normalize(color.1), normalize(color.2))
-        } else {
-            Color(color.0 as u8, color.1 as u8, color.2 as u8)
-        };
-
         Some(ColorStep { color, delay })
     }
 }
 
+/// Streams sharing a frequency and a start, so they stay in lockstep. All streams restart
+/// together on a phase reset, so their relative phases are kept.
+#[derive(Clone, Copy)]
+pub struct TimingGroup {
+    frequency: Hz,
+    start: Duration,
+}
+
+impl TimingGroup {
+    pub fn new(frequency: Hz, start: Option<Duration>) -> Self {
+        Self {
+            frequency,
+            start: start.unwrap_or_default(),
+        }
+    }
+
+    /// Stream of the group, lighting up `phase` into every period of the group. Panics if `phase`
+    /// isn't shorter than the period.
+    pub fn stream(&self, color: Color, burst_duration: Duration, phase: Duration) -> StreamConfig {
+        core::assert!(phase < self.frequency.as_duration());
+
+        StreamConfig::new(
+            color,
+            self.frequency,
+            burst_duration,
+            Some(self.start + phase),
+        )
+    }
+}
+
 impl StreamConfig {
+    /// Panics if the stream isn't valid, see `validate`
     pub fn new(
         color: Color,
         frequency: Hz,
         burst_duration: Duration,
         offset: Option<Duration>,
     ) -> Self {
-        core::assert!(burst_duration <= frequency.as_duration());
+        Self::try_new(color, frequency, burst_duration, offset).unwrap()
+    }
 
-        Self {
+    /// Like `new`, but fails instead of panicking, for streams that aren't known at compile time
+    pub fn try_new(
+        color: Color,
+        frequency: Hz,
+        burst_duration: Duration,
+        offset: Option<Duration>,
+    ) -> Result<Self, ConfigError> {
+        let stream = Self {
             color,
+            end_color: None,
+            random_seed: None,
             frequency,
             burst_duration,
             offset: offset.unwrap_or_default(),
+        };
+        stream.validate()?;
+
+        Ok(stream)
+    }
+
+    /// Checks the rules every stream has to follow: the frequency is positive with a period of at
+    /// least a microsecond, the burst isn't longer than the period, and the offset and period fit
+    /// into the 32 bit tick counter even at one microsecond per tick
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let period = self.frequency.as_duration();
+        if self.frequency.0.is_nan() || self.frequency.0 <= 0. || period.as_micros() == 0 {
+            return Err(ConfigError::ZeroFrequency);
+        }
+        if self.burst_duration > period {
+            return Err(ConfigError::BurstExceedsPeriod);
+        }
+        let longest_delay = self
+            .offset
+            .checked_add(period)
+            .map(|delay| delay.as_micros());
+        if longest_delay.is_none_or(|delay| delay > u32::MAX as u64) {
+            return Err(ConfigError::DelayOverflow);
+        }
+
+        Ok(())
+    }
+
+    /// Stream that shows `color` all the time. It's a single burst filling a long period, so the
+    /// iterator only emits a step every `SOLID_PERIOD`.
+    pub fn solid(color: Color) -> Self {
+        let frequency = Hz(1e6 / SOLID_PERIOD.as_micros() as f32);
+        Self::new(color, frequency, frequency.as_duration(), None)
+    }
+
+    /// Like `try_new`, with the frequency in beats per minute, e.g. a strobe on every beat of a
+    /// 128 BPM track
+    pub fn try_new_bpm(
+        color: Color,
+        bpm: Bpm,
+        burst_duration: Duration,
+        offset: Option<Duration>,
+    ) -> Result<Self, ConfigError> {
+        if bpm.0.is_nan() || bpm.0 <= 0. {
+            return Err(ConfigError::ZeroFrequency);
+        }
+
+        Self::try_new(color, bpm.into(), burst_duration, offset)
+    }
+
+    /// Like `try_new`, but the stream is on for the fraction `duty` (`0 < duty <= 1`) of its
+    /// period, which keeps the perceived brightness the same across frequencies
+    pub fn try_new_with_duty(
+        color: Color,
+        frequency: Hz,
+        duty: f32,
+        offset: Option<Duration>,
+    ) -> Result<Self, ConfigError> {
+        if duty.is_nan() || duty <= 0. || duty > 1. {
+            return Err(ConfigError::InvalidDuty);
+        }
+
+        let period = frequency.as_duration();
+        let burst_duration = Duration::from_micros((period.as_micros() as f32 * duty) as u64);
+
+        Self::try_new(color, frequency, burst_duration, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::{Duration, Instant};
+
+    use super::{
+        Bpm, Color, ColorCorrection, ColorStep, Config, ConfigError, DimmingCurve, Hz, MixMode,
+        StreamConfig, TimingGroup,
+    };
+
+    fn streams() -> [StreamConfig; 2] {
+        [
+            StreamConfig::new(Color(255, 0, 0), Hz(100.), Duration::from_millis(2), None),
+            StreamConfig::new(
+                Color(0, 0, 255),
+                Hz(50.),
+                Duration::from_millis(5),
+                Some(Duration::from_millis(1)),
+            ),
+        ]
+    }
+
+    fn steps(count: usize) -> impl Iterator<Item = ColorStep> {
+        Config::<2>::new(&streams(), 1, 0).into_iter().take(count)
+    }
+
+    #[test]
+    fn arrays_round_trip_in_rgb_order() {
+        let color = Color(1, 2, 3);
+
+        assert_eq!(color.as_array(), [1, 2, 3]);
+        assert_eq!(Color::from_array(color.as_array()), color);
+        assert_eq!(Color::from_array([255, 0, 128]).as_array(), [255, 0, 128]);
+        assert!(color.components().eq([1, 2, 3]));
+    }
+
+    #[test]
+    fn full_scale_keeps_the_color() {
+        assert_eq!(Color(255, 100, 1).scaled(255), Color(255, 100, 1));
+    }
+
+    #[test]
+    fn zero_scale_is_black() {
+        assert_eq!(Color(255, 100, 1).scaled(0), Color::black());
+    }
+
+    #[test]
+    fn scaling_rounds_to_nearest() {
+        // 128/255 of 255, 101 and 1 are 128, 50.7 and 0.502
+        assert_eq!(Color(255, 101, 1).scaled(128), Color(128, 51, 1));
+        // 1/255 of 127 is just below a half
+        assert_eq!(Color(127, 128, 0).scaled(1), Color(0, 1, 0));
+    }
+
+    #[test]
+    fn color_correction_scales_and_rounds_every_channel() {
+        let correction = ColorCorrection {
+            r_scale: 255,
+            g_scale: 200,
+            b_scale: 127,
+        };
+        let at = |level| correction.apply(Color(level, level, level));
+
+        assert_eq!(at(0), Color(0, 0, 0));
+        // 128 * 200 / 255 is 100.39, 128 * 127 / 255 is 63.75
+        assert_eq!(at(128), Color(128, 100, 64));
+        assert_eq!(at(255), Color(255, 200, 127));
+        assert_eq!(
+            ColorCorrection::IDENTITY.apply(Color(0, 128, 255)),
+            Color(0, 128, 255)
+        );
+    }
+
+    #[test]
+    fn lerp_ends_at_both_colors() {
+        let (a, b) = (Color(0, 255, 10), Color(255, 0, 20));
+
+        assert_eq!(Color::lerp(a, b, 0), a);
+        assert_eq!(Color::lerp(a, b, 255), b);
+    }
+
+    #[test]
+    fn lerp_midpoint_rounds_per_component() {
+        let (a, b) = (Color(0, 255, 10), Color(255, 0, 21));
+
+        // 128/255 of the way, the components are 128.0, 127.0 and 15.52
+        assert_eq!(Color::lerp(a, b, 128), Color(128, 127, 16));
+        assert_eq!(Color::lerp(b, a, 127), Color(128, 127, 16));
+    }
+
+    #[test]
+    fn dimming_curves_match_their_formulas() {
+        let at = |curve: DimmingCurve| [0, 64, 128, 255].map(|component| curve.map(component));
+
+        assert_eq!(at(DimmingCurve::Linear), [0, 64, 128, 255]);
+        // 256 ^ (x / 255) - 1 is 3.02 and 15.17 at 64 and 128
+        assert_eq!(at(DimmingCurve::Logarithmic), [0, 3, 15, 255]);
+        // A lightness of 25.1 and 50.2 is a luminance of 4.4% and 18.6%
+        assert_eq!(at(DimmingCurve::Cie1931), [0, 11, 47, 255]);
+    }
+
+    #[test]
+    fn dimming_curves_are_parsed_by_name() {
+        for curve in DimmingCurve::ALL {
+            assert_eq!(curve.name().parse(), Ok(curve));
+        }
+        assert_eq!("CIE1931".parse::<DimmingCurve>(), Err(()));
+    }
+
+    #[test]
+    fn steps_follow_the_timeline() {
+        let reference = Config::<2>::new(&streams(), 1, 0);
+
+        let mut time = Instant::MIN;
+        // The first step ends where the first stream starts, right away here
+        for step in steps(100).skip(1) {
+            let end = time + Duration::from_micros(step.delay as u64);
+            assert_eq!(step.color, reference.color_at(time));
+            assert_eq!(
+                step.color,
+                reference.color_at(end - Duration::from_micros(1))
+            );
+            assert_ne!(step.color, reference.color_at(end));
+            time = end;
+        }
+    }
+
+    #[test]
+    fn changes_past_the_timeline_saturate() {
+        let color = Color(255, 0, 0);
+        let period = Duration::from_millis(10);
+        // The timeline ends 1615 µs into the last period
+        let last_period = Instant::MAX - Duration::from_micros(Instant::MAX.as_micros() % 10_000);
+        assert_eq!(Instant::MAX - last_period, Duration::from_micros(1615));
+
+        // Ends during the burst, and after it
+        for burst in [Duration::from_millis(2), Duration::from_millis(1)] {
+            let stream = StreamConfig::new(color, Hz(100.), burst, None);
+            for before_end in [600, 1, 0] {
+                let instant = Instant::MAX - Duration::from_micros(before_end);
+                assert_eq!(stream.get_next_change_after(Some(instant)), Instant::MAX);
+            }
+            // The period before still ends in time
+            let before = last_period - period + burst + Duration::from_micros(1);
+            assert_eq!(stream.get_next_change_after(Some(before)), last_period);
+        }
+    }
+
+    #[test]
+    fn short_bursts_are_extended_to_the_min_on_time() {
+        let stream = StreamConfig::new(Color(255, 0, 0), Hz(100.), Duration::from_micros(20), None);
+        let config = Config::<1>::new(&[stream], 1, 0).with_min_on_time(500);
+
+        let steps: heapless::Vec<_, 4> = config
+            .into_iter()
+            .take(4)
+            .map(|step| (step.color, step.delay))
+            .collect();
+        // The burst ends after the min time, not with the next change after it
+        let red = Color(255, 0, 0);
+        assert_eq!(
+            steps,
+            [
+                (red, 500),
+                (Color::black(), 9500),
+                (red, 500),
+                (Color::black(), 9500)
+            ]
+        );
+
+        // A burst still on when the next one starts is merged with it
+        let close = Config::<1>::new(
+            &[StreamConfig::new(
+                red,
+                Hz(1000.),
+                Duration::from_micros(900),
+                None,
+            )],
+            1,
+            0,
+        )
+        .with_min_on_time(1050);
+        let steps: heapless::Vec<_, 4> = close
+            .into_iter()
+            .take(4)
+            .map(|step| (step.color, step.delay))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                (red, 1900),
+                (Color::black(), 100),
+                (red, 1900),
+                (Color::black(), 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn random_colors_only_depend_on_the_seed() {
+        let white = StreamConfig::new(
+            Color(255, 255, 255),
+            Hz(100.),
+            Duration::from_millis(2),
+            None,
+        );
+        let burst = |stream: &StreamConfig, n: u64| {
+            stream.get_color_at_instant(Instant::from_micros(n * 10_000 + 1000))
+        };
+        let colors = |seed| -> [Color; 8] {
+            let stream = white.with_random_colors(seed);
+            core::array::from_fn(|n| burst(&stream, n as u64))
+        };
+
+        assert_eq!(colors(7), colors(7));
+        assert_ne!(colors(7), colors(8));
+        // Every burst gets a new color, which holds over the whole burst
+        let sequence = colors(7);
+        assert!(sequence.windows(2).all(|pair| pair[0] != pair[1]));
+        let stream = white.with_random_colors(7);
+        assert_eq!(stream.get_color_at_instant(Instant::MIN), sequence[0]);
+        assert_eq!(
+            stream.get_color_at_instant(Instant::from_micros(1999)),
+            sequence[0]
+        );
+        assert_eq!(
+            stream.get_color_at_instant(Instant::from_micros(2000)),
+            Color::black()
+        );
+
+        // The stream's color limits every channel
+        let red = StreamConfig {
+            color: Color(255, 0, 0),
+            ..white
+        }
+        .with_random_colors(7);
+        for n in 0..8 {
+            assert_eq!(burst(&red, n), Color(sequence[n as usize].0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn grouped_streams_keep_their_phases() {
+        let group = TimingGroup::new(Hz(100.), Some(Duration::from_millis(1)));
+        let burst = Duration::from_millis(2);
+        let red = group.stream(Color(255, 0, 0), burst, Duration::from_ticks(0));
+        let blue = group.stream(Color(0, 0, 255), burst, Duration::from_millis(5));
+
+        for stream in [red, blue] {
+            assert_eq!(stream.frequency.0, 100.);
+        }
+        assert_eq!(red.offset, Duration::from_millis(1));
+        assert_eq!(blue.offset, Duration::from_millis(6));
+
+        // Blue follows red by half a period in every period
+        for period in 0..5 {
+            let start = Instant::from_millis(1 + period * 10);
+            assert_eq!(red.get_color_at_instant(start), Color(255, 0, 0));
+            assert_eq!(
+                blue.get_color_at_instant(start + Duration::from_millis(5)),
+                Color(0, 0, 255)
+            );
+            assert_eq!(blue.get_color_at_instant(start), Color::black());
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn grouped_phases_are_shorter_than_the_period() {
+        TimingGroup::new(Hz(100.), None).stream(
+            Color(255, 0, 0),
+            Duration::from_millis(2),
+            Duration::from_millis(10),
+        );
+    }
+
+    #[test]
+    fn solid_streams_never_turn_off() {
+        let color = Color(12, 34, 56);
+        let solid = StreamConfig::solid(color);
+        let config = Config::<1>::new(&[solid], 1, 0);
+
+        let steps: heapless::Vec<_, 5> = config
+            .into_iter()
+            .take(5)
+            .map(|step| (step.color, step.delay))
+            .collect();
+        // Like every stream, it starts right away, then only the period renews it
+        assert_eq!(
+            steps,
+            [
+                (color, 0),
+                (color, 1_000_000),
+                (color, 1_000_000),
+                (color, 1_000_000),
+                (color, 1_000_000)
+            ]
+        );
+        // In between the steps as well
+        for micros in [0, 1, 999_999, 1_000_000, 1_500_000] {
+            assert_eq!(
+                solid.get_color_at_instant(Instant::from_micros(micros)),
+                color
+            );
+        }
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut iterator = Config::<2>::new(&streams(), 1, 0).into_iter();
+        let first: [_; 10] = core::array::from_fn(|_| iterator.next().unwrap());
+        iterator.reset();
+
+        for step in first {
+            let again = iterator.next().unwrap();
+            assert_eq!((step.color, step.delay), (again.color, again.delay));
+        }
+    }
+
+    #[test]
+    fn long_delays_are_split_into_max_steps() {
+        let color = Color(10, 20, 30);
+        let delay = 3 * ColorStep::MAX_DELAY + 1000;
+        let mut step = ColorStep { color, delay };
+
+        let mut splits = 0;
+        while let Some(split) = step.split_off(0) {
+            assert_eq!((split.color, split.delay), (color, ColorStep::MAX_DELAY));
+            splits += 1;
+        }
+
+        assert_eq!(splits, 3);
+        assert_eq!((step.color, step.delay), (color, 1000));
+    }
+
+    #[test]
+    fn split_steps_deduct_their_overhead() {
+        let mut step = ColorStep {
+            color: Color(1, 2, 3),
+            delay: ColorStep::MAX_DELAY + 100,
+        };
+
+        step.split_off(5).unwrap();
+        assert_eq!(step.delay, 95);
+        assert!(step.split_off(5).is_none());
+    }
+
+    #[test]
+    fn tick_overhead_is_deducted() {
+        let without = steps(10);
+        let with = Config::<2>::new(&streams(), 1, 5).into_iter();
+
+        for (without, with) in without.zip(with) {
+            assert_eq!(with.delay, without.delay.saturating_sub(5));
+        }
+    }
+
+    #[test]
+    fn max_mix_doesnt_wash_out() {
+        let burst = Duration::from_millis(5);
+        let streams = [
+            StreamConfig::new(Color(200, 100, 0), Hz(100.), burst, None),
+            StreamConfig::new(Color(100, 200, 0), Hz(100.), burst, None),
+        ];
+        let config = Config::<2>::new(&streams, 1, 0);
+
+        assert_eq!(config.color_at(Instant::MIN), Color(255, 255, 0));
+        assert_eq!(
+            config.with_mix_mode(MixMode::Max).color_at(Instant::MIN),
+            Color(200, 200, 0)
+        );
+    }
+
+    #[test]
+    fn gradients_sweep_over_the_burst() {
+        let (from, to) = (Color(255, 0, 0), Color(0, 0, 255));
+        let stream =
+            StreamConfig::new(from, Hz(100.), Duration::from_millis(8), None).with_gradient(to);
+        let config = Config::<1>::new(&[stream], 1, 0);
+        let at = |micros| config.color_at(Instant::MIN + Duration::from_micros(micros));
+
+        // 1 ms per step, the fourth of seven mixes 3/7 and 4/7
+        assert_eq!(at(0), from);
+        assert_eq!(at(999), from);
+        assert_eq!(at(4000), Color(109, 0, 146));
+        assert_eq!(at(7000), to);
+        assert_eq!(at(7999), to);
+        assert_eq!(at(10_000), from);
+    }
+
+    #[test]
+    fn gradients_are_black_after_the_burst() {
+        let stream = StreamConfig::new(Color(255, 0, 0), Hz(100.), Duration::from_millis(8), None)
+            .with_gradient(Color(0, 0, 255));
+        let config = Config::<1>::new(&[stream], 1, 0);
+
+        for micros in [8000, 9000, 9999] {
+            assert_eq!(
+                config.color_at(Instant::MIN + Duration::from_micros(micros)),
+                Color::black()
+            );
+        }
+    }
+
+    #[test]
+    fn sampled_steps_are_evenly_spaced() {
+        let interval = Duration::from_micros(700);
+        let config = Config::<2>::new(&streams(), 1, 0).with_sample_interval(interval);
+        let reference = Config::<2>::new(&streams(), 1, 0);
+
+        let mut time = Instant::MIN;
+        for step in config.into_iter().take(100) {
+            assert_eq!(step.delay as u64, interval.as_micros());
+            assert_eq!(step.color, reference.color_at(time));
+            time += interval;
+        }
+    }
+
+    #[test]
+    fn loop_period_of_commensurate_streams() {
+        let config = Config::<2>::new(&streams(), 1, 0);
+
+        assert_eq!(config.loop_period(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn loop_period_of_incommensurate_streams() {
+        let burst = Duration::from_micros(100);
+        let stream = |frequency| StreamConfig::new(Color(255, 0, 0), Hz(frequency), burst, None);
+
+        let droplets = Config::<3>::new(&[stream(60.), stream(60.5), stream(59.5)], 1, 0);
+        let period = droplets.loop_period().as_micros();
+        for stream in droplets.streams {
+            assert_eq!(period % stream.frequency.as_duration().as_micros(), 0);
+        }
+
+        // Periods of over an hour only share small factors, so the loop doesn't fit
+        let unrelated =
+            Config::<3>::new(&[stream(0.00025), stream(0.000251), stream(0.000253)], 1, 0);
+        assert_eq!(unrelated.loop_period(), Duration::MAX);
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected_with_their_reason() {
+        let color = Color(255, 0, 0);
+        let stream = |frequency, burst, offset| {
+            StreamConfig::try_new(color, Hz(frequency), burst, offset).unwrap_err()
+        };
+
+        assert_eq!(
+            stream(100., Duration::from_millis(11), None),
+            ConfigError::BurstExceedsPeriod
+        );
+        assert_eq!(
+            stream(0., Duration::from_ticks(0), None),
+            ConfigError::ZeroFrequency
+        );
+        // 2^32 µs, just past the tick counter
+        assert_eq!(
+            stream(1., Duration::from_ticks(0), Some(Duration::from_secs(4295))),
+            ConfigError::DelayOverflow
+        );
+
+        let streams = streams();
+        assert_eq!(
+            Config::<1>::try_new(&streams, 1, 0).unwrap_err(),
+            ConfigError::TooManyStreams
+        );
+        assert!(Config::<2>::try_new(&streams, 1, 0).is_ok());
+    }
+
+    #[test]
+    fn frequencies_need_a_positive_period() {
+        for frequency in [0., -1., -100., f32::NAN, f32::NEG_INFINITY] {
+            let stream = StreamConfig {
+                frequency: Hz(frequency),
+                ..streams()[0]
+            };
+            assert_eq!(stream.validate().unwrap_err(), ConfigError::ZeroFrequency);
+        }
+        // The period rounds down to 0 µs
+        let stream = StreamConfig {
+            frequency: Hz(2e6),
+            burst_duration: Duration::from_ticks(0),
+            ..streams()[0]
+        };
+        assert_eq!(stream.validate().unwrap_err(), ConfigError::ZeroFrequency);
+        assert!(streams().iter().all(|stream| stream.validate().is_ok()));
+    }
+
+    #[test]
+    fn periods_are_capped() {
+        const CAP: Duration = Duration::from_micros(1 << 40);
+
+        assert_eq!(Hz(100.).as_duration(), Duration::from_millis(10));
+        assert_eq!(Hz(1e-9).as_duration(), CAP);
+        assert_eq!(Hz(0.).as_duration(), CAP);
+        assert_eq!(Hz(f32::MIN_POSITIVE).as_duration(), CAP);
+        // Too long for the tick counter either way
+        let stream = StreamConfig {
+            frequency: Hz(1e-9),
+            burst_duration: Duration::from_ticks(0),
+            ..streams()[0]
+        };
+        assert_eq!(stream.validate().unwrap_err(), ConfigError::DelayOverflow);
+    }
+
+    #[test]
+    fn duty_is_a_fraction_of_the_period() {
+        let color = Color(255, 0, 0);
+
+        let stream = StreamConfig::try_new_with_duty(color, Hz(100.), 0.25, None).unwrap();
+        assert_eq!(stream.burst_duration, Duration::from_micros(2500));
+
+        for duty in [0., -0.5, 1.5, f32::NAN] {
+            assert_eq!(
+                StreamConfig::try_new_with_duty(color, Hz(100.), duty, None).unwrap_err(),
+                ConfigError::InvalidDuty
+            );
+        }
+        let full = StreamConfig::try_new_with_duty(color, Hz(100.), 1., None).unwrap();
+        assert_eq!(full.burst_duration, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn bpm_converts_to_hz() {
+        assert_eq!(Hz::from(Bpm(120.)).0, 2.);
+        assert_eq!(Bpm::from(Hz(2.)).0, 120.);
+        assert_eq!(Bpm::from(Hz::from(Bpm(128.))).0, 128.);
+        assert_eq!(Hz::from(Bpm(-60.)).0, -1.);
+
+        let stream =
+            StreamConfig::try_new_bpm(Color(255, 0, 0), Bpm(128.), Duration::from_millis(10), None)
+                .unwrap();
+        // 60 / 128 s, give or take the rounding of the frequency
+        let period = stream.frequency.as_duration().as_micros();
+        assert!(period.abs_diff(468_750) <= 1);
+
+        assert_eq!(
+            StreamConfig::try_new(
+                Color(255, 0, 0),
+                Bpm(0.).into(),
+                Duration::from_ticks(0),
+                None
+            )
+            .unwrap_err(),
+            ConfigError::ZeroFrequency
+        );
+        for bpm in [0., -128., f32::NAN] {
+            assert_eq!(
+                StreamConfig::try_new_bpm(
+                    Color(255, 0, 0),
+                    Bpm(bpm),
+                    Duration::from_ticks(0),
+                    None
+                )
+                .unwrap_err(),
+                ConfigError::ZeroFrequency
+            );
         }
     }
 }
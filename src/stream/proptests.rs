@@ -0,0 +1,128 @@
+//! Random stream configs fed to the iterator, to catch the edge cases of the modular arithmetic.
+//! Only built with the `std` feature, see the README.
+
+use embassy_time::{Duration, Instant};
+use proptest::{collection, option, prelude::*};
+
+use super::{
+    Color, ColorCorrection, ColorStep, Config, DimmingCurve, Gamma, Hz, MixMode, StreamConfig,
+};
+
+const MAX_STREAMS: usize = 8;
+
+/// Steps taken from every config, enough to wrap around the shorter periods several times
+const STEPS: usize = 256;
+
+fn color() -> impl Strategy<Value = Color> {
+    any::<[u8; 3]>().prop_map(Color::from_array)
+}
+
+/// Streams that pass validation, with the burst given as a fraction of the period
+fn stream() -> impl Strategy<Value = StreamConfig> {
+    (
+        color(),
+        0.01_f32..100_000.,
+        0.0_f64..=1.,
+        0_u64..10_000_000,
+        option::of(color()),
+        option::of(any::<u16>()),
+    )
+        .prop_filter_map(
+            "invalid stream",
+            |(color, hz, burst, offset, end_color, seed)| {
+                let frequency = Hz(hz);
+                let period = frequency.as_duration().as_micros() as f64;
+                let burst = Duration::from_micros((period * burst) as u64);
+                let offset = Some(Duration::from_micros(offset));
+                let stream = StreamConfig::try_new(color, frequency, burst, offset).ok()?;
+
+                Some(match (end_color, seed) {
+                    (Some(end_color), _) => stream.with_gradient(end_color),
+                    (None, Some(seed)) => stream.with_random_colors(seed),
+                    (None, None) => stream,
+                })
+            },
+        )
+}
+
+fn dimming_curve() -> impl Strategy<Value = DimmingCurve> {
+    prop_oneof![
+        Just(DimmingCurve::Linear),
+        Just(DimmingCurve::Logarithmic),
+        Just(DimmingCurve::Cie1931),
+    ]
+}
+
+fn mix_mode() -> impl Strategy<Value = MixMode> {
+    prop_oneof![Just(MixMode::Additive), Just(MixMode::Max)]
+}
+
+/// Configs accepted by `Config::try_new`, with every output setting randomized
+fn config() -> impl Strategy<Value = Config<MAX_STREAMS>> {
+    (
+        collection::vec(stream(), 0..=MAX_STREAMS),
+        1_i32..=128,
+        0_i32..=8,
+        (any::<[u8; 3]>(), 0.5_f32..=3., dimming_curve(), mix_mode()),
+        option::of(1_u64..1_000_000),
+        // Every change within the minimum on time is skipped one by one, so long ones with fast
+        // streams take a while
+        0_u32..=64,
+    )
+        .prop_filter_map(
+            "invalid config",
+            |(streams, micros_per_tick, tick_overhead, output, sample_interval, min_on_ticks)| {
+                let ([r_scale, g_scale, b_scale], gamma, dimming_curve, mix_mode) = output;
+                let config = Config::try_new(&streams, micros_per_tick, tick_overhead).ok()?;
+                let config = config
+                    .with_color_correction(ColorCorrection {
+                        r_scale,
+                        g_scale,
+                        b_scale,
+                    })
+                    .with_gamma(Gamma::new(gamma))
+                    .with_dimming_curve(dimming_curve)
+                    .with_mix_mode(mix_mode)
+                    .with_min_on_time(min_on_ticks);
+
+                Some(match sample_interval {
+                    Some(interval) => config.with_sample_interval(Duration::from_micros(interval)),
+                    None => config,
+                })
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn next_never_panics(config in config()) {
+        let tick_overhead = config.tick_overhead as u32;
+        let mut iterator = config.into_iter();
+
+        for _ in 0..STEPS {
+            let mut step = iterator.next();
+            prop_assert!(step.is_some());
+
+            // What the LED orchestrator pushes to the state machines
+            while let Some(split) = step.as_mut().and_then(|step| step.split_off(tick_overhead)) {
+                prop_assert!(split.delay <= ColorStep::MAX_DELAY);
+            }
+            prop_assert!(step.unwrap().delay <= ColorStep::MAX_DELAY);
+        }
+    }
+
+    #[test]
+    fn color_at_never_panics(config in config(), micros in any::<u64>()) {
+        config.color_at(Instant::from_micros(micros));
+    }
+
+    #[test]
+    fn steps_are_encoded_losslessly(color in color(), delay in 0..=ColorStep::MAX_DELAY) {
+        let [r, g, b] = ColorStep { color, delay }.encode();
+
+        for (word, component) in [r, g, b].into_iter().zip(color.components()) {
+            prop_assert_eq!(word >> 24, component as u32);
+            prop_assert_eq!(word & ColorStep::MAX_DELAY, delay);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use embassy_rp::{
+    adc::{Adc, Async, Channel, Config, InterruptHandler},
+    bind_interrupts,
+};
+
+use super::chip_temperature;
+use crate::peripherals::TemperaturePeripherals;
+
+bind_interrupts!(struct Irqs {
+    ADC_IRQ_FIFO => InterruptHandler;
+});
+
+/// Sensor on the RP2040 die, read through the ADC
+pub struct TemperatureSensor {
+    adc: Adc<'static, Async>,
+    channel: Channel<'static>,
+}
+
+impl TemperatureSensor {
+    pub fn new(p: TemperaturePeripherals) -> Self {
+        Self {
+            adc: Adc::new(p.adc, Irqs, Config::default()),
+            channel: Channel::new_temp_sensor(p.sensor),
+        }
+    }
+
+    /// Temperature of the chip in °C, `None` if the conversion failed
+    pub async fn read(&mut self) -> Option<f32> {
+        self.adc
+            .read(&mut self.channel)
+            .await
+            .ok()
+            .map(chip_temperature)
+    }
+}
@@ -0,0 +1,33 @@
+#[cfg(target_os = "none")]
+pub use driver::TemperatureSensor;
+
+#[cfg(target_os = "none")]
+mod driver;
+
+/// Reference voltage of the ADC, the 3.3 V supply of the Pico W
+const ADC_REFERENCE_VOLTS: f32 = 3.3;
+
+/// Full scale of the 12 bit ADC
+const ADC_FULL_SCALE: f32 = 4096.;
+
+/// Temperature of the RP2040 in °C from a reading of its sensor, following the datasheet:
+/// 0.706 V at 27 °C, falling by 1.721 mV per degree
+pub fn chip_temperature(raw: u16) -> f32 {
+    let volts = raw as f32 * ADC_REFERENCE_VOLTS / ADC_FULL_SCALE;
+
+    27. - (volts - 0.706) / 0.001721
+}
+
+#[cfg(test)]
+mod tests {
+    use super::chip_temperature;
+
+    #[test]
+    fn readings_convert_to_degrees() {
+        // 0.706 V is 876.2 of the full scale
+        assert!((chip_temperature(876) - 27.).abs() < 0.5);
+        // Warmer makes the voltage drop
+        assert!(chip_temperature(800) > chip_temperature(876));
+        assert!((chip_temperature(800) - 62.5).abs() < 0.5);
+    }
+}
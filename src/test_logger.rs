@@ -0,0 +1,19 @@
+//! Discards the defmt output of the host tests, which have neither RTT nor a probe to read it
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    core::panic!("defmt panic")
+}
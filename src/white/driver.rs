@@ -0,0 +1,69 @@
+use embassy_futures::select::{Either3, select3};
+use embassy_rp::pwm::{self, Pwm};
+use embassy_sync::pubsub::WaitResult;
+
+use super::{SHUTDOWN, WhiteMix};
+use crate::{
+    led_orchestrator::light_receiver,
+    mqtt::{Command, MqttRxSubscriber, MqttTxSender, RxPacket, TxPacket, record_lag},
+    peripherals::WhitePeripherals,
+};
+
+/// Drives the warm and cool white channels with plain PWM. Unlike the RGB channels they aren't
+/// strobed, since the PIO block driving those has no state machines left. They follow the on-state
+/// and brightness of the light, and stay off until a color temperature has been set.
+#[embassy_executor::task]
+pub async fn white_task(
+    p: WhitePeripherals,
+    mut subscriber: MqttRxSubscriber<'static>,
+    sender: MqttTxSender<'static>,
+) {
+    let mut config = pwm::Config::default();
+    config.top = 254;
+    let mut pwm = Pwm::new_output_ab(p.slice, p.warm_pin, p.cool_pin, config.clone());
+
+    let mut light_receiver = light_receiver();
+    let mut light = light_receiver.try_get().flatten();
+    let mut mireds = None;
+
+    loop {
+        match select3(
+            subscriber.next_message(),
+            light_receiver.changed(),
+            SHUTDOWN.wait(),
+        )
+        .await
+        {
+            Either3::First(WaitResult::Lagged(num)) => {
+                record_lag(num);
+                continue;
+            }
+            Either3::First(WaitResult::Message(RxPacket::Connected { session_present })) => {
+                if !session_present {
+                    sender
+                        .send(TxPacket::Subscribe(&["picow/light/color_temp/set"]))
+                        .await;
+                }
+                continue;
+            }
+            Either3::First(WaitResult::Message(RxPacket::Command {
+                command: Command::ColorTemperature(temperature),
+                ..
+            })) => mireds = Some(temperature),
+            Either3::First(WaitResult::Message(_)) => continue,
+            Either3::Second(changed) => light = changed,
+            Either3::Third(stopped) => {
+                config.compare_a = 0;
+                config.compare_b = 0;
+                pwm.set_config(&config);
+                stopped.signal(());
+                return;
+            }
+        }
+
+        let mix = mireds.map_or(WhiteMix::OFF, |mireds| WhiteMix::for_light(mireds, light));
+        config.compare_a = mix.warm.into();
+        config.compare_b = mix.cool.into();
+        pwm.set_config(&config);
+    }
+}
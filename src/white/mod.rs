@@ -0,0 +1,111 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+#[cfg(target_os = "none")]
+pub use driver::white_task;
+
+use crate::led_orchestrator::LightState;
+
+#[cfg(target_os = "none")]
+mod driver;
+
+/// Coolest supported color temperature, 6500 K
+pub const MIN_MIREDS: u16 = 153;
+/// Warmest supported color temperature, 2000 K
+pub const MAX_MIREDS: u16 = 500;
+
+/// Turns the white channels off for good, before sleeping. The task signals the given signal once
+/// they are off.
+pub static SHUTDOWN: Signal<CriticalSectionRawMutex, &'static Signal<CriticalSectionRawMutex, ()>> =
+    Signal::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WhiteMix {
+    pub warm: u8,
+    pub cool: u8,
+}
+
+impl WhiteMix {
+    pub const OFF: WhiteMix = WhiteMix { warm: 0, cool: 0 };
+
+    /// Splits `level` between the warm and the cool channel, so that mixing them results in the
+    /// given color temperature. Temperatures outside the supported range are clamped.
+    pub fn from_mireds(mireds: u16, level: u8) -> WhiteMix {
+        let mireds = mireds.clamp(MIN_MIREDS, MAX_MIREDS);
+        let range = (MAX_MIREDS - MIN_MIREDS) as u32;
+        let warm = (level as u32 * (mireds - MIN_MIREDS) as u32 + range / 2) / range;
+        let warm = warm as u8;
+
+        WhiteMix {
+            warm,
+            cool: level - warm,
+        }
+    }
+
+    /// Mix of the color temperature at the brightness of `light`. The channels stay off until the
+    /// light state has been restored, like the effect.
+    pub fn for_light(mireds: u16, light: Option<LightState>) -> WhiteMix {
+        let level = match light {
+            Some(LightState {
+                on: true,
+                brightness,
+                ..
+            }) => brightness,
+            _ => 0,
+        };
+
+        WhiteMix::from_mireds(mireds, level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_MIREDS, MIN_MIREDS, WhiteMix};
+    use crate::led_orchestrator::LightState;
+
+    #[test]
+    fn extremes_use_a_single_channel() {
+        assert_eq!(
+            WhiteMix::from_mireds(MIN_MIREDS, 200),
+            WhiteMix { warm: 0, cool: 200 }
+        );
+        assert_eq!(
+            WhiteMix::from_mireds(MAX_MIREDS, 200),
+            WhiteMix { warm: 200, cool: 0 }
+        );
+        assert_eq!(
+            WhiteMix::from_mireds(0, 200),
+            WhiteMix::from_mireds(MIN_MIREDS, 200)
+        );
+        assert_eq!(
+            WhiteMix::from_mireds(u16::MAX, 200),
+            WhiteMix::from_mireds(MAX_MIREDS, 200)
+        );
+    }
+
+    #[test]
+    fn midpoint_splits_evenly() {
+        let mix = WhiteMix::from_mireds((MIN_MIREDS + MAX_MIREDS).div_ceil(2), u8::MAX);
+
+        assert_eq!(mix.warm + mix.cool, u8::MAX);
+        assert!(mix.warm.abs_diff(mix.cool) <= 1);
+    }
+
+    #[test]
+    fn mix_follows_the_light() {
+        let light = LightState {
+            on: true,
+            brightness: 100,
+            color: None,
+        };
+
+        assert_eq!(
+            WhiteMix::for_light(MAX_MIREDS, Some(light)),
+            WhiteMix { warm: 100, cool: 0 }
+        );
+        assert_eq!(
+            WhiteMix::for_light(MAX_MIREDS, Some(LightState { on: false, ..light })),
+            WhiteMix::OFF
+        );
+        assert_eq!(WhiteMix::for_light(MAX_MIREDS, None), WhiteMix::OFF);
+    }
+}